@@ -0,0 +1,70 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// Benchmarks of frame header encoding and decoding and of masking.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use soketto::{
+	base::{Codec, Header, OpCode},
+	Parsing,
+};
+
+/// Payload lengths covering the three header length encodings.
+const LENGTHS: [usize; 3] = [100, 1000, 100_000];
+
+/// Sizes of the data to mask.
+const SIZES: [usize; 4] = [64, 1024, 16 * 1024, 256 * 1024];
+
+fn header(len: usize) -> Header {
+	let mut header = Header::new(OpCode::Binary);
+	header.set_masked(true).set_mask(0x1234_5678).set_payload_len(len);
+	header
+}
+
+fn encode_header(c: &mut Criterion) {
+	let mut group = c.benchmark_group("encode_header");
+	let mut codec = Codec::new();
+	for len in LENGTHS {
+		let header = header(len);
+		group.bench_with_input(BenchmarkId::from_parameter(len), &header, |b, header| {
+			b.iter(|| codec.encode_header(black_box(header)).len())
+		});
+	}
+	group.finish()
+}
+
+fn decode_header(c: &mut Criterion) {
+	let mut group = c.benchmark_group("decode_header");
+	let mut codec = Codec::new();
+	for len in LENGTHS {
+		let bytes = codec.encode_header(&header(len)).to_vec();
+		group.bench_with_input(BenchmarkId::from_parameter(len), &bytes, |b, bytes| {
+			b.iter(|| match codec.decode_header(black_box(bytes)) {
+				Ok(Parsing::Done { value, .. }) => value,
+				_ => panic!("complete header"),
+			})
+		});
+	}
+	group.finish()
+}
+
+fn apply_mask(c: &mut Criterion) {
+	let mut group = c.benchmark_group("apply_mask");
+	let header = header(0);
+	for size in SIZES {
+		let mut data = vec![0xa5; size];
+		group.throughput(Throughput::Bytes(size as u64));
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			b.iter(|| Codec::apply_mask(&header, black_box(&mut data)))
+		});
+	}
+	group.finish()
+}
+
+criterion_group!(benches, encode_header, decode_header, apply_mask);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// Benchmarks of messages sent to a peer and back over an in-memory stream.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use soketto::connection::{Builder, Mode};
+use std::time::{Duration, Instant};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+/// Sizes of the messages to echo.
+const SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+/// Send `iters` messages of the given size from a client to a server and back.
+async fn echo(size: usize, iters: u64) -> Duration {
+	let (client, server) = tokio::io::duplex(4 * size + 1024);
+	let (mut client_tx, mut client_rx) = Builder::new(client.compat(), Mode::Client).finish();
+	let (mut server_tx, mut server_rx) = Builder::new(server.compat(), Mode::Server).finish();
+	let data = vec![0xa5; size];
+	let mut message = Vec::with_capacity(size);
+	let start = Instant::now();
+	for _ in 0..iters {
+		client_tx.send_binary(&data).await.unwrap();
+		client_tx.flush().await.unwrap();
+		message.clear();
+		server_rx.receive_data(&mut message).await.unwrap();
+		server_tx.send_binary_mut(&mut message).await.unwrap();
+		server_tx.flush().await.unwrap();
+		message.clear();
+		client_rx.receive_data(&mut message).await.unwrap();
+	}
+	start.elapsed()
+}
+
+fn round_trip(c: &mut Criterion) {
+	let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+	let mut group = c.benchmark_group("round_trip");
+	for size in SIZES {
+		group.throughput(Throughput::Bytes(2 * size as u64));
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			b.iter_custom(|iters| runtime.block_on(echo(size, iters)))
+		});
+	}
+	group.finish()
+}
+
+criterion_group!(benches, round_trip);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// Benchmarks of compressing and decompressing messages with the deflate extension.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use soketto::{
+	base::{Header, OpCode},
+	extension::{deflate::Deflate, Extension},
+	Mode, Storage,
+};
+
+/// Sizes of the messages to compress.
+const SIZES: [usize; 4] = [128, 4 * 1024, 64 * 1024, 1024 * 1024];
+
+/// Text-like data which compresses reasonably well.
+fn message(size: usize) -> Vec<u8> {
+	b"{\"jsonrpc\":\"2.0\",\"method\":\"subscribe\",\"params\":[\"newHeads\"],\"id\":42}"
+		.iter()
+		.copied()
+		.cycle()
+		.take(size)
+		.collect()
+}
+
+/// A negotiated pair of extensions, the first compressing for the second.
+fn pair() -> (Deflate, Deflate) {
+	let mut server = Deflate::new(Mode::Server);
+	server.configure(&[]).unwrap();
+	let mut client = Deflate::new(Mode::Client);
+	client.configure(&[]).unwrap();
+	(server, client)
+}
+
+fn encode(c: &mut Criterion) {
+	let mut group = c.benchmark_group("deflate_encode");
+	let (mut deflate, _) = pair();
+	for size in SIZES {
+		let data = message(size);
+		group.throughput(Throughput::Bytes(size as u64));
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			b.iter(|| {
+				let mut header = Header::new(OpCode::Text);
+				let mut storage = Storage::Shared(&data);
+				deflate.encode(&mut header, &mut storage).unwrap();
+				storage.as_ref().len()
+			})
+		});
+	}
+	group.finish()
+}
+
+fn decode(c: &mut Criterion) {
+	let mut group = c.benchmark_group("deflate_decode");
+	for size in SIZES {
+		let (mut encoder, mut decoder) = pair();
+		group.throughput(Throughput::Bytes(size as u64));
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			// Every message is compressed for the next one in the context of the decoder.
+			b.iter_batched(
+				|| {
+					let mut header = Header::new(OpCode::Text);
+					let data = message(size);
+					let mut storage = Storage::Shared(&data);
+					encoder.encode(&mut header, &mut storage).unwrap();
+					(header, storage.as_ref().to_vec())
+				},
+				|(mut header, mut data)| {
+					decoder.decode(&mut header, &mut data).unwrap();
+					data
+				},
+				BatchSize::SmallInput,
+			)
+		});
+	}
+	group.finish()
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
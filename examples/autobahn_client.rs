@@ -10,12 +10,15 @@
 // suite to verify client and server implementations of websocket
 // implementation.
 //
-// Once started, the tests can be executed with: wstest -m fuzzingserver
+// Start the test suite with `wstest -m fuzzingserver -s autobahn/fuzzingserver.json`
+// and then run this example with `cargo run --example autobahn_client --features autobahn`.
+// The configuration includes the compression cases (12.x and 13.x), for which the
+// client offers permessage-deflate.
 //
 // See https://github.com/crossbario/autobahn-testsuite for details.
 
 use futures::io::{BufReader, BufWriter};
-use soketto::{connection, handshake, BoxedError};
+use soketto::{connection, extension::deflate::Deflate, handshake, BoxedError};
 use std::str::FromStr;
 use tokio::net::TcpStream;
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
@@ -24,6 +27,7 @@ const SOKETTO_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[tokio::main]
 async fn main() -> Result<(), BoxedError> {
+	env_logger::init();
 	let n = num_of_cases().await?;
 	for i in 1..=n {
 		if let Err(e) = run_case(i).await {
@@ -84,16 +88,11 @@ async fn update_report() -> Result<(), BoxedError> {
 	Ok(())
 }
 
-#[cfg(not(feature = "deflate"))]
-fn new_client(socket: TcpStream, path: &str) -> handshake::Client<'_, BufReader<BufWriter<Compat<TcpStream>>>> {
-	handshake::Client::new(BufReader::new(BufWriter::new(socket.compat())), "127.0.0.1:9001", path)
-}
-
-#[cfg(feature = "deflate")]
 fn new_client(socket: TcpStream, path: &str) -> handshake::Client<'_, BufReader<BufWriter<Compat<TcpStream>>>> {
 	let socket = BufReader::with_capacity(8 * 1024, BufWriter::with_capacity(64 * 1024, socket.compat()));
 	let mut client = handshake::Client::new(socket, "127.0.0.1:9001", path);
-	let deflate = soketto::extension::deflate::Deflate::new(soketto::Mode::Client);
+	// Offers `client_max_window_bits`, which the 13.x cases require to reply with smaller windows.
+	let deflate = Deflate::new(soketto::Mode::Client);
 	client.add_extension(Box::new(deflate));
 	client
 }
@@ -10,67 +10,70 @@
 // suite to verify client and server implementations of websocket
 // implementation.
 //
-// Once started, the tests can be executed with: wstest -m fuzzingclient
+// Run this example with `cargo run --example autobahn_server --features autobahn`
+// and then start the test suite with `wstest -m fuzzingclient -s autobahn/fuzzingclient.json`.
+// The configuration includes the compression cases (12.x and 13.x), for which the
+// server accepts any permessage-deflate offer of the test suite.
 //
 // See https://github.com/crossbario/autobahn-testsuite for details.
 
 use futures::io::{BufReader, BufWriter};
-use soketto::{connection, handshake, BoxedError};
+use soketto::{connection, extension::deflate::Deflate, handshake, BoxedError};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
 #[tokio::main]
 async fn main() -> Result<(), BoxedError> {
+	env_logger::init();
 	let listener = TcpListener::bind("127.0.0.1:9001").await?;
 	let mut incoming = TcpListenerStream::new(listener);
 	while let Some(socket) = incoming.next().await {
-		let mut server = new_server(socket?);
-		let key = {
-			let req = server.receive_request().await?;
-			req.key()
-		};
-		let accept = handshake::server::Response::Accept { key, protocol: None };
-		server.send_response(&accept).await?;
-		let (mut sender, mut receiver) = server.into_builder().finish();
-		let mut message = Vec::new();
-		loop {
-			message.clear();
-			match receiver.receive_data(&mut message).await {
-				Ok(soketto::Data::Binary(n)) => {
-					assert_eq!(n, message.len());
-					sender.send_binary_mut(&mut message).await?;
-					sender.flush().await?
-				}
-				Ok(soketto::Data::Text(n)) => {
-					assert_eq!(n, message.len());
-					if let Ok(txt) = std::str::from_utf8(&message) {
-						sender.send_text(txt).await?;
-						sender.flush().await?
-					} else {
-						break;
-					}
-				}
-				Err(connection::Error::Closed) => break,
-				Err(e) => {
-					log::error!("connection error: {}", e);
-					break;
-				}
+		let socket = socket?;
+		tokio::spawn(async move {
+			if let Err(e) = echo(socket).await {
+				log::error!("connection error: {}", e)
 			}
-		}
+		});
 	}
 	Ok(())
 }
 
-#[cfg(not(feature = "deflate"))]
-fn new_server<'a>(socket: TcpStream) -> handshake::Server<'a, BufReader<BufWriter<Compat<TcpStream>>>> {
-	handshake::Server::new(BufReader::new(BufWriter::new(socket.compat())))
+/// Accept a connection and send back every message received.
+async fn echo(socket: TcpStream) -> Result<(), BoxedError> {
+	let mut server = new_server(socket);
+	let key = {
+		let req = server.receive_request().await?;
+		req.key()
+	};
+	let accept = handshake::server::Response::Accept { key, protocol: None };
+	server.send_response(&accept).await?;
+	let (mut sender, mut receiver) = server.into_builder().finish();
+	let mut message = Vec::new();
+	loop {
+		message.clear();
+		match receiver.receive_data(&mut message).await {
+			Ok(soketto::Data::Binary(n)) => {
+				assert_eq!(n, message.len());
+				sender.send_binary_mut(&mut message).await?;
+				sender.flush().await?
+			}
+			Ok(soketto::Data::Text(n)) => {
+				assert_eq!(n, message.len());
+				sender.send_text(std::str::from_utf8(&message)?).await?;
+				sender.flush().await?
+			}
+			Err(connection::Error::Closed) => return Ok(()),
+			Err(e) => return Err(e.into()),
+		}
+	}
 }
 
-#[cfg(feature = "deflate")]
 fn new_server<'a>(socket: TcpStream) -> handshake::Server<'a, BufReader<BufWriter<Compat<TcpStream>>>> {
 	let socket = BufReader::with_capacity(8 * 1024, BufWriter::with_capacity(16 * 1024, socket.compat()));
 	let mut server = handshake::Server::new(socket);
-	let deflate = soketto::extension::deflate::Deflate::new(soketto::Mode::Server);
+	// Negotiates the window sizes and context takeover the 13.x cases offer.
+	let deflate = Deflate::new(soketto::Mode::Server);
 	server.add_extension(Box::new(deflate));
 	server
 }
@@ -23,15 +23,14 @@
 
 use std::net::SocketAddr;
 
-use futures::io::{BufReader, BufWriter};
 use hyper::server::conn::http1;
 use hyper::{body::Bytes, service::service_fn, Request, Response};
 use hyper_util::rt::TokioIo;
 use soketto::{
 	handshake::http::{is_upgrade_request, Server},
+	upgrade::hyper::{upgrade, Upgrading},
 	BoxedError,
 };
-use tokio_util::compat::TokioAsyncReadCompatExt;
 
 type FullBody = http_body_util::Full<Bytes>;
 
@@ -76,25 +75,25 @@ async fn main() -> Result<(), BoxedError> {
 }
 
 /// Handle incoming HTTP Requests.
-async fn handler(req: Request<hyper::body::Incoming>) -> Result<hyper::Response<FullBody>, BoxedError> {
+async fn handler(mut req: Request<hyper::body::Incoming>) -> Result<hyper::Response<FullBody>, BoxedError> {
 	if is_upgrade_request(&req) {
 		// Create a new handshake server.
 		let mut server = Server::new();
 
 		// Add any extensions that we want to use.
-		#[cfg(feature = "deflate")]
+		#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
 		{
 			let deflate = soketto::extension::deflate::Deflate::new(soketto::Mode::Server);
 			server.add_extension(Box::new(deflate));
 		}
 
 		// Attempt the handshake.
-		match server.receive_request(&req) {
+		match upgrade(&mut req, server) {
 			// The handshake has been successful so far; return the response we're given back
 			// and spawn a task to handle the long-running WebSocket server:
-			Ok(response) => {
+			Ok((response, upgrading)) => {
 				tokio::spawn(async move {
-					if let Err(e) = websocket_echo_messages(server, req).await {
+					if let Err(e) = websocket_echo_messages(upgrading).await {
 						log::error!("Error upgrading to websocket connection: {}", e);
 					}
 				});
@@ -113,19 +112,15 @@ async fn handler(req: Request<hyper::body::Incoming>) -> Result<hyper::Response<
 }
 
 /// Echo any messages we get from the client back to them
-async fn websocket_echo_messages(server: Server, req: Request<hyper::body::Incoming>) -> Result<(), BoxedError> {
-	// The negotiation to upgrade to a WebSocket connection has been successful so far. Next, we get back the underlying
-	// stream using `hyper::upgrade::on`, and hand this to a Soketto server to use to handle the WebSocket communication
-	// on this socket.
+async fn websocket_echo_messages(upgrading: Upgrading) -> Result<(), BoxedError> {
+	// The negotiation to upgrade to a WebSocket connection has been successful so far. Next, we wait for hyper to
+	// hand over the underlying stream, which Soketto then uses to handle the WebSocket communication.
 	//
 	// Note: awaiting this won't succeed until the handshake response has been returned to the client, so this must be
 	// spawned on a separate task so as not to block that response being handed back.
-	let stream = hyper::upgrade::on(req).await?;
-	let io = TokioIo::new(stream);
-	let stream = BufReader::new(BufWriter::new(io.compat()));
-
-	// Get back a reader and writer that we can use to send and receive websocket messages.
-	let (mut sender, mut receiver) = server.into_builder(stream).finish();
+	//
+	// Once done, we get back a reader and writer that we can use to send and receive websocket messages.
+	let (mut sender, mut receiver) = upgrading.await?.finish();
 
 	// Echo any received messages back to the client:
 	let mut message = Vec::new();
@@ -18,6 +18,7 @@
 //! [base]: https://tools.ietf.org/html/rfc6455#section-5.2
 
 use crate::{as_u64, Parsing};
+use bytes::{Buf, BufMut, BytesMut};
 use std::{fmt, io};
 
 /// Max. size of a frame header.
@@ -68,28 +69,24 @@ pub enum OpCode {
 impl OpCode {
 	/// Is this a control opcode?
 	pub fn is_control(self) -> bool {
-		if let OpCode::Close | OpCode::Ping | OpCode::Pong = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
 	}
 
 	/// Is this opcode reserved?
 	pub fn is_reserved(self) -> bool {
-		match self {
+		matches!(
+			self,
 			OpCode::Reserved3
-			| OpCode::Reserved4
-			| OpCode::Reserved5
-			| OpCode::Reserved6
-			| OpCode::Reserved7
-			| OpCode::Reserved11
-			| OpCode::Reserved12
-			| OpCode::Reserved13
-			| OpCode::Reserved14
-			| OpCode::Reserved15 => true,
-			_ => false,
-		}
+				| OpCode::Reserved4
+				| OpCode::Reserved5
+				| OpCode::Reserved6
+				| OpCode::Reserved7
+				| OpCode::Reserved11
+				| OpCode::Reserved12
+				| OpCode::Reserved13
+				| OpCode::Reserved14
+				| OpCode::Reserved15
+		)
 	}
 }
 
@@ -191,6 +188,7 @@ pub struct Header {
 	opcode: OpCode,
 	mask: u32,
 	payload_len: usize,
+	compressible: bool,
 }
 
 impl fmt::Display for Header {
@@ -213,7 +211,17 @@ impl fmt::Display for Header {
 impl Header {
 	/// Create a new frame header with a given [`OpCode`].
 	pub fn new(oc: OpCode) -> Self {
-		Header { fin: true, rsv1: false, rsv2: false, rsv3: false, masked: false, opcode: oc, mask: 0, payload_len: 0 }
+		Header {
+			fin: true,
+			rsv1: false,
+			rsv2: false,
+			rsv3: false,
+			masked: false,
+			opcode: oc,
+			mask: 0,
+			payload_len: 0,
+			compressible: true,
+		}
 	}
 
 	/// Is the `fin` flag set?
@@ -303,6 +311,20 @@ impl Header {
 		self.payload_len = len;
 		self
 	}
+
+	/// May extensions compress the payload data?
+	pub fn is_compressible(&self) -> bool {
+		self.compressible
+	}
+
+	/// Set whether extensions may compress the payload data (the default).
+	///
+	/// This is not part of the encoded frame, but tells compressing extensions
+	/// to leave an outgoing message as is, while other extensions still apply.
+	pub fn set_compressible(&mut self, compressible: bool) -> &mut Self {
+		self.compressible = compressible;
+		self
+	}
 }
 
 // Base codec ////////////////////////////////////////////////////////////////////////////////////.
@@ -324,13 +346,23 @@ pub struct Codec {
 	max_data_size: usize,
 	/// Bits reserved by an extension.
 	reserved_bits: u8,
+	/// Opcodes reserved by an extension, as a bit set.
+	reserved_opcodes: u16,
+	/// Accept control frames with more than 125 bytes of payload data?
+	allow_oversized_control: bool,
 	/// Scratch buffer used during header encoding.
 	header_buffer: [u8; MAX_HEADER_SIZE],
 }
 
 impl Default for Codec {
 	fn default() -> Self {
-		Codec { max_data_size: 256 * 1024 * 1024, reserved_bits: 0, header_buffer: [0; MAX_HEADER_SIZE] }
+		Codec {
+			max_data_size: 256 * 1024 * 1024,
+			reserved_bits: 0,
+			reserved_opcodes: 0,
+			allow_oversized_control: false,
+			header_buffer: [0; MAX_HEADER_SIZE],
+		}
 	}
 }
 
@@ -354,6 +386,17 @@ impl Codec {
 		self
 	}
 
+	/// Accept control frames with more than 125 bytes of payload data when decoding.
+	///
+	/// Such frames violate [RFC 6455][0] and are rejected with
+	/// [`Error::InvalidControlFrameLen`] by default.
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-5.5
+	pub fn set_allow_oversized_control(&mut self, allow: bool) -> &mut Self {
+		self.allow_oversized_control = allow;
+		self
+	}
+
 	/// The reserved bits currently configured.
 	pub fn reserved_bits(&self) -> (bool, bool, bool) {
 		let r = self.reserved_bits;
@@ -367,11 +410,47 @@ impl Codec {
 		self
 	}
 
+	/// Check that the header only has reserved bits set which are in use.
+	///
+	/// This is done for every decoded header and should be done for headers
+	/// modified by extensions before they are encoded.
+	pub fn check_reserved_bits(&self, header: &Header) -> Result<(), Error> {
+		if header.is_rsv1() && self.reserved_bits & 4 == 0 {
+			return Err(Error::InvalidReservedBit(1));
+		}
+		if header.is_rsv2() && self.reserved_bits & 2 == 0 {
+			return Err(Error::InvalidReservedBit(2));
+		}
+		if header.is_rsv3() && self.reserved_bits & 1 == 0 {
+			return Err(Error::InvalidReservedBit(3));
+		}
+		Ok(())
+	}
+
 	/// Reset the reserved bits.
 	pub fn clear_reserved_bits(&mut self) {
 		self.reserved_bits = 0
 	}
 
+	/// Allow decoding frames with the given reserved opcode, e.g. because an extension uses it.
+	pub fn add_reserved_opcode(&mut self, opcode: OpCode) -> &mut Self {
+		self.reserved_opcodes |= 1 << u8::from(opcode);
+		self
+	}
+
+	/// Is decoding frames with the given opcode allowed?
+	///
+	/// This is the case for all non-reserved opcodes and the reserved opcodes added
+	/// with [`Codec::add_reserved_opcode`].
+	pub fn is_opcode_allowed(&self, opcode: OpCode) -> bool {
+		!opcode.is_reserved() || self.reserved_opcodes & 1 << u8::from(opcode) != 0
+	}
+
+	/// Reset the reserved opcodes.
+	pub fn clear_reserved_opcodes(&mut self) {
+		self.reserved_opcodes = 0
+	}
+
 	/// Decode a websocket frame header.
 	pub fn decode_header(&self, bytes: &[u8]) -> Result<Parsing<Header, usize>, Error> {
 		if bytes.len() < 2 {
@@ -385,7 +464,7 @@ impl Codec {
 		let fin = first & 0x80 != 0;
 		let opcode = OpCode::try_from(first & 0xF)?;
 
-		if opcode.is_reserved() {
+		if !self.is_opcode_allowed(opcode) {
 			return Err(Error::ReservedOpCode);
 		}
 
@@ -396,23 +475,10 @@ impl Codec {
 		let mut header = Header::new(opcode);
 		header.set_fin(fin);
 
-		let rsv1 = first & 0x40 != 0;
-		if rsv1 && (self.reserved_bits & 4 == 0) {
-			return Err(Error::InvalidReservedBit(1));
-		}
-		header.set_rsv1(rsv1);
-
-		let rsv2 = first & 0x20 != 0;
-		if rsv2 && (self.reserved_bits & 2 == 0) {
-			return Err(Error::InvalidReservedBit(2));
-		}
-		header.set_rsv2(rsv2);
-
-		let rsv3 = first & 0x10 != 0;
-		if rsv3 && (self.reserved_bits & 1 == 0) {
-			return Err(Error::InvalidReservedBit(3));
-		}
-		header.set_rsv3(rsv3);
+		header.set_rsv1(first & 0x40 != 0);
+		header.set_rsv2(first & 0x20 != 0);
+		header.set_rsv3(first & 0x10 != 0);
+		self.check_reserved_bits(&header)?;
 		header.set_masked(second & 0x80 != 0);
 
 		let len: u64 = match second & 0x7F {
@@ -436,7 +502,7 @@ impl Codec {
 			n => u64::from(n),
 		};
 
-		if len > MAX_CTRL_BODY_SIZE && header.opcode().is_control() {
+		if len > MAX_CTRL_BODY_SIZE && header.opcode().is_control() && !self.allow_oversized_control {
 			return Err(Error::InvalidControlFrameLen);
 		}
 
@@ -463,59 +529,8 @@ impl Codec {
 
 	/// Encode a websocket frame header.
 	pub fn encode_header(&mut self, header: &Header) -> &[u8] {
-		let mut offset = 0;
-
-		let mut first_byte = 0_u8;
-		if header.is_fin() {
-			first_byte |= 0x80
-		}
-		if header.is_rsv1() {
-			first_byte |= 0x40
-		}
-		if header.is_rsv2() {
-			first_byte |= 0x20
-		}
-		if header.is_rsv3() {
-			first_byte |= 0x10
-		}
-
-		let opcode: u8 = header.opcode().into();
-		first_byte |= opcode;
-
-		self.header_buffer[offset] = first_byte;
-		offset += 1;
-
-		let mut second_byte = 0_u8;
-		if header.is_masked() {
-			second_byte |= 0x80
-		}
-
-		let len = header.payload_len();
-
-		if len < usize::from(TWO_EXT) {
-			second_byte |= len as u8;
-			self.header_buffer[offset] = second_byte;
-			offset += 1;
-		} else if len <= usize::from(u16::max_value()) {
-			second_byte |= TWO_EXT;
-			self.header_buffer[offset] = second_byte;
-			offset += 1;
-			self.header_buffer[offset..offset + 2].copy_from_slice(&(len as u16).to_be_bytes());
-			offset += 2;
-		} else {
-			second_byte |= EIGHT_EXT;
-			self.header_buffer[offset] = second_byte;
-			offset += 1;
-			self.header_buffer[offset..offset + 8].copy_from_slice(&as_u64(len).to_be_bytes());
-			offset += 8;
-		}
-
-		if header.is_masked() {
-			self.header_buffer[offset..offset + 4].copy_from_slice(&header.mask().to_be_bytes());
-			offset += 4;
-		}
-
-		&self.header_buffer[..offset]
+		let len = write_header(header, &mut self.header_buffer);
+		&self.header_buffer[..len]
 	}
 
 	/// Use the given header's mask and apply it to the data.
@@ -529,6 +544,94 @@ impl Codec {
 	}
 }
 
+/// Encode a websocket frame header into `buffer`.
+///
+/// Unlike [`Codec::encode_header`] this does not need a codec, e.g. for tools
+/// which only inspect or forward frames.
+pub fn encode_header(header: &Header, buffer: &mut impl BufMut) {
+	let mut bytes = [0; MAX_HEADER_SIZE];
+	let len = write_header(header, &mut bytes);
+	buffer.put_slice(&bytes[..len])
+}
+
+/// Decode a websocket frame header from the start of `bytes`.
+///
+/// Returns the header and the number of bytes it occupies. Unlike
+/// [`Codec::decode_header`] this accepts any reserved bits and opcodes and
+/// does not limit the payload length, but fragmented control frames are
+/// still rejected. If `bytes` does not contain the whole header,
+/// [`Error::Incomplete`] is returned.
+pub fn decode_header(bytes: &[u8]) -> Result<(Header, usize), Error> {
+	let codec = Codec {
+		max_data_size: usize::MAX,
+		reserved_bits: 7,
+		reserved_opcodes: u16::MAX,
+		allow_oversized_control: true,
+		header_buffer: [0; MAX_HEADER_SIZE],
+	};
+	match codec.decode_header(bytes)? {
+		Parsing::Done { value, offset } => Ok((value, offset)),
+		Parsing::NeedMore(n) => Err(Error::Incomplete(n)),
+	}
+}
+
+/// Write the encoded header to `buffer` and return its length.
+fn write_header(header: &Header, buffer: &mut [u8; MAX_HEADER_SIZE]) -> usize {
+	let mut offset = 0;
+
+	let mut first_byte = 0_u8;
+	if header.is_fin() {
+		first_byte |= 0x80
+	}
+	if header.is_rsv1() {
+		first_byte |= 0x40
+	}
+	if header.is_rsv2() {
+		first_byte |= 0x20
+	}
+	if header.is_rsv3() {
+		first_byte |= 0x10
+	}
+
+	let opcode: u8 = header.opcode().into();
+	first_byte |= opcode;
+
+	buffer[offset] = first_byte;
+	offset += 1;
+
+	let mut second_byte = 0_u8;
+	if header.is_masked() {
+		second_byte |= 0x80
+	}
+
+	let len = header.payload_len();
+
+	if len < usize::from(TWO_EXT) {
+		second_byte |= len as u8;
+		buffer[offset] = second_byte;
+		offset += 1;
+	} else if len <= usize::from(u16::MAX) {
+		second_byte |= TWO_EXT;
+		buffer[offset] = second_byte;
+		offset += 1;
+		buffer[offset..offset + 2].copy_from_slice(&(len as u16).to_be_bytes());
+		offset += 2;
+	} else {
+		second_byte |= EIGHT_EXT;
+		buffer[offset] = second_byte;
+		offset += 1;
+		buffer[offset..offset + 8].copy_from_slice(&as_u64(len).to_be_bytes());
+		offset += 8;
+	}
+
+	if header.is_masked() {
+		buffer[offset..offset + 4].copy_from_slice(&header.mask().to_be_bytes());
+		offset += 4;
+	}
+
+	offset
+}
+
 /// Error cases the base frame decoder may encounter.
 #[non_exhaustive]
 #[derive(Debug)]
@@ -547,6 +650,8 @@ pub enum Error {
 	InvalidReservedBit(u8),
 	/// The payload length of a frame exceeded the configured maximum.
 	PayloadTooLarge { actual: u64, maximum: u64 },
+	/// The header is incomplete and needs this many more bytes.
+	Incomplete(usize),
 }
 
 impl fmt::Display for Error {
@@ -561,6 +666,7 @@ impl fmt::Display for Error {
 			Error::PayloadTooLarge { actual, maximum } => {
 				write!(f, "payload too large: len = {}, maximum = {}", actual, maximum)
 			}
+			Error::Incomplete(n) => write!(f, "incomplete header: {} more bytes needed", n),
 		}
 	}
 }
@@ -574,7 +680,8 @@ impl std::error::Error for Error {
 			| Error::FragmentedControl
 			| Error::InvalidControlFrameLen
 			| Error::InvalidReservedBit(_)
-			| Error::PayloadTooLarge { .. } => None,
+			| Error::PayloadTooLarge { .. }
+			| Error::Incomplete(_) => None,
 		}
 	}
 }
@@ -591,14 +698,153 @@ impl From<UnknownOpCode> for Error {
 	}
 }
 
+// Frame parser //////////////////////////////////////////////////////////////////////////////////
+
+/// A piece of a frame produced by [`FrameParser::parse`].
+#[derive(Debug)]
+pub enum Chunk<'a> {
+	/// A frame header. It is followed by the chunks of its payload, if any.
+	Header(Header),
+	/// A piece of the payload of the last header, with the mask removed.
+	Payload {
+		/// The payload bytes.
+		data: &'a [u8],
+		/// Is this the last piece of the payload?
+		is_last: bool,
+	},
+}
+
+/// An incremental frame parser which can be fed arbitrary chunks of bytes.
+///
+/// Headers are returned once they are complete and payload data as soon as
+/// it is available, so frames need not be buffered completely:
+///
+/// ```
+/// use soketto::base::{Chunk, FrameParser};
+///
+/// let mut parser = FrameParser::default();
+/// parser.push(&[0x82, 0x05, b'h', b'e']);
+/// assert!(matches!(parser.parse(), Ok(Some(Chunk::Header(h))) if h.payload_len() == 5));
+/// assert!(matches!(parser.parse(), Ok(Some(Chunk::Payload { data: b"he", is_last: false }))));
+/// assert!(matches!(parser.parse(), Ok(None)));
+/// parser.push(b"llo");
+/// assert!(matches!(parser.parse(), Ok(Some(Chunk::Payload { data: b"llo", is_last: true }))));
+/// ```
+#[derive(Debug, Default)]
+pub struct FrameParser {
+	codec: Codec,
+	buffer: BytesMut,
+	/// The payload bytes last returned.
+	chunk: BytesMut,
+	/// The header whose payload is being parsed and the number of payload bytes already returned.
+	current: Option<(Header, usize)>,
+}
+
+impl FrameParser {
+	/// Create a new parser decoding headers with the given codec.
+	pub fn new(codec: Codec) -> Self {
+		FrameParser { codec, ..FrameParser::default() }
+	}
+
+	/// Get a mutable reference to the codec, e.g. to configure reserved bits.
+	pub fn codec_mut(&mut self) -> &mut Codec {
+		&mut self.codec
+	}
+
+	/// Add some bytes to parse.
+	pub fn push(&mut self, bytes: &[u8]) {
+		self.buffer.extend_from_slice(bytes)
+	}
+
+	/// Parse the next header or piece of payload data, if available.
+	///
+	/// Returns `Ok(None)` if more bytes need to be pushed.
+	pub fn parse(&mut self) -> Result<Option<Chunk<'_>>, Error> {
+		let Some((header, offset)) = &mut self.current else {
+			return match self.codec.decode_header(&self.buffer)? {
+				Parsing::Done { value, offset } => {
+					self.buffer.advance(offset);
+					if value.payload_len() > 0 {
+						self.current = Some((value.clone(), 0))
+					}
+					Ok(Some(Chunk::Header(value)))
+				}
+				Parsing::NeedMore(_) => Ok(None),
+			};
+		};
+		let n = std::cmp::min(header.payload_len() - *offset, self.buffer.len());
+		if n == 0 {
+			return Ok(None);
+		}
+		self.chunk = self.buffer.split_to(n);
+		if header.is_masked() {
+			let mask = header.mask().to_be_bytes();
+			for (i, byte) in self.chunk.iter_mut().enumerate() {
+				*byte ^= mask[(*offset + i) % 4]
+			}
+		}
+		*offset += n;
+		let is_last = *offset == header.payload_len();
+		if is_last {
+			self.current = None
+		}
+		Ok(Some(Chunk::Payload { data: &self.chunk, is_last }))
+	}
+}
+
 // Tests //////////////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod test {
-	use super::{Codec, Error, OpCode};
+	use super::{decode_header, encode_header, Chunk, Codec, Error, FrameParser, Header, OpCode};
 	use crate::Parsing;
 	use quickcheck::QuickCheck;
 
+	#[test]
+	fn free_functions() {
+		let mut header = Header::new(OpCode::Reserved3);
+		header.set_rsv2(true).set_masked(true).set_mask(0x01020304).set_payload_len(70_000);
+		let mut bytes = Vec::new();
+		encode_header(&header, &mut bytes);
+		assert_eq!(Codec::new().encode_header(&header), &bytes[..]);
+
+		let (decoded, len) = decode_header(&bytes).unwrap();
+		assert_eq!(bytes.len(), len);
+		assert_eq!(header.to_string(), decoded.to_string());
+		assert!(decoded.is_rsv2());
+		assert!(matches!(decode_header(&bytes[..3]), Err(Error::Incomplete(7))));
+		assert!(matches!(decode_header(&[0x09, 0]), Err(Error::FragmentedControl)));
+	}
+
+	#[test]
+	fn frame_parser() {
+		let mut header = Header::new(OpCode::Binary);
+		header.set_masked(true).set_mask(0x01020304).set_payload_len(300);
+		let mut frame = Codec::new().encode_header(&header).to_vec();
+		let payload = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+		let mut masked = payload.clone();
+		Codec::apply_mask(&header, &mut masked);
+		frame.extend(masked);
+		frame.extend([0x89, 0x00]);
+
+		let mut parser = FrameParser::default();
+		let (mut headers, mut data) = (Vec::new(), Vec::new());
+		for bytes in frame.chunks(7) {
+			parser.push(bytes);
+			while let Some(chunk) = parser.parse().unwrap() {
+				match chunk {
+					Chunk::Header(h) => headers.push(h.opcode()),
+					Chunk::Payload { data: d, is_last } => {
+						data.extend_from_slice(d);
+						assert_eq!(is_last, data.len() == 300)
+					}
+				}
+			}
+		}
+		assert_eq!(vec![OpCode::Binary, OpCode::Ping], headers);
+		assert_eq!(payload, data);
+	}
+
 	#[test]
 	fn decode_partial_header() {
 		let partial_header: &[u8] = &[0x89];
@@ -637,7 +883,7 @@ mod test {
 		if let Ok(Parsing::Done { value, offset }) = Codec::new().decode_header(partial_payload) {
 			assert_eq!(3, value.payload_len() - (partial_payload.len() - offset))
 		} else {
-			assert!(false)
+			panic!("expected a complete header")
 		}
 	}
 
@@ -706,7 +952,7 @@ mod test {
 			assert!(header.opcode() == OpCode::Ping);
 			assert!(header.payload_len() == 0)
 		} else {
-			assert!(false)
+			panic!("expected a complete header")
 		}
 	}
 
@@ -0,0 +1,255 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Helpers to establish client connections.
+//!
+//! With the `tls` feature, [`connect_tls`] opens a TLS connection to a `wss://`
+//! URL using [rustls](https://docs.rs/rustls) and returns a [`handshake::Client`]
+//! ready to perform the websocket handshake:
+//!
+//! ```no_run
+//! # async fn doc() -> Result<(), soketto::BoxedError> {
+//! let mut client = soketto::connect_tls("wss://example.com/chat").await?;
+//! client.handshake().await?;
+//! let (sender, receiver) = client.into_builder().finish();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `client-connect` feature, [`connect`] additionally performs the
+//! handshake and also supports `ws://` URLs, and [`Resilient`] reconnects
+//! whenever the connection drops.
+
+use crate::handshake::{
+	self,
+	client::{split_host_port, Location},
+};
+use std::{fmt, io, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+	rustls::{self, pki_types::ServerName, ClientConfig, RootCertStore},
+	TlsConnector,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+#[cfg(feature = "client-connect")]
+mod connect;
+#[cfg(feature = "client-connect")]
+mod resilient;
+
+#[cfg(feature = "client-connect")]
+pub use connect::{connect, Stream};
+#[cfg(feature = "client-connect")]
+pub use resilient::{Event, Resilient, ResilientSender};
+
+/// The TLS stream used by [`connect_tls`].
+pub type TlsStream = Compat<tokio_rustls::client::TlsStream<TcpStream>>;
+
+/// Connect to a `wss://` URL using the [`webpki_roots`] root certificates.
+///
+/// The host name is resolved and verified against the server's certificate.
+pub async fn connect_tls(url: &str) -> Result<handshake::Client<'static, TlsStream>, Error> {
+	connect_tls_with_config(url, default_config()?).await
+}
+
+/// Connect to a `wss://` URL using the given TLS configuration.
+pub async fn connect_tls_with_config(
+	url: &str,
+	config: Arc<ClientConfig>,
+) -> Result<handshake::Client<'static, TlsStream>, Error> {
+	let mut options = TlsOptions::new();
+	options.set_config(config);
+	connect_tls_with_options(url, &options).await
+}
+
+/// Connect to a `wss://` URL using the given TLS options.
+///
+/// ```no_run
+/// # async fn doc() -> Result<(), soketto::BoxedError> {
+/// use soketto::client::{connect_tls_with_options, TlsOptions};
+///
+/// let mut options = TlsOptions::new();
+/// options.set_server_name("front.example.com").set_alpn_protocols([b"http/1.1".to_vec()]);
+/// let client = connect_tls_with_options("wss://example.com/chat", &options).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect_tls_with_options(
+	url: &str,
+	options: &TlsOptions,
+) -> Result<handshake::Client<'static, TlsStream>, Error> {
+	let location = Location::parse(url, "").map_err(|_| Error::InvalidUrl(String::from(url)))?;
+	if location.secure != Some(true) {
+		return Err(Error::InvalidUrl(String::from(url)));
+	}
+	let (host, port) = split_host_port(&location.host, 443).ok_or_else(|| Error::InvalidUrl(String::from(url)))?;
+	let server_name = options.server_name.as_deref().unwrap_or(host);
+	let stream = dial_tls(host, port, server_name, options.client_config()?).await?;
+	Ok(handshake::Client::new(stream, location.host, location.resource))
+}
+
+/// Options for [`connect_tls_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+	config: Option<Arc<ClientConfig>>,
+	server_name: Option<String>,
+	alpn_protocols: Option<Vec<Vec<u8>>>,
+}
+
+impl TlsOptions {
+	/// Create options using the [`webpki_roots`] root certificates, the host
+	/// name of the URL as server name and no ALPN protocols.
+	pub fn new() -> Self {
+		TlsOptions::default()
+	}
+
+	/// Use the given TLS configuration instead of the default one.
+	pub fn set_config(&mut self, config: Arc<ClientConfig>) -> &mut Self {
+		self.config = Some(config);
+		self
+	}
+
+	/// Send the given server name (SNI) and verify the server's certificate
+	/// against it instead of the host name of the URL.
+	///
+	/// This is needed when connecting through a fronting domain. The `Host`
+	/// header of the handshake request still contains the host of the URL.
+	pub fn set_server_name(&mut self, name: impl Into<String>) -> &mut Self {
+		self.server_name = Some(name.into());
+		self
+	}
+
+	/// Offer the given ALPN protocols, e.g. `http/1.1`, in order of preference.
+	///
+	/// This overrides the protocols of the TLS configuration.
+	pub fn set_alpn_protocols(&mut self, protocols: impl IntoIterator<Item = Vec<u8>>) -> &mut Self {
+		self.alpn_protocols = Some(protocols.into_iter().collect());
+		self
+	}
+
+	/// The TLS configuration with these options applied.
+	fn client_config(&self) -> Result<Arc<ClientConfig>, Error> {
+		let config = match &self.config {
+			Some(config) => config.clone(),
+			None => default_config()?,
+		};
+		let Some(protocols) = &self.alpn_protocols else { return Ok(config) };
+		let mut config = ClientConfig::clone(&config);
+		config.alpn_protocols = protocols.clone();
+		Ok(Arc::new(config))
+	}
+}
+
+/// The TLS configuration using the [`webpki_roots`] root certificates.
+fn default_config() -> Result<Arc<ClientConfig>, Error> {
+	let provider = Arc::new(rustls::crypto::ring::default_provider());
+	let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+	let config = ClientConfig::builder_with_provider(provider)
+		.with_safe_default_protocol_versions()
+		.map_err(Error::Tls)?
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+	Ok(Arc::new(config))
+}
+
+/// Open a TLS connection to the given host and port, verifying the given server name.
+async fn dial_tls(host: &str, port: u16, server_name: &str, config: Arc<ClientConfig>) -> Result<TlsStream, Error> {
+	let server_name = ServerName::try_from(String::from(server_name))
+		.map_err(|_| Error::InvalidServerName(String::from(server_name)))?;
+	let socket = TcpStream::connect((host, port)).await?;
+	socket.set_nodelay(true)?;
+	let stream = TlsConnector::from(config).connect(server_name, socket).await?;
+	Ok(stream.compat())
+}
+
+/// Enumeration of possible client connection errors.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+	/// An I/O error has been encountered.
+	Io(io::Error),
+	/// The URL could not be parsed or has an unsupported scheme.
+	InvalidUrl(String),
+	/// The host is not a valid TLS server name.
+	InvalidServerName(String),
+	/// The TLS configuration could not be created.
+	Tls(rustls::Error),
+	/// The websocket handshake failed.
+	Handshake(handshake::Error),
+	/// The server rejected the websocket handshake.
+	Rejected {
+		/// HTTP response status code.
+		status_code: u16,
+	},
+	/// The connection failed.
+	Connection(crate::connection::Error),
+	/// There is currently no connection.
+	NotConnected,
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "i/o error: {}", e),
+			Error::InvalidUrl(url) => write!(f, "invalid url: {}", url),
+			Error::InvalidServerName(name) => write!(f, "invalid server name: {}", name),
+			Error::Tls(e) => write!(f, "tls error: {}", e),
+			Error::Handshake(e) => write!(f, "handshake error: {}", e),
+			Error::Rejected { status_code } => write!(f, "handshake rejected with status code {}", status_code),
+			Error::Connection(e) => write!(f, "connection error: {}", e),
+			Error::NotConnected => f.write_str("not connected"),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Io(e) => Some(e),
+			Error::Tls(e) => Some(e),
+			Error::Handshake(e) => Some(e),
+			Error::Connection(e) => Some(e),
+			Error::InvalidUrl(_) | Error::InvalidServerName(_) | Error::Rejected { .. } | Error::NotConnected => None,
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl From<handshake::Error> for Error {
+	fn from(e: handshake::Error) -> Self {
+		Error::Handshake(e)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{connect_tls_with_options, Error, TlsOptions};
+
+	#[test]
+	fn alpn_protocols() {
+		let mut options = TlsOptions::new();
+		assert!(options.client_config().unwrap().alpn_protocols.is_empty());
+		options.set_alpn_protocols([b"http/1.1".to_vec()]);
+		assert_eq!(vec![b"http/1.1".to_vec()], options.client_config().unwrap().alpn_protocols);
+	}
+
+	#[tokio::test]
+	async fn invalid_server_name() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("wss://{}/chat", listener.local_addr().unwrap());
+		let mut options = TlsOptions::new();
+		options.set_server_name("not a name");
+		let result = connect_tls_with_options(&url, &options).await;
+		assert!(matches!(result, Err(Error::InvalidServerName(name)) if name == "not a name"));
+	}
+}
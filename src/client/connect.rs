@@ -0,0 +1,161 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::{default_config, dial_tls, split_host_port, Error, TlsStream};
+use crate::{
+	connection::{Receiver, Sender},
+	handshake::{self, client::Location, ServerResponse},
+};
+use futures::io::{AsyncRead, AsyncWrite};
+use std::{
+	io,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// Maximum number of redirects followed by [`connect`].
+const MAX_REDIRECTS: usize = 5;
+
+/// A plain TCP or a TLS stream, as used by [`connect`].
+#[derive(Debug)]
+pub enum Stream {
+	/// A plain TCP stream for `ws://` URLs.
+	Plain(Compat<TcpStream>),
+	/// A TLS stream for `wss://` URLs.
+	Tls(Box<TlsStream>),
+}
+
+impl AsyncRead for Stream {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+			Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for Stream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+			Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+			Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+		}
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(s) => Pin::new(s).poll_close(cx),
+			Stream::Tls(s) => Pin::new(s).poll_close(cx),
+		}
+	}
+}
+
+/// Connect to a `ws://` or `wss://` URL and perform the websocket handshake.
+///
+/// The host name is resolved and connected to with tokio, using TLS with the
+/// [`webpki_roots`] root certificates for `wss://` URLs. Up to 5 redirects are
+/// followed. The returned response is always [`ServerResponse::Accepted`];
+/// rejections fail with [`Error::Rejected`].
+///
+/// ```no_run
+/// # async fn doc() -> Result<(), soketto::BoxedError> {
+/// let (mut sender, _receiver, _) = soketto::connect("wss://example.com/chat").await?;
+/// sender.send_text("hello").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect(url: &str) -> Result<(Sender<Stream>, Receiver<Stream>, ServerResponse), Error> {
+	connect_with(url, |_| ()).await
+}
+
+/// Like [`connect`], but `configure` is called with the handshake client before the handshake.
+pub(super) async fn connect_with<F>(
+	url: &str,
+	configure: F,
+) -> Result<(Sender<Stream>, Receiver<Stream>, ServerResponse), Error>
+where
+	F: FnOnce(&mut handshake::Client<'static, Stream>),
+{
+	let invalid = || Error::InvalidUrl(String::from(url));
+	let location = Location::parse(url, "").map_err(|_| invalid())?;
+	let mut secure = location.secure.ok_or_else(invalid)?;
+	let config = default_config()?;
+
+	let socket = dial(secure, &location.host, config.clone()).await?;
+	let mut client = handshake::Client::from_url(socket, url)?;
+	configure(&mut client);
+	let response = client
+		.handshake_with_redirects(MAX_REDIRECTS, |location| {
+			secure = location.secure.unwrap_or(secure);
+			let (secure, host, config) = (secure, location.host.clone(), config.clone());
+			async move { dial(secure, &host, config).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e)) }
+		})
+		.await?;
+
+	match response {
+		ServerResponse::Accepted { .. } => {
+			let (sender, receiver) = client.into_builder().finish();
+			Ok((sender, receiver, response))
+		}
+		ServerResponse::Redirect { status_code, .. } | ServerResponse::Rejected { status_code } => {
+			Err(Error::Rejected { status_code })
+		}
+	}
+}
+
+/// Open a plain TCP or TLS connection to `host`, which may include a port.
+async fn dial(secure: bool, host: &str, config: Arc<ClientConfig>) -> Result<Stream, Error> {
+	let (host, port) =
+		split_host_port(host, if secure { 443 } else { 80 }).ok_or_else(|| Error::InvalidUrl(String::from(host)))?;
+	if secure {
+		return Ok(Stream::Tls(Box::new(dial_tls(host, port, host, config).await?)));
+	}
+	let socket = TcpStream::connect((host, port)).await?;
+	socket.set_nodelay(true)?;
+	Ok(Stream::Plain(socket.compat()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::connect;
+	use crate::handshake::{server::Response, Server};
+	use tokio_util::compat::TokioAsyncReadCompatExt;
+
+	#[tokio::test]
+	async fn connect_plain() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("ws://{}/chat", listener.local_addr().unwrap());
+
+		let server = tokio::spawn(async move {
+			let (socket, _) = listener.accept().await.unwrap();
+			let mut server = Server::new(socket.compat());
+			let key = server.receive_request().await.unwrap().key();
+			server.send_response(&Response::Accept { key, protocol: None }).await.unwrap();
+			let (_, mut receiver) = server.into_builder().finish();
+			let mut message = Vec::new();
+			receiver.receive_data(&mut message).await.unwrap();
+			message
+		});
+
+		let (mut sender, _, _) = connect(&url).await.unwrap();
+		sender.send_text("hello").await.unwrap();
+		sender.flush().await.unwrap();
+		assert_eq!(b"hello", &server.await.unwrap()[..]);
+	}
+}
@@ -0,0 +1,346 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::{connect::connect_with, Error, Stream};
+use crate::{
+	connection::{self, Receiver, Sender},
+	data::Data,
+	extension::Extension,
+	handshake::ServerResponse,
+};
+use futures::lock::Mutex;
+use futures_timer::Delay;
+use std::{
+	fmt,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+/// A client connection which reconnects when the connection drops.
+///
+/// `Resilient` owns the URL, protocols and extensions of the connection and
+/// repeats the handshake with them whenever it reconnects. Messages are sent
+/// through [`ResilientSender`]s, which remain usable across reconnects, and
+/// received with [`Resilient::receive`], which also reports changes of the
+/// connection state and must be called continuously to reconnect:
+///
+/// ```no_run
+/// use soketto::client::{Event, Resilient};
+///
+/// # async fn doc() -> Result<(), soketto::client::Error> {
+/// let mut client = Resilient::new("wss://example.com/chat");
+/// client.add_protocol("chat");
+/// let mut sender = client.sender();
+/// let mut message = Vec::new();
+/// loop {
+///     match client.receive(&mut message).await? {
+///         Event::Connected(_) => sender.send_text("hello").await?,
+///         Event::Data(_) => message.clear(),
+///         Event::Closed => break,
+///         _ => (),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Reconnects are delayed with an exponential backoff, starting at 100 ms and
+/// doubling after every failed attempt up to 30 s, see [`Resilient::set_backoff`].
+/// Messages sent while the connection is down are not queued, but fail with
+/// [`Error::NotConnected`].
+pub struct Resilient {
+	url: String,
+	protocols: Vec<String>,
+	extensions: Vec<Box<dyn Fn() -> Box<dyn Extension + Send> + Send + Sync>>,
+	initial_backoff: Duration,
+	max_backoff: Duration,
+	max_retries: Option<usize>,
+	/// Number of failed connection attempts since the last connection.
+	attempts: usize,
+	receiver: Option<Receiver<Stream>>,
+	shared: Arc<Shared>,
+}
+
+/// State shared with the [`ResilientSender`]s.
+struct Shared {
+	sender: Mutex<Option<Sender<Stream>>>,
+	/// Set when the application closes the connection.
+	closed: AtomicBool,
+}
+
+/// An event reported by [`Resilient::receive`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Event {
+	/// A connection has been established.
+	Connected(ServerResponse),
+	/// The connection has been lost and will be re-established.
+	Disconnected(connection::Error),
+	/// A connection attempt failed and will be retried after the given delay.
+	Reconnecting {
+		/// Number of failed attempts since the last connection.
+		attempt: usize,
+		/// The delay before the next attempt.
+		delay: Duration,
+		/// The reason the attempt failed.
+		error: Error,
+	},
+	/// A message has been received.
+	Data(Data),
+	/// The connection has been closed with [`ResilientSender::close`].
+	Closed,
+}
+
+impl fmt::Debug for Resilient {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Resilient")
+			.field("url", &self.url)
+			.field("protocols", &self.protocols)
+			.field("attempts", &self.attempts)
+			.field("connected", &self.receiver.is_some())
+			.finish()
+	}
+}
+
+impl Resilient {
+	/// Create a new client for a `ws://` or `wss://` URL.
+	///
+	/// No connection is made until [`Resilient::receive`] is called.
+	pub fn new(url: impl Into<String>) -> Self {
+		Resilient {
+			url: url.into(),
+			protocols: Vec::new(),
+			extensions: Vec::new(),
+			initial_backoff: Duration::from_millis(100),
+			max_backoff: Duration::from_secs(30),
+			max_retries: None,
+			attempts: 0,
+			receiver: None,
+			shared: Arc::new(Shared { sender: Mutex::new(None), closed: AtomicBool::new(false) }),
+		}
+	}
+
+	/// Add a protocol to be included in every handshake.
+	pub fn add_protocol(&mut self, p: impl Into<String>) -> &mut Self {
+		self.protocols.push(p.into());
+		self
+	}
+
+	/// Add a factory of an extension to be included in every handshake.
+	///
+	/// A fresh extension is created for every connection.
+	pub fn add_extension_with<F>(&mut self, f: F) -> &mut Self
+	where
+		F: Fn() -> Box<dyn Extension + Send> + Send + Sync + 'static,
+	{
+		self.extensions.push(Box::new(f));
+		self
+	}
+
+	/// Set the delay before the first reconnect and the maximum delay.
+	///
+	/// The delay doubles after every failed attempt.
+	pub fn set_backoff(&mut self, initial: Duration, max: Duration) -> &mut Self {
+		self.initial_backoff = initial;
+		self.max_backoff = max;
+		self
+	}
+
+	/// Give up after the given number of failed connection attempts in a row.
+	///
+	/// By default, reconnecting is retried indefinitely.
+	pub fn set_max_retries(&mut self, max: usize) -> &mut Self {
+		self.max_retries = Some(max);
+		self
+	}
+
+	/// Get a handle to send messages over the current connection.
+	pub fn sender(&self) -> ResilientSender {
+		ResilientSender { shared: self.shared.clone() }
+	}
+
+	/// Receive the next message or connection state event.
+	///
+	/// If not connected, this connects first, waiting for the backoff delay if the
+	/// previous attempt failed. Fails if the number of attempts set with
+	/// [`Resilient::set_max_retries`] is exceeded.
+	pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Event, Error> {
+		if self.shared.closed.load(Ordering::SeqCst) {
+			self.disconnect().await;
+			return Ok(Event::Closed);
+		}
+
+		let Some(receiver) = &mut self.receiver else { return self.reconnect().await };
+
+		match receiver.receive_data(message).await {
+			Ok(data) => Ok(Event::Data(data)),
+			Err(_) if self.shared.closed.load(Ordering::SeqCst) => {
+				self.disconnect().await;
+				Ok(Event::Closed)
+			}
+			Err(e) => {
+				log::debug!("connection to {} lost: {}", self.url, e);
+				self.disconnect().await;
+				Ok(Event::Disconnected(e))
+			}
+		}
+	}
+
+	/// Make a connection attempt, after the backoff delay if previous attempts failed.
+	async fn reconnect(&mut self) -> Result<Event, Error> {
+		if self.attempts > 0 {
+			Delay::new(self.backoff()).await
+		}
+
+		let (protocols, extensions) = (&self.protocols, &self.extensions);
+		let result = connect_with(&self.url, |client| {
+			for p in protocols {
+				client.add_protocol(p.clone());
+			}
+			for e in extensions {
+				client.add_extension(e());
+			}
+		})
+		.await;
+
+		match result {
+			Ok((sender, receiver, response)) => {
+				self.attempts = 0;
+				self.receiver = Some(receiver);
+				*self.shared.sender.lock().await = Some(sender);
+				Ok(Event::Connected(response))
+			}
+			Err(e) if self.max_retries.map_or(true, |max| self.attempts < max) => {
+				self.attempts += 1;
+				log::debug!("connection attempt {} to {} failed: {}", self.attempts, self.url, e);
+				Ok(Event::Reconnecting { attempt: self.attempts, delay: self.backoff(), error: e })
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	/// The delay before the next connection attempt.
+	fn backoff(&self) -> Duration {
+		let factor = 1_u32.checked_shl(self.attempts.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+		std::cmp::min(self.initial_backoff.saturating_mul(factor), self.max_backoff)
+	}
+
+	/// Drop the current connection.
+	async fn disconnect(&mut self) {
+		self.receiver = None;
+		*self.shared.sender.lock().await = None
+	}
+}
+
+/// A handle to send messages over the connection of a [`Resilient`] client.
+#[derive(Clone)]
+pub struct ResilientSender {
+	shared: Arc<Shared>,
+}
+
+impl fmt::Debug for ResilientSender {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ResilientSender")
+	}
+}
+
+impl ResilientSender {
+	/// Send a text value over the current connection.
+	pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
+		let mut sender = self.shared.sender.lock().await;
+		let result = sender.as_mut().ok_or(Error::NotConnected)?.send_text(data).await;
+		drop_on_error(&mut sender, result)
+	}
+
+	/// Send some binary data over the current connection.
+	pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		let mut sender = self.shared.sender.lock().await;
+		let result = sender.as_mut().ok_or(Error::NotConnected)?.send_binary(data).await;
+		drop_on_error(&mut sender, result)
+	}
+
+	/// Flush the current connection.
+	pub async fn flush(&mut self) -> Result<(), Error> {
+		let mut sender = self.shared.sender.lock().await;
+		let result = sender.as_mut().ok_or(Error::NotConnected)?.flush().await;
+		drop_on_error(&mut sender, result)
+	}
+
+	/// Close the connection and stop reconnecting.
+	pub async fn close(&mut self) -> Result<(), Error> {
+		self.shared.closed.store(true, Ordering::SeqCst);
+		let mut sender = self.shared.sender.lock().await;
+		let Some(s) = sender.as_mut() else { return Ok(()) };
+		let result = s.close().await;
+		drop_on_error(&mut sender, result)
+	}
+}
+
+/// Drop the sender of a connection if using it failed.
+fn drop_on_error(sender: &mut Option<Sender<Stream>>, result: Result<(), connection::Error>) -> Result<(), Error> {
+	result.map_err(|e| {
+		*sender = None;
+		Error::Connection(e)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Event, Resilient};
+	use crate::handshake::{server::Response, Server, ServerResponse};
+	use std::time::Duration;
+	use tokio_util::compat::TokioAsyncReadCompatExt;
+
+	#[tokio::test]
+	async fn reconnect() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let mut client = Resilient::new(format!("ws://{}/", listener.local_addr().unwrap()));
+		client.add_protocol("chat").set_backoff(Duration::from_millis(10), Duration::from_millis(10));
+		let mut sender = client.sender();
+
+		// Accepts two connections, sending one message over each before dropping it.
+		let server = tokio::spawn(async move {
+			for text in ["one", "two"] {
+				let (socket, _) = listener.accept().await.unwrap();
+				let mut server = Server::new(socket.compat());
+				server.add_protocol("chat");
+				let req = server.receive_request().await.unwrap();
+				let (key, protocol) = (req.key(), req.protocols().next().map(String::from));
+				server.send_response(&Response::Accept { key, protocol: protocol.as_deref() }).await.unwrap();
+				let (mut sender, _) = server.into_builder().finish();
+				sender.send_text(text).await.unwrap();
+				sender.flush().await.unwrap();
+			}
+		});
+
+		fn is_send<T: Send>(_: &T) {}
+		is_send(&client.receive(&mut Vec::new()));
+
+		let mut message = Vec::new();
+		for expected in ["one", "two"] {
+			let event = client.receive(&mut message).await.unwrap();
+			assert!(
+				matches!(event, Event::Connected(ServerResponse::Accepted { protocol: Some(ref p), .. }) if p == "chat")
+			);
+			assert!(matches!(client.receive(&mut message).await.unwrap(), Event::Data(_)));
+			assert_eq!(expected.as_bytes(), &message[..]);
+			message.clear();
+			assert!(matches!(client.receive(&mut message).await.unwrap(), Event::Disconnected(_)));
+			assert!(sender.send_text("lost").await.is_err());
+		}
+		server.await.unwrap();
+
+		// The server is gone, so the next attempt fails and is retried.
+		assert!(matches!(client.receive(&mut message).await.unwrap(), Event::Reconnecting { attempt: 1, .. }));
+		sender.close().await.unwrap();
+		assert!(matches!(client.receive(&mut message).await.unwrap(), Event::Closed));
+	}
+}
@@ -9,22 +9,45 @@
 //! A persistent websocket connection after the handshake phase, represented
 //! as a [`Sender`] and [`Receiver`] pair.
 
-use crate::data::{ByteSlice125, Data, Incoming};
+use crate::data::{ByteSlice125, Data, Incoming, Message};
 use crate::{
-	base::{self, Header, OpCode, MAX_HEADER_SIZE},
-	extension::Extension,
-	Parsing, Storage,
+	base::{self, Header, OpCode},
+	extension::{self, Extension},
+	proto::{
+		self, Decoder, Encoder, Event, MaskRng, MemoryLimiter, OversizedControl, Unmasked, Utf8Policy, MAX_MESSAGE_SIZE,
+	},
+	Storage,
 };
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use futures::{
+	channel::{mpsc, oneshot},
+	future::Either,
 	io::{ReadHalf, WriteHalf},
 	lock::BiLock,
 	prelude::*,
+	ready,
 };
-use std::{fmt, io, str};
+use futures_timer::Delay;
+use std::{
+	fmt, io,
+	pin::{pin, Pin},
+	str,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+	task::{Context, Poll, Waker},
+	time::{Duration, Instant},
+};
+
+/// Default max. number of bytes read from the socket at once.
+const MAX_READ_SIZE: usize = 64 * 1024;
 
-/// Accumulated max. size of a complete message.
-const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+/// Max. number of control events buffered for [`ControlEvents`].
+const MAX_CONTROL_EVENTS: usize = 64;
+
+/// Payload of the PINGs sent by [`Builder::set_keepalive`].
+const KEEPALIVE_PAYLOAD: &[u8] = b"keepalive";
 
 /// Max. size of a single message frame.
 const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE;
@@ -40,11 +63,7 @@ pub enum Mode {
 
 impl Mode {
 	pub fn is_client(self) -> bool {
-		if let Mode::Client = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Mode::Client)
 	}
 
 	pub fn is_server(self) -> bool {
@@ -62,32 +81,394 @@ impl fmt::Display for Id {
 	}
 }
 
+/// State shared by [`Sender`] and [`Receiver`].
+#[derive(Debug)]
+struct Shared {
+	/// Counter to create unique latency probe payloads.
+	next_probe: AtomicU64,
+	/// PINGs awaiting their PONG, `None` once the [`Receiver`] is dropped.
+	probes: Mutex<Option<Vec<Probe>>>,
+	/// Statistics of the connection.
+	counters: Counters,
+	/// Set once a CLOSE frame has been sent by the [`Sender`].
+	close_sent: AtomicBool,
+	/// Notified when the remote's CLOSE is received, see [`Sender::shutdown`].
+	close_reply: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+/// Statistics counters of a connection, see [`Stats`].
+#[derive(Debug, Default)]
+struct Counters {
+	frames_sent: AtomicU64,
+	frames_received: AtomicU64,
+	control_frames_sent: AtomicU64,
+	control_frames_received: AtomicU64,
+	messages_sent: AtomicU64,
+	messages_received: AtomicU64,
+	bytes_sent: AtomicU64,
+	bytes_received: AtomicU64,
+	message_bytes_sent: AtomicU64,
+	message_bytes_received: AtomicU64,
+	activity: Activity,
+}
+
+/// When frames were last sent and received, in nanoseconds since `created`.
+#[derive(Debug)]
+struct Activity {
+	created: Instant,
+	last_sent: AtomicU64,
+	last_received: AtomicU64,
+}
+
+impl Default for Activity {
+	fn default() -> Self {
+		Activity { created: Instant::now(), last_sent: AtomicU64::new(0), last_received: AtomicU64::new(0) }
+	}
+}
+
+impl Activity {
+	fn on_activity(&self, last: &AtomicU64) {
+		let nanos = u64::try_from(self.created.elapsed().as_nanos()).unwrap_or(u64::MAX);
+		last.fetch_max(nanos, Ordering::Relaxed);
+	}
+
+	fn idle_for(&self, last: &AtomicU64) -> Duration {
+		self.created.elapsed().saturating_sub(Duration::from_nanos(last.load(Ordering::Relaxed)))
+	}
+}
+
+impl Counters {
+	fn on_frame_sent(&self, header: &Header) {
+		self.activity.on_activity(&self.activity.last_sent);
+		self.frames_sent.fetch_add(1, Ordering::Relaxed);
+		self.bytes_sent.fetch_add(header.payload_len() as u64, Ordering::Relaxed);
+		if header.opcode().is_control() {
+			self.control_frames_sent.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	fn on_frame_received(&self, header: &Header) {
+		self.activity.on_activity(&self.activity.last_received);
+		self.frames_received.fetch_add(1, Ordering::Relaxed);
+		self.bytes_received.fetch_add(header.payload_len() as u64, Ordering::Relaxed);
+		if header.opcode().is_control() {
+			self.control_frames_received.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Count a message of `len` bytes, `encoded_len` bytes after extensions were applied, if any.
+	fn on_message_sent(&self, len: usize, encoded_len: Option<usize>) {
+		self.messages_sent.fetch_add(1, Ordering::Relaxed);
+		self.message_bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+		#[cfg(feature = "metrics")]
+		record_message_metrics("sent", len, encoded_len);
+		#[cfg(not(feature = "metrics"))]
+		let _ = encoded_len;
+	}
+
+	/// Count a message of `len` bytes, `encoded_len` bytes before extensions were applied, if any.
+	fn on_message_received(&self, len: usize, encoded_len: Option<usize>) {
+		self.messages_received.fetch_add(1, Ordering::Relaxed);
+		self.message_bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+		#[cfg(feature = "metrics")]
+		record_message_metrics("received", len, encoded_len);
+		#[cfg(not(feature = "metrics"))]
+		let _ = encoded_len;
+	}
+
+	fn snapshot(&self) -> Stats {
+		Stats {
+			frames_sent: self.frames_sent.load(Ordering::Relaxed),
+			frames_received: self.frames_received.load(Ordering::Relaxed),
+			control_frames_sent: self.control_frames_sent.load(Ordering::Relaxed),
+			control_frames_received: self.control_frames_received.load(Ordering::Relaxed),
+			messages_sent: self.messages_sent.load(Ordering::Relaxed),
+			messages_received: self.messages_received.load(Ordering::Relaxed),
+			bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+			bytes_received: self.bytes_received.load(Ordering::Relaxed),
+			message_bytes_sent: self.message_bytes_sent.load(Ordering::Relaxed),
+			message_bytes_received: self.message_bytes_received.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// Record the size and compression ratio of a message with the `metrics` facade.
+#[cfg(feature = "metrics")]
+fn record_message_metrics(direction: &'static str, len: usize, encoded_len: Option<usize>) {
+	metrics::histogram!("soketto_message_size_bytes", "direction" => direction).record(len as f64);
+	if let Some(encoded_len) = encoded_len.filter(|_| len > 0) {
+		metrics::histogram!("soketto_compression_ratio", "direction" => direction)
+			.record(encoded_len as f64 / len as f64)
+	}
+}
+
+/// A snapshot of the statistics of a connection, see [`Sender::stats`] and [`Receiver::stats`].
+///
+/// Byte counts are payload bytes, excluding frame headers. The message byte counts
+/// are taken before extensions are applied when sending and after when receiving,
+/// whereas the other byte counts are taken on the wire, so with a compression
+/// extension their ratio is the compression ratio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+	/// Number of frames sent, including control frames.
+	pub frames_sent: u64,
+	/// Number of frames received, including control frames.
+	pub frames_received: u64,
+	/// Number of PING, PONG and CLOSE frames sent.
+	pub control_frames_sent: u64,
+	/// Number of PING, PONG and CLOSE frames received.
+	pub control_frames_received: u64,
+	/// Number of messages sent.
+	pub messages_sent: u64,
+	/// Number of messages received.
+	pub messages_received: u64,
+	/// Number of payload bytes sent.
+	pub bytes_sent: u64,
+	/// Number of payload bytes received.
+	pub bytes_received: u64,
+	/// Number of message bytes sent, before extensions are applied.
+	pub message_bytes_sent: u64,
+	/// Number of message bytes received, after extensions are applied.
+	pub message_bytes_received: u64,
+}
+
+/// A PING sent by [`Sender::ping`] or [`Sender::measure_latency`].
+#[derive(Debug)]
+struct Probe {
+	payload: Vec<u8>,
+	sent: Instant,
+	tx: oneshot::Sender<Pong>,
+}
+
+/// The write half of a connection.
+///
+/// Frames are assembled in a persistent buffer, which is written to the socket
+/// right away or, in buffered mode, queued, see [`Builder::set_send_buffer`].
+#[derive(Debug)]
+struct Writer<T> {
+	io: WriteHalf<T>,
+	/// Bytes queued but not yet written to `io`, reused for every frame.
+	buffer: BytesMut,
+	/// The high and low watermarks if buffered mode is enabled.
+	watermarks: Option<(usize, usize)>,
+	/// Set once the high watermark is exceeded, until the low watermark is reached.
+	is_blocked: bool,
+	/// The min. number of bytes by which `buffer` grows.
+	grow: usize,
+	/// The automatic flushing state, see [`Builder::set_auto_flush`].
+	auto_flush: Option<AutoFlush>,
+}
+
+/// Why [`Receiver::read`] woke up.
+enum Wakeup {
+	/// Reading completed.
+	Read(io::Result<()>),
+	/// The writer was flushed automatically.
+	Flushed(io::Result<()>),
+	/// A timeout fired.
+	Failed(Error),
+	/// The keepalive timer fired.
+	Keepalive,
+}
+
+/// When and whether a [`Writer`] has to be flushed automatically.
+#[derive(Debug)]
+struct AutoFlush {
+	max_bytes: usize,
+	interval: Duration,
+	/// Number of bytes written since the last flush.
+	unflushed: usize,
+	/// When the unflushed bytes have to be flushed at the latest.
+	deadline: Option<Instant>,
+	/// Fires at the deadline.
+	timer: Option<Delay>,
+	/// Waiting for a deadline to be set.
+	waker: Option<Waker>,
+}
+
+impl<T: AsyncWrite + Unpin> Writer<T> {
+	/// Write queued bytes to the socket until at most `n` bytes remain.
+	fn poll_drain(&mut self, cx: &mut Context, n: usize) -> Poll<io::Result<()>> {
+		while self.buffer.len() > n {
+			match ready!(Pin::new(&mut self.io).poll_write(cx, &self.buffer))? {
+				0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+				k => self.buffer.advance(k),
+			}
+		}
+		Poll::Ready(Ok(()))
+	}
+
+	/// Append bytes to the queue and return them.
+	fn queue(&mut self, bytes: &[u8]) -> &mut [u8] {
+		if self.buffer.capacity() - self.buffer.len() < bytes.len() {
+			self.buffer.reserve(std::cmp::max(bytes.len(), self.grow))
+		}
+		let start = self.buffer.len();
+		self.buffer.extend_from_slice(bytes);
+		&mut self.buffer[start..]
+	}
+
+	/// Count `n` written bytes and return whether an automatic flush is due.
+	fn on_write(&mut self, n: usize) -> bool {
+		let Some(a) = &mut self.auto_flush else { return false };
+		a.unflushed += n;
+		let now = Instant::now();
+		let deadline = *a.deadline.get_or_insert_with(|| {
+			if let Some(w) = a.waker.take() {
+				w.wake()
+			}
+			now + a.interval
+		});
+		a.unflushed >= a.max_bytes || now >= deadline
+	}
+
+	/// Flush once the deadline for the unflushed bytes has passed.
+	///
+	/// Remains pending while nothing has been written since the last flush.
+	fn poll_auto_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+		let Some(a) = &mut self.auto_flush else { return Poll::Pending };
+		let Some(deadline) = a.deadline else {
+			a.waker = Some(cx.waker().clone());
+			return Poll::Pending;
+		};
+		let timer = a.timer.get_or_insert_with(|| Delay::new(deadline.saturating_duration_since(Instant::now())));
+		ready!(timer.poll_unpin(cx));
+		Pin::new(self).poll_flush(cx)
+	}
+
+	/// Reset the automatic flushing state after a flush.
+	fn on_flush(&mut self) {
+		if let Some(a) = &mut self.auto_flush {
+			a.unflushed = 0;
+			a.deadline = None;
+			a.timer = None
+		}
+	}
+
+	/// Check if more bytes may be queued, draining the queue to the low
+	/// watermark if the high watermark has been exceeded.
+	fn poll_ready(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+		let Some((high, low)) = self.watermarks else { return Poll::Ready(Ok(())) };
+		if self.buffer.len() > high {
+			self.is_blocked = true
+		}
+		if self.is_blocked {
+			ready!(self.poll_drain(cx, low))?;
+			self.is_blocked = false
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Writer<T> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		if this.watermarks.is_none() {
+			// A frame may be left over if writing it has been cancelled.
+			ready!(this.poll_drain(cx, 0))?;
+			return Pin::new(&mut this.io).poll_write(cx, buf);
+		}
+		this.queue(buf);
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		ready!(this.poll_drain(cx, 0))?;
+		ready!(Pin::new(&mut this.io).poll_flush(cx))?;
+		this.on_flush();
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		ready!(this.poll_drain(cx, 0))?;
+		ready!(Pin::new(&mut this.io).poll_close(cx))?;
+		this.on_flush();
+		Poll::Ready(Ok(()))
+	}
+}
+
 /// The sending half of a connection.
 #[derive(Debug)]
 pub struct Sender<T> {
 	id: Id,
-	mode: Mode,
-	codec: base::Codec,
-	writer: BiLock<WriteHalf<T>>,
-	mask_buffer: Vec<u8>,
+	encoder: Encoder,
+	writer: BiLock<Writer<T>>,
 	extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
 	has_extensions: bool,
+	/// Names of extensions not applied to outgoing messages, see [`Sender::set_extension_paused`].
+	paused: Vec<String>,
+	/// The max. payload size of the frames of a message, see [`Sender::set_fragment_size`].
+	fragment_size: Option<usize>,
+	shared: Arc<Shared>,
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
 }
 
 /// The receiving half of a connection.
 #[derive(Debug)]
 pub struct Receiver<T> {
 	id: Id,
-	mode: Mode,
-	codec: base::Codec,
+	decoder: Decoder,
+	encoder: Encoder,
 	reader: ReadHalf<T>,
-	writer: BiLock<WriteHalf<T>>,
+	/// The max. number of bytes read from `reader` at once, see [`Builder::set_read_buffer_size`].
+	read_size: usize,
+	writer: BiLock<Writer<T>>,
+	/// Whether to flush the writer when the auto-flush interval elapses while reading.
+	auto_flush: bool,
+	/// Fires when the current [`Receiver::receive_timeout`] call times out.
+	timeout: Option<Delay>,
+	/// The time allowed to receive a frame after its header, see [`Builder::set_frame_timeout`].
+	frame_timeout: Option<Duration>,
+	/// Fires when the current frame times out.
+	frame_timer: Option<Delay>,
+	/// The keepalive interval and timeout, see [`Builder::set_keepalive`].
+	keepalive: Option<(Duration, Duration)>,
+	/// Fires when a PING is due or, if one has been sent, when its timeout elapses.
+	keepalive_timer: Option<Delay>,
+	/// Whether a keepalive PING has been sent and nothing has been received since.
+	awaiting_pong: bool,
+	/// Creates the payload of PONGs answering PINGs, see [`Builder::set_pong_hook`].
+	pong_hook: Option<PongHook>,
+	/// Whether to return PONGs not answering a tracked PING.
+	unsolicited_pongs: bool,
+	/// Where control events go, see [`Receiver::control_events`].
+	control: Option<mpsc::Sender<Control>>,
+	/// Messages received with [`Receiver::receive_data_into`].
+	scratch: Vec<u8>,
+	/// The message in `scratch` and the number of its bytes copied so far, if incomplete.
+	pending: Option<(Data, usize)>,
+	/// Messages received with [`Receiver::receive_text`] to append to a non-empty
+	/// `String` and with [`Receiver::receive_data_to`].
+	buffer: Vec<u8>,
 	extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
 	has_extensions: bool,
-	buffer: BytesMut,
-	ctrl_buffer: BytesMut,
-	max_message_size: usize,
-	is_closed: bool,
+	shared: Arc<Shared>,
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
+}
+
+/// Creates the payload of a PONG, see [`Builder::set_pong_hook`].
+struct PongHook(Box<PongFn>);
+
+type PongFn = dyn FnMut(&[u8]) -> Option<Vec<u8>> + Send;
+
+impl fmt::Debug for PongHook {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("PongHook")
+	}
+}
+
+/// Creates an extension, see [`Builder::add_extension_with`].
+struct ExtensionFactory(Box<dyn FnOnce() -> Box<dyn Extension + Send> + Send>);
+
+impl fmt::Debug for ExtensionFactory {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ExtensionFactory")
+	}
 }
 
 /// A connection builder.
@@ -102,8 +483,26 @@ pub struct Builder<T> {
 	socket: T,
 	codec: base::Codec,
 	extensions: Vec<Box<dyn Extension + Send>>,
+	extension_factories: Vec<ExtensionFactory>,
 	buffer: BytesMut,
+	read_buffer_size: (usize, usize),
+	write_buffer_size: (usize, usize),
 	max_message_size: usize,
+	max_fragments: usize,
+	partial_delivery: bool,
+	strict_close_codes: bool,
+	masking: bool,
+	mask_rng: Option<MaskRng>,
+	accept_unmasked: bool,
+	utf8_policy: Utf8Policy,
+	oversized_control: OversizedControl,
+	frame_timeout: Option<Duration>,
+	keepalive: Option<(Duration, Duration)>,
+	pong_hook: Option<PongHook>,
+	unsolicited_pongs: bool,
+	memory_limiter: Option<MemoryLimiter>,
+	watermarks: Option<(usize, usize)>,
+	auto_flush: Option<(usize, Duration)>,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
@@ -124,8 +523,26 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
 			socket,
 			codec,
 			extensions: Vec::new(),
+			extension_factories: Vec::new(),
 			buffer: BytesMut::new(),
+			read_buffer_size: (0, MAX_READ_SIZE),
+			write_buffer_size: (0, 0),
 			max_message_size: MAX_MESSAGE_SIZE,
+			max_fragments: usize::MAX,
+			partial_delivery: false,
+			strict_close_codes: true,
+			masking: true,
+			mask_rng: None,
+			accept_unmasked: false,
+			utf8_policy: Utf8Policy::Strict,
+			oversized_control: OversizedControl::Fail,
+			frame_timeout: None,
+			keepalive: None,
+			pong_hook: None,
+			unsolicited_pongs: true,
+			memory_limiter: None,
+			watermarks: None,
+			auto_flush: None,
 		}
 	}
 
@@ -134,18 +551,96 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
 		self.buffer = b
 	}
 
+	/// Set the initial capacity of the read buffer and the max. number of
+	/// bytes read from the socket at once, by which the buffer grows.
+	///
+	/// By default, the buffer is allocated on the first read, which reads up
+	/// to 64 KiB. Smaller values save memory with many idle connections,
+	/// larger values may increase the throughput.
+	///
+	/// # Panics
+	///
+	/// If `step` is zero.
+	pub fn set_read_buffer_size(&mut self, initial: usize, step: usize) {
+		assert!(step > 0, "read buffer step must not be zero");
+		self.read_buffer_size = (initial, step)
+	}
+
+	/// Set the initial capacity of the send buffer and the min. number of
+	/// bytes by which it grows.
+	///
+	/// Every frame is assembled in the send buffer before it is written, which
+	/// keeps it until written to the socket in buffered mode, see
+	/// [`Builder::set_send_buffer`]. By default, it is allocated once needed
+	/// and grows as needed, but it is never shrunk.
+	pub fn set_write_buffer_size(&mut self, initial: usize, step: usize) {
+		self.write_buffer_size = (initial, step)
+	}
+
 	/// Add extensions to use with this connection.
 	///
-	/// Only enabled extensions will be considered.
+	/// Only enabled extensions will be considered. Extensions are applied to
+	/// outgoing messages in the order in which they are added and to incoming
+	/// messages in reverse order.
+	///
+	/// An extension using the same reserved bits or opcode as one added before
+	/// is ignored, see [`Builder::try_add_extensions`] to treat this as an error.
 	pub fn add_extensions<I>(&mut self, extensions: I)
 	where
 		I: IntoIterator<Item = Box<dyn Extension + Send>>,
 	{
 		for e in extensions.into_iter().filter(|e| e.is_enabled()) {
-			log::debug!("{}: using extension: {}", self.id, e.name());
-			self.codec.add_reserved_bits(e.reserved_bits());
-			self.extensions.push(e)
+			if let Some(other) = self.extensions.iter().find(|x| extension::is_conflicting(&***x, &*e)) {
+				log::debug!("{}: ignoring extension {} which conflicts with {}", self.id, e.name(), other.name());
+				continue;
+			}
+			self.push_extension(e)
+		}
+	}
+
+	/// Add extensions to use with this connection, unless they conflict.
+	///
+	/// Like [`Builder::add_extensions`], except that if an extension uses the
+	/// same reserved bits or opcode as another one, an error is returned and
+	/// none of the extensions are added.
+	pub fn try_add_extensions<I>(&mut self, extensions: I) -> Result<(), Error>
+	where
+		I: IntoIterator<Item = Box<dyn Extension + Send>>,
+	{
+		let extensions = extensions.into_iter().filter(|e| e.is_enabled()).collect::<Vec<_>>();
+		for (i, e) in extensions.iter().enumerate() {
+			let previous = self.extensions.iter().chain(&extensions[..i]);
+			if let Some(other) = previous.into_iter().find(|x| extension::is_conflicting(&***x, &**e)) {
+				return Err(Error::ConflictingExtensions(other.name().into(), e.name().into()));
+			}
+		}
+		for e in extensions {
+			self.push_extension(e)
+		}
+		Ok(())
+	}
+
+	/// Use an enabled, non-conflicting extension.
+	fn push_extension(&mut self, e: Box<dyn Extension + Send>) {
+		log::debug!("{}: using extension: {}", self.id, e.name());
+		self.codec.add_reserved_bits(e.reserved_bits());
+		if let Some(opcode) = e.reserved_opcode() {
+			self.codec.add_reserved_opcode(opcode);
 		}
+		self.extensions.push(e)
+	}
+
+	/// Add a factory creating an extension to use with this connection.
+	///
+	/// The factory is called by [`Builder::finish`], i.e. after the extensions
+	/// negotiated during the handshake have been added, so it may be set up from
+	/// shared configuration before the connection is established. If the created
+	/// extension is disabled or conflicts with another one, it is ignored.
+	pub fn add_extension_with<F>(&mut self, f: F)
+	where
+		F: FnOnce() -> Box<dyn Extension + Send> + Send + 'static,
+	{
+		self.extension_factories.push(ExtensionFactory(Box::new(f)))
 	}
 
 	/// Set the maximum size of a complete message.
@@ -158,40 +653,275 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
 		self.max_message_size = max
 	}
 
+	/// Set the maximum number of frames a single message may consist of.
+	///
+	/// Messages with more frames fail with [`Error::TooManyFragments`]. By
+	/// default, the number of frames is only limited by the maximum message
+	/// size, which allows peers to send messages of many tiny frames.
+	pub fn set_max_fragments(&mut self, max: usize) {
+		self.max_fragments = max
+	}
+
+	/// On [`Error::MessageTooLarge`], leave the data received up to the
+	/// maximum message size in the buffer given to [`Receiver::receive`].
+	///
+	/// This allows inspecting what the peer sent, e.g. for logging. The data
+	/// is left as received, without extensions applied or text validated.
+	/// By default, oversized messages are skipped entirely.
+	pub fn set_partial_delivery(&mut self, on: bool) {
+		self.partial_delivery = on
+	}
+
+	/// Fail the connection if a frame is not received completely within
+	/// `timeout` after its header has been received.
+	///
+	/// This prevents peers from tying up resources by sending frames very
+	/// slowly. The connection is closed with code 1008 (policy violation) and
+	/// [`Error::FrameTimeout`] is returned.
+	pub fn set_frame_timeout(&mut self, timeout: Duration) {
+		self.frame_timeout = Some(timeout)
+	}
+
+	/// Send a PING whenever nothing has been received for `interval` and fail
+	/// the connection if nothing is received within `timeout` after that.
+	///
+	/// The timers are checked while [`Receiver::receive`] and its variants are
+	/// polled, so no background task is needed, but keepalive only works while
+	/// the application is receiving. When it fails, [`Error::KeepaliveTimeout`]
+	/// is returned. The PONGs answering these PINGs are returned like any other.
+	pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+		self.keepalive = Some((interval, timeout))
+	}
+
+	/// Set a hook creating the payload of the PONG answering a PING.
+	///
+	/// The hook is called with the payload of every PING received. It returns
+	/// the payload of the PONG, which is truncated to 125 bytes, or `None` to
+	/// not answer the PING at all. By default, the PING payload is echoed as
+	/// required by [RFC 6455][0].
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-5.5.2
+	pub fn set_pong_hook<F>(&mut self, f: F)
+	where
+		F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+	{
+		self.pong_hook = Some(PongHook(Box::new(f)))
+	}
+
+	/// Whether to return PONGs which do not answer a PING sent with
+	/// [`Sender::ping`], [`Sender::measure_latency`] or for keepalive.
+	///
+	/// By default, all PONGs are returned as [`Incoming::Pong`] or as control
+	/// events. Otherwise, unsolicited PONGs, which RFC 6455 allows as
+	/// unidirectional heartbeats, are dropped. This includes PONGs answering
+	/// PINGs sent with [`Sender::send_ping`].
+	pub fn set_unsolicited_pongs(&mut self, allow: bool) {
+		self.unsolicited_pongs = allow
+	}
+
+	/// Count the bytes of messages being received against a budget shared with other connections.
+	///
+	/// Messages which would exceed the budget fail with
+	/// [`Error::MemoryLimitExceeded`], see [`MemoryLimiter`].
+	pub fn set_memory_limiter(&mut self, limiter: MemoryLimiter) {
+		self.memory_limiter = Some(limiter)
+	}
+
 	/// Set the maximum size of a single websocket frame payload.
 	pub fn set_max_frame_size(&mut self, max: usize) {
 		self.codec.set_max_data_size(max);
 	}
 
+	/// Enable or disable masking of the frames sent by a client (enabled by default).
+	///
+	/// **Note**: Disabling masking violates [RFC 6455][0], which requires clients
+	/// to mask all frames, and servers will usually reject unmasked frames. Only
+	/// disable it if both ends are trusted, e.g. in tests and benchmarks or behind
+	/// a proxy, as masking protects intermediaries against cache poisoning.
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-5.3
+	pub fn set_masking(&mut self, masking: bool) {
+		self.masking = masking
+	}
+
+	/// Generate masking keys with the given random number generator.
+	///
+	/// By default, a thread-local generator seeded by the operating system is
+	/// used. A deterministic generator, e.g. [`MaskRng::from_seed`], makes the
+	/// bytes sent by a client reproducible, which is useful for tests.
+	pub fn set_mask_rng(&mut self, rng: MaskRng) {
+		self.mask_rng = Some(rng)
+	}
+
+	/// Accept frames without mask from clients (disabled by default).
+	///
+	/// [RFC 6455][0] requires servers to fail the connection if a client sends
+	/// an unmasked frame, which results in [`Error::UnmaskedFrame`]. With this
+	/// option, servers accept such frames and log a warning instead, e.g. for
+	/// interoperability with clients which cannot be fixed. It has no effect
+	/// on clients.
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-5.1
+	pub fn set_accept_unmasked_frames(&mut self, accept: bool) {
+		self.accept_unmasked = accept
+	}
+
+	/// Set how text messages which are not valid UTF-8 are handled.
+	///
+	/// By default, they fail the connection with close code 1007 and
+	/// [`Error::Utf8`], as required by [RFC 6455][0].
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-8.1
+	pub fn set_utf8_policy(&mut self, policy: Utf8Policy) {
+		self.utf8_policy = policy
+	}
+
+	/// Set whether close messages with invalid or reserved codes are treated
+	/// as protocol errors.
+	///
+	/// By default, such codes are reported as [`CloseCode::ProtocolError`] and
+	/// answered with code 1002, as required by [RFC 6455][0]. Otherwise, they
+	/// are passed on as received, since some peers send arbitrary codes.
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-7.4
+	pub fn set_strict_close_codes(&mut self, strict: bool) {
+		self.strict_close_codes = strict
+	}
+
+	/// Set how control frames with more than 125 bytes of payload data are handled.
+	///
+	/// By default, they fail the connection with [`Error::Codec`], as required
+	/// by [RFC 6455][0]. Other policies help talking to peers which are known
+	/// to send such frames, see [`OversizedControl`].
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-5.5
+	pub fn set_oversized_control(&mut self, policy: OversizedControl) {
+		self.oversized_control = policy
+	}
+
+	/// Enable buffered sending with the given high and low watermarks.
+	///
+	/// In buffered mode, frames are queued in memory and only written to the
+	/// socket when the [`Sender`] is flushed or the queue exceeds `high` bytes.
+	/// [`Sender::poll_ready`] then waits until the queue has been written down
+	/// to `low` bytes, and so does sending, so that a slow peer cannot make the
+	/// queue grow without bounds. `low` is capped at `high`.
+	pub fn set_send_buffer(&mut self, high: usize, low: usize) {
+		self.watermarks = Some((high, std::cmp::min(low, high)))
+	}
+
+	/// Flush automatically once `max_bytes` have been sent since the last
+	/// flush or `interval` has passed since the first frame sent after it.
+	///
+	/// Both limits are checked whenever a frame is sent. In addition, the
+	/// [`Receiver`] flushes when the interval elapses while it waits for data,
+	/// so that the last messages of a burst are not delayed indefinitely.
+	pub fn set_auto_flush(&mut self, max_bytes: usize, interval: Duration) {
+		self.auto_flush = Some((max_bytes, interval))
+	}
+
 	/// Create a configured [`Sender`]/[`Receiver`] pair.
-	pub fn finish(self) -> (Sender<T>, Receiver<T>) {
+	pub fn finish(mut self) -> (Sender<T>, Receiver<T>) {
+		for factory in std::mem::take(&mut self.extension_factories) {
+			self.add_extensions(Some((factory.0)()))
+		}
 		let (rhlf, whlf) = self.socket.split();
-		let (wrt1, wrt2) = BiLock::new(whlf);
+		let auto_flush = self.auto_flush.map(|(max_bytes, interval)| AutoFlush {
+			max_bytes,
+			interval,
+			unflushed: 0,
+			deadline: None,
+			timer: None,
+			waker: None,
+		});
+		let (initial, grow) = self.write_buffer_size;
+		let writer = Writer {
+			io: whlf,
+			buffer: BytesMut::with_capacity(initial),
+			watermarks: self.watermarks,
+			is_blocked: false,
+			grow,
+			auto_flush,
+		};
+		let (wrt1, wrt2) = BiLock::new(writer);
 		let has_extensions = !self.extensions.is_empty();
+		#[cfg(feature = "tracing")]
+		let span = {
+			let extensions = self.extensions.iter().map(|e| e.name()).collect::<Vec<_>>().join(", ");
+			tracing::debug_span!("websocket", id = %self.id, mode = ?self.mode, extensions)
+		};
 		let (ext1, ext2) = BiLock::new(self.extensions);
+		let shared = Arc::new(Shared {
+			next_probe: AtomicU64::new(0),
+			probes: Mutex::new(Some(Vec::new())),
+			counters: Counters::default(),
+			close_sent: AtomicBool::new(false),
+			close_reply: Mutex::new(None),
+		});
+
+		let (initial, read_size) = self.read_buffer_size;
+		let mut buffer = self.buffer;
+		buffer.reserve(initial);
+		let mut decoder = Decoder::new(self.codec.clone());
+		decoder.set_buffer(buffer).set_max_message_size(self.max_message_size).set_utf8_policy(self.utf8_policy);
+		decoder.set_oversized_control(self.oversized_control).set_max_fragments(self.max_fragments);
+		decoder.set_partial_delivery(self.partial_delivery).set_strict_close_codes(self.strict_close_codes);
+		decoder.set_defer_extensions(true);
+		if let Some(limiter) = self.memory_limiter {
+			decoder.set_memory_limiter(limiter);
+		}
+		match self.mode {
+			Mode::Server if self.accept_unmasked => decoder.set_unmasked(Unmasked::Warn),
+			Mode::Server => decoder.set_unmasked(Unmasked::Reject),
+			Mode::Client => decoder.set_unmasked(Unmasked::Accept),
+		};
+		let mut encoder = Encoder::new(self.mode, self.codec);
+		encoder.set_masking(self.masking);
+		let mut answer_encoder = Encoder::new(self.mode, encoder.codec().clone());
+		answer_encoder.set_masking(self.masking);
+		if let Some(rng) = self.mask_rng {
+			encoder.set_rng(rng.clone());
+			answer_encoder.set_rng(rng);
+		}
 
 		let recv = Receiver {
 			id: self.id,
-			mode: self.mode,
+			decoder,
+			encoder: answer_encoder,
 			reader: rhlf,
+			read_size,
 			writer: wrt1,
-			codec: self.codec.clone(),
+			auto_flush: self.auto_flush.is_some(),
+			timeout: None,
+			frame_timeout: self.frame_timeout,
+			frame_timer: None,
+			keepalive: self.keepalive,
+			keepalive_timer: None,
+			awaiting_pong: false,
+			pong_hook: self.pong_hook,
+			unsolicited_pongs: self.unsolicited_pongs,
+			control: None,
+			scratch: Vec::new(),
+			buffer: Vec::new(),
+			pending: None,
 			extensions: ext1,
 			has_extensions,
-			buffer: self.buffer,
-			ctrl_buffer: BytesMut::new(),
-			max_message_size: self.max_message_size,
-			is_closed: false,
+			shared: shared.clone(),
+			#[cfg(feature = "tracing")]
+			span: span.clone(),
 		};
 
 		let send = Sender {
 			id: self.id,
-			mode: self.mode,
+			encoder,
 			writer: wrt2,
-			mask_buffer: Vec::new(),
-			codec: self.codec,
 			extensions: ext2,
 			has_extensions,
+			paused: Vec::new(),
+			fragment_size: None,
+			shared,
+			#[cfg(feature = "tracing")]
+			span,
 		};
 
 		(send, recv)
@@ -199,6 +929,19 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
+	/// Hand out every frame of a text or binary message as
+	/// [`Incoming::Fragment`] as soon as it arrives, instead of the complete
+	/// message (disabled by default).
+	///
+	/// Each fragment is appended to the buffer given to [`Receiver::receive`],
+	/// which may be cleared in between, so messages can be processed in
+	/// constant memory. Extensions are not applied and text is not validated,
+	/// see [`Decoder::set_fragment_mode`]. [`Receiver::receive_data`] and the
+	/// methods based on it still return complete messages.
+	pub fn set_fragment_mode(&mut self, on: bool) {
+		self.decoder.set_fragment_mode(on);
+	}
+
 	/// Receive the next websocket message.
 	///
 	/// The received frames forming the complete message will be appended to
@@ -210,247 +953,493 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
 	/// [`Receiver::receive_data`] may be used instead which skips over PONGs
 	/// and considers only application payload data.
 	pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
-		let mut first_fragment_opcode = None;
-		let mut length: usize = 0;
-		let message_len = message.len();
+		self.receive_until(message, None, None).await
+	}
+
+	/// Receive the next websocket message like [`Receiver::receive`], but
+	/// fail with [`Error::Timeout`] if it is not complete within `timeout`.
+	///
+	/// The connection remains usable after a timeout. A partially received
+	/// message remains in `message` and is completed by the next call, which
+	/// must be given the same buffer.
+	pub async fn receive_timeout(&mut self, message: &mut Vec<u8>, timeout: Duration) -> Result<Incoming<'_>, Error> {
+		self.receive_until(message, Some(timeout), None).await
+	}
+
+	/// Receive the next websocket message, failing once the timeout elapses, if any.
+	///
+	/// Extensions may write complete messages to `sink`, if any, see [`Receiver::receive_data_to`].
+	async fn receive_until(
+		&mut self,
+		message: &mut Vec<u8>,
+		timeout: Option<Duration>,
+		sink: Option<&mut (dyn io::Write + Send + '_)>,
+	) -> Result<Incoming<'_>, Error> {
+		self.timeout = timeout.map(Delay::new);
+		#[cfg(feature = "tracing")]
+		let span = self.span.clone();
+		let result = self.receive_frames(message, sink).await;
+		#[cfg(feature = "tracing")]
+		match &result {
+			Err(
+				e @ (Error::Codec(_)
+				| Error::Extension(_)
+				| Error::UnexpectedOpCode(_)
+				| Error::Utf8(_)
+				| Error::UnmaskedFrame
+				| Error::ControlFrameTooLarge(_)),
+			) => {
+				tracing::warn!(parent: &span, error = %e, "failed to decode frame")
+			}
+			Err(
+				e
+				@ (Error::MessageTooLarge { .. } | Error::TooManyFragments { .. } | Error::MemoryLimitExceeded { .. }),
+			) => {
+				tracing::warn!(parent: &span, error = %e, "message too large")
+			}
+			Ok(Incoming::Closed(reason)) => {
+				tracing::debug!(parent: &span, code = u16::from(reason.code), reason = ?reason.descr, "close received")
+			}
+			_ => (),
+		}
+		#[cfg(feature = "metrics")]
+		if let Ok(Incoming::Closed(reason)) = &result {
+			metrics::counter!("soketto_close_total", "direction" => "received", "code" => reason.code.to_string())
+				.increment(1)
+		}
+		result
+	}
+
+	async fn receive_frames(
+		&mut self,
+		message: &mut Vec<u8>,
+		mut sink: Option<&mut (dyn io::Write + Send + '_)>,
+	) -> Result<Incoming<'_>, Error> {
 		loop {
-			if self.is_closed {
+			if self.decoder.is_closed() {
 				log::debug!("{}: cannot receive, connection is closed", self.id);
 				return Err(Error::Closed);
 			}
 
-			self.ctrl_buffer.clear();
-			let mut header = self.receive_header().await?;
-			log::trace!("{}: recv: {}", self.id, header);
-
-			// Handle control frames: PING, PONG and CLOSE.
-			if header.opcode().is_control() {
-				self.read_buffer(&header).await?;
-				self.ctrl_buffer = self.buffer.split_to(header.payload_len());
-				base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
-				if header.opcode() == OpCode::Pong {
-					return Ok(Incoming::Pong(&self.ctrl_buffer[..]));
+			let result = if self.has_extensions {
+				let mut extensions = self.extensions.lock().await;
+				match self.decoder.decode(message, &mut extensions[..]) {
+					Ok(Event::Decode { mut header, opcode }) => match sink.as_deref_mut() {
+						Some(sink) => {
+							let written =
+								proto::decode_with_extensions_to(&mut extensions[..], &mut header, message, sink).await;
+							self.decoder.finish_message_to(opcode, message, written)
+						}
+						None => {
+							let decoded =
+								proto::decode_with_extensions_async(&mut extensions[..], &mut header, message).await;
+							self.decoder.finish_message(opcode, message, decoded)
+						}
+					},
+					result => result,
 				}
-				if let Some(close_reason) = self.on_control(&header).await? {
-					log::trace!("{}: recv, incoming CLOSE: {:?}", self.id, close_reason);
-					return Ok(Incoming::Closed(close_reason));
+			} else {
+				self.decoder.decode(message, &mut [])
+			};
+
+			let event = match result {
+				Ok(event) => event,
+				Err(e @ Error::Utf8(_)) if self.decoder.is_closed() => {
+					log::debug!("{}: invalid utf-8, closing connection", self.id);
+					let _ = self.send_close(Some(CloseCode::InvalidPayload)).await;
+					return Err(e);
 				}
-				continue;
-			}
-
-			length = length.saturating_add(header.payload_len());
-
-			// Check if total message does not exceed maximum.
-			if length > self.max_message_size {
-				log::warn!("{}: accumulated message length exceeds maximum", self.id);
-
-				// Discard bytes that were too large to fit in the buffer.
-				discard_bytes(length as u64, &mut self.reader).await?;
-				return Err(Error::MessageTooLarge { current: length, maximum: self.max_message_size });
-			}
-
-			// Get the frame's payload data bytes from buffer or socket.
-			{
-				let old_msg_len = message.len();
-
-				let bytes_to_read = {
-					let required = header.payload_len();
-					let buffered = self.buffer.len();
-
-					if buffered == 0 {
-						required
-					} else if required > buffered {
-						message.extend_from_slice(&self.buffer);
-						self.buffer.clear();
-						required - buffered
-					} else {
-						message.extend_from_slice(&self.buffer.split_to(required));
-						0
+				Err(e) => return Err(e),
+			};
+
+			match event {
+				Event::NeedMore(n) => match self.read(std::cmp::min(n, self.read_size)).await {
+					Err(Error::FrameTimeout) => {
+						log::debug!("{}: frame timed out, closing connection", self.id);
+						let _ = self.send_close(Some(CloseCode::PolicyViolation)).await;
+						return Err(Error::FrameTimeout);
 					}
-				};
-
-				if bytes_to_read > 0 {
-					let n = message.len();
-					message.resize(n + bytes_to_read, 0u8);
-					self.reader.read_exact(&mut message[n..]).await?
+					result => result?,
+				},
+				Event::Frame(header) => {
+					log::trace!("{}: recv: {}", self.id, header);
+					self.frame_timer = None;
+					self.shared.counters.on_frame_received(&header)
 				}
-
-				debug_assert_eq!(header.payload_len(), message.len() - old_msg_len);
-
-				base::Codec::apply_mask(&header, &mut message[old_msg_len..]);
-			}
-
-			match (header.is_fin(), header.opcode()) {
-				(false, OpCode::Continue) => {
-					// Intermediate message fragment.
-					if first_fragment_opcode.is_none() {
-						log::debug!("{}: continue frame while not processing message fragments", self.id);
-						return Err(Error::UnexpectedOpCode(OpCode::Continue));
-					}
-					continue;
+				Event::Decode { .. } => unreachable!("extensions are applied above; qed"),
+				Event::Message { opcode, len, encoded_len } => {
+					self.shared.counters.on_message_received(len, self.has_extensions.then_some(encoded_len));
+					return match opcode {
+						OpCode::Text => Ok(Incoming::Data(Data::Text(len))),
+						oc if oc.is_reserved() => Ok(Incoming::Reserved(oc, len)),
+						_ => Ok(Incoming::Data(Data::Binary(len))),
+					};
 				}
-				(false, oc) => {
-					// Initial message fragment.
-					if first_fragment_opcode.is_some() {
-						log::debug!("{}: initial fragment while processing a fragmented message", self.id);
-						return Err(Error::UnexpectedOpCode(oc));
-					}
-					first_fragment_opcode = Some(oc);
-					self.decode_with_extensions(&mut header, message).await?;
-					continue;
+				Event::Fragment { opcode, fin, len } => {
+					return Ok(Incoming::Fragment { first_opcode: opcode, fin, len })
 				}
-				(true, OpCode::Continue) => {
-					// Last message fragment.
-					if let Some(oc) = first_fragment_opcode.take() {
-						header.set_payload_len(message.len());
-						log::trace!("{}: last fragment: total length = {} bytes", self.id, message.len());
-						self.decode_with_extensions(&mut header, message).await?;
-						header.set_opcode(oc);
+				Event::Ping => {
+					let payload = self.decoder.control_payload();
+					let answer = match &mut self.pong_hook {
+						Some(PongHook(hook)) => hook(payload).map(|mut p| {
+							p.truncate(125);
+							Storage::Owned(p)
+						}),
+						None => Some(Storage::Shared(payload)),
+					};
+					if let Some(mut data) = answer {
+						let mut answer = Header::new(OpCode::Pong);
+						write(self.id, &mut self.encoder, &mut self.writer, &mut answer, &mut data).await?;
+						self.shared.counters.on_frame_sent(&answer);
+						self.flush().await?
 					} else {
-						log::debug!("{}: last continue frame while not processing message fragments", self.id);
-						return Err(Error::UnexpectedOpCode(OpCode::Continue));
+						log::trace!("{}: not answering PING", self.id)
 					}
+					self.on_control(|payload| Control::Ping(payload.to_vec()))
 				}
-				(true, oc) => {
-					// Regular non-fragmented message.
-					if first_fragment_opcode.is_some() {
-						log::debug!("{}: regular message while processing fragmented message", self.id);
-						return Err(Error::UnexpectedOpCode(oc));
+				Event::Pong => {
+					let solicited = self.on_pong() || self.decoder.control_payload() == KEEPALIVE_PAYLOAD;
+					if !solicited && !self.unsolicited_pongs {
+						log::trace!("{}: dropping unsolicited PONG", self.id);
+						continue;
+					}
+					if self.control.is_none() {
+						return Ok(Incoming::Pong(self.decoder.control_payload()));
+					}
+					self.on_control(|payload| Control::Pong(payload.to_vec()))
+				}
+				Event::Closed(reason) => {
+					if let Some(tx) = self.shared.close_reply.lock().unwrap_or_else(PoisonError::into_inner).take() {
+						let _ = tx.send(());
+					}
+					if let Some(reason) = &reason {
+						self.on_control(|_| Control::Closed(reason.clone()))
+					}
+					log::trace!("{}: Acknowledging CLOSE to sender", self.id);
+					self.send_close(reason.as_ref().map(|r| r.code)).await?;
+					if let Some(reason) = reason {
+						log::trace!("{}: recv, incoming CLOSE: {:?}", self.id, reason);
+						return Ok(Incoming::Closed(reason));
 					}
-					self.decode_with_extensions(&mut header, message).await?
 				}
-			}
-
-			let num_bytes = message.len() - message_len;
-
-			if header.opcode() == OpCode::Text {
-				return Ok(Incoming::Data(Data::Text(num_bytes)));
-			} else {
-				return Ok(Incoming::Data(Data::Binary(num_bytes)));
 			}
 		}
 	}
 
-	/// Receive the next websocket message, skipping over control frames.
-	pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
-		loop {
-			if let Incoming::Data(d) = self.receive(message).await? {
-				return Ok(d);
+	/// Read up to `n` bytes into the decoder's buffer.
+	///
+	/// Meanwhile, the writer is flushed whenever the auto-flush interval
+	/// elapses, PINGs are sent when the connection has been idle for the
+	/// keepalive interval and reading fails with [`Error::Timeout`] if the
+	/// timeout fires, [`Error::FrameTimeout`] if the current frame times out
+	/// or [`Error::KeepaliveTimeout`] if nothing is received in reply to a PING.
+	async fn read(&mut self, n: usize) -> Result<(), Error> {
+		match self.frame_timeout {
+			Some(timeout) if self.decoder.is_in_frame() => {
+				self.frame_timer.get_or_insert_with(|| Delay::new(timeout));
 			}
+			_ => self.frame_timer = None,
 		}
-	}
-
-	/// Read the next frame header.
-	async fn receive_header(&mut self) -> Result<Header, Error> {
+		if let Some((interval, _)) = self.keepalive {
+			self.keepalive_timer.get_or_insert_with(|| Delay::new(interval));
+		}
+		if !self.auto_flush && self.timeout.is_none() && self.frame_timer.is_none() && self.keepalive.is_none() {
+			return Ok(crate::read(&mut self.reader, self.decoder.buffer_mut(), n).await?);
+		}
+		let mut read = pin!(crate::read(&mut self.reader, self.decoder.buffer_mut(), n));
 		loop {
-			match self.codec.decode_header(&self.buffer)? {
-				Parsing::Done { value: header, offset } => {
-					debug_assert!(offset <= MAX_HEADER_SIZE);
-					self.buffer.advance(offset);
-					return Ok(header);
+			let wakeup = future::poll_fn(|cx| {
+				if let Poll::Ready(result) = read.as_mut().poll(cx) {
+					return Poll::Ready(Wakeup::Read(result));
+				}
+				if self.auto_flush {
+					if let Poll::Ready(mut writer) = self.writer.poll_lock(cx) {
+						if let Poll::Ready(result) = writer.poll_auto_flush(cx) {
+							return Poll::Ready(Wakeup::Flushed(result));
+						}
+					}
+				}
+				if let Some(Poll::Ready(())) = self.timeout.as_mut().map(|t| t.poll_unpin(cx)) {
+					return Poll::Ready(Wakeup::Failed(Error::Timeout));
+				}
+				if let Some(Poll::Ready(())) = self.frame_timer.as_mut().map(|t| t.poll_unpin(cx)) {
+					return Poll::Ready(Wakeup::Failed(Error::FrameTimeout));
+				}
+				if let Some(Poll::Ready(())) = self.keepalive_timer.as_mut().map(|t| t.poll_unpin(cx)) {
+					return Poll::Ready(Wakeup::Keepalive);
+				}
+				Poll::Pending
+			});
+			match wakeup.await {
+				Wakeup::Read(result) => {
+					// Anything received shows that the remote is alive.
+					self.keepalive_timer = None;
+					self.awaiting_pong = false;
+					return Ok(result?);
+				}
+				Wakeup::Flushed(result) => result.or(Err(Error::Closed))?,
+				Wakeup::Failed(e) => return Err(e),
+				Wakeup::Keepalive => {
+					let (interval, timeout) = self.keepalive.expect("timer is only set with keepalive; qed");
+					if self.awaiting_pong {
+						return Err(Error::KeepaliveTimeout);
+					}
+					log::trace!("{}: idle for {:?}, sending PING", self.id, interval);
+					let mut header = Header::new(OpCode::Ping);
+					let mut data = Storage::Shared(KEEPALIVE_PAYLOAD);
+					write(self.id, &mut self.encoder, &mut self.writer, &mut header, &mut data).await?;
+					self.shared.counters.on_frame_sent(&header);
+					self.writer.lock().await.flush().await.or(Err(Error::Closed))?;
+					self.awaiting_pong = true;
+					self.keepalive_timer = Some(Delay::new(timeout))
 				}
-				Parsing::NeedMore(n) => crate::read(&mut self.reader, &mut self.buffer, n).await?,
 			}
 		}
 	}
 
-	/// Read the complete payload data into the read buffer.
-	async fn read_buffer(&mut self, header: &Header) -> Result<(), Error> {
-		if header.payload_len() <= self.buffer.len() {
-			return Ok(());
+	/// Write a CLOSE frame with the given code, unless the [`Sender`] has
+	/// already sent one, and close the socket.
+	async fn send_close(&mut self, code: Option<CloseCode>) -> Result<(), Error> {
+		let mut header = Header::new(OpCode::Close);
+		let code = code.map(|c| u16::from(c).to_be_bytes());
+		let mut data = Storage::Shared(code.as_ref().map_or(&[][..], |c| &c[..]));
+		if !self.shared.close_sent.load(Ordering::Acquire)
+			&& write(self.id, &mut self.encoder, &mut self.writer, &mut header, &mut data).await.is_ok()
+		{
+			self.shared.counters.on_frame_sent(&header)
 		}
-		let i = self.buffer.len();
-		let d = header.payload_len() - i;
-		self.buffer.resize(i + d, 0u8);
-		self.reader.read_exact(&mut self.buffer[i..]).await?;
+		self.flush().await?;
+		self.writer.lock().await.close().await?;
 		Ok(())
 	}
 
-	/// Answer incoming control frames.
-	/// `PING`: replied to immediately with a `PONG`
-	/// `PONG`: no action
-	/// `CLOSE`: replied to immediately with a `CLOSE`; returns the [`CloseReason`]
-	/// All other [`OpCode`]s return [`Error::UnexpectedOpCode`]
-	async fn on_control(&mut self, header: &Header) -> Result<Option<CloseReason>, Error> {
-		match header.opcode() {
-			OpCode::Ping => {
-				let mut answer = Header::new(OpCode::Pong);
-				let mut unused = Vec::new();
-				let mut data = Storage::Unique(&mut self.ctrl_buffer);
-				write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut answer, &mut data, &mut unused)
-					.await?;
-				self.flush().await?;
-				Ok(None)
-			}
-			OpCode::Pong => Ok(None),
-			OpCode::Close => {
-				log::trace!("{}: Acknowledging CLOSE to sender", self.id);
-				self.is_closed = true;
-				let (mut header, reason) = close_answer(&self.ctrl_buffer)?;
-				// Write back a Close frame
-				let mut unused = Vec::new();
-				if let Some(CloseReason { code, .. }) = reason {
-					let mut data = code.to_be_bytes();
-					let mut data = Storage::Unique(&mut data);
-					let _ = write(
-						self.id,
-						self.mode,
-						&mut self.codec,
-						&mut self.writer,
-						&mut header,
-						&mut data,
-						&mut unused,
-					)
-					.await;
-				} else {
-					let mut data = Storage::Unique(&mut []);
-					let _ = write(
-						self.id,
-						self.mode,
-						&mut self.codec,
-						&mut self.writer,
-						&mut header,
-						&mut data,
-						&mut unused,
-					)
-					.await;
-				}
-				self.flush().await?;
-				self.writer.lock().await.close().await?;
-				Ok(reason)
+	/// Get a stream of the control frames received from now on.
+	///
+	/// Afterwards, PINGs, PONGs and close messages are passed to the returned
+	/// [`ControlEvents`], which can be consumed by another task, and
+	/// [`Receiver::receive`] no longer returns PONGs. It still returns close
+	/// messages, so that the end of the connection is noticed. PINGs are
+	/// answered as before. The control events are only received while this
+	/// receiver is polled. If the stream is not consumed, events are dropped
+	/// once 64 events are pending.
+	///
+	/// Calling this method again replaces the previous stream, which ends.
+	pub fn control_events(&mut self) -> ControlEvents {
+		let (tx, rx) = mpsc::channel(MAX_CONTROL_EVENTS);
+		self.control = Some(tx);
+		ControlEvents(rx)
+	}
+
+	/// Pass a control event created from the current control frame payload to the [`ControlEvents`], if any.
+	fn on_control(&mut self, f: impl FnOnce(&[u8]) -> Control) {
+		let Some(control) = &mut self.control else { return };
+		if let Err(e) = control.try_send(f(self.decoder.control_payload())) {
+			if e.is_disconnected() {
+				self.control = None
+			} else {
+				log::debug!("{}: control events are not consumed, dropping {:?}", self.id, e.into_inner())
+			}
+		}
+	}
+
+	/// Complete the oldest PING matching the received PONG and return whether there was one.
+	fn on_pong(&mut self) -> bool {
+		let mut probes = self.shared.probes.lock().unwrap_or_else(PoisonError::into_inner);
+		let Some(probes) = probes.as_mut() else { return false };
+		let Some(i) = probes.iter().position(|p| p.payload[..] == *self.decoder.control_payload()) else {
+			return false;
+		};
+		let probe = probes.remove(i);
+		let _ = probe.tx.send(Pong { rtt: probe.sent.elapsed(), payload: probe.payload });
+		true
+	}
+
+	/// Get a snapshot of the statistics of this connection.
+	pub fn stats(&self) -> Stats {
+		self.shared.counters.snapshot()
+	}
+
+	/// The time since the last frame was received, or since the connection was
+	/// created if nothing has been received yet, e.g. to close idle connections.
+	pub fn idle_for(&self) -> Duration {
+		self.shared.counters.activity.idle_for(&self.shared.counters.activity.last_received)
+	}
+
+	/// Receive the next websocket message, skipping over control frames
+	/// and frames with reserved opcodes.
+	pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
+		self.receive_data_with(message, None).await
+	}
+
+	/// Receive the next text or binary message like [`Receiver::receive_data`]
+	/// and write it to `sink`.
+	///
+	/// If the first extension supports it with [`Extension::decode_to`], e.g.
+	/// [`Deflate`](crate::extension::deflate::Deflate), it writes decoded data
+	/// to `sink` in chunks, so large messages need not be held in memory. Such
+	/// messages are not validated to be UTF-8, even if text. Other messages are
+	/// received into a buffer owned by the receiver and written when complete.
+	pub async fn receive_data_to<W: io::Write + Send>(&mut self, sink: &mut W) -> Result<Data, Error> {
+		let mut message = std::mem::take(&mut self.buffer);
+		let result = self.receive_data_with(&mut message, Some(&mut *sink)).await.and_then(|data| {
+			sink.write_all(&message)?;
+			Ok(data)
+		});
+		message.clear();
+		self.buffer = message;
+		result
+	}
+
+	/// Receive the next text or binary message, letting extensions write it to `sink`, if any.
+	async fn receive_data_with(
+		&mut self,
+		message: &mut Vec<u8>,
+		mut sink: Option<&mut (dyn io::Write + Send + '_)>,
+	) -> Result<Data, Error> {
+		let message_len = message.len();
+		loop {
+			match self.receive_until(message, None, sink.as_deref_mut()).await? {
+				Incoming::Data(d) => return Ok(d),
+				Incoming::Reserved(..) => message.truncate(message_len),
+				Incoming::Fragment { first_opcode, fin: true, .. } => {
+					let len = message.len() - message_len;
+					match first_opcode {
+						OpCode::Text => return Ok(Data::Text(len)),
+						OpCode::Binary => return Ok(Data::Binary(len)),
+						_ => message.truncate(message_len),
+					}
+				}
+				Incoming::Pong(_) | Incoming::Closed(_) | Incoming::Fragment { .. } => (),
 			}
-			OpCode::Binary
-			| OpCode::Text
-			| OpCode::Continue
-			| OpCode::Reserved3
-			| OpCode::Reserved4
-			| OpCode::Reserved5
-			| OpCode::Reserved6
-			| OpCode::Reserved7
-			| OpCode::Reserved11
-			| OpCode::Reserved12
-			| OpCode::Reserved13
-			| OpCode::Reserved14
-			| OpCode::Reserved15 => Err(Error::UnexpectedOpCode(header.opcode())),
-		}
-	}
-
-	/// Apply all extensions to the given header and the internal message buffer.
-	async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>) -> Result<(), Error> {
-		if !self.has_extensions {
-			return Ok(());
 		}
-		for e in self.extensions.lock().await.iter_mut() {
-			log::trace!("{}: decoding with extension: {}", self.id, e.name());
-			e.decode(header, message).map_err(Error::Extension)?
+	}
+
+	/// Receive the next text or binary message and write it into `buf`.
+	///
+	/// Like [`Receiver::receive_data`], control frames and frames with reserved
+	/// opcodes are skipped. If `buf` has not enough room for the message, it is
+	/// filled and [`Error::BufferFull`] is returned. The next call then writes
+	/// the remaining bytes of the message before receiving the next one, so a
+	/// fixed-size buffer can be drained and passed again.
+	///
+	/// Messages are assembled in a buffer owned by the receiver, which is reused.
+	pub async fn receive_data_into<B: BufMut>(&mut self, buf: &mut B) -> Result<Data, Error> {
+		if self.pending.is_none() {
+			let mut scratch = std::mem::take(&mut self.scratch);
+			scratch.clear();
+			let result = self.receive_data(&mut scratch).await;
+			self.scratch = scratch;
+			self.pending = Some((result?, 0));
+		}
+		let (_, offset) = self.pending.as_mut().expect("pending is set above; qed");
+		let n = std::cmp::min(buf.remaining_mut(), self.scratch.len() - *offset);
+		buf.put_slice(&self.scratch[*offset..*offset + n]);
+		*offset += n;
+		if *offset < self.scratch.len() {
+			return Err(Error::BufferFull { remaining: self.scratch.len() - *offset });
+		}
+		Ok(self.pending.take().expect("pending is set above; qed").0)
+	}
+
+	/// Receive the next text message and append it to `text`.
+	///
+	/// Like [`Receiver::receive_data`], control frames and frames with reserved
+	/// opcodes are skipped. Binary messages fail with [`Error::UnexpectedOpCode`]
+	/// and are discarded. Returns the number of bytes appended.
+	///
+	/// The message is not checked again after the decoder has validated it.
+	/// Only if validation is skipped, see [`Builder::set_utf8_policy`], or in
+	/// fragment mode, the received bytes are checked and [`Error::Utf8`] is
+	/// returned if they are not valid UTF-8. Into an empty `text`, the message
+	/// is received directly, otherwise it is received into a buffer owned by
+	/// the receiver and copied.
+	#[allow(unsafe_code)]
+	pub async fn receive_text(&mut self, text: &mut String) -> Result<usize, Error> {
+		if text.is_empty() {
+			// The bytes are moved out, so `text` is left empty if this is cancelled.
+			let mut message = std::mem::take(text).into_bytes();
+			let result = self.receive_text_data(&mut message).await;
+			if self.decoder.validates_text() {
+				// SAFETY: `message` is either empty or holds a complete text message,
+				// which the decoder has checked to be valid UTF-8 or made valid, see
+				// `Decoder::validates_text`.
+				*text = unsafe { String::from_utf8_unchecked(message) };
+				return result;
+			}
+			return match String::from_utf8(message) {
+				Ok(t) => {
+					*text = t;
+					result
+				}
+				Err(e) => {
+					let error = e.utf8_error();
+					let mut message = e.into_bytes();
+					message.clear();
+					*text = String::from_utf8(message).expect("buffer is empty; qed");
+					Err(Error::Utf8(error))
+				}
+			};
+		}
+		let mut message = std::mem::take(&mut self.buffer);
+		let result = self.receive_text_data(&mut message).await.and_then(|n| {
+			if self.decoder.validates_text() {
+				// SAFETY: `message` holds a complete text message, which the decoder has
+				// checked to be valid UTF-8 or made valid, see `Decoder::validates_text`.
+				text.push_str(unsafe { str::from_utf8_unchecked(&message) })
+			} else {
+				text.push_str(str::from_utf8(&message).map_err(Error::Utf8)?)
+			}
+			Ok(n)
+		});
+		message.clear();
+		self.buffer = message;
+		result
+	}
+
+	/// Receive the next text message into `message`, which is left as is otherwise.
+	async fn receive_text_data(&mut self, message: &mut Vec<u8>) -> Result<usize, Error> {
+		let len = message.len();
+		let result = match self.receive_data(message).await {
+			Ok(Data::Text(n)) => return Ok(n),
+			Ok(Data::Binary(_)) => Err(Error::UnexpectedOpCode(OpCode::Binary)),
+			Err(e) => Err(e),
+		};
+		message.truncate(len);
+		result
+	}
+
+	/// Receive the next message, PONG or close message as an owned [`Message`].
+	///
+	/// This is a simpler alternative to [`Receiver::receive_data`] which
+	/// allocates a new buffer for every message. Frames with reserved opcodes
+	/// are skipped. Text which is not valid UTF-8, e.g. because validation is
+	/// disabled with [`Builder::set_utf8_policy`], fails with [`Error::Utf8`].
+	pub async fn receive_message(&mut self) -> Result<Message, Error> {
+		let mut message = Vec::new();
+		loop {
+			let data = match self.receive(&mut message).await? {
+				Incoming::Data(d) => d,
+				Incoming::Pong(payload) => return Ok(Message::Pong(payload.to_vec())),
+				Incoming::Closed(reason) => return Ok(Message::Closed(reason)),
+				Incoming::Fragment { first_opcode: OpCode::Text, fin: true, .. } => Data::Text(message.len()),
+				Incoming::Fragment { first_opcode: OpCode::Binary, fin: true, .. } => Data::Binary(message.len()),
+				Incoming::Reserved(..) | Incoming::Fragment { fin: true, .. } => {
+					message.clear();
+					continue;
+				}
+				Incoming::Fragment { .. } => continue,
+			};
+			return match data {
+				Data::Text(_) => String::from_utf8(message).map(Message::Text).map_err(|e| Error::Utf8(e.utf8_error())),
+				Data::Binary(_) => Ok(Message::Binary(message)),
+			};
 		}
-		Ok(())
 	}
 
 	/// Flush the socket buffer.
 	async fn flush(&mut self) -> Result<(), Error> {
 		log::trace!("{}: Receiver flushing connection", self.id);
-		if self.is_closed {
+		if self.decoder.is_closed() {
 			return Ok(());
 		}
 		self.writer.lock().await.flush().await.or(Err(Error::Closed))
@@ -458,6 +1447,37 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
+	/// Split messages into frames with payloads of at most `size` bytes.
+	///
+	/// Extensions are applied to the whole message before it is split. By
+	/// default, every message is sent as a single frame.
+	///
+	/// # Panics
+	///
+	/// If `size` is zero.
+	pub fn set_fragment_size(&mut self, size: usize) {
+		assert!(size > 0, "fragment size must not be zero");
+		self.fragment_size = Some(size)
+	}
+
+	/// Stop or resume applying the extension with the given name to outgoing messages.
+	///
+	/// Incoming messages are still decoded by a paused extension. This only
+	/// works for extensions which, like `permessage-deflate`, may be applied
+	/// to some messages but not to others, e.g. to stop compressing while
+	/// the CPU is saturated. Returns `false` if the connection has no such
+	/// extension.
+	pub async fn set_extension_paused(&mut self, name: &str, paused: bool) -> bool {
+		if !self.extensions.lock().await.iter().any(|e| e.name() == name) {
+			return false;
+		}
+		self.paused.retain(|p| p != name);
+		if paused {
+			self.paused.push(name.to_string())
+		}
+		true
+	}
+
 	/// Send a text value over the websocket connection.
 	pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
 		let mut header = Header::new(OpCode::Text);
@@ -478,15 +1498,153 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
 		self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
 	}
 
+	/// Send a text value over the websocket connection without taking ownership of it.
+	///
+	/// Use this to send the same buffer to many peers. See
+	/// [`Sender::send_binary_shared`] for how the data is written.
+	pub async fn send_text_shared(&mut self, data: &str) -> Result<(), Error> {
+		let mut header = Header::new(OpCode::Text);
+		self.send_frame(&mut header, &mut Storage::Shared(data.as_bytes())).await
+	}
+
+	/// Send some binary data over the websocket connection without taking ownership of it.
+	///
+	/// Use this to send the same buffer to many peers. The data is never
+	/// modified or cloned, but masked, if necessary, while being copied into
+	/// the send buffer, see [`Builder::set_write_buffer_size`]. Extensions
+	/// which transform the message produce a copy of their own.
+	pub async fn send_binary_shared(&mut self, data: &[u8]) -> Result<(), Error> {
+		let mut header = Header::new(OpCode::Binary);
+		self.send_frame(&mut header, &mut Storage::Shared(data)).await
+	}
+
 	/// Send some binary data over the websocket connection.
 	///
-	/// This method performs one copy fewer than [`Sender::send_binary`].
-	/// The `data` buffer may be modified by this method, e.g. if masking is necessary.
+	/// The `data` buffer may be modified by this method, e.g. by extensions.
+	/// As every frame is copied into the send buffer, this performs as many
+	/// copies as [`Sender::send_binary`] otherwise.
 	pub async fn send_binary_mut(&mut self, mut data: impl AsMut<[u8]>) -> Result<(), Error> {
 		let mut header = Header::new(OpCode::Binary);
 		self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await
 	}
 
+	/// Send all messages of a stream over the websocket connection.
+	///
+	/// Messages which are immediately available are sent in a batch and the
+	/// connection is flushed whenever the stream has to wait for the next
+	/// message and once it ends. Sending waits while the send buffer is full,
+	/// see [`Builder::set_send_buffer`], which in turn stops pulling messages
+	/// from the stream. The connection remains open when the stream ends.
+	///
+	/// A [`Message::Pong`] is sent as an unsolicited PONG and must not carry
+	/// more than 125 bytes, or [`Error::ControlPayloadTooLarge`] is returned.
+	/// A [`Message::Closed`] is sent as a close message with the given reason,
+	/// after which no more messages are pulled from the stream. Like with
+	/// [`Sender::shutdown`], the description of the reason is truncated to fit
+	/// into a control frame.
+	pub async fn send_all<S>(&mut self, messages: S) -> Result<(), Error>
+	where
+		S: Stream<Item = Message>,
+	{
+		let mut messages = pin!(messages);
+		loop {
+			let message = match messages.next().now_or_never() {
+				Some(Some(message)) => message,
+				Some(None) => break,
+				None => {
+					self.flush().await?;
+					match messages.next().await {
+						Some(message) => message,
+						None => return Ok(()),
+					}
+				}
+			};
+			let (mut header, data) = match message {
+				Message::Text(text) => (Header::new(OpCode::Text), text.into_bytes()),
+				Message::Binary(data) => (Header::new(OpCode::Binary), data),
+				Message::Pong(data) => {
+					let data =
+						ByteSlice125::try_from(&data[..]).map_err(|_| Error::ControlPayloadTooLarge(data.len()))?;
+					self.send_pong(data).await?;
+					continue;
+				}
+				Message::Closed(reason) => {
+					let mut header = Header::new(OpCode::Close);
+					self.write(&mut header, &mut Storage::Owned(close_payload(&reason))).await?;
+					self.shared.close_sent.store(true, Ordering::Release);
+					break;
+				}
+			};
+			self.send_frame(&mut header, &mut Storage::Owned(data)).await?
+		}
+		self.flush().await
+	}
+
+	/// Send a text value over the websocket connection without compressing it.
+	///
+	/// Compressing extensions leave the message as is, while others apply as
+	/// usual, see [`Header::set_compressible`]. Use this for messages which are
+	/// not worth compressing.
+	pub async fn send_text_uncompressed(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
+		let mut header = Header::new(OpCode::Text);
+		header.set_compressible(false);
+		self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await
+	}
+
+	/// Send some binary data over the websocket connection without compressing it.
+	///
+	/// Like [`Sender::send_text_uncompressed`], only compressing extensions leave
+	/// the data as is. Use this for data which does not compress well, e.g. data
+	/// which is already compressed or encrypted.
+	pub async fn send_binary_uncompressed(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		let mut header = Header::new(OpCode::Binary);
+		header.set_compressible(false);
+		self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
+	}
+
+	/// Send a frame with a reserved opcode over the websocket connection.
+	///
+	/// The opcode must be used by one of the connection's extensions, which are
+	/// applied like to any other message. Frames with a reserved control opcode
+	/// (0xB to 0xF) are never fragmented and carry at most 125 bytes, or
+	/// [`Error::ControlPayloadTooLarge`] is returned.
+	pub async fn send_reserved(&mut self, opcode: OpCode, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		check_reserved(self.encoder.codec(), opcode, data.as_ref().len())?;
+		let mut header = Header::new(opcode);
+		self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
+	}
+
+	/// Send a PING and measure the round-trip time until the matching PONG is received.
+	///
+	/// The returned [`Latency`] future resolves once the [`Receiver`] of this
+	/// connection receives the PONG, so the receiver must be polled concurrently.
+	/// It fails with [`Error::Closed`] if the receiver is dropped before.
+	pub async fn measure_latency(&mut self) -> Result<Latency, Error> {
+		let mut payload = *b"rtt:\0\0\0\0\0\0\0\0";
+		payload[4..].copy_from_slice(&self.shared.next_probe.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+		let pong = self.ping(ByteSlice125::try_from(&payload[..]).expect("payload is 12 bytes long; qed")).await?;
+		Ok(Latency(pong))
+	}
+
+	/// Send a PING with the given payload and flush the connection.
+	///
+	/// The returned [`PendingPong`] future resolves once the [`Receiver`] of
+	/// this connection receives a PONG with the same payload, so the receiver
+	/// must be polled concurrently. PINGs with the same payload are answered
+	/// in the order they were sent. The future fails with [`Error::Closed`] if
+	/// the receiver is dropped before.
+	pub async fn ping(&mut self, data: ByteSlice125<'_>) -> Result<PendingPong, Error> {
+		let (tx, rx) = oneshot::channel();
+		let probe = Probe { payload: data.as_ref().to_vec(), sent: Instant::now(), tx };
+		match self.shared.probes.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+			Some(probes) => probes.push(probe),
+			None => return Err(Error::Closed),
+		}
+		self.send_ping(data).await?;
+		self.flush().await?;
+		Ok(PendingPong(rx))
+	}
+
 	/// Ping the remote end.
 	pub async fn send_ping(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
 		let mut header = Header::new(OpCode::Ping);
@@ -499,7 +1657,36 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
 		self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
 	}
 
+	/// Get a snapshot of the statistics of this connection.
+	pub fn stats(&self) -> Stats {
+		self.shared.counters.snapshot()
+	}
+
+	/// The time since the last frame was sent, or since the connection was
+	/// created if nothing has been sent yet.
+	pub fn idle_for(&self) -> Duration {
+		self.shared.counters.activity.idle_for(&self.shared.counters.activity.last_sent)
+	}
+
+	/// Check if the sender is ready to queue more data.
+	///
+	/// This is always the case unless buffered mode is enabled with
+	/// [`Builder::set_send_buffer`], in which case it returns `Poll::Pending`
+	/// while the queued bytes exceed the high watermark, until they have been
+	/// written down to the low watermark.
+	pub fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+		let mut writer = ready!(self.writer.poll_lock(cx));
+		writer.poll_ready(cx).map_err(|_| Error::Closed)
+	}
+
+	/// Wait until the sender is ready to queue more data, see [`Sender::poll_ready`].
+	pub async fn ready(&mut self) -> Result<(), Error> {
+		future::poll_fn(|cx| self.poll_ready(cx)).await
+	}
+
 	/// Flush the socket buffer.
+	///
+	/// In buffered mode, this writes all queued data to the socket.
 	pub async fn flush(&mut self) -> Result<(), Error> {
 		log::trace!("{}: Sender flushing connection", self.id);
 		self.writer.lock().await.flush().await.or(Err(Error::Closed))
@@ -508,27 +1695,91 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
 	/// Send a close message and close the connection.
 	pub async fn close(&mut self) -> Result<(), Error> {
 		log::trace!("{}: closing connection", self.id);
+		#[cfg(feature = "tracing")]
+		tracing::debug!(parent: &self.span, "closing connection");
+		#[cfg(feature = "metrics")]
+		metrics::counter!("soketto_close_total", "direction" => "sent", "code" => "1000").increment(1);
 		let mut header = Header::new(OpCode::Close);
-		let code = 1000_u16.to_be_bytes(); // 1000 = normal closure
+		let code = u16::from(CloseCode::NormalClosure).to_be_bytes();
 		self.write(&mut header, &mut Storage::Shared(&code[..])).await?;
+		self.shared.close_sent.store(true, Ordering::Release);
 		self.flush().await?;
 		self.writer.lock().await.close().await.or(Err(Error::Closed))
 	}
 
+	/// Close the connection with the closing handshake of [RFC 6455][0].
+	///
+	/// Any queued data is flushed, a close message with the given reason is
+	/// sent and the remote's close message is awaited for at most `timeout`,
+	/// before the socket is closed. The reason's description is truncated to
+	/// fit into a control frame. The remote's close message is received by the
+	/// [`Receiver`] of this connection, which must be polled concurrently.
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-7
+	pub async fn shutdown(&mut self, reason: CloseReason, timeout: Duration) -> Result<(), Error> {
+		log::trace!("{}: shutting down connection", self.id);
+		#[cfg(feature = "tracing")]
+		tracing::debug!(parent: &self.span, code = u16::from(reason.code), "shutting down connection");
+		#[cfg(feature = "metrics")]
+		metrics::counter!("soketto_close_total", "direction" => "sent", "code" => reason.code.to_string()).increment(1);
+		let (tx, rx) = oneshot::channel();
+		*self.shared.close_reply.lock().unwrap_or_else(PoisonError::into_inner) = Some(tx);
+
+		let mut header = Header::new(OpCode::Close);
+		self.write(&mut header, &mut Storage::Owned(close_payload(&reason))).await?;
+		self.shared.close_sent.store(true, Ordering::Release);
+		self.flush().await?;
+
+		if let Either::Right(_) = future::select(rx, Delay::new(timeout)).await {
+			log::debug!("{}: no close message received within {:?}", self.id, timeout)
+		}
+		// The receiver closes the socket as well after receiving the close message.
+		let _ = self.writer.lock().await.close().await;
+		Ok(())
+	}
+
 	/// Send arbitrary websocket frames.
 	///
 	/// Before sending, extensions will be applied to header and payload data.
 	async fn send_frame(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-		if !self.has_extensions {
-			return self.write(header, data).await;
+		let len = data.as_ref().len();
+
+		if self.has_extensions {
+			let mut extensions = self.extensions.lock().await;
+			let (id, paused) = (self.id, &self.paused);
+			let active = |e: &dyn Extension| {
+				let is_paused = paused.iter().any(|p| p == e.name());
+				if is_paused {
+					log::trace!("{}: not encoding with paused extension: {}", id, e.name())
+				}
+				!is_paused
+			};
+			proto::encode_with_extensions_async(&mut extensions[..], header, data, active).await?;
+			drop(extensions);
+			self.encoder.codec().check_reserved_bits(header)?
 		}
 
-		for e in self.extensions.lock().await.iter_mut() {
-			log::trace!("{}: encoding with extension: {}", self.id, e.name());
-			e.encode(header, data).map_err(Error::Extension)?
-		}
+		let encoded_len = self.has_extensions.then(|| data.as_ref().len());
+		self.write_message(header, data).await?;
+		self.shared.counters.on_message_sent(len, encoded_len);
+		Ok(())
+	}
 
-		self.write(header, data).await
+	/// Write the frames of a message, splitting it as configured with [`Sender::set_fragment_size`].
+	///
+	/// The reserved bits set by extensions are only kept in the first frame.
+	async fn write_message(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
+		let size = match self.fragment_size {
+			Some(size) if data.as_ref().len() > size && !is_control_range(header.opcode()) => size,
+			_ => return self.write(header, data).await,
+		};
+		let mut fragments = data.as_ref().chunks(size).peekable();
+		while let Some(fragment) = fragments.next() {
+			header.set_fin(fragments.peek().is_none());
+			self.write(header, &mut Storage::Shared(fragment)).await?;
+			*header = Header::new(OpCode::Continue)
+		}
+		Ok(())
 	}
 
 	/// Write final header and payload data to socket.
@@ -536,81 +1787,198 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
 	/// The data will be masked if necessary.
 	/// No extensions will be applied to header and payload data.
 	async fn write(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-		write(self.id, self.mode, &mut self.codec, &mut self.writer, header, data, &mut self.mask_buffer).await
+		self.ready().await?;
+		write(self.id, &mut self.encoder, &mut self.writer, header, data).await?;
+		self.shared.counters.on_frame_sent(header);
+		Ok(())
+	}
+}
+
+#[cfg(feature = "json")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
+	/// Serialize a value to JSON and send it as a text message.
+	pub async fn send_json<V: serde::Serialize + ?Sized>(&mut self, value: &V) -> Result<(), Error> {
+		let json = serde_json::to_vec(value).map_err(Error::Json)?;
+		let mut header = Header::new(OpCode::Text);
+		self.send_frame(&mut header, &mut Storage::Owned(json)).await
+	}
+}
+
+#[cfg(feature = "json")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
+	/// Receive the next text or binary message and deserialize it from JSON.
+	///
+	/// The message is appended to `message` like with [`Receiver::receive_data`]
+	/// and deserialized from there, so `message` may be reused for every message.
+	pub async fn receive_json<V: serde::de::DeserializeOwned>(&mut self, message: &mut Vec<u8>) -> Result<V, Error> {
+		let message_len = message.len();
+		self.receive_data(message).await?;
+		serde_json::from_slice(&message[message_len..]).map_err(Error::Json)
+	}
+}
+
+#[cfg(feature = "msgpack")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
+	/// Serialize a value to MessagePack and send it as a binary message.
+	///
+	/// Structs are serialized as maps with field names, like with JSON.
+	pub async fn send_msgpack<V: serde::Serialize + ?Sized>(&mut self, value: &V) -> Result<(), Error> {
+		let data = rmp_serde::to_vec_named(value).map_err(Error::MessagePackEncode)?;
+		let mut header = Header::new(OpCode::Binary);
+		self.send_frame(&mut header, &mut Storage::Owned(data)).await
+	}
+}
+
+#[cfg(feature = "msgpack")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
+	/// Receive the next text or binary message and deserialize it from MessagePack.
+	///
+	/// The message is appended to `message` like with [`Receiver::receive_data`].
+	pub async fn receive_msgpack<V: serde::de::DeserializeOwned>(&mut self, message: &mut Vec<u8>) -> Result<V, Error> {
+		let message_len = message.len();
+		self.receive_data(message).await?;
+		rmp_serde::from_slice(&message[message_len..]).map_err(Error::MessagePackDecode)
+	}
+}
+
+#[cfg(feature = "cbor")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
+	/// Serialize a value to CBOR and send it as a binary message.
+	pub async fn send_cbor<V: serde::Serialize>(&mut self, value: &V) -> Result<(), Error> {
+		let data = serde_cbor::to_vec(value).map_err(Error::Cbor)?;
+		let mut header = Header::new(OpCode::Binary);
+		self.send_frame(&mut header, &mut Storage::Owned(data)).await
+	}
+}
+
+#[cfg(feature = "cbor")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
+	/// Receive the next text or binary message and deserialize it from CBOR.
+	///
+	/// The message is appended to `message` like with [`Receiver::receive_data`].
+	pub async fn receive_cbor<V: serde::de::DeserializeOwned>(&mut self, message: &mut Vec<u8>) -> Result<V, Error> {
+		let message_len = message.len();
+		self.receive_data(message).await?;
+		serde_cbor::from_slice(&message[message_len..]).map_err(Error::Cbor)
+	}
+}
+
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		// Pending latency probes can no longer complete.
+		*self.shared.probes.lock().unwrap_or_else(PoisonError::into_inner) = None
+	}
+}
+
+/// A control frame received by a connection, see [`Receiver::control_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Control {
+	/// A PING with its payload, which has been answered.
+	Ping(Vec<u8>),
+	/// A PONG with its payload.
+	Pong(Vec<u8>),
+	/// The other end closed the connection.
+	Closed(CloseReason),
+}
+
+/// The control frames received by a connection, see [`Receiver::control_events`].
+///
+/// The stream ends when the [`Receiver`] is dropped.
+#[derive(Debug)]
+pub struct ControlEvents(mpsc::Receiver<Control>);
+
+impl Stream for ControlEvents {
+	type Item = Control;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		self.0.poll_next_unpin(cx)
+	}
+}
+
+/// The round-trip time of a PING, see [`Sender::measure_latency`].
+#[derive(Debug)]
+pub struct Latency(PendingPong);
+
+impl Future for Latency {
+	type Output = Result<Duration, Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		self.0.poll_unpin(cx).map_ok(|pong| pong.rtt)
 	}
 }
 
+/// The PONG answering a PING, see [`Sender::ping`].
+#[derive(Debug)]
+pub struct PendingPong(oneshot::Receiver<Pong>);
+
+impl Future for PendingPong {
+	type Output = Result<Pong, Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		self.0.poll_unpin(cx).map_err(|_| Error::Closed)
+	}
+}
+
+/// A PONG received in answer to a PING sent with [`Sender::ping`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pong {
+	/// The payload of the PING and PONG.
+	pub payload: Vec<u8>,
+	/// The time between sending the PING and receiving the PONG.
+	pub rtt: Duration,
+}
+
 /// Write header and payload data to socket.
 async fn write<T: AsyncWrite + Unpin>(
 	id: Id,
-	mode: Mode,
-	codec: &mut base::Codec,
-	writer: &mut BiLock<WriteHalf<T>>,
+	encoder: &mut Encoder,
+	writer: &mut BiLock<Writer<T>>,
 	header: &mut Header,
 	data: &mut Storage<'_>,
-	mask_buffer: &mut Vec<u8>,
 ) -> Result<(), Error> {
-	if mode.is_client() {
-		header.set_masked(true);
-		header.set_mask(rand::random());
-	}
-	header.set_payload_len(data.as_ref().len());
-
+	let mut w = writer.lock().await;
+	// The frame is assembled and masked in the writer's buffer, so it is
+	// written at once and the payload is copied exactly once.
+	let header_bytes = encoder.encode_header(header, data.as_ref().len());
 	log::trace!("{}: send: {}", id, header);
+	let n = header_bytes.len() + data.as_ref().len();
+	w.queue(header_bytes);
+	base::Codec::apply_mask(header, w.queue(data.as_ref()));
+	if w.watermarks.is_none() {
+		future::poll_fn(|cx| w.poll_drain(cx, 0)).await.or(Err(Error::Closed))?
+	}
+	if w.on_write(n) {
+		log::trace!("{}: auto-flushing connection", id);
+		w.flush().await.or(Err(Error::Closed))?
+	}
+	Ok(())
+}
 
-	let header_bytes = codec.encode_header(&header);
-	let mut w = writer.lock().await;
-	w.write_all(&header_bytes).await.or(Err(Error::Closed))?;
+/// Is the opcode in the range of control opcodes, including reserved ones?
+fn is_control_range(opcode: OpCode) -> bool {
+	u8::from(opcode) & 0x8 != 0
+}
 
-	if !header.is_masked() {
-		return w.write_all(data.as_ref()).await.or(Err(Error::Closed));
+/// Check that a frame with the given opcode and payload length may be sent with `send_reserved`.
+pub(crate) fn check_reserved(codec: &base::Codec, opcode: OpCode, len: usize) -> Result<(), Error> {
+	if !opcode.is_reserved() || !codec.is_opcode_allowed(opcode) {
+		return Err(Error::UnexpectedOpCode(opcode));
 	}
-
-	match data {
-		Storage::Shared(slice) => {
-			mask_buffer.clear();
-			mask_buffer.extend_from_slice(slice);
-			base::Codec::apply_mask(header, mask_buffer);
-			w.write_all(mask_buffer).await.or(Err(Error::Closed))
-		}
-		Storage::Unique(slice) => {
-			base::Codec::apply_mask(header, slice);
-			w.write_all(slice).await.or(Err(Error::Closed))
-		}
-		Storage::Owned(ref mut bytes) => {
-			base::Codec::apply_mask(header, bytes);
-			w.write_all(bytes).await.or(Err(Error::Closed))
-		}
+	if is_control_range(opcode) && len > 125 {
+		return Err(Error::ControlPayloadTooLarge(len));
 	}
+	Ok(())
 }
 
-/// Create a close frame based on the given data. The close frame is echoed back
-/// to the sender.
-fn close_answer(data: &[u8]) -> Result<(Header, Option<CloseReason>), Error> {
-	let answer = Header::new(OpCode::Close);
-	if data.len() < 2 {
-		return Ok((answer, None));
+/// The payload of a close message, with the description truncated to fit into a control frame.
+fn close_payload(reason: &CloseReason) -> Vec<u8> {
+	let descr = reason.descr.as_deref().unwrap_or("");
+	let mut n = std::cmp::min(descr.len(), 123);
+	while !descr.is_char_boundary(n) {
+		n -= 1
 	}
-	// Check that the reason string is properly encoded
-	let descr = std::str::from_utf8(&data[2..])?.into();
-	let code = u16::from_be_bytes([data[0], data[1]]);
-	let reason = CloseReason { code, descr: Some(descr) };
-
-	// Status codes are defined in
-	// https://tools.ietf.org/html/rfc6455#section-7.4.1 and
-	// https://mailarchive.ietf.org/arch/msg/hybi/P_1vbD9uyHl63nbIIbFxKMfSwcM/
-	match code {
-        | 1000 ..= 1003
-        | 1007 ..= 1011
-        | 1012 // Service Restart
-        | 1013 // Try Again Later
-        | 1015
-        | 3000 ..= 4999 => Ok((answer, Some(reason))), // acceptable codes
-        _               => {
-            // invalid code => protocol error (1002)
-            Ok((answer, Some(CloseReason { code: 1002, descr: None})))
-        }
-    }
+	[&u16::from(reason.code).to_be_bytes()[..], &descr.as_bytes()[..n]].concat()
 }
 
 /// Errors which may occur when sending or receiving messages.
@@ -625,21 +1993,167 @@ pub enum Error {
 	Extension(crate::BoxedError),
 	/// An unexpected opcode was encountered.
 	UnexpectedOpCode(OpCode),
-	/// A close reason was not correctly UTF-8 encoded.
+	/// A close reason or text message was not correctly UTF-8 encoded.
 	Utf8(str::Utf8Error),
 	/// The total message payload data size exceeds the configured maximum.
 	MessageTooLarge { current: usize, maximum: usize },
 	/// The connection is closed.
 	Closed,
+	/// Two extensions use the same reserved bits or opcode.
+	ConflictingExtensions(String, String),
+	/// A client sent a frame without mask.
+	UnmaskedFrame,
+	/// No complete message was received in time.
+	Timeout,
+	/// A control frame with the given payload length exceeding 125 bytes was skipped.
+	ControlFrameTooLarge(usize),
+	/// A control frame to send has the given payload length exceeding 125 bytes.
+	ControlPayloadTooLarge(usize),
+	/// A message consists of more frames than the configured maximum.
+	TooManyFragments { maximum: usize },
+	/// A frame was not received completely in time.
+	FrameTimeout,
+	/// Nothing was received in reply to a keepalive PING in time.
+	KeepaliveTimeout,
+	/// The memory budget shared by several connections is exhausted.
+	MemoryLimitExceeded { limit: usize },
+	/// The buffer given to [`Receiver::receive_data_into`] is full and the
+	/// given number of bytes of the message remain to be written.
+	BufferFull { remaining: usize },
+	/// A value could not be serialized to or deserialized from JSON.
+	#[cfg(feature = "json")]
+	Json(serde_json::Error),
+	/// A value could not be serialized to MessagePack.
+	#[cfg(feature = "msgpack")]
+	MessagePackEncode(rmp_serde::encode::Error),
+	/// A value could not be deserialized from MessagePack.
+	#[cfg(feature = "msgpack")]
+	MessagePackDecode(rmp_serde::decode::Error),
+	/// A value could not be serialized to or deserialized from CBOR.
+	#[cfg(feature = "cbor")]
+	Cbor(serde_cbor::Error),
 }
 
 /// Reason for closing the connection.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct CloseReason {
-	pub code: u16,
+	pub code: CloseCode,
 	pub descr: Option<String>,
 }
 
+/// Status code of a close message.
+///
+/// Covers the codes of the [IANA registry][0]. Codes without a variant of
+/// their own are represented by the range they belong to.
+///
+/// [0]: https://www.iana.org/assignments/websocket/websocket.xhtml#close-code-number
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum CloseCode {
+	/// 1000: The purpose of the connection has been fulfilled.
+	NormalClosure,
+	/// 1001: An endpoint is going away, e.g. a server going down.
+	GoingAway,
+	/// 1002: An endpoint received a frame violating the protocol.
+	ProtocolError,
+	/// 1003: An endpoint received a type of data it cannot accept.
+	UnsupportedData,
+	/// 1005: No status code was present. Must not be sent.
+	NoStatusReceived,
+	/// 1006: The connection was closed without close message. Must not be sent.
+	AbnormalClosure,
+	/// 1007: A message was inconsistent with its type, e.g. text not in UTF-8.
+	InvalidPayload,
+	/// 1008: A message violated the policy of an endpoint.
+	PolicyViolation,
+	/// 1009: A message was too large to process.
+	MessageTooBig,
+	/// 1010: The server did not negotiate an extension the client requires.
+	MandatoryExtension,
+	/// 1011: The server encountered an unexpected condition.
+	InternalError,
+	/// 1012: The service is restarted.
+	ServiceRestart,
+	/// 1013: The service is overloaded, the client should try again later.
+	TryAgainLater,
+	/// 1014: A gateway or proxy received an invalid response from upstream.
+	BadGateway,
+	/// 1015: The TLS handshake failed. Must not be sent.
+	TlsHandshake,
+	/// 3000: The endpoint is not authorized.
+	Unauthorized,
+	/// 3003: The endpoint is authorized but lacks permission.
+	Forbidden,
+	/// 3008: A timeout occurred.
+	Timeout,
+	/// Another code from 3000 to 3999, registered for libraries and frameworks.
+	Library(u16),
+	/// A code from 4000 to 4999, for private use.
+	Private(u16),
+	/// Any other code, reserved or not valid at all.
+	Reserved(u16),
+}
+
+impl From<u16> for CloseCode {
+	fn from(code: u16) -> Self {
+		match code {
+			1000 => CloseCode::NormalClosure,
+			1001 => CloseCode::GoingAway,
+			1002 => CloseCode::ProtocolError,
+			1003 => CloseCode::UnsupportedData,
+			1005 => CloseCode::NoStatusReceived,
+			1006 => CloseCode::AbnormalClosure,
+			1007 => CloseCode::InvalidPayload,
+			1008 => CloseCode::PolicyViolation,
+			1009 => CloseCode::MessageTooBig,
+			1010 => CloseCode::MandatoryExtension,
+			1011 => CloseCode::InternalError,
+			1012 => CloseCode::ServiceRestart,
+			1013 => CloseCode::TryAgainLater,
+			1014 => CloseCode::BadGateway,
+			1015 => CloseCode::TlsHandshake,
+			3000 => CloseCode::Unauthorized,
+			3003 => CloseCode::Forbidden,
+			3008 => CloseCode::Timeout,
+			3001..=3002 | 3004..=3007 | 3009..=3999 => CloseCode::Library(code),
+			4000..=4999 => CloseCode::Private(code),
+			_ => CloseCode::Reserved(code),
+		}
+	}
+}
+
+impl From<CloseCode> for u16 {
+	fn from(code: CloseCode) -> Self {
+		match code {
+			CloseCode::NormalClosure => 1000,
+			CloseCode::GoingAway => 1001,
+			CloseCode::ProtocolError => 1002,
+			CloseCode::UnsupportedData => 1003,
+			CloseCode::NoStatusReceived => 1005,
+			CloseCode::AbnormalClosure => 1006,
+			CloseCode::InvalidPayload => 1007,
+			CloseCode::PolicyViolation => 1008,
+			CloseCode::MessageTooBig => 1009,
+			CloseCode::MandatoryExtension => 1010,
+			CloseCode::InternalError => 1011,
+			CloseCode::ServiceRestart => 1012,
+			CloseCode::TryAgainLater => 1013,
+			CloseCode::BadGateway => 1014,
+			CloseCode::TlsHandshake => 1015,
+			CloseCode::Unauthorized => 3000,
+			CloseCode::Forbidden => 3003,
+			CloseCode::Timeout => 3008,
+			CloseCode::Library(code) | CloseCode::Private(code) | CloseCode::Reserved(code) => code,
+		}
+	}
+}
+
+impl fmt::Display for CloseCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", u16::from(*self))
+	}
+}
+
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
@@ -652,6 +2166,24 @@ impl fmt::Display for Error {
 				write!(f, "message too large: len >= {}, maximum = {}", current, maximum)
 			}
 			Error::Closed => f.write_str("connection closed"),
+			Error::ConflictingExtensions(a, b) => write!(f, "extensions {} and {} conflict", a, b),
+			Error::UnmaskedFrame => f.write_str("unmasked frame from client"),
+			Error::Timeout => f.write_str("receive timed out"),
+			Error::ControlFrameTooLarge(n) => write!(f, "control frame too large: len = {}", n),
+			Error::ControlPayloadTooLarge(n) => write!(f, "control payload too large to send: len = {}", n),
+			Error::TooManyFragments { maximum } => write!(f, "message has more than {} frames", maximum),
+			Error::FrameTimeout => f.write_str("frame timed out"),
+			Error::KeepaliveTimeout => f.write_str("keepalive timed out"),
+			Error::MemoryLimitExceeded { limit } => write!(f, "memory limit of {} bytes exceeded", limit),
+			Error::BufferFull { remaining } => write!(f, "buffer full: {} bytes remaining", remaining),
+			#[cfg(feature = "json")]
+			Error::Json(e) => write!(f, "json error: {}", e),
+			#[cfg(feature = "msgpack")]
+			Error::MessagePackEncode(e) => write!(f, "messagepack encode error: {}", e),
+			#[cfg(feature = "msgpack")]
+			Error::MessagePackDecode(e) => write!(f, "messagepack decode error: {}", e),
+			#[cfg(feature = "cbor")]
+			Error::Cbor(e) => write!(f, "cbor error: {}", e),
 		}
 	}
 }
@@ -663,7 +2195,27 @@ impl std::error::Error for Error {
 			Error::Codec(e) => Some(e),
 			Error::Extension(e) => Some(&**e),
 			Error::Utf8(e) => Some(e),
-			Error::UnexpectedOpCode(_) | Error::MessageTooLarge { .. } | Error::Closed => None,
+			#[cfg(feature = "json")]
+			Error::Json(e) => Some(e),
+			#[cfg(feature = "msgpack")]
+			Error::MessagePackEncode(e) => Some(e),
+			#[cfg(feature = "msgpack")]
+			Error::MessagePackDecode(e) => Some(e),
+			#[cfg(feature = "cbor")]
+			Error::Cbor(e) => Some(e),
+			Error::UnexpectedOpCode(_)
+			| Error::MessageTooLarge { .. }
+			| Error::Closed
+			| Error::ConflictingExtensions(..)
+			| Error::UnmaskedFrame
+			| Error::Timeout
+			| Error::ControlFrameTooLarge(_)
+			| Error::ControlPayloadTooLarge(_)
+			| Error::TooManyFragments { .. }
+			| Error::FrameTimeout
+			| Error::KeepaliveTimeout
+			| Error::MemoryLimitExceeded { .. }
+			| Error::BufferFull { .. } => None,
 		}
 	}
 }
@@ -690,23 +2242,1071 @@ impl From<base::Error> for Error {
 	}
 }
 
-/// Discard `n` bytes from the underlying reader.
-async fn discard_bytes<R: AsyncRead + Unpin>(n: u64, reader: R) -> Result<u64, io::Error> {
-	futures::io::copy(&mut reader.take(n), &mut futures::io::sink()).await
-}
-
 #[cfg(test)]
-mod tests {
-	use super::discard_bytes;
-	use futures::{io::Cursor, AsyncReadExt};
-
-	#[tokio::test]
-	async fn discard_bytes_works() {
-		let bytes: Vec<u8> = (0..5).collect();
-		let mut cursor = Cursor::new(bytes);
-		discard_bytes(1_u64, &mut cursor).await.unwrap();
-		let mut read = vec![0; 4];
-		cursor.read_exact(&mut read).await.unwrap();
-		assert_eq!(read, vec![1, 2, 3, 4]);
+pub(crate) mod tests {
+	use super::{Builder, Mode};
+	use tokio::io::DuplexStream;
+	use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+	/// Client and server builders for the two ends of an in-memory stream.
+	fn pair(capacity: usize) -> (Builder<Compat<DuplexStream>>, Builder<Compat<DuplexStream>>) {
+		let (client, server) = tokio::io::duplex(capacity);
+		(Builder::new(client.compat(), Mode::Client), Builder::new(server.compat(), Mode::Server))
+	}
+
+	/// A deflate extension enabled with the default parameters.
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	pub(crate) fn deflate(mode: Mode) -> crate::extension::deflate::Deflate {
+		use crate::extension::Extension;
+
+		let mut deflate = crate::extension::deflate::Deflate::new(mode);
+		deflate.configure(&[]).unwrap();
+		deflate
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn send_uncompressed() {
+		let (mut client, server) = pair(1024);
+		client.add_extensions(Some(Box::new(deflate(Mode::Client)) as Box<_>));
+		let (mut sender, _) = client.finish();
+		// The server does not support the extension and would reject compressed frames.
+		let (_, mut receiver) = server.finish();
+
+		sender.send_binary_uncompressed(b"uncompressed").await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(b"uncompressed", &message[..]);
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn paused_extension() {
+		let (mut client, mut server) = pair(64 * 1024);
+		let (encoder, decoder) = (deflate(Mode::Client), deflate(Mode::Server));
+		let (sent, received) = (encoder.stats(), decoder.stats());
+		client.add_extensions(Some(Box::new(encoder) as Box<_>));
+		let (mut sender, _) = client.finish();
+		server.add_extensions(Some(Box::new(decoder) as Box<_>));
+		let (_, mut receiver) = server.finish();
+
+		let text = "hello world ".repeat(100);
+		let mut message = Vec::new();
+		assert!(!sender.set_extension_paused("unknown", true).await);
+		assert!(sender.set_extension_paused("permessage-deflate", true).await);
+		sender.send_text(&text).await.unwrap();
+		sender.flush().await.unwrap();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(text.as_bytes(), &message[..]);
+		assert_eq!(0, sent.compress_input());
+		assert_eq!(0, received.decompress_input());
+
+		assert!(sender.set_extension_paused("permessage-deflate", false).await);
+		sender.send_text(&text).await.unwrap();
+		sender.flush().await.unwrap();
+		message.clear();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(text.as_bytes(), &message[..]);
+		assert_eq!(text.len() as u64, sent.compress_input());
+		assert!(received.decompress_input() > 0);
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn decompress_appends() {
+		use crate::Data;
+
+		let (mut client, mut server) = pair(64 * 1024);
+		client.add_extensions(Some(Box::new(deflate(Mode::Client)) as Box<_>));
+		let (mut sender, _) = client.finish();
+		server.add_extensions(Some(Box::new(deflate(Mode::Server)) as Box<_>));
+		let (_, mut receiver) = server.finish();
+		sender.set_fragment_size(8);
+
+		sender.send_text("hello hello hello").await.unwrap();
+		sender.send_text("world world world").await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::with_capacity(1024);
+		let ptr = message.as_ptr();
+		assert_eq!(Data::Text(17), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(Data::Text(17), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hello hello helloworld world world", &message[..]);
+		// Messages are decompressed into the given buffer.
+		assert_eq!(ptr, message.as_ptr());
+	}
+
+	#[cfg(all(feature = "testing", any(feature = "deflate", feature = "deflate-rust")))]
+	#[tokio::test]
+	async fn corrupt_compressed_message() {
+		use crate::{
+			base::{Header, OpCode},
+			extension::Extension,
+			testing, Data, Storage,
+		};
+
+		let mut encoder = deflate(Mode::Client);
+		let mut valid = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(b"hello");
+		encoder.encode(&mut valid, &mut data).unwrap();
+		let compressed = data.as_ref().to_vec();
+
+		// Without and with offloaded decompression.
+		for offload in [false, true] {
+			let mut deflate = deflate(Mode::Server);
+			if offload {
+				deflate
+					.set_offload(0, |work| Box::pin(async move { tokio::task::spawn_blocking(work).await.unwrap() }));
+			}
+			let (mut builder, mut peer) = testing::pair(Mode::Server);
+			builder.add_extensions(Some(Box::new(deflate) as Box<_>));
+			let (_, mut receiver) = builder.finish();
+
+			let mut corrupt = Header::new(OpCode::Text);
+			corrupt.set_rsv1(true);
+			peer.send_frame(corrupt, &[0xff, 0xff]).await.unwrap();
+			peer.send_frame(valid.clone(), &compressed).await.unwrap();
+			assert!(matches!(receiver.receive_data(&mut Vec::new()).await, Err(super::Error::Extension(_))));
+			let mut message = Vec::new();
+			assert_eq!(Data::Text(5), receiver.receive_data(&mut message).await.unwrap());
+			assert_eq!(b"hello", &message[..]);
+		}
+	}
+
+	#[cfg(all(feature = "testing", any(feature = "deflate", feature = "deflate-rust")))]
+	#[tokio::test]
+	async fn receive_data_to() {
+		use crate::{
+			base::{Header, OpCode},
+			extension::Extension,
+			testing, Data, Storage,
+		};
+
+		let message = b"hello hello hello hello hello hello hello hello".repeat(32);
+		let mut encoder = deflate(Mode::Client);
+		let mut compressed = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(&message);
+		encoder.encode(&mut compressed, &mut data).unwrap();
+		let data = data.as_ref().to_vec();
+
+		// Too large to be decompressed into a buffer.
+		let mut deflate = deflate(Mode::Server);
+		deflate.set_grow_buffer_size(64);
+		deflate.set_max_buffer_size(64);
+		let (mut builder, mut peer) = testing::pair(Mode::Server);
+		builder.add_extensions(Some(Box::new(deflate) as Box<_>));
+		let (_, mut receiver) = builder.finish();
+
+		peer.send_frame(compressed, &data).await.unwrap();
+		peer.send_frame(Header::new(OpCode::Binary), b"world").await.unwrap();
+		let mut sink = Vec::new();
+		assert_eq!(Data::Text(message.len()), receiver.receive_data_to(&mut sink).await.unwrap());
+		assert_eq!(message, sink);
+		sink.clear();
+		assert_eq!(Data::Binary(5), receiver.receive_data_to(&mut sink).await.unwrap());
+		assert_eq!(b"world", &sink[..]);
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn offload_compression() {
+		use crate::Data;
+
+		let offloaded = |mode| {
+			let mut deflate = deflate(mode);
+			deflate.set_offload(0, |work| Box::pin(async move { tokio::task::spawn_blocking(work).await.unwrap() }));
+			Box::new(deflate) as Box<_>
+		};
+		let (mut client, mut server) = pair(64 * 1024);
+		client.add_extensions(Some(offloaded(Mode::Client)));
+		let (mut sender, _) = client.finish();
+		server.add_extensions(Some(offloaded(Mode::Server)));
+		let (_, mut receiver) = server.finish();
+		sender.set_fragment_size(8);
+
+		let text = "hello world ".repeat(100);
+		sender.send_text(&text).await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		assert_eq!(Data::Text(text.len()), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(text.as_bytes(), &message[..]);
+		assert!(receiver.stats().frames_received > 1);
+	}
+
+	#[tokio::test]
+	async fn buffer_sizes() {
+		use crate::Data;
+
+		let (mut client, mut server) = pair(1024);
+		client.set_send_buffer(64, 0);
+		client.set_write_buffer_size(16, 8);
+		let (mut sender, _) = client.finish();
+		server.set_read_buffer_size(8, 3);
+		let (_, mut receiver) = server.finish();
+
+		let data = (0..100).collect::<Vec<u8>>();
+		sender.send_binary(&data).await.unwrap();
+		sender.send_text("hello").await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		assert_eq!(Data::Binary(100), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(data, message);
+		message.clear();
+		assert_eq!(Data::Text(5), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hello", &message[..]);
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn extension_factory() {
+		use crate::Data;
+
+		let factory = |mode| move || Box::new(deflate(mode)) as Box<_>;
+		let (mut client, mut server) = pair(1024);
+		client.add_extension_with(factory(Mode::Client));
+		let (mut sender, _) = client.finish();
+		server.add_extension_with(factory(Mode::Server));
+		// Conflicts with the first one and is ignored.
+		server.add_extension_with(factory(Mode::Server));
+		let (_, mut receiver) = server.finish();
+
+		let text = "hello ".repeat(100);
+		sender.send_text(&text).await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		assert_eq!(Data::Text(text.len()), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(text.as_bytes(), &message[..]);
+		assert!(sender.stats().bytes_sent < text.len() as u64);
+	}
+
+	#[tokio::test]
+	async fn fragment_size() {
+		use crate::Data;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.set_fragment_size(4);
+
+		sender.send_text("hello world").await.unwrap();
+		sender.send_binary([1, 2, 3, 4]).await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		assert_eq!(Data::Text(11), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(Data::Binary(4), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hello world\x01\x02\x03\x04", &message[..]);
+		assert_eq!(4, sender.stats().frames_sent);
+		assert_eq!(4, receiver.stats().frames_received);
+	}
+
+	#[tokio::test]
+	async fn fragment_mode() {
+		use crate::{base::OpCode, Data, Incoming};
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		receiver.set_fragment_mode(true);
+		sender.set_fragment_size(4);
+
+		sender.send_text("hello world").await.unwrap();
+		sender.send_binary([1, 2]).await.unwrap();
+		sender.send_text("bye").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut message = Vec::new();
+		for (chunk, fin) in [(&b"hell"[..], false), (b"o wo", false), (b"rld", true)] {
+			let incoming = receiver.receive(&mut message).await.unwrap();
+			assert_eq!(Incoming::Fragment { first_opcode: OpCode::Text, fin, len: chunk.len() }, incoming);
+			assert_eq!(chunk, &message[..]);
+			message.clear();
+		}
+		let incoming = receiver.receive(&mut message).await.unwrap();
+		assert_eq!(Incoming::Fragment { first_opcode: OpCode::Binary, fin: true, len: 2 }, incoming);
+		message.clear();
+		assert_eq!(Data::Text(3), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"bye", &message[..]);
+	}
+
+	#[tokio::test]
+	async fn unmasked_client() {
+		use tokio::io::AsyncReadExt;
+
+		let (client, mut server) = tokio::io::duplex(1024);
+		let mut client = Builder::new(client.compat(), Mode::Client);
+		client.set_masking(false);
+		let (mut sender, _) = client.finish();
+		sender.send_text("hi").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut frame = [0; 4];
+		server.read_exact(&mut frame).await.unwrap();
+		assert_eq!([0x81, 0x02, b'h', b'i'], frame);
+	}
+
+	#[tokio::test]
+	async fn seeded_masks() {
+		use crate::proto::MaskRng;
+		use tokio::io::AsyncReadExt;
+
+		let mut frames = Vec::new();
+		for _ in 0..2 {
+			let (client, mut server) = tokio::io::duplex(1024);
+			let mut client = Builder::new(client.compat(), Mode::Client);
+			client.set_mask_rng(MaskRng::from_seed(42));
+			let (mut sender, _) = client.finish();
+			sender.send_text("hi").await.unwrap();
+			sender.flush().await.unwrap();
+			let mut frame = [0; 8];
+			server.read_exact(&mut frame).await.unwrap();
+			frames.push(frame)
+		}
+		assert_eq!(frames[0], frames[1]);
+	}
+
+	#[tokio::test]
+	async fn unmasked_frames() {
+		use super::Error;
+
+		for accept in [false, true] {
+			let (mut client, mut server) = pair(1024);
+			client.set_masking(false);
+			let (mut sender, _) = client.finish();
+			server.set_accept_unmasked_frames(accept);
+			let (_, mut receiver) = server.finish();
+
+			sender.send_text("hi").await.unwrap();
+			sender.flush().await.unwrap();
+			let mut message = Vec::new();
+			match receiver.receive_data(&mut message).await {
+				Ok(_) => assert!(accept && message == b"hi"),
+				Err(e) => assert!(!accept && matches!(e, Error::UnmaskedFrame)),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn invalid_utf8() {
+		use super::{CloseCode, Error};
+		use crate::{
+			base::{Header, OpCode},
+			proto::Utf8Policy,
+			Incoming, Storage,
+		};
+
+		let (client, server) = pair(1024);
+		let (mut sender, mut client_receiver) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.send_frame(&mut Header::new(OpCode::Text), &mut Storage::Shared(b"a\xffb")).await.unwrap();
+		sender.flush().await.unwrap();
+		assert!(matches!(receiver.receive_data(&mut Vec::new()).await, Err(Error::Utf8(_))));
+		assert!(matches!(receiver.receive_data(&mut Vec::new()).await, Err(Error::Closed)));
+		let closed = client_receiver.receive(&mut Vec::new()).await.unwrap();
+		assert!(matches!(closed, Incoming::Closed(r) if r.code == CloseCode::InvalidPayload));
+
+		let (client, mut server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		server.set_utf8_policy(Utf8Policy::Lossy);
+		let (_, mut receiver) = server.finish();
+		sender.send_frame(&mut Header::new(OpCode::Text), &mut Storage::Shared(b"a\xffb")).await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!("a\u{fffd}b".as_bytes(), &message[..]);
+	}
+
+	#[tokio::test]
+	async fn receive_text() {
+		use super::Error;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.send_text("héllo").await.unwrap();
+		sender.send_binary(b"binary").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut text = String::from("> ");
+		assert_eq!(6, receiver.receive_text(&mut text).await.unwrap());
+		assert_eq!("> héllo", text);
+		assert!(matches!(receiver.receive_text(&mut text).await, Err(Error::UnexpectedOpCode(_))));
+		assert_eq!("> héllo", text);
+	}
+
+	#[cfg(feature = "testing")]
+	#[tokio::test]
+	async fn receive_text_unchecked() {
+		use super::Error;
+		use crate::{
+			base::{Header, OpCode},
+			proto::Utf8Policy,
+			testing,
+		};
+
+		let (mut builder, mut peer) = testing::pair(Mode::Server);
+		builder.set_utf8_policy(Utf8Policy::Skip);
+		let (_, mut receiver) = builder.finish();
+		peer.send_frame(Header::new(OpCode::Text), b"\xff").await.unwrap();
+		peer.send_text("abc").await.unwrap();
+		peer.send_frame(Header::new(OpCode::Text), b"\xff").await.unwrap();
+
+		let mut text = String::new();
+		assert!(matches!(receiver.receive_text(&mut text).await, Err(Error::Utf8(_))));
+		assert_eq!("", text);
+		assert_eq!(3, receiver.receive_text(&mut text).await.unwrap());
+		assert!(matches!(receiver.receive_text(&mut text).await, Err(Error::Utf8(_))));
+		assert_eq!("abc", text);
+
+		// Text fragments are not validated in fragment mode either.
+		let (builder, mut peer) = testing::pair(Mode::Server);
+		let (_, mut receiver) = builder.finish();
+		receiver.set_fragment_mode(true);
+		let mut first = Header::new(OpCode::Text);
+		first.set_fin(false);
+		peer.send_frame(first, b"a").await.unwrap();
+		peer.send_frame(Header::new(OpCode::Continue), b"\xff").await.unwrap();
+		let mut text = String::new();
+		assert!(matches!(receiver.receive_text(&mut text).await, Err(Error::Utf8(_))));
+		assert_eq!("", text);
+	}
+
+	#[cfg(feature = "json")]
+	#[tokio::test]
+	async fn json() {
+		use super::Error;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.send_json(&("answer", 42)).await.unwrap();
+		sender.send_text("not json").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut message = Vec::new();
+		assert_eq!(("answer".to_string(), 42), receiver.receive_json::<(String, u32)>(&mut message).await.unwrap());
+		assert_eq!(br#"["answer",42]"#, &message[..]);
+		assert!(matches!(receiver.receive_json::<(String, u32)>(&mut message).await, Err(Error::Json(_))));
+	}
+
+	#[cfg(all(feature = "msgpack", feature = "cbor"))]
+	#[tokio::test]
+	async fn msgpack_and_cbor() {
+		use super::Error;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.send_msgpack(&("answer", 42)).await.unwrap();
+		sender.send_cbor(&("answer", 42)).await.unwrap();
+		sender.send_binary([0xff]).await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut message = Vec::new();
+		assert_eq!(("answer".to_string(), 42), receiver.receive_msgpack::<(String, u32)>(&mut message).await.unwrap());
+		message.clear();
+		assert_eq!(("answer".to_string(), 42), receiver.receive_cbor::<(String, u32)>(&mut message).await.unwrap());
+		assert!(matches!(receiver.receive_cbor::<(String, u32)>(&mut message).await, Err(Error::Cbor(_))));
+	}
+
+	#[tokio::test]
+	async fn send_buffer() {
+		use futures::{future, FutureExt};
+
+		let (client, mut server) = pair(64);
+		server.set_send_buffer(256, 64);
+		let (mut sender, _) = server.finish();
+		let (_, mut receiver) = client.finish();
+
+		// Frames of 102 bytes are queued until the high watermark is exceeded.
+		for _ in 0..3 {
+			sender.send_binary([1; 100]).await.unwrap();
+		}
+		assert!(future::poll_fn(|cx| sender.poll_ready(cx)).now_or_never().is_none());
+
+		let send = async {
+			sender.ready().await.unwrap();
+			sender.flush().await.unwrap();
+		};
+		let receive = async {
+			let mut message = Vec::new();
+			for _ in 0..3 {
+				receiver.receive_data(&mut message).await.unwrap();
+			}
+			message
+		};
+		let ((), message) = tokio::join!(send, receive);
+		assert_eq!([1; 300], &message[..]);
+	}
+
+	#[tokio::test]
+	async fn auto_flush() {
+		use std::time::Duration;
+
+		let (client, mut server) = pair(1024);
+		// Nothing is written to the socket unless flushed.
+		server.set_send_buffer(usize::MAX, 0);
+		server.set_auto_flush(150, Duration::from_millis(10));
+		let (mut sender, mut receiver) = server.finish();
+		let (_, mut peer) = client.finish();
+
+		let mut message = Vec::new();
+		sender.send_binary([1; 100]).await.unwrap();
+		sender.send_binary([2; 100]).await.unwrap();
+		peer.receive_data(&mut message).await.unwrap();
+		peer.receive_data(&mut message).await.unwrap();
+		assert_eq!(200, message.len());
+
+		// Flushed by the receiver once the interval has elapsed.
+		message.clear();
+		sender.send_binary([3; 10]).await.unwrap();
+		let mut unexpected = Vec::new();
+		tokio::select! {
+			_ = receiver.receive(&mut unexpected) => panic!("nothing has been sent to the receiver"),
+			result = peer.receive_data(&mut message) => result.unwrap(),
+		};
+		assert_eq!([3; 10], &message[..]);
+	}
+
+	#[tokio::test]
+	async fn send_all() {
+		use crate::Message;
+		use futures::{channel::mpsc, SinkExt};
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = server.finish();
+		let (_, mut receiver) = client.finish();
+
+		let (mut tx, rx) = mpsc::channel(4);
+		let send = async {
+			sender.send_all(rx).await.unwrap();
+			sender
+		};
+		let produce = async move {
+			tx.send(Message::from("one".to_string())).await.unwrap();
+			tx.send(Message::from(vec![2])).await.unwrap();
+			tx.send(Message::from("three".to_string())).await.unwrap();
+		};
+		let receive = async {
+			let mut message = Vec::new();
+			for _ in 0..3 {
+				receiver.receive_data(&mut message).await.unwrap();
+			}
+			message
+		};
+		let (mut sender, (), message) = tokio::join!(send, produce, receive);
+		assert_eq!(b"one\x02three", &message[..]);
+		sender.send_text("open").await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn send_shared() {
+		use crate::Data;
+
+		let payload = b"shared payload".repeat(8);
+		let text = "shared text";
+		for buffered in [false, true] {
+			let (mut client, server) = pair(1024);
+			if buffered {
+				client.set_send_buffer(64, 0);
+			}
+			let (mut sender, _) = client.finish();
+			let (_, mut receiver) = server.finish();
+			sender.set_fragment_size(50);
+
+			sender.send_binary_shared(&payload).await.unwrap();
+			sender.send_text_shared(text).await.unwrap();
+			sender.flush().await.unwrap();
+			let mut message = Vec::new();
+			assert_eq!(Data::Binary(payload.len()), receiver.receive_data(&mut message).await.unwrap());
+			assert_eq!(&payload[..], &message[..]);
+			message.clear();
+			assert_eq!(Data::Text(text.len()), receiver.receive_data(&mut message).await.unwrap());
+			assert_eq!(text.as_bytes(), &message[..]);
+		}
+	}
+
+	#[tokio::test]
+	async fn frame_buffer() {
+		let (mut client, server) = pair(4096);
+		client.set_write_buffer_size(256, 64);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+
+		let payload = [7; 100];
+		let start = sender.writer.lock().await.buffer.as_ptr() as usize;
+		for _ in 0..10 {
+			sender.send_binary(payload).await.unwrap();
+			// The buffer is drained and still within its initial allocation.
+			let writer = sender.writer.lock().await;
+			let (ptr, cap) = (writer.buffer.as_ptr() as usize, writer.buffer.capacity());
+			assert!(writer.buffer.is_empty());
+			assert!(start <= ptr && ptr + cap <= start + 256);
+		}
+		let mut message = Vec::new();
+		for _ in 0..10 {
+			message.clear();
+			receiver.receive_data(&mut message).await.unwrap();
+			assert_eq!(&payload[..], &message[..]);
+		}
+	}
+
+	#[tokio::test]
+	async fn receive_message() {
+		use super::{CloseCode, CloseReason, Error};
+		use crate::Message;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.set_fragment_size(2);
+
+		let reason = CloseReason { code: CloseCode::GoingAway, descr: Some("bye".into()) };
+		let messages = vec![
+			Message::Text("hello".into()),
+			Message::Binary(vec![1, 2, 3]),
+			Message::Pong(b"pong".to_vec()),
+			Message::Closed(reason.clone()),
+			Message::Text("not sent".into()),
+		];
+		sender.send_all(futures::stream::iter(messages.clone())).await.unwrap();
+		for expected in &messages[..4] {
+			assert_eq!(*expected, receiver.receive_message().await.unwrap());
+		}
+		assert!(matches!(receiver.receive_message().await, Err(Error::Closed)));
+
+		let large = Message::Pong(vec![0; 126]);
+		let result = sender.send_all(futures::stream::iter(Some(large))).await;
+		assert!(matches!(result, Err(Error::ControlPayloadTooLarge(126))));
+	}
+
+	#[tokio::test]
+	async fn receive_timeout() {
+		use super::Error;
+		use crate::{Data, Incoming};
+		use std::time::Duration;
+		use tokio::io::AsyncWriteExt;
+
+		let (client, mut server) = tokio::io::duplex(1024);
+		let (_, mut receiver) = Builder::new(client.compat(), Mode::Client).finish();
+
+		// Half of a text frame with payload "hi".
+		server.write_all(&[0x81, 0x02, b'h']).await.unwrap();
+		let mut message = Vec::new();
+		let result = receiver.receive_timeout(&mut message, Duration::from_millis(10)).await;
+		assert!(matches!(result, Err(Error::Timeout)));
+
+		server.write_all(b"i").await.unwrap();
+		let result = receiver.receive_timeout(&mut message, Duration::from_millis(10)).await;
+		assert!(matches!(result, Ok(Incoming::Data(Data::Text(2)))));
+		assert_eq!(b"hi", &message[..]);
+	}
+
+	#[test]
+	fn close_codes() {
+		use super::CloseCode;
+
+		for code in [1000, 1004, 1014, 3000, 3001, 4000, 5000] {
+			assert_eq!(code, u16::from(CloseCode::from(code)))
+		}
+		assert_eq!(CloseCode::MessageTooBig, 1009.into());
+		assert_eq!(CloseCode::Library(3001), 3001.into());
+		assert_eq!(CloseCode::Private(4999), 4999.into());
+		assert_eq!(CloseCode::Reserved(1004), 1004.into());
+	}
+
+	#[tokio::test]
+	async fn shutdown() {
+		use super::{CloseCode, CloseReason};
+		use crate::Incoming;
+		use std::time::Duration;
+
+		let (client, server) = pair(1024);
+		let (mut sender, mut receiver) = client.finish();
+		let (_, mut server) = server.finish();
+
+		let reason = CloseReason { code: CloseCode::GoingAway, descr: Some("going away".into()) };
+		let shutdown = async {
+			// Long enough to fail the test if the reply is not noticed.
+			sender.shutdown(reason.clone(), Duration::from_secs(60)).await.unwrap();
+		};
+		let mut message = Vec::new();
+		let receive = async {
+			match receiver.receive(&mut message).await.unwrap() {
+				Incoming::Closed(reason) => reason.code,
+				other => panic!("unexpected {:?}", other),
+			}
+		};
+		let mut server_message = Vec::new();
+		let reply = async {
+			match server.receive(&mut server_message).await.unwrap() {
+				Incoming::Closed(reason) => reason,
+				other => panic!("unexpected {:?}", other),
+			}
+		};
+		let ((), code, received) = tokio::join!(shutdown, receive, reply);
+		assert_eq!(CloseCode::GoingAway, code);
+		assert_eq!(reason, received);
+
+		// Without a reply, shutdown completes after the timeout.
+		let (client, _server) = pair(1024);
+		let (mut sender, _receiver) = client.finish();
+		let reason = CloseReason { code: CloseCode::NormalClosure, descr: None };
+		sender.shutdown(reason, Duration::from_millis(10)).await.unwrap();
+	}
+
+	#[cfg(feature = "testing")]
+	#[tokio::test]
+	async fn control_events() {
+		use super::{CloseCode, CloseReason, Control};
+		use crate::{
+			base::{Header, OpCode},
+			testing, Data, Incoming,
+		};
+		use futures::StreamExt;
+
+		let (builder, mut peer) = testing::pair(Mode::Server);
+		let (_, mut receiver) = builder.finish();
+		let control = receiver.control_events();
+
+		peer.send_ping(b"ping").await.unwrap();
+		peer.send_frame(Header::new(OpCode::Pong), b"pong").await.unwrap();
+		peer.send_text("data").await.unwrap();
+		peer.send_close(1000, "").await.unwrap();
+
+		let mut message = Vec::new();
+		assert!(matches!(receiver.receive(&mut message).await.unwrap(), Incoming::Data(Data::Text(4))));
+		assert!(
+			matches!(receiver.receive(&mut message).await.unwrap(), Incoming::Closed(r) if r.code == CloseCode::NormalClosure)
+		);
+		drop(receiver);
+
+		let events = control.collect::<Vec<_>>().await;
+		let closed = Control::Closed(CloseReason { code: CloseCode::NormalClosure, descr: Some(String::new()) });
+		assert_eq!(vec![Control::Ping(b"ping".to_vec()), Control::Pong(b"pong".to_vec()), closed], events);
+	}
+
+	#[cfg(feature = "testing")]
+	#[tokio::test]
+	async fn lenient_close_codes() {
+		use super::CloseCode;
+		use crate::{base::OpCode, testing, Incoming};
+
+		let (mut builder, mut peer) = testing::pair(Mode::Server);
+		builder.set_strict_close_codes(false);
+		let (_, mut receiver) = builder.finish();
+
+		peer.send_close(999, "junk").await.unwrap();
+		let mut message = Vec::new();
+		let incoming = receiver.receive(&mut message).await.unwrap();
+		assert!(matches!(incoming, Incoming::Closed(r) if r.code == CloseCode::Reserved(999)));
+		let (header, payload) = peer.receive_frame().await.unwrap();
+		assert_eq!((OpCode::Close, &999_u16.to_be_bytes()[..]), (header.opcode(), &payload[..]));
+	}
+
+	#[tokio::test]
+	async fn frame_timeout() {
+		use super::Error;
+		use std::time::Duration;
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		let (client, mut server) = tokio::io::duplex(1024);
+		let mut builder = Builder::new(client.compat(), Mode::Client);
+		builder.set_frame_timeout(Duration::from_millis(10));
+		let (_, mut receiver) = builder.finish();
+
+		// A complete frame followed by the header and part of the payload of another one.
+		server.write_all(&[0x82, 0x01, 0x00, 0x82, 0x02, 0x00]).await.unwrap();
+		let mut message = Vec::new();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::FrameTimeout)));
+
+		let mut close = [0; 8];
+		server.read_exact(&mut close).await.unwrap();
+		let payload = [close[6] ^ close[2], close[7] ^ close[3]];
+		assert_eq!(([0x88, 0x82], 1008), ([close[0], close[1]], u16::from_be_bytes(payload)));
+	}
+
+	#[tokio::test]
+	async fn keepalive() {
+		use super::Error;
+		use crate::Incoming;
+		use std::time::Duration;
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		let (client, mut server) = tokio::io::duplex(1024);
+		let mut builder = Builder::new(client.compat(), Mode::Client);
+		builder.set_keepalive(Duration::from_millis(10), Duration::from_millis(10));
+		let (_, mut receiver) = builder.finish();
+
+		let peer = async {
+			// A masked PING with the 9 byte keepalive payload.
+			let mut ping = [0; 15];
+			server.read_exact(&mut ping).await.unwrap();
+			assert_eq!([0x89, 0x89], [ping[0], ping[1]]);
+			server.write_all(&[0x8a, 0x00]).await.unwrap();
+			server
+		};
+		let mut message = Vec::new();
+		let (_server, result) = tokio::join!(peer, receiver.receive(&mut message));
+		assert!(matches!(result, Ok(Incoming::Pong(_))));
+		// The next PING remains unanswered.
+		assert!(matches!(receiver.receive(&mut message).await, Err(Error::KeepaliveTimeout)));
+	}
+
+	#[tokio::test]
+	async fn receive_data_into() {
+		use super::Error;
+		use crate::Data;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+		sender.send_binary(b"hello world").await.unwrap();
+		sender.send_text("bye").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut buf = [0; 8];
+		let mut slice = &mut buf[..];
+		assert!(matches!(receiver.receive_data_into(&mut slice).await, Err(Error::BufferFull { remaining: 3 })));
+		assert_eq!(b"hello wo", &buf);
+		let mut slice = &mut buf[..];
+		assert_eq!(Data::Binary(11), receiver.receive_data_into(&mut slice).await.unwrap());
+		assert_eq!(b"rld", &buf[..3]);
+
+		let mut bytes = bytes::BytesMut::new();
+		assert_eq!(Data::Text(3), receiver.receive_data_into(&mut bytes).await.unwrap());
+		assert_eq!(b"bye", &bytes[..]);
+	}
+
+	#[tokio::test]
+	async fn reserved_opcode() {
+		use super::Error;
+		use crate::{base::OpCode, extension::TestExtension, Incoming};
+
+		let metadata = |opcode| {
+			Box::new(TestExtension { reserved_opcode: Some(opcode), ..TestExtension::new("metadata") }) as Box<_>
+		};
+
+		let (mut client, mut server) = pair(1024);
+		client.add_extensions(Some(metadata(OpCode::Reserved3)));
+		let (mut sender, _) = client.finish();
+		server.add_extensions(Some(metadata(OpCode::Reserved3)));
+		let (_, mut receiver) = server.finish();
+
+		assert!(matches!(sender.send_reserved(OpCode::Reserved4, b"x").await, Err(Error::UnexpectedOpCode(_))));
+		assert!(matches!(sender.send_reserved(OpCode::Binary, b"x").await, Err(Error::UnexpectedOpCode(_))));
+		assert!(matches!(sender.send_reserved(OpCode::Reserved11, b"x").await, Err(Error::UnexpectedOpCode(_))));
+		sender.send_reserved(OpCode::Reserved3, b"meta").await.unwrap();
+		sender.send_binary(b"data").await.unwrap();
+		sender.send_reserved(OpCode::Reserved3, b"skipped").await.unwrap();
+		sender.send_binary(b"more").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut message = Vec::new();
+		assert_eq!(Incoming::Reserved(OpCode::Reserved3, 4), receiver.receive(&mut message).await.unwrap());
+		assert_eq!(b"meta", &message[..]);
+		message.clear();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(b"data", &message[..]);
+		message.clear();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(b"more", &message[..]);
+
+		// Extensions using the same opcode conflict.
+		let twice = || vec![metadata(OpCode::Reserved3), metadata(OpCode::Reserved3)];
+		let mut builder = Builder::new(futures::io::Cursor::new(Vec::new()), Mode::Client);
+		assert!(matches!(builder.try_add_extensions(twice()), Err(Error::ConflictingExtensions(..))));
+		assert!(builder.extensions.is_empty());
+		builder.add_extensions(twice());
+		assert_eq!(1, builder.extensions.len());
+
+		// Frames with a reserved control opcode are limited like other control frames.
+		let (mut client, mut server) = pair(1024);
+		client.add_extensions(Some(metadata(OpCode::Reserved11)));
+		let (mut sender, _) = client.finish();
+		server.add_extensions(Some(metadata(OpCode::Reserved11)));
+		let (_, mut receiver) = server.finish();
+		sender.set_fragment_size(4);
+
+		let result = sender.send_reserved(OpCode::Reserved11, [0; 126]).await;
+		assert!(matches!(result, Err(Error::ControlPayloadTooLarge(126))));
+		sender.send_reserved(OpCode::Reserved11, b"control").await.unwrap();
+		sender.flush().await.unwrap();
+		message.clear();
+		assert_eq!(Incoming::Reserved(OpCode::Reserved11, 7), receiver.receive(&mut message).await.unwrap());
+		assert_eq!(1, receiver.stats().frames_received);
+	}
+
+	#[tokio::test]
+	async fn reserved_bits() {
+		use super::Error;
+		use crate::{base, extension::TestExtension};
+
+		// Marks every message with RSV2 and RSV3.
+		let marker = |reserved_bits| {
+			Box::new(TestExtension { reserved_bits, mark: (false, true, true), ..TestExtension::new("marker") })
+				as Box<_>
+		};
+
+		let (mut client, mut server) = pair(1024);
+		client.add_extensions(Some(marker((false, true, true))));
+		let (mut sender, _) = client.finish();
+		server.add_extensions(Some(marker((false, true, true))));
+		let (_, mut receiver) = server.finish();
+
+		sender.send_binary(b"data").await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(b"data", &message[..]);
+
+		// RSV3 is not reserved by the extension.
+		let (mut client, _server) = pair(1024);
+		client.add_extensions(Some(marker((false, true, false))));
+		let (mut sender, _) = client.finish();
+		let result = sender.send_binary(b"data").await;
+		assert!(matches!(result, Err(Error::Codec(base::Error::InvalidReservedBit(3)))));
+	}
+
+	#[tokio::test]
+	async fn measure_latency() {
+		use super::Error;
+
+		let (client, server) = pair(1024);
+		let (mut sender, mut receiver) = client.finish();
+		let (mut server_sender, mut server_receiver) = server.finish();
+
+		// The server answers the PING while receiving.
+		let server = tokio::spawn(async move { server_receiver.receive_data(&mut Vec::new()).await });
+		let latency = sender.measure_latency().await.unwrap();
+		let client = tokio::spawn(async move { receiver.receive_data(&mut Vec::new()).await });
+		assert!(latency.await.is_ok());
+		server_sender.close().await.unwrap();
+		let _ = tokio::join!(client, server);
+
+		// Pending probes fail once the receiver is gone.
+		let (client, _server) = pair(1024);
+		let (mut sender, receiver) = client.finish();
+		let latency = sender.measure_latency().await.unwrap();
+		drop(receiver);
+		assert!(matches!(latency.await, Err(Error::Closed)));
+		assert!(matches!(sender.measure_latency().await, Err(Error::Closed)));
+	}
+
+	#[tokio::test]
+	async fn ping() {
+		use crate::data::ByteSlice125;
+
+		let (client, server) = pair(1024);
+		let (mut sender, mut receiver) = client.finish();
+		let (_server_sender, mut server_receiver) = server.finish();
+		let server = tokio::spawn(async move { server_receiver.receive_data(&mut Vec::new()).await });
+		let client = tokio::spawn(async move { receiver.receive_data(&mut Vec::new()).await });
+
+		let a = sender.ping(ByteSlice125::try_from(&b"a"[..]).unwrap()).await.unwrap();
+		let b = sender.ping(ByteSlice125::try_from(&b"b"[..]).unwrap()).await.unwrap();
+		let (b, a) = (b.await.unwrap(), a.await.unwrap());
+		assert_eq!(b"a", &a.payload[..]);
+		assert_eq!(b"b", &b.payload[..]);
+		client.abort();
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn pong_hook() {
+		use crate::{data::ByteSlice125, Data, Incoming};
+
+		let (client, mut server) = pair(1024);
+		let (mut sender, mut receiver) = client.finish();
+		server.set_pong_hook(|ping| (ping != b"ignored").then(|| b"load: 0.5".to_vec()));
+		let (mut server_sender, mut server_receiver) = server.finish();
+
+		sender.send_ping(ByteSlice125::try_from(&b"ignored"[..]).unwrap()).await.unwrap();
+		sender.send_ping(ByteSlice125::try_from(&b"load?"[..]).unwrap()).await.unwrap();
+		sender.flush().await.unwrap();
+		let server = tokio::spawn(async move { server_receiver.receive_data(&mut Vec::new()).await });
+		let mut message = Vec::new();
+		assert!(matches!(receiver.receive(&mut message).await.unwrap(), Incoming::Pong(b"load: 0.5")));
+		server_sender.send_text("done").await.unwrap();
+		server_sender.flush().await.unwrap();
+		assert_eq!(Data::Text(4), receiver.receive_data(&mut message).await.unwrap());
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn unsolicited_pongs() {
+		use crate::{data::ByteSlice125, Incoming};
+
+		let (mut client, server) = pair(1024);
+		client.set_unsolicited_pongs(false);
+		let (mut sender, mut receiver) = client.finish();
+		let (mut server_sender, mut server_receiver) = server.finish();
+
+		server_sender.send_pong(ByteSlice125::try_from(&b"heartbeat"[..]).unwrap()).await.unwrap();
+		server_sender.flush().await.unwrap();
+		let server = tokio::spawn(async move { server_receiver.receive_data(&mut Vec::new()).await });
+		let pong = sender.ping(ByteSlice125::try_from(&b"tracked"[..]).unwrap()).await.unwrap();
+		let mut message = Vec::new();
+		assert!(matches!(receiver.receive(&mut message).await.unwrap(), Incoming::Pong(b"tracked")));
+		assert_eq!(b"tracked", &pong.await.unwrap().payload[..]);
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn idle_for() {
+		use std::time::Duration;
+
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		assert!(sender.idle_for() >= Duration::from_millis(20));
+		assert!(receiver.idle_for() >= Duration::from_millis(20));
+
+		sender.send_text("hello").await.unwrap();
+		sender.flush().await.unwrap();
+		assert!(sender.idle_for() < Duration::from_millis(20));
+		receiver.receive_data(&mut Vec::new()).await.unwrap();
+		assert!(receiver.idle_for() < Duration::from_millis(20));
+	}
+
+	#[tokio::test]
+	async fn stats() {
+		let (client, server) = pair(1024);
+		let (mut sender, _) = client.finish();
+		let (_, mut receiver) = server.finish();
+
+		sender.send_text("hello").await.unwrap();
+		sender.send_binary_uncompressed([1, 2, 3]).await.unwrap();
+		sender.send_pong(b"pong"[..].try_into().unwrap()).await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		receiver.receive_data(&mut message).await.unwrap();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert!(receiver.receive(&mut message).await.unwrap().is_pong());
+
+		let stats = sender.stats();
+		assert_eq!(
+			(3, 1, 2, 12, 8),
+			(
+				stats.frames_sent,
+				stats.control_frames_sent,
+				stats.messages_sent,
+				stats.bytes_sent,
+				stats.message_bytes_sent
+			)
+		);
+		let stats = receiver.stats();
+		assert_eq!(
+			(3, 1, 2, 12, 8),
+			(
+				stats.frames_received,
+				stats.control_frames_received,
+				stats.messages_received,
+				stats.bytes_received,
+				stats.message_bytes_received
+			)
+		);
 	}
 }
@@ -10,10 +10,11 @@
 
 use std::fmt;
 
-use crate::connection::CloseReason;
+use crate::{base::OpCode, connection::CloseReason};
 
 /// Data received from the remote end.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
 pub enum Incoming<'a> {
 	/// Text or binary data.
 	Data(Data),
@@ -21,25 +22,28 @@ pub enum Incoming<'a> {
 	Pong(&'a [u8]),
 	/// The other end closed the connection.
 	Closed(CloseReason),
+	/// Data of a reserved opcode used by an extension (number of bytes).
+	Reserved(OpCode, usize),
+	/// A single frame of a message, received in fragment mode.
+	Fragment {
+		/// The opcode of the message, i.e. of its first frame.
+		first_opcode: OpCode,
+		/// Is this the last frame of the message?
+		fin: bool,
+		/// The number of bytes of the frame.
+		len: usize,
+	},
 }
 
 impl Incoming<'_> {
 	/// Is this text or binary data?
 	pub fn is_data(&self) -> bool {
-		if let Incoming::Data(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Incoming::Data(_))
 	}
 
 	/// Is this a PONG?
 	pub fn is_pong(&self) -> bool {
-		if let Incoming::Pong(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Incoming::Pong(_))
 	}
 
 	/// Is this text data?
@@ -72,20 +76,12 @@ pub enum Data {
 impl Data {
 	/// Is this text data?
 	pub fn is_text(&self) -> bool {
-		if let Data::Text(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Data::Text(_))
 	}
 
 	/// Is this binary data?
 	pub fn is_binary(&self) -> bool {
-		if let Data::Binary(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Data::Binary(_))
 	}
 
 	/// The length of data (number of bytes).
@@ -95,6 +91,38 @@ impl Data {
 			Data::Binary(n) => *n,
 		}
 	}
+
+	/// Is the data empty?
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+/// An owned message, e.g. to send with [`Sender::send_all`](crate::Sender::send_all)
+/// or received with [`Receiver::receive_message`](crate::Receiver::receive_message).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum Message {
+	/// A text message.
+	Text(String),
+	/// A binary message.
+	Binary(Vec<u8>),
+	/// Data sent with a PONG control frame.
+	Pong(Vec<u8>),
+	/// A close message with the reason for closing the connection.
+	Closed(CloseReason),
+}
+
+impl From<String> for Message {
+	fn from(text: String) -> Self {
+		Message::Text(text)
+	}
+}
+
+impl From<Vec<u8>> for Message {
+	fn from(data: Vec<u8>) -> Self {
+		Message::Binary(data)
+	}
 }
 
 /// Wrapper type which restricts the length of its byte slice to 125 bytes.
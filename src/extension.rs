@@ -11,11 +11,21 @@
 //!
 //! [rfc6455]: https://tools.ietf.org/html/rfc6455#section-9
 
-#[cfg(feature = "deflate")]
+#[cfg(feature = "brotli")]
+pub mod brotli;
+#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
 pub mod deflate;
+#[cfg(feature = "snappy")]
+pub mod snappy;
+#[cfg(feature = "zstd")]
+pub mod zstd;
 
-use crate::{base::Header, BoxedError, Storage};
-use std::{borrow::Cow, fmt};
+use crate::{
+	base::{Header, OpCode},
+	BoxedError, Storage,
+};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{borrow::Cow, fmt, io};
 
 /// A websocket extension as per RFC 6455, section 9.
 ///
@@ -27,21 +37,21 @@ use std::{borrow::Cow, fmt};
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When receiving a handshake request from a client, for each extension
-/// with a matching name, [`Extension::configure`] will be applied to the
-/// request parameters. The extension may internally enable itself.
+///    with a matching name, [`Extension::configure`] will be applied to the
+///    request parameters. The extension may internally enable itself.
 /// 3. When sending back the response, for each extension whose
-/// [`Extension::is_enabled`] returns true, the extension name and its
-/// parameters (as returned by [`Extension::params`]) will be included in the
-/// response.
+///    [`Extension::is_enabled`] returns true, the extension name and its
+///    parameters (as returned by [`Extension::params`]) will be included in the
+///    response.
 ///
 /// # Client
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When creating the handshake request, all extensions and its parameters
-/// (as returned by [`Extension::params`]) will be included in the request.
+///    (as returned by [`Extension::params`]) will be included in the request.
 /// 3. When receiving the response from the server, for every extension with
-/// a matching name in the response, [`Extension::configure`] will be applied
-/// to the response parameters. The extension may internally enable itself.
+///    a matching name in the response, [`Extension::configure`] will be applied
+///    to the response parameters. The extension may internally enable itself.
 ///
 /// After this handshake phase, extensions have been configured and are
 /// potentially enabled. Enabled extensions can then be used for further base
@@ -54,24 +64,95 @@ pub trait Extension: std::fmt::Debug {
 	fn name(&self) -> &str;
 
 	/// The parameters this extension wants to send for negotiation.
-	fn params(&self) -> &[Param];
+	fn params(&self) -> &[Param<'_>];
 
 	/// Configure this extension with the parameters received from negotiation.
 	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError>;
 
+	/// Configure this extension asynchronously, e.g. to fetch keys, dictionaries
+	/// or policy decisions from elsewhere during negotiation.
+	///
+	/// The handshakes of [`Client`](crate::handshake::Client) and
+	/// [`Server`](crate::handshake::Server) call this method instead of
+	/// [`Extension::configure`], which it calls by default. Handshakes which
+	/// are completed synchronously, e.g. with the `http` crate, still call
+	/// [`Extension::configure`].
+	fn configure_async<'a>(&'a mut self, params: &'a [Param<'a>]) -> BoxFuture<'a, Result<(), BoxedError>> {
+		future::ready(self.configure(params)).boxed()
+	}
+
 	/// Encode a frame, given as frame header and payload data.
 	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError>;
 
 	/// Decode a frame.
 	///
-	/// The frame header is given, as well as the accumulated payload data, i.e.
-	/// the concatenated payload data of all message fragments.
+	/// The frame header is given, as well as the buffer the message has been
+	/// received into, e.g. the one given to [`Receiver::receive_data`]. Its
+	/// last [`Header::payload_len`] bytes are the accumulated payload data,
+	/// i.e. the concatenated payload data of all message fragments. Any bytes
+	/// before belong to the caller and must be left as is, so the decoded
+	/// message can be written straight into the buffer after them.
+	///
+	/// [`Receiver::receive_data`]: crate::connection::Receiver::receive_data
 	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError>;
 
+	/// Encode a frame like [`Extension::encode`], e.g. on a thread pool.
+	///
+	/// [`Sender`](crate::connection::Sender) calls this method instead of
+	/// [`Extension::encode`], which it calls by default.
+	fn encode_async<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Storage<'_>,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		future::ready(self.encode(header, data)).boxed()
+	}
+
+	/// Decode a frame like [`Extension::decode`], e.g. on a thread pool.
+	///
+	/// [`Receiver`](crate::connection::Receiver) calls this method instead of
+	/// [`Extension::decode`] for complete messages, which it calls by default.
+	fn decode_async<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Vec<u8>,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		future::ready(self.decode(header, data)).boxed()
+	}
+
+	/// Decode a complete message like [`Extension::decode`], but write the
+	/// decoded data to `sink` instead of back into `data`.
+	///
+	/// [`Receiver::receive_data_to`] calls this method on the first extension
+	/// after the others have decoded the message. If it returns the number of
+	/// bytes written, `header` and `data` must be left as is. `None`, the
+	/// default, means the extension does not support this and the message is
+	/// decoded with [`Extension::decode_async`] instead.
+	///
+	/// [`Receiver::receive_data_to`]: crate::connection::Receiver::receive_data_to
+	fn decode_to(
+		&mut self,
+		_header: &mut Header,
+		_data: &mut Vec<u8>,
+		_sink: &mut dyn io::Write,
+	) -> Result<Option<usize>, BoxedError> {
+		Ok(None)
+	}
+
 	/// The reserved bits this extension uses.
+	///
+	/// Incoming frames may only have the reserved bits of enabled extensions
+	/// set, which these see in [`Extension::decode`] and may set on outgoing
+	/// messages in [`Extension::encode`]. Sending a message with any other
+	/// reserved bit set fails.
 	fn reserved_bits(&self) -> (bool, bool, bool) {
 		(false, false, false)
 	}
+
+	/// The reserved opcode this extension uses, if any.
+	fn reserved_opcode(&self) -> Option<OpCode> {
+		None
+	}
 }
 
 impl<E: Extension + ?Sized> Extension for Box<E> {
@@ -83,7 +164,7 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
 		(**self).name()
 	}
 
-	fn params(&self) -> &[Param] {
+	fn params(&self) -> &[Param<'_>] {
 		(**self).params()
 	}
 
@@ -91,6 +172,10 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
 		(**self).configure(params)
 	}
 
+	fn configure_async<'a>(&'a mut self, params: &'a [Param<'a>]) -> BoxFuture<'a, Result<(), BoxedError>> {
+		(**self).configure_async(params)
+	}
+
 	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
 		(**self).encode(header, data)
 	}
@@ -99,9 +184,117 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
 		(**self).decode(header, data)
 	}
 
+	fn encode_async<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Storage<'_>,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		(**self).encode_async(header, data)
+	}
+
+	fn decode_async<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Vec<u8>,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		(**self).decode_async(header, data)
+	}
+
+	fn decode_to(
+		&mut self,
+		header: &mut Header,
+		data: &mut Vec<u8>,
+		sink: &mut dyn io::Write,
+	) -> Result<Option<usize>, BoxedError> {
+		(**self).decode_to(header, data, sink)
+	}
+
 	fn reserved_bits(&self) -> (bool, bool, bool) {
 		(**self).reserved_bits()
 	}
+
+	fn reserved_opcode(&self) -> Option<OpCode> {
+		(**self).reserved_opcode()
+	}
+}
+
+/// Check if two extensions use the same reserved bits or opcode.
+pub(crate) fn is_conflicting(a: &dyn Extension, b: &dyn Extension) -> bool {
+	let (a1, a2, a3) = a.reserved_bits();
+	let (b1, b2, b3) = b.reserved_bits();
+	let same_opcode = match (a.reserved_opcode(), b.reserved_opcode()) {
+		(Some(x), Some(y)) => x == y,
+		_ => false,
+	};
+	(a1 && b1) || (a2 && b2) || (a3 && b3) || same_opcode
+}
+
+/// Where the message given to [`Extension::decode`] starts in `data`.
+#[cfg(any(feature = "brotli", feature = "deflate", feature = "deflate-rust", feature = "snappy", feature = "zstd"))]
+pub(crate) fn message_start(header: &Header, data: &[u8]) -> usize {
+	data.len().saturating_sub(header.payload_len())
+}
+
+/// Is a compressing extension, identified by `name` in log messages, to
+/// decompress the message of the given frame now?
+///
+/// Fragmented messages are decompressed with the header of their last frame,
+/// of which only the first has RSV1 set. `await_last_fragment` is the
+/// extension's record of having seen such a first frame.
+#[cfg(any(feature = "brotli", feature = "deflate", feature = "deflate-rust", feature = "snappy", feature = "zstd"))]
+pub(crate) fn is_decompressing(name: &str, header: &Header, await_last_fragment: &mut bool) -> bool {
+	match header.opcode() {
+		OpCode::Binary | OpCode::Text if header.is_rsv1() => {
+			if !header.is_fin() {
+				*await_last_fragment = true;
+				log::trace!("{}: not decoding {}; awaiting last fragment", name, header);
+				return false;
+			}
+			log::trace!("{}: decoding {}", name, header);
+			true
+		}
+		OpCode::Continue if header.is_fin() && *await_last_fragment => {
+			*await_last_fragment = false;
+			log::trace!("{}: decoding {}", name, header);
+			true
+		}
+		_ => {
+			log::trace!("{}: not decoding {}", name, header);
+			false
+		}
+	}
+}
+
+/// Is a compressing extension, identified by `name` in log messages, to
+/// compress the message of the given frame? This is the case for non-empty
+/// text and binary messages, unless [`Header::is_compressible`] is unset.
+#[cfg(any(feature = "brotli", feature = "deflate", feature = "deflate-rust", feature = "snappy", feature = "zstd"))]
+pub(crate) fn is_compressing(name: &str, header: &Header, data: &[u8]) -> bool {
+	if data.is_empty() {
+		return false;
+	}
+	if !header.is_compressible() {
+		log::trace!("{}: not encoding {}; compression disabled", name, header);
+		return false;
+	}
+	if let OpCode::Binary | OpCode::Text = header.opcode() {
+		log::trace!("{}: encoding {}", name, header);
+		true
+	} else {
+		log::trace!("{}: not encoding {}", name, header);
+		false
+	}
+}
+
+/// Replace the message starting at `start` in `data` with `output`.
+#[cfg(any(feature = "brotli", feature = "snappy", feature = "zstd"))]
+pub(crate) fn replace_message(data: &mut Vec<u8>, start: usize, output: &mut Vec<u8>) {
+	if start == 0 {
+		std::mem::swap(data, output)
+	} else {
+		data.truncate(start);
+		data.extend_from_slice(output)
+	}
 }
 
 /// Extension parameter (used for negotiation).
@@ -148,3 +341,92 @@ impl<'a> Param<'a> {
 		Param { name: Cow::Owned(self.name.into_owned()), value: self.value.map(|v| Cow::Owned(v.into_owned())) }
 	}
 }
+
+/// A configurable extension for tests.
+///
+/// Configuring it enables it and stores the value of an `id` parameter. It
+/// sets the bits of `mark` on every frame it encodes and requires them on
+/// every message it decodes.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct TestExtension {
+	pub(crate) name: &'static str,
+	pub(crate) enabled: bool,
+	pub(crate) reserved_bits: (bool, bool, bool),
+	pub(crate) reserved_opcode: Option<OpCode>,
+	pub(crate) mark: (bool, bool, bool),
+	/// Fail [`Extension::configure`], i.e. only support asynchronous handshakes.
+	pub(crate) async_only: bool,
+	pub(crate) id: Option<String>,
+}
+
+#[cfg(test)]
+impl TestExtension {
+	/// An enabled extension with the given name which does nothing.
+	pub(crate) fn new(name: &'static str) -> Self {
+		TestExtension { name, enabled: true, ..TestExtension::default() }
+	}
+
+	fn on_configure(&mut self, params: &[Param]) {
+		self.enabled = true;
+		self.id = params.iter().find(|p| p.name() == "id").and_then(|p| p.value()).map(String::from)
+	}
+}
+
+#[cfg(test)]
+impl Extension for TestExtension {
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn name(&self) -> &str {
+		self.name
+	}
+
+	fn params(&self) -> &[Param<'_>] {
+		&[]
+	}
+
+	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		if self.async_only {
+			return Err("requires an asynchronous handshake".into());
+		}
+		self.on_configure(params);
+		Ok(())
+	}
+
+	fn configure_async<'a>(&'a mut self, params: &'a [Param<'a>]) -> BoxFuture<'a, Result<(), BoxedError>> {
+		async move {
+			// E.g. fetch the key with the given id.
+			tokio::task::yield_now().await;
+			self.on_configure(params);
+			Ok(())
+		}
+		.boxed()
+	}
+
+	fn encode(&mut self, header: &mut Header, _: &mut Storage) -> Result<(), BoxedError> {
+		let (rsv1, rsv2, rsv3) = (header.is_rsv1(), header.is_rsv2(), header.is_rsv3());
+		let (m1, m2, m3) = self.mark;
+		header.set_rsv1(rsv1 || m1).set_rsv2(rsv2 || m2).set_rsv3(rsv3 || m3);
+		Ok(())
+	}
+
+	fn decode(&mut self, header: &mut Header, _: &mut Vec<u8>) -> Result<(), BoxedError> {
+		let (rsv1, rsv2, rsv3) = (header.is_rsv1(), header.is_rsv2(), header.is_rsv3());
+		let (m1, m2, m3) = self.mark;
+		if (m1 && !rsv1) || (m2 && !rsv2) || (m3 && !rsv3) {
+			return Err("unmarked message".into());
+		}
+		header.set_rsv1(rsv1 && !m1).set_rsv2(rsv2 && !m2).set_rsv3(rsv3 && !m3);
+		Ok(())
+	}
+
+	fn reserved_bits(&self) -> (bool, bool, bool) {
+		self.reserved_bits
+	}
+
+	fn reserved_opcode(&self) -> Option<OpCode> {
+		self.reserved_opcode
+	}
+}
@@ -0,0 +1,360 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Brotli compression extension.
+//!
+//! Brotli takes the place of deflate in what is otherwise [RFC 7692][rfc7692]'s
+//! permessage-deflate, under the name `permessage-brotli`. The parameters are the
+//! same: `server_no_context_takeover`, `client_no_context_takeover`,
+//! `server_max_window_bits` and `client_max_window_bits`, except that window bits
+//! are within 10 ..= 24. Each message is terminated by a brotli flush, so that
+//! it can be decoded on its own when context takeover is in effect.
+//!
+//! [rfc7692]: https://tools.ietf.org/html/rfc7692
+
+use crate::{
+	base::Header,
+	connection::Mode,
+	extension::{is_compressing, is_decompressing, message_start, replace_message, Extension, Param},
+	BoxedError, Storage,
+};
+use ::brotli::{CompressorWriter, DecompressorWriter};
+use std::{
+	fmt,
+	io::{self, Write},
+	mem,
+};
+
+const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
+const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
+
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
+
+const BUFFER_SIZE: usize = 4096;
+const DEFAULT_QUALITY: u32 = 5;
+const DEFAULT_WINDOW_BITS: u32 = 22;
+const DEFAULT_DECOMPRESS_SIZE: usize = 256 * 1024 * 1024;
+
+/// The brotli extension type.
+pub struct Brotli {
+	mode: Mode,
+	enabled: bool,
+	params: Vec<Param<'static>>,
+	quality: u32,
+	our_max_window_bits: u32,
+	their_max_window_bits: u32,
+	no_our_context_takeover: bool,
+	no_their_context_takeover: bool,
+	await_last_fragment: bool,
+	max_buffer_size: usize,
+	encoder: Option<CompressorWriter<Vec<u8>>>,
+	decoder: Option<DecompressorWriter<Limited>>,
+}
+
+impl fmt::Debug for Brotli {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Brotli")
+			.field("mode", &self.mode)
+			.field("enabled", &self.enabled)
+			.field("params", &self.params)
+			.field("quality", &self.quality)
+			.field("our_max_window_bits", &self.our_max_window_bits)
+			.field("their_max_window_bits", &self.their_max_window_bits)
+			.field("no_our_context_takeover", &self.no_our_context_takeover)
+			.field("no_their_context_takeover", &self.no_their_context_takeover)
+			.finish_non_exhaustive()
+	}
+}
+
+impl Brotli {
+	/// Create a new brotli extension either on client or server side.
+	pub fn new(mode: Mode) -> Self {
+		Brotli {
+			mode,
+			enabled: false,
+			params: Vec::new(),
+			quality: DEFAULT_QUALITY,
+			our_max_window_bits: DEFAULT_WINDOW_BITS,
+			their_max_window_bits: DEFAULT_WINDOW_BITS,
+			no_our_context_takeover: false,
+			no_their_context_takeover: false,
+			await_last_fragment: false,
+			max_buffer_size: DEFAULT_DECOMPRESS_SIZE,
+			encoder: None,
+			decoder: None,
+		}
+	}
+
+	/// Ask the server not to use context takeover.
+	///
+	/// The extension must be in client mode.
+	pub fn set_server_no_context_takeover(&mut self) {
+		assert!(self.mode == Mode::Client, "server context takeover can only be set in client mode");
+		self.set_param(SERVER_NO_CONTEXT_TAKEOVER, None)
+	}
+
+	/// Do not use context takeover for the messages sent by the client.
+	///
+	/// The extension must be in client mode.
+	pub fn set_client_no_context_takeover(&mut self) {
+		assert!(self.mode == Mode::Client, "client context takeover can only be set in client mode");
+		self.no_our_context_takeover = true;
+		self.set_param(CLIENT_NO_CONTEXT_TAKEOVER, None)
+	}
+
+	/// Set the server's max. window bits.
+	///
+	/// The value must be within 10 ..= 24.
+	/// The extension must be in client mode.
+	pub fn set_max_server_window_bits(&mut self, max: u32) {
+		assert!(self.mode == Mode::Client, "setting max. server window bits requires client mode");
+		assert!((10..=24).contains(&max), "max. server window bits have to be within 10 ..= 24");
+		self.their_max_window_bits = max;
+		self.set_param(SERVER_MAX_WINDOW_BITS, Some(max))
+	}
+
+	/// Set the client's max. window bits.
+	///
+	/// The value must be within 10 ..= 24.
+	/// The extension must be in client mode.
+	pub fn set_max_client_window_bits(&mut self, max: u32) {
+		assert!(self.mode == Mode::Client, "setting max. client window bits requires client mode");
+		assert!((10..=24).contains(&max), "max. client window bits have to be within 10 ..= 24");
+		self.our_max_window_bits = max;
+		self.set_param(CLIENT_MAX_WINDOW_BITS, Some(max))
+	}
+
+	/// Set the brotli quality to use. The range is from 0 (fastest) to 11 (best compression).
+	///
+	/// The default is 5.
+	pub fn set_quality(&mut self, quality: u32) {
+		assert!(quality <= 11, "invalid quality: {}", quality);
+		self.quality = quality
+	}
+
+	/// Set the maximum size of the internal buffer used for decompression.
+	///
+	/// Messages that decompress to a size larger than this will fail to decode.
+	pub fn set_max_buffer_size(&mut self, size: usize) {
+		self.max_buffer_size = size;
+	}
+
+	fn set_param(&mut self, name: &'static str, value: Option<u32>) {
+		self.params.retain(|p| p.name() != name);
+		let mut p = Param::new(name);
+		p.set_value(value.map(|v| v.to_string()));
+		self.params.push(p)
+	}
+}
+
+/// Parse a window bits parameter value.
+fn window_bits(p: &Param) -> Option<u32> {
+	match p.value().map(|s| s.parse::<u32>()) {
+		Some(Ok(v)) if (10..=24).contains(&v) => Some(v),
+		_ => {
+			log::debug!("invalid {}: {:?}", p.name(), p.value());
+			None
+		}
+	}
+}
+
+impl Extension for Brotli {
+	fn name(&self) -> &str {
+		"permessage-brotli"
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn params(&self) -> &[Param<'_>] {
+		&self.params
+	}
+
+	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		match self.mode {
+			Mode::Server => {
+				self.params.clear();
+				for p in params {
+					log::trace!("configure server with: {}", p);
+					match p.name() {
+						CLIENT_MAX_WINDOW_BITS => match window_bits(p) {
+							Some(v) => self.their_max_window_bits = v,
+							None => return Ok(()),
+						},
+						SERVER_MAX_WINDOW_BITS => match window_bits(p) {
+							Some(v) => {
+								self.our_max_window_bits = v;
+								self.set_param(SERVER_MAX_WINDOW_BITS, Some(v))
+							}
+							None => return Ok(()),
+						},
+						CLIENT_NO_CONTEXT_TAKEOVER => {
+							self.no_their_context_takeover = true;
+							self.set_param(CLIENT_NO_CONTEXT_TAKEOVER, None)
+						}
+						SERVER_NO_CONTEXT_TAKEOVER => {
+							self.no_our_context_takeover = true;
+							self.set_param(SERVER_NO_CONTEXT_TAKEOVER, None)
+						}
+						_ => {
+							log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+							return Ok(());
+						}
+					}
+				}
+			}
+			Mode::Client => {
+				for p in params {
+					log::trace!("configure client with: {}", p);
+					match p.name() {
+						SERVER_NO_CONTEXT_TAKEOVER => self.no_their_context_takeover = true,
+						CLIENT_NO_CONTEXT_TAKEOVER => self.no_our_context_takeover = true,
+						SERVER_MAX_WINDOW_BITS => match window_bits(p) {
+							Some(v) if v <= self.their_max_window_bits => self.their_max_window_bits = v,
+							_ => return Ok(()),
+						},
+						CLIENT_MAX_WINDOW_BITS => match window_bits(p) {
+							Some(v) => self.our_max_window_bits = std::cmp::min(self.our_max_window_bits, v),
+							None => return Ok(()),
+						},
+						_ => {
+							log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+							return Ok(());
+						}
+					}
+				}
+			}
+		}
+		self.enabled = true;
+		self.encoder = None;
+		self.decoder = None;
+		Ok(())
+	}
+
+	fn reserved_bits(&self) -> (bool, bool, bool) {
+		(true, false, false)
+	}
+
+	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+		if !is_decompressing("brotli", header, &mut self.await_last_fragment) {
+			return Ok(());
+		}
+
+		if self.no_their_context_takeover {
+			self.decoder = None
+		}
+
+		let max = self.max_buffer_size;
+		let decoder = self.decoder.get_or_insert_with(|| DecompressorWriter::new(Limited::default(), BUFFER_SIZE));
+		decoder.get_mut().max = max;
+		let start = message_start(header, data);
+		let result = decoder.write_all(&data[start..]).and_then(|()| decoder.flush());
+		let mut output = mem::take(&mut decoder.get_mut().buffer);
+		if let Err(e) = result {
+			// The decoder state is unusable after an error.
+			self.decoder = None;
+			return Err(e.into());
+		}
+
+		replace_message(data, start, &mut output);
+		header.set_rsv1(false);
+		header.set_payload_len(data.len() - start);
+		Ok(())
+	}
+
+	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+		if !is_compressing("brotli", header, data.as_ref()) {
+			return Ok(());
+		}
+
+		let (quality, window_bits) = (self.quality, self.our_max_window_bits);
+		let encoder =
+			self.encoder.get_or_insert_with(|| CompressorWriter::new(Vec::new(), BUFFER_SIZE, quality, window_bits));
+		encoder.write_all(data.as_ref())?;
+		encoder.flush()?;
+		*data = Storage::Owned(mem::take(encoder.get_mut()));
+
+		if self.no_our_context_takeover {
+			self.encoder = None
+		}
+
+		header.set_rsv1(true);
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+}
+
+/// A buffer which fails to grow beyond a maximum size.
+#[derive(Default)]
+struct Limited {
+	buffer: Vec<u8>,
+	max: usize,
+}
+
+impl Write for Limited {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.buffer.len() + buf.len() > self.max {
+			return Err(io::Error::new(io::ErrorKind::Other, "decompressed message too large"));
+		}
+		self.buffer.extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Brotli;
+	use crate::{
+		base::{Header, OpCode},
+		connection::Mode,
+		extension::Extension,
+		Storage,
+	};
+
+	fn round_trip(encoder: &mut Brotli, decoder: &mut Brotli, message: &[u8]) -> usize {
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(message);
+		encoder.encode(&mut header, &mut data).unwrap();
+		assert!(header.is_rsv1());
+		let mut data = data.as_ref().to_vec();
+		let len = data.len();
+		decoder.decode(&mut header, &mut data).unwrap();
+		assert_eq!(message, data);
+		len
+	}
+
+	#[test]
+	fn context_takeover() {
+		let mut client = Brotli::new(Mode::Client);
+		client.set_client_no_context_takeover();
+		client.set_max_server_window_bits(16);
+		let mut server = Brotli::new(Mode::Server);
+		server.configure(client.params()).unwrap();
+		client.configure(server.params()).unwrap();
+		assert!(client.is_enabled() && server.is_enabled());
+		assert_eq!(16, server.our_max_window_bits);
+
+		let message = b"the quick brown fox jumps over the lazy dog, ".repeat(8);
+
+		// The server keeps its context, so repeated messages shrink.
+		let first = round_trip(&mut server, &mut client, &message);
+		let second = round_trip(&mut server, &mut client, &message);
+		assert!(second < first);
+
+		// The client does not.
+		let first = round_trip(&mut client, &mut server, &message);
+		let second = round_trip(&mut client, &mut server, &message);
+		assert_eq!(second, first);
+	}
+}
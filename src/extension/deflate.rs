@@ -12,16 +12,26 @@
 
 use crate::{
 	as_u64,
-	base::{Header, OpCode},
+	base::Header,
 	connection::Mode,
-	extension::{Extension, Param},
+	extension::{is_compressing, is_decompressing, message_start, Extension, Param},
 	BoxedError, Storage,
 };
 use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::{
+	channel::oneshot,
+	future::{self, BoxFuture},
+	FutureExt,
+};
 use std::{
 	convert::TryInto,
+	fmt,
 	io::{self},
 	mem,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, MutexGuard, PoisonError,
+	},
 };
 
 const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
@@ -34,10 +44,24 @@ const DEFAULT_GROWTH: usize = 4096;
 const DEFAULT_DECOMPRESS_SIZE: usize = 256 * 1024 * 1024;
 const TRAILER: [u8; 4] = [0, 0, 0xFF, 0xFF];
 
+// Approximate sizes of the pure-Rust contexts, whose window size is fixed.
+const RUST_COMPRESS_MEMORY: usize = 300 * 1024;
+const RUST_DECOMPRESS_MEMORY: usize = 43 * 1024;
+
 /// The deflate extension type.
 ///
-/// The extension does currently not support max. window bits other than the
-/// default, which is 15 and will ask for no context takeover during handshake.
+/// Clients ask for no context takeover during the handshake. Window bits in
+/// the full range of 8 ..= 15 can be negotiated, see
+/// [`Deflate::set_max_server_window_bits`] and [`Deflate::set_max_client_window_bits`].
+///
+/// zlib does not support compressing with a window of 8 bits. If our window
+/// is restricted to 8 bits, messages are deliberately sent as stored, i.e.
+/// uncompressed, deflate blocks. This is intended: stored blocks are valid for
+/// any window size, so the peer's restriction is honoured, at the price of no
+/// compression in that direction. With the pure-Rust backend (`deflate-rust`
+/// feature, without `deflate`) the window size can not be configured at all,
+/// so the same applies to any window of less than 15 bits. Received messages
+/// are decompressed for every window size.
 #[derive(Debug)]
 pub struct Deflate {
 	mode: Mode,
@@ -52,8 +76,29 @@ pub struct Deflate {
 	await_last_fragment: bool,
 	max_buffer_size: usize,
 	grow_buffer_size: usize,
-	encoder: Compress,
-	decoder: Decompress,
+	min_compress_size: usize,
+	stats: Stats,
+	offload: Option<Offload>,
+	/// The min. message size and number of threads for parallel compression.
+	parallel: Option<(usize, usize)>,
+	// Shared with work offloaded to other threads.
+	encoder: Arc<Mutex<Compress>>,
+	decoder: Arc<Mutex<Decompress>>,
+}
+
+/// Runs compression work elsewhere, see [`Deflate::set_offload`].
+#[derive(Clone)]
+struct Offload {
+	threshold: usize,
+	spawn: Arc<Spawn>,
+}
+
+type Spawn = dyn Fn(Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> + Send + Sync;
+
+impl fmt::Debug for Offload {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Offload").field("threshold", &self.threshold).finish_non_exhaustive()
+	}
 }
 
 impl Deflate {
@@ -61,15 +106,13 @@ impl Deflate {
 	pub fn new(mode: Mode) -> Self {
 		let params = match mode {
 			Mode::Server => Vec::new(),
-			Mode::Client => {
-				let mut params = Vec::new();
-				params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER));
-				params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER));
-				params.push(Param::new(CLIENT_MAX_WINDOW_BITS));
-				params
-			}
+			Mode::Client => vec![
+				Param::new(SERVER_NO_CONTEXT_TAKEOVER),
+				Param::new(CLIENT_NO_CONTEXT_TAKEOVER),
+				Param::new(CLIENT_MAX_WINDOW_BITS),
+			],
 		};
-		Deflate {
+		let this = Deflate {
 			mode,
 			enabled: false,
 			buffer: Vec::new(),
@@ -82,14 +125,20 @@ impl Deflate {
 			no_their_context_takeover: false,
 			max_buffer_size: DEFAULT_DECOMPRESS_SIZE,
 			grow_buffer_size: DEFAULT_GROWTH,
-			encoder: Compress::new(Compression::fast(), false),
-			decoder: Decompress::new(false),
-		}
+			min_compress_size: 0,
+			stats: Stats::default(),
+			offload: None,
+			parallel: None,
+			encoder: Arc::new(Mutex::new(Compress::new(Compression::fast(), false))),
+			decoder: Arc::new(Mutex::new(Decompress::new(false))),
+		};
+		this.on_memory();
+		this
 	}
 
 	/// Set the server's max. window bits.
 	///
-	/// The value must be within 9 ..= 15.
+	/// The value must be within 8 ..= 15.
 	/// The extension must be in client mode.
 	///
 	/// By including this parameter, a client limits the LZ77 sliding window
@@ -98,7 +147,7 @@ impl Deflate {
 	/// response with the same or smaller value as the offer.
 	pub fn set_max_server_window_bits(&mut self, max: u8) {
 		assert!(self.mode == Mode::Client, "setting max. server window bits requires client mode");
-		assert!(max > 8 && max <= 15, "max. server window bits have to be within 9 ..= 15");
+		assert!((8..=15).contains(&max), "max. server window bits have to be within 8 ..= 15");
 		self.their_max_window_bits = max; // upper bound of the server's window
 		let mut p = Param::new(SERVER_MAX_WINDOW_BITS);
 		p.set_value(Some(max.to_string()));
@@ -107,7 +156,7 @@ impl Deflate {
 
 	/// Set the client's max. window bits.
 	///
-	/// The value must be within 9 ..= 15.
+	/// The value must be within 8 ..= 15.
 	/// The extension must be in client mode.
 	///
 	/// The parameter informs the server that even if it doesn't include the
@@ -119,7 +168,7 @@ impl Deflate {
 	/// to reduce its sliding window even more.
 	pub fn set_max_client_window_bits(&mut self, max: u8) {
 		assert!(self.mode == Mode::Client, "setting max. client window bits requires client mode");
-		assert!(max > 8 && max <= 15, "max. client window bits have to be within 9 ..= 15");
+		assert!((8..=15).contains(&max), "max. client window bits have to be within 8 ..= 15");
 		self.our_max_window_bits = max; // upper bound of the client's window
 		if let Some(p) = self.params.iter_mut().find(|p| p.name() == CLIENT_MAX_WINDOW_BITS) {
 			p.set_value(Some(max.to_string()));
@@ -142,6 +191,94 @@ impl Deflate {
 		self.grow_buffer_size = size;
 	}
 
+	/// Set the minimum size of messages to compress.
+	///
+	/// Smaller messages are sent uncompressed, as compressing them costs more than
+	/// it saves and often makes them larger. The default is 0, i.e. all messages
+	/// are compressed.
+	pub fn set_min_compress_size(&mut self, size: usize) {
+		self.min_compress_size = size;
+	}
+
+	/// Compress and decompress messages of at least `threshold` bytes with
+	/// work given to `spawn`, so that large messages do not block the executor.
+	///
+	/// `spawn` returns a future which completes once the work has run, e.g. on
+	/// a thread pool for blocking tasks. Only connections use it, which call
+	/// [`Extension::encode_async`] and [`Extension::decode_async`]:
+	///
+	/// ```
+	/// # use soketto::{extension::deflate::Deflate, Mode};
+	/// let mut deflate = Deflate::new(Mode::Server);
+	/// deflate.set_offload(1024 * 1024, |work| Box::pin(async { let _ = tokio::task::spawn_blocking(work).await; }));
+	/// ```
+	pub fn set_offload<F>(&mut self, threshold: usize, spawn: F)
+	where
+		F: Fn(Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+	{
+		self.offload = Some(Offload { threshold, spawn: Arc::new(spawn) })
+	}
+
+	/// Compress messages of at least `threshold` bytes in `threads` parts in
+	/// parallel.
+	///
+	/// Each part is compressed without referring to the others, which
+	/// compresses somewhat worse but takes less time for large messages. The
+	/// parts are given to the hook of [`Deflate::set_offload`], regardless of
+	/// its threshold, so they run on its thread pool. Without a hook, and with
+	/// [`Extension::encode`], messages are compressed as usual. With context
+	/// takeover, the context is carried over to the next message, which
+	/// requires the `deflate` feature. Without it, messages are then also
+	/// compressed as usual.
+	///
+	/// # Panics
+	///
+	/// If `threads` is zero.
+	pub fn set_parallel_compression(&mut self, threshold: usize, threads: usize) {
+		assert!(threads > 0, "number of threads must not be zero");
+		self.parallel = Some((threshold, threads))
+	}
+
+	/// Get a handle to the compression statistics of this extension.
+	///
+	/// The handle remains valid after the extension has been added to a connection.
+	pub fn stats(&self) -> Stats {
+		self.stats.clone()
+	}
+
+	/// An estimate of the memory in bytes held by this extension.
+	///
+	/// This includes the compression and decompression contexts, based on
+	/// the window sizes in use, and the capacity of the internal buffer.
+	/// Buffers of messages which are currently being processed elsewhere,
+	/// e.g. by offloaded or parallel compression, are not accounted for.
+	pub fn memory_usage(&self) -> usize {
+		compress_memory(self.our_max_window_bits)
+			+ decompress_memory(self.their_max_window_bits)
+			+ self.buffer.capacity()
+	}
+
+	/// The parameters agreed with the peer, if the extension is enabled.
+	pub fn negotiated(&self) -> Option<Negotiated> {
+		if !self.enabled {
+			return None;
+		}
+		let (our, their) = (
+			(self.our_max_window_bits, self.no_our_context_takeover),
+			(self.their_max_window_bits, self.no_their_context_takeover),
+		);
+		let ((server_bits, server_no_takeover), (client_bits, client_no_takeover)) = match self.mode {
+			Mode::Server => (our, their),
+			Mode::Client => (their, our),
+		};
+		Some(Negotiated {
+			server_max_window_bits: server_bits,
+			client_max_window_bits: client_bits,
+			server_no_context_takeover: server_no_takeover,
+			client_no_context_takeover: client_no_takeover,
+		})
+	}
+
 	/// Set the zlib compression level to use. The range is from 0 (no compression) to 9 (best compression).
 	///
 	/// The default is 1 (fastest compression).
@@ -150,12 +287,173 @@ impl Deflate {
 			0..=9 => Compression::new(level),
 			_ => panic!("invalid compression level: {}", level),
 		};
-		let _ = self.encoder.set_level(self.zlib_compression_level);
+		#[cfg(feature = "deflate")]
+		if is_window_supported(self.our_max_window_bits) {
+			let _ = lock(&self.encoder).set_level(self.zlib_compression_level);
+		}
+		// Without zlib the level of an encoder can not be changed. The encoder
+		// has not been used before the extension is configured, so we replace it.
+		#[cfg(not(feature = "deflate"))]
+		{
+			self.encoder = Arc::new(Mutex::new(new_compress(self.zlib_compression_level, self.our_max_window_bits)))
+		}
+	}
+
+	/// Create a decoder for their max. window bits.
+	#[cfg(feature = "deflate")]
+	fn new_decoder(&self) -> Decompress {
+		Decompress::new_with_window_bits(false, self.their_max_window_bits)
+	}
+
+	/// Create a decoder for their max. window bits.
+	///
+	/// The window size is fixed to 15 bits, which can decode any smaller window.
+	#[cfg(not(feature = "deflate"))]
+	fn new_decoder(&self) -> Decompress {
+		Decompress::new(false)
+	}
+
+	/// Whether the given message is to be compressed.
+	fn is_encoding(&self, header: &Header, data: &[u8]) -> bool {
+		if !data.is_empty() && data.len() < self.min_compress_size {
+			log::trace!("deflate: not encoding {}; below minimum size", header);
+			return false;
+		}
+		is_compressing("deflate", header, data)
+	}
+
+	/// How to compress a message.
+	fn settings(&self) -> Settings {
+		Settings {
+			level: self.zlib_compression_level,
+			window_bits: self.our_max_window_bits,
+			grow: self.grow_buffer_size,
+			reset: self.no_our_context_takeover,
+		}
+	}
+
+	/// The number of parts to compress a message of the given length in, and
+	/// the hook to run them, if it is to be compressed in parallel.
+	fn parts_for(&self, len: usize) -> Option<(usize, Offload)> {
+		let offload = self.offload.clone()?;
+		match self.parallel {
+			// The context can only be carried over with zlib.
+			Some((threshold, threads))
+				if threads > 1
+					&& len >= threshold
+					&& len >= threads
+					&& (self.no_our_context_takeover || cfg!(feature = "deflate")) =>
+			{
+				Some((threads, offload))
+			}
+			_ => None,
+		}
+	}
+
+	/// Reset our context after `input` has been compressed in parts without it.
+	#[cfg_attr(not(feature = "deflate"), allow(unused_variables))]
+	fn carry_over(&self, input: &[u8]) -> Result<(), BoxedError> {
+		let mut encoder = lock(&self.encoder);
+		encoder.reset();
+		// With context takeover, the next message may refer to the end of this one.
+		#[cfg(feature = "deflate")]
+		if !self.no_our_context_takeover {
+			let window = std::cmp::min(input.len(), 1 << self.our_max_window_bits);
+			encoder.set_dictionary(&input[input.len() - window..])?;
+		}
+		Ok(())
+	}
+
+	/// Compress a message in `threads` parts, each run by `offload`.
+	fn encode_parts<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Storage<'_>,
+		threads: usize,
+		offload: Offload,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		async move {
+			if !self.is_encoding(header, data.as_ref()) {
+				return Ok(());
+			}
+			let input = Arc::new(match &mut *data {
+				Storage::Owned(d) => mem::take(d),
+				d => d.as_ref().to_vec(),
+			});
+			let settings = self.settings();
+			// Every part ends with an empty stored block, so the parts can be concatenated.
+			let size = (input.len() + threads - 1) / threads;
+			let parts = (0..input.len()).step_by(size).map(|start| {
+				let input = input.clone();
+				let (tx, rx) = oneshot::channel();
+				let work = (offload.spawn)(Box::new(move || {
+					let mut output = Vec::new();
+					let mut encoder = new_compress(settings.level, settings.window_bits);
+					let end = std::cmp::min(start + size, input.len());
+					let result = compress(&mut encoder, &input[start..end], settings.grow, &mut output);
+					drop(input);
+					let _ = tx.send(result.map(|()| output));
+				}));
+				async move {
+					work.await;
+					rx.await.map_err(|_| BoxedError::from("offloaded compression did not run"))?
+				}
+			});
+			let parts = future::join_all(parts).await.into_iter().collect::<Result<Vec<_>, _>>();
+			let input = Arc::try_unwrap(input).unwrap_or_else(|input| input.to_vec());
+			let result = parts.and_then(|parts| {
+				self.carry_over(&input)?;
+				Ok(parts)
+			});
+			let parts = match result {
+				Ok(parts) => parts,
+				Err(e) => {
+					*data = Storage::Owned(input);
+					return Err(e);
+				}
+			};
+			let mut output = mem::take(&mut self.buffer);
+			output.clear();
+			for (i, part) in parts.iter().enumerate() {
+				output.extend_from_slice(part);
+				if i + 1 < parts.len() {
+					output.extend_from_slice(&TRAILER)
+				}
+			}
+			self.on_compressed(header, input.len(), output.len());
+			self.buffer = input;
+			*data = Storage::Owned(output);
+			self.on_memory();
+			Ok(())
+		}
+		.boxed()
+	}
+
+	/// The offload hook, if messages of the given length are to be offloaded.
+	fn offload_for(&self, len: usize) -> Option<Offload> {
+		self.offload.as_ref().filter(|o| len >= o.threshold).cloned()
+	}
+
+	fn on_compressed(&self, header: &mut Header, input: usize, output: usize) {
+		self.stats.add(&self.stats.0.compress_in, input);
+		self.stats.add(&self.stats.0.compress_out, output);
+		header.set_rsv1(true);
+		header.set_payload_len(output);
+	}
+
+	fn on_decompressed(&self, input: usize, output: usize) {
+		self.stats.add(&self.stats.0.decompress_in, input);
+		self.stats.add(&self.stats.0.decompress_out, output);
+	}
+
+	/// Update the memory estimate of our stats after contexts or buffer changed.
+	fn on_memory(&self) {
+		self.stats.0.memory.store(as_u64(self.memory_usage()), Ordering::Relaxed)
 	}
 
 	fn set_their_max_window_bits(&mut self, p: &Param, expected: Option<u8>) -> Result<(), ()> {
 		if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-			if v < 8 || v > 15 {
+			if !(8..=15).contains(&v) {
 				log::debug!("invalid {}: {} (expected range: 8 ..= 15)", p.name(), v);
 				return Err(());
 			}
@@ -180,7 +478,7 @@ impl Extension for Deflate {
 		self.enabled
 	}
 
-	fn params(&self) -> &[Param] {
+	fn params(&self) -> &[Param<'_>] {
 		&self.params
 	}
 
@@ -192,16 +490,14 @@ impl Extension for Deflate {
 					log::trace!("configure server with: {}", p);
 					match p.name() {
 						CLIENT_MAX_WINDOW_BITS => {
-							if self.set_their_max_window_bits(&p, None).is_err() {
+							if self.set_their_max_window_bits(p, None).is_err() {
 								// we just accept the client's offer as is => no need to reply
 								return Ok(());
 							}
 						}
 						SERVER_MAX_WINDOW_BITS => {
 							if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-								// The RFC allows 8 to 15 bits, but due to zlib limitations we
-								// only support 9 to 15.
-								if v < 9 || v > 15 {
+								if !(8..=15).contains(&v) {
 									log::debug!("unacceptable server_max_window_bits: {}", v);
 									return Ok(());
 								}
@@ -237,20 +533,17 @@ impl Extension for Deflate {
 						CLIENT_NO_CONTEXT_TAKEOVER => self.no_our_context_takeover = true,
 						SERVER_MAX_WINDOW_BITS => {
 							let expected = Some(self.their_max_window_bits);
-							if self.set_their_max_window_bits(&p, expected).is_err() {
+							if self.set_their_max_window_bits(p, expected).is_err() {
 								return Ok(());
 							}
 						}
 						CLIENT_MAX_WINDOW_BITS => {
 							if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-								if v < 8 || v > 15 {
+								if !(8..=15).contains(&v) {
 									log::debug!("unacceptable client_max_window_bits: {}", v);
 									return Ok(());
 								}
-								use std::cmp::{max, min};
-								// Due to zlib limitations we have to use 9 as a lower bound
-								// here, even if the server allowed us to go down to 8 bits.
-								self.our_max_window_bits = min(self.our_max_window_bits, max(9, v));
+								self.our_max_window_bits = std::cmp::min(self.our_max_window_bits, v);
 							}
 						}
 						_ => {
@@ -262,8 +555,10 @@ impl Extension for Deflate {
 			}
 		}
 		self.enabled = true;
-		self.encoder = Compress::new_with_window_bits(self.zlib_compression_level, false, self.our_max_window_bits);
-		self.decoder = Decompress::new_with_window_bits(false, self.their_max_window_bits);
+		*self.stats.0.negotiated.lock().unwrap_or_else(PoisonError::into_inner) = self.negotiated();
+		self.encoder = Arc::new(Mutex::new(new_compress(self.zlib_compression_level, self.our_max_window_bits)));
+		self.decoder = Arc::new(Mutex::new(self.new_decoder()));
+		self.on_memory();
 		Ok(())
 	}
 
@@ -272,121 +567,761 @@ impl Extension for Deflate {
 	}
 
 	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
-		if data.is_empty() {
+		let start = message_start(header, data);
+		if data.len() == start || !is_decompressing("deflate", header, &mut self.await_last_fragment) {
 			return Ok(());
 		}
 
-		match header.opcode() {
-			OpCode::Binary | OpCode::Text if header.is_rsv1() => {
-				if !header.is_fin() {
-					self.await_last_fragment = true;
-					log::trace!("deflate: not decoding {}; awaiting last fragment", header);
-					return Ok(());
-				}
-				log::trace!("deflate: decoding {}", header)
+		let mut decoder = lock(&self.decoder);
+		if self.no_their_context_takeover {
+			decoder.reset(false);
+		}
+		let len = data.len() - start;
+		let result = decompress_into(&mut decoder, data, start, self.grow_buffer_size, self.max_buffer_size);
+		drop(decoder);
+		let n = result?;
+		self.on_decompressed(len, n);
+		self.on_memory();
+
+		header.set_rsv1(false);
+		header.set_payload_len(n);
+
+		Ok(())
+	}
+
+	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+		if !self.is_encoding(header, data.as_ref()) {
+			return Ok(());
+		}
+
+		let settings = self.settings();
+		let mut encoder = lock(&self.encoder);
+		compress_message(&mut encoder, data.as_ref(), settings, &mut self.buffer)?;
+		drop(encoder);
+		self.on_compressed(header, data.as_ref().len(), self.buffer.len());
+
+		if let Storage::Owned(d) = data {
+			mem::swap(d, &mut self.buffer)
+		} else {
+			*data = Storage::Owned(mem::take(&mut self.buffer))
+		}
+		self.on_memory();
+		Ok(())
+	}
+
+	fn encode_async<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Storage<'_>,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		if let Some((threads, offload)) = self.parts_for(data.as_ref().len()) {
+			return self.encode_parts(header, data, threads, offload);
+		}
+		let Some(offload) = self.offload_for(data.as_ref().len()) else {
+			return futures::future::ready(self.encode(header, data)).boxed();
+		};
+		async move {
+			if !self.is_encoding(header, data.as_ref()) {
+				return Ok(());
 			}
-			OpCode::Continue if header.is_fin() && self.await_last_fragment => {
-				self.await_last_fragment = false;
-				log::trace!("deflate: decoding {}", header)
+			let input = match &mut *data {
+				Storage::Owned(d) => mem::take(d),
+				d => d.as_ref().to_vec(),
+			};
+			let mut output = mem::take(&mut self.buffer);
+			let (encoder, settings) = (self.encoder.clone(), self.settings());
+			let (tx, rx) = oneshot::channel();
+			(offload.spawn)(Box::new(move || {
+				let result = compress_message(&mut lock(&encoder), &input, settings, &mut output);
+				let _ = tx.send((result, input, output));
+			}))
+			.await;
+			let (result, input, output) = rx.await.map_err(|_| "offloaded compression did not run")?;
+			if let Err(e) = result {
+				*data = Storage::Owned(input);
+				self.buffer = output;
+				return Err(e);
 			}
-			_ => {
-				log::trace!("deflate: not decoding {}", header);
+			self.on_compressed(header, input.len(), output.len());
+			self.buffer = input;
+			*data = Storage::Owned(output);
+			self.on_memory();
+			Ok(())
+		}
+		.boxed()
+	}
+
+	fn decode_async<'a>(
+		&'a mut self,
+		header: &'a mut Header,
+		data: &'a mut Vec<u8>,
+	) -> BoxFuture<'a, Result<(), BoxedError>> {
+		let start = message_start(header, data);
+		let Some(offload) = self.offload_for(data.len() - start) else {
+			return futures::future::ready(self.decode(header, data)).boxed();
+		};
+		async move {
+			if data.len() == start || !is_decompressing("deflate", header, &mut self.await_last_fragment) {
 				return Ok(());
 			}
+			let len = data.len() - start;
+			let mut message = mem::take(data);
+			let (decoder, reset) = (self.decoder.clone(), self.no_their_context_takeover);
+			let (grow, max) = (self.grow_buffer_size, self.max_buffer_size);
+			let (tx, rx) = oneshot::channel();
+			(offload.spawn)(Box::new(move || {
+				let mut decoder = lock(&decoder);
+				if reset {
+					decoder.reset(false);
+				}
+				let result = decompress_into(&mut decoder, &mut message, start, grow, max);
+				let _ = tx.send((result, message));
+			}))
+			.await;
+			let (result, message) = rx.await.map_err(|_| "offloaded decompression did not run")?;
+			*data = message;
+			let n = result?;
+			self.on_decompressed(len, n);
+			self.on_memory();
+			header.set_rsv1(false);
+			header.set_payload_len(n);
+			Ok(())
 		}
+		.boxed()
+	}
 
-		if header.is_rsv1() {
-			// Restore LEN and NLEN:
-			data.extend_from_slice(&TRAILER); // cf. RFC 7692, 7.2.2
+	/// Decompress the message to `sink` in chunks of at most the grow buffer
+	/// size, so that large messages need not be held in memory. The max.
+	/// buffer size does not apply.
+	fn decode_to(
+		&mut self,
+		header: &mut Header,
+		data: &mut Vec<u8>,
+		sink: &mut dyn io::Write,
+	) -> Result<Option<usize>, BoxedError> {
+		let start = message_start(header, data);
+		if data.len() == start || !is_decompressing("deflate", header, &mut self.await_last_fragment) {
+			return Ok(None);
+		}
+		let mut decoder = lock(&self.decoder);
+		if self.no_their_context_takeover {
+			decoder.reset(false);
+		}
+		let len = data.len() - start;
+		let n = decompress(&mut decoder, data, start, self.grow_buffer_size, &mut |chunk| sink.write_all(chunk))?;
+		drop(decoder);
+		self.on_decompressed(len, n);
+		Ok(Some(n))
+	}
+}
 
-			if self.no_their_context_takeover {
-				self.decoder.reset(false);
-			}
+/// Whether the window bits are supported by the compression backend.
+fn is_window_supported(bits: u8) -> bool {
+	if cfg!(feature = "deflate") {
+		bits > 8
+	} else {
+		bits == 15
+	}
+}
 
-			self.buffer.clear();
+/// Estimate the memory held by an encoder for the given window bits.
+fn compress_memory(bits: u8) -> usize {
+	if cfg!(feature = "deflate") {
+		// window, prev and hash tables plus pending buffer at memLevel 8, see zconf.h
+		let bits = if is_window_supported(bits) { bits } else { 9 };
+		(1 << (bits + 2)) + (1 << (8 + 9))
+	} else {
+		RUST_COMPRESS_MEMORY
+	}
+}
 
-			loop {
-				if self.buffer.len() >= self.max_buffer_size {
-					return Err(io::Error::new(io::ErrorKind::Other, "decompressed message too large").into());
-				}
+/// Estimate the memory held by a decoder for the given window bits.
+fn decompress_memory(bits: u8) -> usize {
+	if cfg!(feature = "deflate") {
+		// window plus inflate state and code tables, see zconf.h
+		(1 << bits) + 7 * 1024
+	} else {
+		RUST_DECOMPRESS_MEMORY
+	}
+}
 
-				self.buffer.reserve(self.grow_buffer_size);
-				let status = self.decoder.decompress_vec(&data, &mut self.buffer, FlushDecompress::Sync)?;
+/// Create an encoder for the given window bits.
+#[cfg(feature = "deflate")]
+fn new_compress(level: Compression, bits: u8) -> Compress {
+	if is_window_supported(bits) {
+		Compress::new_with_window_bits(level, false, bits)
+	} else {
+		// zlib would silently use 9 bits, so fall back to uncompressed blocks
+		// which do not refer to any previous data.
+		Compress::new_with_window_bits(Compression::none(), false, 9)
+	}
+}
 
-				match status {
-					Status::Ok => break,
-					Status::BufError => continue,
-					Status::StreamEnd => break,
-				}
-			}
+/// Create an encoder for the given window bits.
+#[cfg(not(feature = "deflate"))]
+fn new_compress(level: Compression, bits: u8) -> Compress {
+	if is_window_supported(bits) {
+		Compress::new(level, false)
+	} else {
+		// The window size is fixed to 15 bits, so fall back to uncompressed
+		// blocks which do not refer to any previous data.
+		Compress::new(Compression::none(), false)
+	}
+}
+
+/// Lock a compressor or decompressor, which can not be left in an inconsistent state.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+	m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What is needed to compress a message, also on other threads.
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+	level: Compression,
+	window_bits: u8,
+	grow: usize,
+	/// Reset the context before compressing?
+	reset: bool,
+}
+
+/// Compress a complete message into `output`.
+fn compress_message(
+	encoder: &mut Compress,
+	input: &[u8],
+	settings: Settings,
+	output: &mut Vec<u8>,
+) -> Result<(), BoxedError> {
+	if settings.reset {
+		encoder.reset();
+	}
+	compress(encoder, input, settings.grow, output)
+}
+
+/// Compress a complete message into `output`, without the empty block at the end.
+fn compress(encoder: &mut Compress, input: &[u8], grow: usize, output: &mut Vec<u8>) -> Result<(), BoxedError> {
+	output.clear();
+	output.reserve(input.len());
+
+	let start_total_in = encoder.total_in();
+	let mut total_in = 0;
+
+	// Compress all input bytes.
+	while total_in < as_u64(input.len()) {
+		total_in = encoder.total_in() - start_total_in;
+		let i: usize = total_in.try_into()?;
 
-			mem::swap(data, &mut self.buffer);
+		match encoder.compress_vec(&input[i..], output, FlushCompress::None)? {
+			Status::BufError => output.reserve(grow),
+			Status::Ok => continue,
+			Status::StreamEnd => break,
 		}
+	}
 
-		header.set_rsv1(false);
-		header.set_payload_len(data.len());
+	// We need to append an empty deflate block if not there yet (RFC 7692, 7.2.1).
+	while !output.ends_with(&TRAILER) {
+		output.reserve(5); // Make sure there is room for the trailing end bytes.
+		match encoder.compress_vec(&[], output, FlushCompress::Sync)? {
+			Status::Ok => continue,
+			Status::BufError => continue, // more capacity is reserved above
+			Status::StreamEnd => break,
+		}
+	}
 
-		Ok(())
+	// If we still have not seen the empty deflate block appended, something is wrong.
+	if !output.ends_with(&TRAILER) {
+		return Err(io::Error::new(io::ErrorKind::Other, "missing 00 00 FF FF").into());
 	}
 
-	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
-		if data.as_ref().is_empty() {
-			return Ok(());
+	output.truncate(output.len() - 4); // Remove 00 00 FF FF; cf. RFC 7692, 7.2.1
+	Ok(())
+}
+
+/// The input of the message `data` at `offset`, followed by the LEN and NLEN
+/// bytes removed by the sender; cf. RFC 7692, 7.2.2.
+fn input_at(data: &[u8], offset: usize) -> &[u8] {
+	if offset < data.len() {
+		&data[offset..]
+	} else {
+		&TRAILER[offset - data.len()..]
+	}
+}
+
+/// Decompress the complete message starting at `start` in `data` in place.
+///
+/// The output is written behind the message, into spare capacity which grows
+/// by at least `grow` bytes once full, and moved to `start` when complete, so
+/// the message is neither copied nor is another buffer needed. Fails if the
+/// output exceeds `max` bytes, leaving `data` as is, and returns the
+/// decompressed length otherwise.
+fn decompress_into(
+	decoder: &mut Decompress,
+	data: &mut Vec<u8>,
+	start: usize,
+	grow: usize,
+	max: usize,
+) -> Result<usize, BoxedError> {
+	let len = data.len();
+	let end = len - start + TRAILER.len();
+	let mut offset = 0;
+	let mut written = 0;
+	let result = loop {
+		if len + written == data.len() {
+			if data.len() == data.capacity() {
+				data.reserve(grow)
+			}
+			let spare = std::cmp::min(data.capacity() - data.len(), grow);
+			data.resize(data.len() + spare, 0)
 		}
+		let (message, output) = data.split_at_mut(len);
+		let output = &mut output[written..];
+		let (total_in, total_out) = (decoder.total_in(), decoder.total_out());
+		let status = match decoder.decompress(input_at(&message[start..], offset), output, FlushDecompress::Sync) {
+			Ok(status) => status,
+			Err(e) => break Err(e.into()),
+		};
+		let consumed = usize::try_from(decoder.total_in() - total_in).expect("at most the input length; qed");
+		let produced = usize::try_from(decoder.total_out() - total_out).expect("at most the output length; qed");
+		let full = produced == output.len();
+		offset += consumed;
+		written += produced;
+		if written > max {
+			break Err(io::Error::new(io::ErrorKind::Other, "decompressed message too large").into());
+		}
+		// Output remains pending as long as the spare capacity was filled.
+		let done = offset == end && !full;
+		let stuck = consumed == 0 && produced == 0;
+		if done || stuck || status == Status::StreamEnd {
+			break Ok(written);
+		}
+	};
+	match result {
+		Ok(n) => {
+			data.copy_within(len..len + n, start);
+			data.truncate(start + n)
+		}
+		Err(_) => {
+			data.truncate(len);
+			// The decoder state is unusable after an error.
+			decoder.reset(false)
+		}
+	}
+	result
+}
 
-		if let OpCode::Binary | OpCode::Text = header.opcode() {
-			log::trace!("deflate: encoding {}", header)
-		} else {
-			log::trace!("deflate: not encoding {}", header);
-			return Ok(());
+/// Decompress the complete message starting at `start` in `data` and pass the
+/// output to `out` in chunks of at most `grow` bytes. Returns the decompressed length.
+fn decompress(
+	decoder: &mut Decompress,
+	data: &[u8],
+	start: usize,
+	grow: usize,
+	out: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+) -> Result<usize, BoxedError> {
+	let message = &data[start..];
+	let end = message.len() + TRAILER.len();
+	let mut chunk = Vec::with_capacity(grow);
+	let mut offset = 0;
+	let mut total = 0;
+	let result = loop {
+		chunk.clear();
+		let total_in = decoder.total_in();
+		let status = match decoder.decompress_vec(input_at(message, offset), &mut chunk, FlushDecompress::Sync) {
+			Ok(status) => status,
+			Err(e) => break Err(e.into()),
+		};
+		let consumed = usize::try_from(decoder.total_in() - total_in).expect("at most the input length; qed");
+		offset += consumed;
+		if let Err(e) = out(&chunk) {
+			break Err(e.into());
+		}
+		total += chunk.len();
+		// Output remains pending as long as the chunk was filled.
+		let done = offset == end && chunk.len() < chunk.capacity();
+		let stuck = consumed == 0 && chunk.is_empty();
+		if done || stuck || status == Status::StreamEnd {
+			break Ok(total);
+		}
+	};
+	if result.is_err() {
+		// The decoder state is unusable after an error.
+		decoder.reset(false)
+	}
+	result
+}
+
+/// The parameters of a [`Deflate`] extension agreed with the peer during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+	/// The max. window bits the server compresses with.
+	pub server_max_window_bits: u8,
+	/// The max. window bits the client compresses with.
+	pub client_max_window_bits: u8,
+	/// Whether the server resets its compression context after each message.
+	pub server_no_context_takeover: bool,
+	/// Whether the client resets its compression context after each message.
+	pub client_no_context_takeover: bool,
+}
+
+/// Compression statistics of a [`Deflate`] extension.
+///
+/// Unless noted otherwise, values are totals in bytes over the lifetime of the
+/// extension and only include messages which have been compressed.
+#[derive(Debug, Clone, Default)]
+pub struct Stats(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+	compress_in: AtomicU64,
+	compress_out: AtomicU64,
+	decompress_in: AtomicU64,
+	decompress_out: AtomicU64,
+	memory: AtomicU64,
+	negotiated: Mutex<Option<Negotiated>>,
+}
+
+impl Stats {
+	/// The number of bytes given to the compressor.
+	pub fn compress_input(&self) -> u64 {
+		self.0.compress_in.load(Ordering::Relaxed)
+	}
+
+	/// The number of bytes produced by the compressor.
+	pub fn compress_output(&self) -> u64 {
+		self.0.compress_out.load(Ordering::Relaxed)
+	}
+
+	/// The number of bytes given to the decompressor.
+	pub fn decompress_input(&self) -> u64 {
+		self.0.decompress_in.load(Ordering::Relaxed)
+	}
+
+	/// The number of bytes produced by the decompressor.
+	pub fn decompress_output(&self) -> u64 {
+		self.0.decompress_out.load(Ordering::Relaxed)
+	}
+
+	/// An estimate of the memory in bytes held by the extension.
+	///
+	/// This is updated whenever a message has been processed, see
+	/// [`Deflate::memory_usage`]. Unlike the totals above, it is not
+	/// cumulative and may decrease.
+	pub fn memory_usage(&self) -> u64 {
+		self.0.memory.load(Ordering::Relaxed)
+	}
+
+	/// The parameters agreed with the peer, once the extension has been enabled.
+	///
+	/// Unlike [`Deflate::negotiated`], this is available after the extension
+	/// has been added to a connection.
+	pub fn negotiated(&self) -> Option<Negotiated> {
+		*self.0.negotiated.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	fn add(&self, counter: &AtomicU64, n: usize) {
+		counter.fetch_add(as_u64(n), Ordering::Relaxed);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Deflate, Negotiated};
+	use crate::{
+		base::{Header, OpCode},
+		connection::Mode,
+		extension::{Extension, Param},
+		Storage,
+	};
+	use futures::future::BoxFuture;
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	};
+
+	/// Configure both ends with the parameters offered by the other one.
+	fn negotiate(client: &mut Deflate, server: &mut Deflate) {
+		server.configure(client.params()).unwrap();
+		client.configure(server.params()).unwrap();
+	}
+
+	/// A client and a server extension which agreed on the default parameters.
+	fn negotiated_pair() -> (Deflate, Deflate) {
+		let (mut client, mut server) = (Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		negotiate(&mut client, &mut server);
+		(client, server)
+	}
+
+	/// An offload hook running work on the blocking pool, counting each call in `spawned`.
+	fn counting_offload(
+		spawned: &Arc<AtomicUsize>,
+	) -> impl Fn(Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> + Send + Sync + 'static {
+		let spawned = spawned.clone();
+		move |work| {
+			spawned.fetch_add(1, Ordering::SeqCst);
+			Box::pin(async move { tokio::task::spawn_blocking(work).await.unwrap() })
 		}
+	}
 
-		self.buffer.clear();
-		self.buffer.reserve(data.as_ref().len());
+	fn round_trip(encoder: &mut dyn Extension, decoder: &mut dyn Extension) {
+		let message = b"hello hello hello hello hello hello hello hello".repeat(32);
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Owned(message.clone());
+		encoder.encode(&mut header, &mut data).unwrap();
+		assert!(header.is_rsv1());
+		let mut data = data.as_ref().to_vec();
+		decoder.decode(&mut header, &mut data).unwrap();
+		assert_eq!(message, data);
+	}
+
+	#[test]
+	fn stats() {
+		let (client, mut server) = negotiated_pair();
+		let (client_stats, server_stats) = (client.stats(), server.stats());
+		let mut client: Box<dyn Extension> = Box::new(client);
+		round_trip(&mut server, &mut client);
+
+		let message_len = 47 * 32;
+		assert_eq!(message_len, server_stats.compress_input());
+		assert!(server_stats.compress_output() < message_len);
+		assert_eq!(server_stats.compress_output(), client_stats.decompress_input());
+		assert_eq!(message_len, client_stats.decompress_output());
+		assert_eq!(0, client_stats.compress_input());
+	}
+
+	#[test]
+	fn negotiated() {
+		let mut client = Deflate::new(Mode::Client);
+		client.set_max_server_window_bits(10);
+		let stats = client.stats();
+		assert_eq!(None, client.negotiated());
+
+		let mut server = Deflate::new(Mode::Server);
+		negotiate(&mut client, &mut server);
+		let expected = Negotiated {
+			server_max_window_bits: 10,
+			client_max_window_bits: 15,
+			server_no_context_takeover: true,
+			client_no_context_takeover: true,
+		};
+		assert_eq!(Some(expected), server.negotiated());
+		assert_eq!(Some(expected), client.negotiated());
+		assert_eq!(Some(expected), stats.negotiated());
+	}
+
+	#[test]
+	fn memory_usage() {
+		let mut client = Deflate::new(Mode::Client);
+		let mut server = Deflate::new(Mode::Server);
+		let stats = server.stats();
+		assert_eq!(stats.memory_usage(), super::as_u64(server.memory_usage()));
 
-		if self.no_our_context_takeover {
-			self.encoder.reset();
+		negotiate(&mut client, &mut server);
+		let before = server.memory_usage();
+		round_trip(&mut server, &mut client);
+		assert!(server.memory_usage() > before);
+		assert_eq!(stats.memory_usage(), super::as_u64(server.memory_usage()));
+
+		if cfg!(feature = "deflate") {
+			let mut small = Deflate::new(Mode::Client);
+			small.set_max_client_window_bits(9);
+			assert!(small.memory_usage() < Deflate::new(Mode::Client).memory_usage());
 		}
+	}
 
-		let start_total_in = self.encoder.total_in();
-		let mut total_in = 0;
+	#[test]
+	fn min_compress_size() {
+		let mut deflate = Deflate::new(Mode::Server);
+		deflate.configure(&[]).unwrap();
+		deflate.set_min_compress_size(16);
 
-		// Compress all input bytes.
-		while total_in < as_u64(data.as_ref().len()) {
-			total_in = self.encoder.total_in() - start_total_in;
-			let i: usize = total_in.try_into()?;
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(b"small");
+		deflate.encode(&mut header, &mut data).unwrap();
+		assert!(!header.is_rsv1());
+		assert_eq!(b"small", data.as_ref());
 
-			match self.encoder.compress_vec(&data.as_ref()[i..], &mut self.buffer, FlushCompress::None)? {
-				Status::BufError => self.buffer.reserve(self.grow_buffer_size),
-				Status::Ok => continue,
-				Status::StreamEnd => break,
-			}
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(b"large enough to be compressed");
+		deflate.encode(&mut header, &mut data).unwrap();
+		assert!(header.is_rsv1());
+
+		// Unless compression is disabled for the message.
+		let mut header = Header::new(OpCode::Text);
+		header.set_compressible(false);
+		let mut data = Storage::Shared(b"large enough to be compressed");
+		deflate.encode(&mut header, &mut data).unwrap();
+		assert!(!header.is_rsv1());
+		assert_eq!(b"large enough to be compressed", data.as_ref());
+	}
+
+	#[test]
+	fn eight_bit_window() {
+		let mut client = Deflate::new(Mode::Client);
+		client.set_max_server_window_bits(8);
+		client.set_max_client_window_bits(8);
+
+		let mut server = Deflate::new(Mode::Server);
+		server.configure(client.params()).unwrap();
+		assert!(server.is_enabled());
+		assert_eq!(8, server.our_max_window_bits);
+		client.configure(server.params()).unwrap();
+		assert!(client.is_enabled());
+
+		round_trip(&mut client, &mut server);
+		round_trip(&mut server, &mut client);
+
+		let mut server = Deflate::new(Mode::Server);
+		let mut param = Param::new("server_max_window_bits");
+		param.set_value(Some("7"));
+		server.configure(&[param]).unwrap();
+		assert!(!server.is_enabled());
+	}
+
+	#[test]
+	fn decode_to() {
+		let (mut client, mut server) = negotiated_pair();
+		client.set_grow_buffer_size(64);
+		client.set_max_buffer_size(64);
+
+		let message = b"hello hello hello hello hello hello hello hello".repeat(32);
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		server.encode(&mut header, &mut data).unwrap();
+		let compressed = data.as_ref().to_vec();
+
+		// Split into two fragments, of which only the first has RSV1 set.
+		let mut first = header.clone();
+		first.set_fin(false);
+		let mut last = Header::new(OpCode::Continue);
+		last.set_payload_len(compressed.len());
+		let mut data = compressed.clone();
+		let mut sink = Vec::new();
+		assert_eq!(None, client.decode_to(&mut first, &mut data, &mut sink).unwrap());
+		assert_eq!(Some(message.len()), client.decode_to(&mut last, &mut data, &mut sink).unwrap());
+		assert_eq!(compressed, data);
+		assert_eq!(compressed.len(), last.payload_len());
+		assert_eq!(message, sink);
+
+		// The max. buffer size only applies to `decode`.
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		server.encode(&mut header, &mut data).unwrap();
+		let error = client.decode(&mut header, &mut data.as_ref().to_vec()).unwrap_err();
+		assert_eq!("decompressed message too large", error.to_string());
+	}
+
+	#[test]
+	fn decode_after_prefix() {
+		let (mut client, mut server) = negotiated_pair();
+
+		let message = b"hello hello hello hello".repeat(8);
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(&message);
+		server.encode(&mut header, &mut data).unwrap();
+
+		let mut buffer = Vec::with_capacity(1024);
+		buffer.extend_from_slice(b"prefix");
+		buffer.extend_from_slice(data.as_ref());
+		let ptr = buffer.as_ptr();
+		client.decode(&mut header, &mut buffer).unwrap();
+		assert_eq!(message.len(), header.payload_len());
+		assert_eq!(b"prefix", &buffer[..6]);
+		assert_eq!(message, buffer[6..]);
+		assert_eq!(ptr, buffer.as_ptr());
+		assert_eq!(0, client.buffer.capacity());
+
+		// The buffer is left as is if decompression fails.
+		let mut buffer = b"prefix\x01\x02\x03".to_vec();
+		let mut header = Header::new(OpCode::Text);
+		header.set_rsv1(true).set_payload_len(3);
+		assert!(client.decode(&mut header, &mut buffer).is_err());
+		assert_eq!(b"prefix\x01\x02\x03", &buffer[..]);
+	}
+
+	#[test]
+	fn decode_in_place() {
+		let (_, mut server) = negotiated_pair();
+		let message = (0..4096).map(|i| (i % 61) as u8).collect::<Vec<_>>();
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		server.encode(&mut header, &mut data).unwrap();
+		let compressed = data.as_ref().to_vec();
+
+		// The output grows behind the message, so all of it is read from the
+		// given buffer and no other buffer is allocated.
+		for grow in [1, 7, 4096] {
+			let (mut client, _) = negotiated_pair();
+			client.set_grow_buffer_size(grow);
+			let mut header = header.clone();
+			let mut data = compressed.clone();
+			client.decode(&mut header, &mut data).unwrap();
+			assert_eq!(message, data);
+			assert_eq!(message.len(), header.payload_len());
+			assert_eq!(0, client.buffer.capacity());
 		}
+	}
+
+	#[tokio::test]
+	async fn parallel_compression() {
+		let message = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let spawned = Arc::new(AtomicUsize::new(0));
 
-		// We need to append an empty deflate block if not there yet (RFC 7692, 7.2.1).
-		while !self.buffer.ends_with(&TRAILER) {
-			self.buffer.reserve(5); // Make sure there is room for the trailing end bytes.
-			match self.encoder.compress_vec(&[], &mut self.buffer, FlushCompress::Sync)? {
-				Status::Ok => continue,
-				Status::BufError => continue, // more capacity is reserved above
-				Status::StreamEnd => break,
+		// Without and with context takeover.
+		for params in [Deflate::new(Mode::Client).params().to_vec(), Vec::new()] {
+			let mut server = Deflate::new(Mode::Server);
+			server.configure(&params).unwrap();
+			server.set_parallel_compression(1024, 4);
+			let mut client = Deflate::new(Mode::Client);
+			client.configure(server.params()).unwrap();
+
+			// Without a hook to run the parts, messages are compressed as usual.
+			let mut header = Header::new(OpCode::Binary);
+			let mut data = Storage::Shared(&message);
+			server.encode_async(&mut header, &mut data).await.unwrap();
+			let mut data = data.as_ref().to_vec();
+			client.decode(&mut header, &mut data).unwrap();
+			assert_eq!(message, data);
+
+			server.set_offload(usize::MAX, counting_offload(&spawned));
+			for _ in 0..3 {
+				let mut header = Header::new(OpCode::Binary);
+				let mut data = Storage::Shared(&message);
+				server.encode_async(&mut header, &mut data).await.unwrap();
+				assert!(header.is_rsv1());
+				let mut data = data.as_ref().to_vec();
+				client.decode(&mut header, &mut data).unwrap();
+				assert_eq!(message, data);
 			}
-		}
 
-		// If we still have not seen the empty deflate block appended, something is wrong.
-		if !self.buffer.ends_with(&TRAILER) {
-			return Err(io::Error::new(io::ErrorKind::Other, "missing 00 00 FF FF").into());
+			// Synchronous encoding does not run any parts.
+			let mut header = Header::new(OpCode::Binary);
+			let mut data = Storage::Shared(&message);
+			server.encode(&mut header, &mut data).unwrap();
+			let mut data = data.as_ref().to_vec();
+			client.decode(&mut header, &mut data).unwrap();
+			assert_eq!(message, data);
 		}
+		// With context takeover, only zlib compresses in parts.
+		let messages = if cfg!(feature = "deflate") { 2 * 3 } else { 3 };
+		assert_eq!(messages * 4, spawned.load(Ordering::SeqCst));
+	}
 
-		self.buffer.truncate(self.buffer.len() - 4); // Remove 00 00 FF FF; cf. RFC 7692, 7.2.1
+	#[tokio::test]
+	async fn offload() {
+		let spawned = Arc::new(AtomicUsize::new(0));
+		let (mut client, mut server) = negotiated_pair();
+		server.set_offload(1024, counting_offload(&spawned));
+		client.set_offload(1024, counting_offload(&spawned));
 
-		if let Storage::Owned(d) = data {
-			mem::swap(d, &mut self.buffer)
-		} else {
-			*data = Storage::Owned(mem::take(&mut self.buffer))
+		for message in [b"small".to_vec(), b"hello ".repeat(1024)] {
+			let mut header = Header::new(OpCode::Text);
+			let mut data = Storage::Shared(&message);
+			server.encode_async(&mut header, &mut data).await.unwrap();
+			assert!(header.is_rsv1());
+			let mut data = data.as_ref().to_vec();
+			client.decode_async(&mut header, &mut data).await.unwrap();
+			assert_eq!(message, data);
 		}
-		header.set_rsv1(true);
-		header.set_payload_len(data.as_ref().len());
-		Ok(())
+		// Only the large message is compressed on another thread, the compressed one is small.
+		assert_eq!(1, spawned.load(Ordering::SeqCst));
 	}
 }
@@ -0,0 +1,152 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Snappy compression extension.
+//!
+//! Offered and accepted as a plain `permessage-snappy`, without any parameters,
+//! which only peers knowing this extension will agree to. Each message is
+//! compressed independently using the raw [snappy format][format], trading
+//! compression ratio for very low CPU overhead.
+//!
+//! [format]: https://github.com/google/snappy/blob/main/format_description.txt
+
+use crate::{
+	base::Header,
+	connection::Mode,
+	extension::{is_compressing, is_decompressing, message_start, replace_message, Extension, Param},
+	BoxedError, Storage,
+};
+use snap::raw::{decompress_len, Decoder, Encoder};
+use std::io;
+
+const DEFAULT_DECOMPRESS_SIZE: usize = 256 * 1024 * 1024;
+
+/// The snappy extension type.
+#[derive(Debug)]
+pub struct Snappy {
+	mode: Mode,
+	enabled: bool,
+	await_last_fragment: bool,
+	max_buffer_size: usize,
+	encoder: Encoder,
+	decoder: Decoder,
+}
+
+impl Snappy {
+	/// Create a new snappy extension either on client or server side.
+	pub fn new(mode: Mode) -> Self {
+		Snappy {
+			mode,
+			enabled: false,
+			await_last_fragment: false,
+			max_buffer_size: DEFAULT_DECOMPRESS_SIZE,
+			encoder: Encoder::new(),
+			decoder: Decoder::new(),
+		}
+	}
+
+	/// Set the maximum size of a decompressed message.
+	///
+	/// Messages that decompress to a size larger than this will fail to decode.
+	pub fn set_max_buffer_size(&mut self, size: usize) {
+		self.max_buffer_size = size;
+	}
+}
+
+impl Extension for Snappy {
+	fn name(&self) -> &str {
+		"permessage-snappy"
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn params(&self) -> &[Param<'_>] {
+		&[]
+	}
+
+	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		if let Some(p) = params.first() {
+			log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+			return Ok(());
+		}
+		log::trace!("configure {:?}", self.mode);
+		self.enabled = true;
+		Ok(())
+	}
+
+	fn reserved_bits(&self) -> (bool, bool, bool) {
+		(true, false, false)
+	}
+
+	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+		if !is_decompressing("snappy", header, &mut self.await_last_fragment) {
+			return Ok(());
+		}
+
+		let start = message_start(header, data);
+		if decompress_len(&data[start..])? > self.max_buffer_size {
+			return Err(io::Error::new(io::ErrorKind::Other, "decompressed message too large").into());
+		}
+
+		let mut output = self.decoder.decompress_vec(&data[start..])?;
+		replace_message(data, start, &mut output);
+		header.set_rsv1(false);
+		header.set_payload_len(data.len() - start);
+		Ok(())
+	}
+
+	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+		if !is_compressing("snappy", header, data.as_ref()) {
+			return Ok(());
+		}
+
+		*data = Storage::Owned(self.encoder.compress_vec(data.as_ref())?);
+		header.set_rsv1(true);
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Snappy;
+	use crate::{
+		base::{Header, OpCode},
+		connection::Mode,
+		extension::Extension,
+		Storage,
+	};
+
+	#[test]
+	fn round_trip() {
+		let mut client = Snappy::new(Mode::Client);
+		let mut server = Snappy::new(Mode::Server);
+		server.configure(client.params()).unwrap();
+		client.configure(server.params()).unwrap();
+		assert!(client.is_enabled() && server.is_enabled());
+
+		let message = b"snappy snappy snappy snappy snappy snappy".repeat(16);
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		client.encode(&mut header, &mut data).unwrap();
+		assert!(header.is_rsv1());
+		assert!(data.as_ref().len() < message.len());
+
+		let mut data = data.as_ref().to_vec();
+		server.decode(&mut header, &mut data).unwrap();
+		assert_eq!(message, data);
+
+		server.set_max_buffer_size(message.len() - 1);
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		client.encode(&mut header, &mut data).unwrap();
+		assert!(server.decode(&mut header, &mut data.as_ref().to_vec()).is_err());
+	}
+}
@@ -0,0 +1,209 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Zstandard compression extension.
+//!
+//! `permessage-zstd` is a custom extension name, so both peers need to use this
+//! crate or an implementation following it. Negotiation works like in
+//! [RFC 7692][rfc7692]. Each message is compressed into a single zstd frame, i.e.
+//! there is no context takeover. Both sides announce the largest window they are
+//! willing to decode with the `max_window_log` parameter, which limits the window
+//! of the other side's encoder. Without the parameter, a window log of at most 23
+//! (8 MiB) is used.
+//!
+//! [rfc7692]: https://tools.ietf.org/html/rfc7692
+
+use crate::{
+	base::Header,
+	connection::Mode,
+	extension::{is_compressing, is_decompressing, message_start, replace_message, Extension, Param},
+	BoxedError, Storage,
+};
+use ::zstd::{bulk::Compressor, stream::read::Decoder, zstd_safe::CParameter};
+use std::{
+	cmp::min,
+	io::{self, Read},
+};
+
+const MAX_WINDOW_LOG: &str = "max_window_log";
+
+const DEFAULT_WINDOW_LOG: u32 = 23;
+const DEFAULT_LEVEL: i32 = 3;
+const DEFAULT_DECOMPRESS_SIZE: usize = 256 * 1024 * 1024;
+
+/// The zstd extension type.
+#[derive(Debug)]
+pub struct Zstd {
+	mode: Mode,
+	enabled: bool,
+	buffer: Vec<u8>,
+	params: Vec<Param<'static>>,
+	level: i32,
+	our_max_window_log: u32,
+	their_max_window_log: u32,
+	await_last_fragment: bool,
+	max_buffer_size: usize,
+}
+
+impl Zstd {
+	/// Create a new zstd extension either on client or server side.
+	pub fn new(mode: Mode) -> Self {
+		let mut zstd = Zstd {
+			mode,
+			enabled: false,
+			buffer: Vec::new(),
+			params: Vec::new(),
+			level: DEFAULT_LEVEL,
+			our_max_window_log: DEFAULT_WINDOW_LOG,
+			their_max_window_log: DEFAULT_WINDOW_LOG,
+			await_last_fragment: false,
+			max_buffer_size: DEFAULT_DECOMPRESS_SIZE,
+		};
+		zstd.set_max_window_log(DEFAULT_WINDOW_LOG);
+		zstd
+	}
+
+	/// Set the largest window we are willing to decode, as log2 of its size.
+	///
+	/// The value must be within 10 ..= 31. The default is 23 (8 MiB).
+	pub fn set_max_window_log(&mut self, max: u32) {
+		assert!((10..=31).contains(&max), "max. window log has to be within 10 ..= 31");
+		self.our_max_window_log = max;
+		let mut p = Param::new(MAX_WINDOW_LOG);
+		p.set_value(Some(max.to_string()));
+		self.params = vec![p]
+	}
+
+	/// Set the compression level to use. The range is from 1 to 22.
+	///
+	/// The default is 3.
+	pub fn set_compression_level(&mut self, level: i32) {
+		assert!((1..=22).contains(&level), "invalid compression level: {}", level);
+		self.level = level
+	}
+
+	/// Set the maximum size of the internal buffer used for decompression.
+	///
+	/// Messages that decompress to a size larger than this will fail to decode.
+	pub fn set_max_buffer_size(&mut self, size: usize) {
+		self.max_buffer_size = size;
+	}
+}
+
+impl Extension for Zstd {
+	fn name(&self) -> &str {
+		"permessage-zstd"
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn params(&self) -> &[Param<'_>] {
+		&self.params
+	}
+
+	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		for p in params {
+			log::trace!("configure {:?} with: {}", self.mode, p);
+			match p.name() {
+				MAX_WINDOW_LOG => match p.value().map(|s| s.parse::<u32>()) {
+					Some(Ok(v)) if (10..=31).contains(&v) => self.their_max_window_log = min(v, DEFAULT_WINDOW_LOG),
+					_ => {
+						log::debug!("invalid {}: {:?}", MAX_WINDOW_LOG, p.value());
+						return Ok(());
+					}
+				},
+				_ => {
+					log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+					return Ok(());
+				}
+			}
+		}
+		self.enabled = true;
+		Ok(())
+	}
+
+	fn reserved_bits(&self) -> (bool, bool, bool) {
+		(true, false, false)
+	}
+
+	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+		if !is_decompressing("zstd", header, &mut self.await_last_fragment) {
+			return Ok(());
+		}
+
+		let start = message_start(header, data);
+		self.buffer.clear();
+		let mut decoder = Decoder::with_buffer(&data[start..])?;
+		decoder.window_log_max(self.our_max_window_log)?;
+		let limit = u64::try_from(self.max_buffer_size).unwrap_or(u64::MAX).saturating_add(1);
+		decoder.take(limit).read_to_end(&mut self.buffer)?;
+		if self.buffer.len() > self.max_buffer_size {
+			return Err(io::Error::new(io::ErrorKind::Other, "decompressed message too large").into());
+		}
+
+		replace_message(data, start, &mut self.buffer);
+		header.set_rsv1(false);
+		header.set_payload_len(data.len() - start);
+		Ok(())
+	}
+
+	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+		if !is_compressing("zstd", header, data.as_ref()) {
+			return Ok(());
+		}
+
+		let mut encoder = Compressor::new(self.level)?;
+		encoder.set_parameter(CParameter::WindowLog(self.their_max_window_log))?;
+		*data = Storage::Owned(encoder.compress(data.as_ref())?);
+		header.set_rsv1(true);
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Zstd;
+	use crate::{
+		base::{Header, OpCode},
+		connection::Mode,
+		extension::Extension,
+		Storage,
+	};
+
+	#[test]
+	fn round_trip() {
+		let mut client = Zstd::new(Mode::Client);
+		client.set_max_window_log(16);
+		let mut server = Zstd::new(Mode::Server);
+		server.configure(client.params()).unwrap();
+		assert_eq!(16, server.their_max_window_log);
+		client.configure(server.params()).unwrap();
+		assert!(client.is_enabled() && server.is_enabled());
+
+		let message = br#"{"jsonrpc":"2.0","method":"subscribe","params":[]}"#.repeat(64);
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(&message);
+		server.encode(&mut header, &mut data).unwrap();
+		assert!(header.is_rsv1());
+		assert!(data.as_ref().len() < message.len());
+
+		let mut data = data.as_ref().to_vec();
+		client.decode(&mut header, &mut data).unwrap();
+		assert!(!header.is_rsv1());
+		assert_eq!(message, data);
+
+		client.set_max_buffer_size(message.len() - 1);
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(&message);
+		server.encode(&mut header, &mut data).unwrap();
+		assert!(client.decode(&mut header, &mut data.as_ref().to_vec()).is_err());
+	}
+}
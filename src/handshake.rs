@@ -10,16 +10,21 @@
 //!
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
+pub mod access_control;
 pub mod client;
 #[cfg(feature = "http")]
 pub mod http;
+pub mod rate_limit;
 pub mod server;
 
-use crate::extension::{Extension, Param};
+use crate::connection::Mode;
+use crate::extension::{self, Extension, Param};
 use base64::Engine;
 use bytes::BytesMut;
+use futures::future::{self, Either, Future};
+use futures_timer::Delay;
 use sha1::{Digest, Sha1};
-use std::{fmt, io, str};
+use std::{cmp, fmt, io, str, time::Duration};
 
 pub use client::{Client, ServerResponse};
 pub use server::{ClientRequest, Server};
@@ -28,7 +33,7 @@ pub use server::{ClientRequest, Server};
 // in the server handshake response.
 const KEY: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-// How many HTTP headers do we support during parsing?
+// How many HTTP headers do we support during parsing by default?
 const MAX_NUM_HEADERS: usize = 32;
 
 // Some HTTP headers we need to check during parsing.
@@ -46,9 +51,9 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
 	headers
 		.iter()
 		.filter(|h| h.name.eq_ignore_ascii_case(name))
-		.fold(Ok(State::Init), |result, header| {
-			if let Ok(State::Match) = result {
-				return result;
+		.try_fold(State::Init, |state, header| {
+			if let State::Match = state {
+				return Ok(state);
 			}
 			if str::from_utf8(header.value)?.split(',').any(|v| v.trim().eq_ignore_ascii_case(ours)) {
 				return Ok(State::Match);
@@ -62,6 +67,49 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
 		})
 }
 
+/// Limits of the headers of an HTTP request or response.
+#[derive(Debug, Clone, Copy)]
+struct HeaderLimits {
+	/// The max. number of headers.
+	count: usize,
+	/// The max. total size of the header names and values in bytes.
+	size: usize,
+}
+
+impl Default for HeaderLimits {
+	fn default() -> Self {
+		HeaderLimits { count: MAX_NUM_HEADERS, size: usize::MAX }
+	}
+}
+
+/// Parse the head of an HTTP message with `parse`, which is given a table for
+/// the headers and returns the parsing result and the number of headers parsed.
+///
+/// The table starts with [`MAX_NUM_HEADERS`] entries and grows up to the
+/// limit while there are more headers.
+fn parse_head<'b, F, R>(limits: HeaderLimits, mut parse: F) -> Result<(R, Vec<httparse::Header<'b>>), Error>
+where
+	F: FnMut(&mut [httparse::Header<'b>]) -> Result<(R, usize), httparse::Error>,
+{
+	let mut len = cmp::min(MAX_NUM_HEADERS, limits.count);
+	loop {
+		let mut headers = vec![httparse::EMPTY_HEADER; len];
+		match parse(&mut headers) {
+			Ok((result, n)) => {
+				headers.truncate(n);
+				if headers.iter().map(|h| h.name.len() + h.value.len()).sum::<usize>() > limits.size {
+					return Err(Error::HeadersTooLarge);
+				}
+				return Ok((result, headers));
+			}
+			Err(httparse::Error::TooManyHeaders) if len < limits.count => {
+				len = cmp::min(len.saturating_mul(2), limits.count)
+			}
+			Err(e) => return Err(Error::Http(Box::new(e))),
+		}
+	}
+}
+
 /// Pick the first header with the given name and apply the given closure to it.
 fn with_first_header<'a, F, R>(headers: &[httparse::Header<'a>], name: &str, f: F) -> Result<R, Error>
 where
@@ -74,30 +122,107 @@ where
 	}
 }
 
+/// Merge the values of repeated header lines into one comma-separated list, as per RFC 7230, section 3.2.2.
+///
+/// Returns `None` if there are no values.
+fn merge_header_lines<'a, I>(values: I) -> Result<Option<String>, Error>
+where
+	I: IntoIterator<Item = &'a [u8]>,
+{
+	let mut merged: Option<String> = None;
+	for value in values {
+		let value = str::from_utf8(value)?.trim();
+		match &mut merged {
+			Some(m) => {
+				m.push_str(", ");
+				m.push_str(value)
+			}
+			None => merged = Some(String::from(value)),
+		}
+	}
+	Ok(merged)
+}
+
 // Configure all extensions with parsed parameters.
-fn configure_extensions(extensions: &mut [Box<dyn Extension + Send>], line: &str) -> Result<(), Error> {
-	for e in line.split(',') {
-		let mut ext_parts = e.split(';');
-		if let Some(name) = ext_parts.next() {
-			let name = name.trim();
-			if let Some(ext) = extensions.iter_mut().find(|x| x.name().eq_ignore_ascii_case(name)) {
-				let mut params = Vec::new();
-				for p in ext_parts {
-					let mut key_value = p.split('=');
-					if let Some(key) = key_value.next().map(str::trim) {
-						let val = key_value.next().map(|v| v.trim().trim_matches('"'));
-						let mut p = Param::new(key);
-						p.set_value(val);
-						params.push(p)
-					}
-				}
-				ext.configure(&params).map_err(Error::Extension)?
+//
+// Enabled extensions are moved to the front, in the order in which they are
+// listed, which is the order in which they are applied to outgoing messages.
+// An extension using the same reserved bits or opcode as one enabled before is
+// ignored by servers and rejected by clients.
+#[cfg(any(feature = "http", test))]
+fn configure_extensions(extensions: &mut [Box<dyn Extension + Send>], line: &str, mode: Mode) -> Result<(), Error> {
+	for (name, params) in parse_extensions(line) {
+		if let Some((enabled, i)) = select_extension(extensions, name, mode)? {
+			extensions[i].configure(&params).map_err(Error::Extension)?;
+			if extensions[i].is_enabled() {
+				extensions[enabled..=i].rotate_right(1)
 			}
 		}
 	}
 	Ok(())
 }
 
+// Like `configure_extensions`, but using `Extension::configure_async`.
+async fn configure_extensions_async(
+	extensions: &mut [Box<dyn Extension + Send>],
+	line: &str,
+	mode: Mode,
+) -> Result<(), Error> {
+	for (name, params) in parse_extensions(line) {
+		if let Some((enabled, i)) = select_extension(extensions, name, mode)? {
+			extensions[i].configure_async(&params).await.map_err(Error::Extension)?;
+			if extensions[i].is_enabled() {
+				extensions[enabled..=i].rotate_right(1)
+			}
+		}
+	}
+	Ok(())
+}
+
+// Parse the names and parameters of the extensions in a header value.
+fn parse_extensions(line: &str) -> impl Iterator<Item = (&str, Vec<Param<'_>>)> {
+	line.split(',').filter_map(|e| {
+		let mut ext_parts = e.split(';');
+		let name = ext_parts.next()?.trim();
+		let mut params = Vec::new();
+		for p in ext_parts {
+			let mut key_value = p.split('=');
+			if let Some(key) = key_value.next().map(str::trim) {
+				let val = key_value.next().map(|v| v.trim().trim_matches('"'));
+				let mut p = Param::new(key);
+				p.set_value(val);
+				params.push(p)
+			}
+		}
+		Some((name, params))
+	})
+}
+
+// Find the extension to configure with the given name.
+//
+// Returns the number of extensions enabled so far and the index of the
+// extension, unless there is none or it conflicts with an enabled one.
+fn select_extension(
+	extensions: &[Box<dyn Extension + Send>],
+	name: &str,
+	mode: Mode,
+) -> Result<Option<(usize, usize)>, Error> {
+	let enabled = extensions.iter().take_while(|x| x.is_enabled()).count();
+	let i = match extensions[enabled..].iter().position(|x| x.name().eq_ignore_ascii_case(name)) {
+		Some(i) => enabled + i,
+		None => return Ok(None),
+	};
+	let conflict = extensions[..enabled].iter().find(|x| extension::is_conflicting(&***x, &*extensions[i]));
+	if let Some(other) = conflict {
+		if mode.is_client() {
+			return Err(Error::ConflictingExtensions(other.name().into(), name.into()));
+		}
+		log::debug!("ignoring extension {} which conflicts with {}", name, other.name());
+		return Ok(None);
+	}
+	Ok(Some((enabled, i)))
+}
+
 // Write all extensions to the given buffer.
 fn append_extensions<'a, I>(extensions: I, bytes: &mut BytesMut)
 where
@@ -142,7 +267,7 @@ where
 // bytes in total for all of the characters.
 //
 // See https://datatracker.ietf.org/doc/html/rfc6455#section-1.3 for more information on this.
-fn generate_accept_key<'k>(key_base64: &WebSocketKey) -> [u8; 28] {
+fn generate_accept_key(key_base64: &WebSocketKey) -> [u8; 28] {
 	let mut digest = Sha1::new();
 	digest.update(key_base64);
 	digest.update(KEY);
@@ -168,6 +293,8 @@ pub enum Error {
 	IncompleteHttpRequest,
 	/// The value of the `Sec-WebSocket-Key` header is of unexpected length.
 	SecWebSocketKeyInvalidLength(usize),
+	/// The value of the `Sec-WebSocket-Key` header is not 16 base64-encoded bytes.
+	SecWebSocketKeyInvalid,
 	/// The handshake request was not a GET request.
 	InvalidRequestMethod,
 	/// An HTTP header has not been present.
@@ -186,6 +313,26 @@ pub enum Error {
 	Http(crate::BoxedError),
 	/// UTF-8 decoding failed.
 	Utf8(str::Utf8Error),
+	/// The server accepted two extensions using the same reserved bits or opcode.
+	ConflictingExtensions(String, String),
+	/// A redirect location could not be parsed.
+	InvalidLocation(String),
+	/// The maximum number of redirects has been exceeded.
+	TooManyRedirects,
+	/// The value of an HTTP header is not allowed by the server's access control.
+	Forbidden(String),
+	/// The client exceeded the server's handshake rate limit.
+	RateLimited,
+	/// The server's router rejected a request for the given target.
+	RouteRejected(String),
+	/// The headers exceed the configured total size.
+	HeadersTooLarge,
+	/// The request or response exceeds the configured buffer size.
+	HandshakeTooLarge,
+	/// The handshake did not complete within the configured timeout.
+	Timeout,
+	/// A URL could not be parsed or has an unsupported scheme.
+	InvalidUrl(String),
 }
 
 impl fmt::Display for Error {
@@ -197,6 +344,7 @@ impl fmt::Display for Error {
 			Error::SecWebSocketKeyInvalidLength(len) => {
 				write!(f, "Sec-WebSocket-Key header was {} bytes long, expected 24", len)
 			}
+			Error::SecWebSocketKeyInvalid => f.write_str("Sec-WebSocket-Key header was not 16 base64 encoded bytes"),
 			Error::InvalidRequestMethod => f.write_str("handshake was not a GET request"),
 			Error::HeaderNotFound(name) => write!(f, "header {} not found", name),
 			Error::UnexpectedHeader(name) => write!(f, "header {} had an unexpected value", name),
@@ -206,6 +354,16 @@ impl fmt::Display for Error {
 			Error::Extension(e) => write!(f, "extension error: {}", e),
 			Error::Http(e) => write!(f, "http parser error: {}", e),
 			Error::Utf8(e) => write!(f, "utf-8 decoding error: {}", e),
+			Error::ConflictingExtensions(a, b) => write!(f, "extensions {} and {} conflict", a, b),
+			Error::InvalidLocation(l) => write!(f, "invalid redirect location: {}", l),
+			Error::TooManyRedirects => f.write_str("too many redirects"),
+			Error::Forbidden(name) => write!(f, "header {} is not allowed", name),
+			Error::RateLimited => f.write_str("handshake rate limit exceeded"),
+			Error::RouteRejected(target) => write!(f, "no route for {}", target),
+			Error::HeadersTooLarge => f.write_str("headers too large"),
+			Error::HandshakeTooLarge => f.write_str("handshake request or response too large"),
+			Error::Timeout => f.write_str("handshake timed out"),
+			Error::InvalidUrl(u) => write!(f, "invalid url: {}", u),
 		}
 	}
 }
@@ -220,12 +378,23 @@ impl std::error::Error for Error {
 			Error::UnsupportedHttpVersion
 			| Error::IncompleteHttpRequest
 			| Error::SecWebSocketKeyInvalidLength(_)
+			| Error::SecWebSocketKeyInvalid
 			| Error::InvalidRequestMethod
 			| Error::HeaderNotFound(_)
 			| Error::UnexpectedHeader(_)
 			| Error::InvalidSecWebSocketAccept
 			| Error::UnsolicitedExtension
-			| Error::UnsolicitedProtocol => None,
+			| Error::UnsolicitedProtocol
+			| Error::ConflictingExtensions(..)
+			| Error::InvalidLocation(_)
+			| Error::TooManyRedirects
+			| Error::Forbidden(_)
+			| Error::RateLimited
+			| Error::RouteRejected(_)
+			| Error::HeadersTooLarge
+			| Error::HandshakeTooLarge
+			| Error::Timeout
+			| Error::InvalidUrl(_) => None,
 		}
 	}
 }
@@ -242,6 +411,17 @@ impl From<str::Utf8Error> for Error {
 	}
 }
 
+/// Run the given handshake future, failing with [`Error::Timeout`] if it
+/// does not complete within the timeout, if any.
+async fn with_timeout<R>(timeout: Option<Duration>, f: impl Future<Output = Result<R, Error>>) -> Result<R, Error> {
+	let Some(timeout) = timeout else { return f.await };
+	futures::pin_mut!(f);
+	match future::select(f, Delay::new(timeout)).await {
+		Either::Left((result, _)) => result,
+		Either::Right(_) => Err(Error::Timeout),
+	}
+}
+
 /// Owned value of the `Sec-WebSocket-Key` header.
 ///
 /// Per [RFC 6455](https://datatracker.ietf.org/doc/html/rfc6455#section-4.1):
@@ -257,7 +437,47 @@ pub type WebSocketKey = [u8; 24];
 
 #[cfg(test)]
 mod tests {
-	use super::expect_ascii_header;
+	use super::{configure_extensions, expect_ascii_header, merge_header_lines, Error};
+	use crate::{
+		connection::Mode,
+		extension::{Extension, TestExtension},
+	};
+
+	fn extensions() -> Vec<Box<dyn Extension + Send>> {
+		let disabled = |name, reserved_bits| TestExtension { name, reserved_bits, ..TestExtension::default() };
+		vec![
+			Box::new(disabled("a", (true, false, false))),
+			Box::new(disabled("b", (false, true, false))),
+			Box::new(disabled("c", (true, false, false))),
+		]
+	}
+
+	#[test]
+	fn extension_order_and_conflicts() {
+		let mut exts = extensions();
+		configure_extensions(&mut exts, "c, b; x=1, a", Mode::Server).unwrap();
+		let enabled = exts.iter().filter(|e| e.is_enabled()).map(|e| e.name()).collect::<Vec<_>>();
+		assert_eq!(vec!["c", "b"], enabled);
+		assert_eq!("c", exts[0].name());
+		assert_eq!("b", exts[1].name());
+
+		let mut exts = extensions();
+		let result = configure_extensions(&mut exts, "a, c", Mode::Client);
+		assert!(matches!(result, Err(Error::ConflictingExtensions(..))));
+	}
+
+	#[test]
+	fn merged_extension_headers() {
+		let lines: [&[u8]; 2] = [b"c; x=1", b" b "];
+		let merged = merge_header_lines(lines).unwrap().unwrap();
+		assert_eq!("c; x=1, b", merged);
+
+		let mut exts = extensions();
+		configure_extensions(&mut exts, &merged, Mode::Client).unwrap();
+		let enabled = exts.iter().filter(|e| e.is_enabled()).map(|e| e.name()).collect::<Vec<_>>();
+		assert_eq!(vec!["c", "b"], enabled);
+		assert_eq!(None, merge_header_lines(None).unwrap());
+	}
 
 	#[test]
 	fn header_match() {
@@ -0,0 +1,228 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Access control of handshake requests based on their `Origin` and `Host` headers.
+//!
+//! A [`Policy`] or [`AsyncPolicy`] decides whether a header value is allowed. Policies are set with
+//! [`Server::set_origins`] and [`Server::set_hosts`] and requests with values
+//! they do not allow are rejected with [`Error::Forbidden`].
+//!
+//! [`Server::set_origins`]: super::Server::set_origins
+//! [`Server::set_hosts`]: super::Server::set_hosts
+//! [`Error::Forbidden`]: super::Error::Forbidden
+
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{str, sync::Arc};
+
+/// A policy deciding which `Origin` or `Host` header values are allowed.
+///
+/// It is implemented for closures taking the header value, e.g.:
+///
+/// ```
+/// use soketto::handshake::Server;
+///
+/// # fn doc(socket: futures::io::Cursor<Vec<u8>>) {
+/// let mut server = Server::new(socket);
+/// server.set_hosts(|host: &[u8]| host.starts_with(b"localhost"));
+/// # }
+/// ```
+pub trait Policy: Send + Sync {
+	/// Is the given header value allowed?
+	fn is_allowed(&self, value: &[u8]) -> bool;
+}
+
+impl<F: Fn(&[u8]) -> bool + Send + Sync> Policy for F {
+	fn is_allowed(&self, value: &[u8]) -> bool {
+		self(value)
+	}
+}
+
+impl<P: Policy + ?Sized> Policy for Arc<P> {
+	fn is_allowed(&self, value: &[u8]) -> bool {
+		(**self).is_allowed(value)
+	}
+}
+
+/// An asynchronous [`Policy`], e.g. to consult a database or a remote
+/// authorization service during the handshake.
+///
+/// Every [`Policy`] is also an `AsyncPolicy`.
+pub trait AsyncPolicy: Send + Sync {
+	/// Is the given header value allowed?
+	fn is_allowed<'a>(&'a self, value: &'a [u8]) -> BoxFuture<'a, bool>;
+}
+
+impl<P: Policy + ?Sized> AsyncPolicy for P {
+	fn is_allowed<'a>(&'a self, value: &'a [u8]) -> BoxFuture<'a, bool> {
+		future::ready(Policy::is_allowed(self, value)).boxed()
+	}
+}
+
+/// A policy allowing an explicit list of values.
+///
+/// Values are compared ASCII case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+	values: Vec<Vec<u8>>,
+}
+
+impl AllowList {
+	/// Create an empty list which does not allow any value.
+	pub fn new() -> Self {
+		AllowList::default()
+	}
+
+	/// Add a value to the list.
+	pub fn add(&mut self, value: impl AsRef<[u8]>) -> &mut Self {
+		self.values.push(value.as_ref().to_vec());
+		self
+	}
+}
+
+impl<V: AsRef<[u8]>> FromIterator<V> for AllowList {
+	fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+		AllowList { values: iter.into_iter().map(|v| v.as_ref().to_vec()).collect() }
+	}
+}
+
+impl Policy for AllowList {
+	fn is_allowed(&self, value: &[u8]) -> bool {
+		self.values.iter().any(|v| v.eq_ignore_ascii_case(value))
+	}
+}
+
+/// A policy allowing values which match one of a list of patterns.
+///
+/// Patterns have the form `[scheme://]host[:port]`, which matches both `Origin`
+/// values like `https://example.com:8080` and `Host` values like `example.com:8080`.
+///
+/// - A pattern without scheme matches values with any or no scheme. A scheme of
+///   `*` matches any scheme.
+/// - A host of `*` matches any host. A host of `*.example.com` matches all
+///   subdomains of `example.com`, but not `example.com` itself.
+/// - A pattern without port only matches values without port. A port of `*`
+///   matches values with any or no port.
+///
+/// All parts are compared ASCII case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct AllowPattern {
+	patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+	scheme: Option<String>,
+	host: String,
+	port: Option<String>,
+}
+
+impl AllowPattern {
+	/// Create an empty pattern list which does not allow any value.
+	pub fn new() -> Self {
+		AllowPattern::default()
+	}
+
+	/// Add a pattern to the list.
+	pub fn add(&mut self, pattern: &str) -> &mut Self {
+		let (scheme, host, port) = split(pattern);
+		self.patterns.push(Pattern {
+			scheme: scheme.map(String::from),
+			host: host.into(),
+			port: port.map(String::from),
+		});
+		self
+	}
+}
+
+impl<'a> FromIterator<&'a str> for AllowPattern {
+	fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+		let mut this = AllowPattern::new();
+		for p in iter {
+			this.add(p);
+		}
+		this
+	}
+}
+
+impl Policy for AllowPattern {
+	fn is_allowed(&self, value: &[u8]) -> bool {
+		let (scheme, host, port) = match str::from_utf8(value) {
+			Ok(v) => split(v),
+			Err(_) => return false,
+		};
+		self.patterns.iter().any(|p| {
+			let scheme_matches = match (p.scheme.as_deref(), scheme) {
+				(None, _) | (Some("*"), Some(_)) => true,
+				(Some(ours), Some(theirs)) => ours.eq_ignore_ascii_case(theirs),
+				(Some(_), None) => false,
+			};
+			let host_matches = if p.host == "*" {
+				true
+			} else if let Some(suffix) = p.host.strip_prefix('*') {
+				host.len() > suffix.len()
+					&& host.is_char_boundary(host.len() - suffix.len())
+					&& host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+			} else {
+				p.host.eq_ignore_ascii_case(host)
+			};
+			let port_matches = match (p.port.as_deref(), port) {
+				(Some("*"), _) => true,
+				(Some(ours), Some(theirs)) => ours == theirs,
+				(None, None) => true,
+				_ => false,
+			};
+			scheme_matches && host_matches && port_matches
+		})
+	}
+}
+
+/// Split a value of the form `[scheme://]host[:port]` into its parts.
+fn split(value: &str) -> (Option<&str>, &str, Option<&str>) {
+	let (scheme, rest) = match value.split_once("://") {
+		Some((scheme, rest)) => (Some(scheme), rest),
+		None => (None, value),
+	};
+	// The colons of IPv6 addresses are enclosed in brackets.
+	match rest.rfind(':') {
+		Some(i) if !rest[i..].contains(']') => (scheme, &rest[..i], Some(&rest[i + 1..])),
+		_ => (scheme, rest, None),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{AllowList, AllowPattern, Policy};
+
+	#[test]
+	fn allow_list() {
+		let list: AllowList = ["https://example.com", "http://localhost:8080"].into_iter().collect();
+		assert!(list.is_allowed(b"https://EXAMPLE.com"));
+		assert!(list.is_allowed(b"http://localhost:8080"));
+		assert!(!list.is_allowed(b"http://example.com"));
+		assert!(!list.is_allowed(b"http://localhost"));
+	}
+
+	#[test]
+	fn allow_pattern() {
+		let patterns: AllowPattern = ["https://*.example.com", "localhost:*", "*://[::1]:8080"].into_iter().collect();
+		assert!(patterns.is_allowed(b"https://a.example.com"));
+		assert!(patterns.is_allowed(b"https://a.b.Example.com"));
+		assert!(!patterns.is_allowed(b"https://example.com"));
+		assert!(!patterns.is_allowed(b"https://evilexample.com"));
+		assert!(!patterns.is_allowed(b"http://a.example.com"));
+		assert!(!patterns.is_allowed(b"https://a.example.com:8443"));
+		assert!(!patterns.is_allowed(b"a.example.com"));
+		assert!(patterns.is_allowed(b"localhost"));
+		assert!(patterns.is_allowed(b"localhost:9944"));
+		assert!(patterns.is_allowed(b"http://localhost:9944"));
+		assert!(patterns.is_allowed(b"ws://[::1]:8080"));
+		assert!(!patterns.is_allowed(b"ws://[::1]"));
+		assert!(!patterns.is_allowed(b"[::1]:8080"));
+		assert!(!patterns.is_allowed(b"null"));
+	}
+}
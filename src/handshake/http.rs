@@ -12,9 +12,15 @@ with [`http::Request`] and [`http::Response`] types, making it easier to integra
 external web servers such as Hyper.
 
 See `examples/hyper_server.rs` from this crate's repository for example usage.
+
+[`Client`] performs the client side of the HTTP/2 ([RFC 8441]) and HTTP/3 ([RFC 9220])
+extended CONNECT bootstrap, with [`Server::receive_extended_connect`] as its counterpart.
+
+[RFC 8441]: https://www.rfc-editor.org/rfc/rfc8441
+[RFC 9220]: https://www.rfc-editor.org/rfc/rfc9220
 */
 
-use super::{WebSocketKey, SEC_WEBSOCKET_EXTENSIONS};
+use super::{ServerResponse, WebSocketKey, SEC_WEBSOCKET_EXTENSIONS};
 use crate::connection::{self, Mode};
 use crate::extension::Extension;
 use crate::handshake;
@@ -29,6 +35,7 @@ pub type Error = handshake::Error;
 /// Websocket handshake server. This is similar to [`handshake::Server`], but it is
 /// focused on performing the WebSocket handshake using a provided [`http::Request`], as opposed
 /// to decoding the request internally.
+#[derive(Debug)]
 pub struct Server {
 	// Extensions the server supports.
 	extensions: Vec<Box<dyn Extension + Send>>,
@@ -36,6 +43,12 @@ pub struct Server {
 	buffer: BytesMut,
 }
 
+impl Default for Server {
+	fn default() -> Self {
+		Server::new()
+	}
+}
+
 impl Server {
 	/// Create a new server handshake.
 	pub fn new() -> Self {
@@ -67,19 +80,19 @@ impl Server {
 	/// Attempt to interpret the provided [`http::Request`] as a WebSocket Upgrade request. If successful, this
 	/// returns an [`http::Response`] that should be returned to the client to complete the handshake.
 	pub fn receive_request<B>(&mut self, req: &http::Request<B>) -> Result<http::Response<()>, Error> {
-		if !is_upgrade_request(&req) {
+		if !is_upgrade_request(req) {
 			return Err(Error::InvalidSecWebSocketAccept);
 		}
 
 		let key = match req.headers().get("Sec-WebSocket-Key") {
 			Some(key) => key,
 			None => {
-				return Err(Error::HeaderNotFound("Sec-WebSocket-Key".into()).into());
+				return Err(Error::HeaderNotFound("Sec-WebSocket-Key".into()));
 			}
 		};
 
 		if req.headers().get("Sec-WebSocket-Version").map(|v| v.as_bytes()) != Some(b"13") {
-			return Err(Error::HeaderNotFound("Sec-WebSocket-Version".into()).into());
+			return Err(Error::HeaderNotFound("Sec-WebSocket-Version".into()));
 		}
 
 		// Pull out the Sec-WebSocket-Key and generate the appropriate response to it.
@@ -89,39 +102,80 @@ impl Server {
 		};
 		let accept_key = handshake::generate_accept_key(key);
 
-		// Get extension information out of the request as we'll need this as well.
-		let extension_config = req
-			.headers()
-			.iter()
-			.filter(|&(name, _)| name.as_str().eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS))
-			.map(|(_, value)| Ok(std::str::from_utf8(value.as_bytes())?.to_string()))
-			.collect::<Result<Vec<_>, Error>>()?;
-
-		// Attempt to set the extension configuration params that the client requested.
-		for config_str in &extension_config {
-			handshake::configure_extensions(&mut self.extensions, &config_str)?;
-		}
+		self.configure_extensions(req)?;
 
 		// Build a response that should be sent back to the client to acknowledge the upgrade.
-		let mut response = Response::builder()
+		let response = Response::builder()
 			.status(http::StatusCode::SWITCHING_PROTOCOLS)
 			.header(http::header::CONNECTION, "upgrade")
 			.header(http::header::UPGRADE, "websocket")
 			.header("Sec-WebSocket-Accept", &accept_key[..]);
 
-		// Tell the client about the agreed-upon extension configuration. We reuse code to build up the
-		// extension header value, but that does make this a little more clunky.
-		if !self.extensions.is_empty() {
-			let mut buf = bytes::BytesMut::new();
-			let enabled_extensions = self.extensions.iter().filter(|e| e.is_enabled()).peekable();
-			handshake::append_extension_header_value(enabled_extensions, &mut buf);
-			response = response.header("Sec-WebSocket-Extensions", buf.as_ref());
+		let response = self.append_extensions(response).body(()).expect("bug: failed to build response");
+		Ok(response)
+	}
+
+	/// Attempt to interpret the provided [`http::Request`] as an HTTP/2 ([RFC 8441]) or HTTP/3
+	/// ([RFC 9220]) extended CONNECT request bootstrapping a websocket. If successful, this returns
+	/// an [`http::Response`] that should be returned to the client to complete the handshake.
+	///
+	/// The value of the `:protocol` pseudo-header is not part of [`http::Request`] and must be
+	/// provided by the caller, e.g. from the `hyper::ext::Protocol` or `h3::ext::Protocol` request
+	/// extension. There is no `Sec-WebSocket-Key` exchange; the websocket connection runs over the
+	/// request and response bodies of the HTTP/2 or HTTP/3 stream, which is given to
+	/// [`Server::into_builder`] as `AsyncRead` and `AsyncWrite` once the response has been sent.
+	///
+	/// [RFC 8441]: https://www.rfc-editor.org/rfc/rfc8441
+	/// [RFC 9220]: https://www.rfc-editor.org/rfc/rfc9220
+	pub fn receive_extended_connect<B>(
+		&mut self,
+		req: &http::Request<B>,
+		protocol: &str,
+	) -> Result<http::Response<()>, Error> {
+		if req.method() != http::Method::CONNECT {
+			return Err(Error::InvalidRequestMethod);
+		}
+
+		if req.version() != http::Version::HTTP_2 && req.version() != http::Version::HTTP_3 {
+			return Err(Error::UnsupportedHttpVersion);
+		}
+
+		if !protocol.eq_ignore_ascii_case("websocket") {
+			return Err(Error::UnexpectedHeader(":protocol".into()));
+		}
+
+		if req.headers().get("Sec-WebSocket-Version").map(|v| v.as_bytes()) != Some(b"13") {
+			return Err(Error::HeaderNotFound("Sec-WebSocket-Version".into()));
 		}
 
-		let response = response.body(()).expect("bug: failed to build response");
+		self.configure_extensions(req)?;
+
+		let response = Response::builder().status(http::StatusCode::OK);
+		let response = self.append_extensions(response).body(()).expect("bug: failed to build response");
 		Ok(response)
 	}
 
+	// Set the extension configuration params that the client requested.
+	fn configure_extensions<B>(&mut self, req: &http::Request<B>) -> Result<(), Error> {
+		let offers = req.headers().get_all(SEC_WEBSOCKET_EXTENSIONS).iter().map(|v| v.as_bytes());
+		if let Some(line) = handshake::merge_header_lines(offers)? {
+			handshake::configure_extensions(&mut self.extensions, &line, Mode::Server)?
+		}
+		Ok(())
+	}
+
+	// Tell the client about the agreed-upon extension configuration. We reuse code to build up the
+	// extension header value, but that does make this a little more clunky.
+	fn append_extensions(&self, response: http::response::Builder) -> http::response::Builder {
+		if self.extensions.is_empty() {
+			return response;
+		}
+		let mut buf = bytes::BytesMut::new();
+		let enabled_extensions = self.extensions.iter().filter(|e| e.is_enabled()).peekable();
+		handshake::append_extension_header_value(enabled_extensions, &mut buf);
+		response.header("Sec-WebSocket-Extensions", buf.as_ref())
+	}
+
 	/// Turn this handshake into a [`connection::Builder`].
 	pub fn into_builder<T: AsyncRead + AsyncWrite + Unpin>(mut self, socket: T) -> connection::Builder<T> {
 		let mut builder = connection::Builder::new(socket, Mode::Server);
@@ -131,6 +185,112 @@ impl Server {
 	}
 }
 
+/// Websocket handshake client bootstrapping a websocket over an HTTP/2 ([RFC 8441]) or
+/// HTTP/3 ([RFC 9220]) extended CONNECT stream.
+///
+/// This does not speak HTTP/2 or HTTP/3 itself. The request built by
+/// [`Client::extended_connect_request`] is sent with the caller's HTTP stack, which also has to
+/// set the `:protocol` pseudo-header to `websocket` (e.g. using the `hyper::ext::Protocol` or
+/// `h3::ext::Protocol` request extension). Once the response has been checked with
+/// [`Client::receive_response`], the stream is given to [`Client::into_builder`] as `AsyncRead`
+/// and `AsyncWrite`.
+///
+/// [RFC 8441]: https://www.rfc-editor.org/rfc/rfc8441
+/// [RFC 9220]: https://www.rfc-editor.org/rfc/rfc9220
+#[derive(Debug)]
+pub struct Client {
+	// Extensions the client supports.
+	extensions: Vec<Box<dyn Extension + Send>>,
+	// Encoding/decoding buffer.
+	buffer: BytesMut,
+}
+
+impl Default for Client {
+	fn default() -> Self {
+		Client::new()
+	}
+}
+
+impl Client {
+	/// Create a new client handshake.
+	pub fn new() -> Self {
+		Client { extensions: Vec::new(), buffer: BytesMut::new() }
+	}
+
+	/// Override the buffer to use for request/response handling.
+	pub fn set_buffer(&mut self, b: BytesMut) -> &mut Self {
+		self.buffer = b;
+		self
+	}
+
+	/// Extract the buffer.
+	pub fn take_buffer(&mut self) -> BytesMut {
+		mem::take(&mut self.buffer)
+	}
+
+	/// Add an extension to be included in the request.
+	pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
+		self.extensions.push(e);
+		self
+	}
+
+	/// Get back all extensions.
+	pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
+		self.extensions.drain(..)
+	}
+
+	/// Build the extended CONNECT request for the given URI.
+	///
+	/// The version must be [`http::Version::HTTP_2`] or [`http::Version::HTTP_3`].
+	pub fn extended_connect_request(&self, uri: http::Uri, version: http::Version) -> Result<http::Request<()>, Error> {
+		if version != http::Version::HTTP_2 && version != http::Version::HTTP_3 {
+			return Err(Error::UnsupportedHttpVersion);
+		}
+
+		let request = http::Request::builder()
+			.method(http::Method::CONNECT)
+			.version(version)
+			.uri(uri)
+			.header("Sec-WebSocket-Version", "13");
+
+		let request = if self.extensions.is_empty() {
+			request
+		} else {
+			let mut buf = BytesMut::new();
+			handshake::append_extension_header_value(self.extensions.iter().peekable(), &mut buf);
+			request.header(SEC_WEBSOCKET_EXTENSIONS, buf.as_ref())
+		};
+
+		Ok(request.body(()).expect("bug: failed to build request"))
+	}
+
+	/// Check the server's response to the extended CONNECT request.
+	///
+	/// Any 2xx status code accepts the websocket, in which case the extensions are configured
+	/// with the parameters the server has selected. Other responses are rejections; CONNECT
+	/// requests are not redirected.
+	pub fn receive_response<B>(&mut self, res: &http::Response<B>) -> Result<ServerResponse, Error> {
+		if !res.status().is_success() {
+			return Ok(ServerResponse::Rejected { status_code: res.status().as_u16() });
+		}
+
+		let params = res.headers().get_all(SEC_WEBSOCKET_EXTENSIONS).iter().map(|v| v.as_bytes());
+		if let Some(line) = handshake::merge_header_lines(params)? {
+			handshake::configure_extensions(&mut self.extensions, &line, Mode::Client)?
+		}
+
+		Ok(ServerResponse::Accepted { protocol: None })
+	}
+
+	/// Turn this handshake into a [`connection::Builder`].
+	pub fn into_builder<T: AsyncRead + AsyncWrite + Unpin>(mut self, socket: T) -> connection::Builder<T> {
+		let mut builder = connection::Builder::new(socket, Mode::Client);
+		builder.set_buffer(self.buffer);
+		builder.add_extensions(self.extensions.drain(..));
+		builder
+	}
+}
+
 /// Check if an [`http::Request`] looks like a valid websocket upgrade request.
 pub fn is_upgrade_request<B>(request: &http::Request<B>) -> bool {
 	header_contains_value(request.headers(), header::CONNECTION, b"upgrade")
@@ -155,3 +315,85 @@ fn header_contains_value(headers: &HeaderMap, header: header::HeaderName, value:
 	}
 	false
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{Client, Error, Server};
+	use crate::handshake::ServerResponse;
+	use tokio_util::compat::TokioAsyncReadCompatExt;
+
+	#[test]
+	fn extended_connect() {
+		let request = http::Request::builder()
+			.method(http::Method::CONNECT)
+			.version(http::Version::HTTP_2)
+			.uri("https://example.com/chat")
+			.header("Sec-WebSocket-Version", "13")
+			.body(())
+			.unwrap();
+		let response = Server::new().receive_extended_connect(&request, "websocket").unwrap();
+		assert_eq!(http::StatusCode::OK, response.status());
+		assert!(response.headers().get("Sec-WebSocket-Accept").is_none());
+
+		let result = Server::new().receive_extended_connect(&request, "webtransport");
+		assert!(matches!(result, Err(Error::UnexpectedHeader(_))));
+
+		let (mut parts, ()) = request.into_parts();
+		parts.version = http::Version::HTTP_3;
+		let request = http::Request::from_parts(parts, ());
+		let response = Server::new().receive_extended_connect(&request, "websocket").unwrap();
+		assert_eq!(http::StatusCode::OK, response.status());
+
+		let (mut parts, ()) = request.into_parts();
+		parts.version = http::Version::HTTP_11;
+		let request = http::Request::from_parts(parts, ());
+		let result = Server::new().receive_extended_connect(&request, "websocket");
+		assert!(matches!(result, Err(Error::UnsupportedHttpVersion)));
+
+		let request = http::Request::get("https://example.com/chat").version(http::Version::HTTP_2).body(()).unwrap();
+		let result = Server::new().receive_extended_connect(&request, "websocket");
+		assert!(matches!(result, Err(Error::InvalidRequestMethod)));
+	}
+
+	#[tokio::test]
+	async fn extended_connect_bootstrap() {
+		let uri: http::Uri = "https://example.com/chat".parse().unwrap();
+		let mut client = Client::new();
+		let mut server = Server::new();
+		#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+		{
+			use crate::{connection::Mode, extension::deflate::Deflate};
+			client.add_extension(Box::new(Deflate::new(Mode::Client)));
+			server.add_extension(Box::new(Deflate::new(Mode::Server)));
+		}
+
+		let result = client.extended_connect_request(uri.clone(), http::Version::HTTP_11);
+		assert!(matches!(result, Err(Error::UnsupportedHttpVersion)));
+
+		let request = client.extended_connect_request(uri, http::Version::HTTP_3).unwrap();
+		assert_eq!(http::Method::CONNECT, request.method());
+		let response = server.receive_extended_connect(&request, "websocket").unwrap();
+		#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+		{
+			let extensions = response.headers().get("Sec-WebSocket-Extensions");
+			assert!(extensions.is_some_and(|v| v.as_bytes().starts_with(b"permessage-deflate")))
+		}
+		let result = client.receive_response(&response).unwrap();
+		assert!(matches!(result, ServerResponse::Accepted { protocol: None }));
+
+		// The HTTP/3 stream, as provided by the caller's HTTP stack.
+		let (client_stream, server_stream) = tokio::io::duplex(1024);
+		let (mut sender, _) = client.into_builder(client_stream.compat()).finish();
+		let (_, mut receiver) = server.into_builder(server_stream.compat()).finish();
+
+		sender.send_text("hello").await.unwrap();
+		sender.flush().await.unwrap();
+		let mut message = Vec::new();
+		receiver.receive_data(&mut message).await.unwrap();
+		assert_eq!(b"hello", &message[..]);
+
+		let response = http::Response::builder().status(http::StatusCode::FORBIDDEN).body(()).unwrap();
+		let result = Client::new().receive_response(&response).unwrap();
+		assert!(matches!(result, ServerResponse::Rejected { status_code: 403 }));
+	}
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Rate limiting of handshake requests.
+//!
+//! A [`RateLimiter`] is shared by the servers of all connections and keyed by
+//! an identity of the client, e.g. its IP address:
+//!
+//! ```
+//! use soketto::handshake::{rate_limit::RateLimiter, Server};
+//! use std::{net::IpAddr, time::Duration};
+//!
+//! // Allow bursts of 10 handshakes per client, refilled at one per second.
+//! let limiter = RateLimiter::<IpAddr>::new(10, Duration::from_secs(1));
+//!
+//! # fn doc(socket: futures::io::Cursor<Vec<u8>>, limiter: &RateLimiter<IpAddr>, peer: IpAddr) {
+//! let mut server = Server::new(socket);
+//! server.set_rate_limiter(limiter, peer);
+//! # }
+//! ```
+
+use std::{
+	collections::HashMap,
+	hash::Hash,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+// Number of keys above which idle ones are removed.
+const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+/// A token bucket rate limiter keyed by client identity.
+///
+/// Each key may make up to `burst` attempts at once, and one more attempt
+/// becomes available every `period`. Cloning the limiter is cheap and all
+/// clones share their state.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+	/// The time after which one more attempt becomes available.
+	period: Duration,
+	/// How far the arrival time of a key may be ahead of now, i.e. `period * (burst - 1)`.
+	tolerance: Duration,
+	state: Arc<Mutex<State<K>>>,
+}
+
+#[derive(Debug)]
+struct State<K> {
+	/// The theoretical arrival time of the next attempt of each key.
+	arrivals: HashMap<K, Instant>,
+	/// Number of keys above which idle keys are removed.
+	prune_threshold: usize,
+}
+
+impl<K> Clone for RateLimiter<K> {
+	fn clone(&self) -> Self {
+		RateLimiter { period: self.period, tolerance: self.tolerance, state: self.state.clone() }
+	}
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+	/// Create a new rate limiter allowing `burst` attempts at once and one more every `period`.
+	///
+	/// A `burst` of 0 is treated as 1.
+	pub fn new(burst: u32, period: Duration) -> Self {
+		RateLimiter {
+			period,
+			tolerance: period * burst.saturating_sub(1),
+			state: Arc::new(Mutex::new(State { arrivals: HashMap::new(), prune_threshold: MIN_PRUNE_THRESHOLD })),
+		}
+	}
+
+	/// Record an attempt of the given key and return whether it is within the rate limit.
+	///
+	/// Rejected attempts do not count towards the limit.
+	pub fn try_acquire(&self, key: K) -> bool {
+		self.try_acquire_at(key, Instant::now())
+	}
+
+	/// Like [`RateLimiter::try_acquire`], with the current time given by the caller.
+	fn try_acquire_at(&self, key: K, now: Instant) -> bool {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+		// Keys whose arrival time has passed have all their attempts available
+		// again and are indistinguishable from new keys.
+		if state.arrivals.len() >= state.prune_threshold {
+			state.arrivals.retain(|_, t| *t > now);
+			state.prune_threshold = std::cmp::max(MIN_PRUNE_THRESHOLD, state.arrivals.len() * 2)
+		}
+
+		let arrival = state.arrivals.entry(key).or_insert(now);
+		let start = std::cmp::max(*arrival, now);
+		if start - now > self.tolerance {
+			return false;
+		}
+		*arrival = start + self.period;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::RateLimiter;
+	use std::time::{Duration, Instant};
+
+	#[test]
+	fn burst_and_refill() {
+		let start = Instant::now();
+		let limiter = RateLimiter::new(2, Duration::from_millis(50));
+		assert!(limiter.try_acquire_at("a", start));
+		assert!(limiter.try_acquire_at("a", start));
+		assert!(!limiter.try_acquire_at("a", start));
+		assert!(limiter.clone().try_acquire_at("b", start));
+		assert!(!limiter.try_acquire_at("a", start + Duration::from_millis(49)));
+		assert!(limiter.try_acquire_at("a", start + Duration::from_millis(50)));
+		assert!(!limiter.try_acquire_at("a", start + Duration::from_millis(60)));
+	}
+}
@@ -11,17 +11,21 @@
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
 use super::{
-	append_extensions, configure_extensions, expect_ascii_header, with_first_header, Error, WebSocketKey,
-	MAX_NUM_HEADERS, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL,
+	access_control::AsyncPolicy, append_extensions, configure_extensions_async, expect_ascii_header,
+	merge_header_lines, parse_head, rate_limit::RateLimiter, with_first_header, with_timeout, Error, HeaderLimits,
+	WebSocketKey, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL,
 };
 use crate::connection::{self, Mode};
 use crate::extension::Extension;
+use base64::Engine;
 use bytes::BytesMut;
 use futures::prelude::*;
-use std::{mem, str};
+use std::{borrow::Cow, fmt, mem, str, sync::Arc, time::Duration};
+
+pub use httparse::Header;
 
 // Most HTTP servers default to 8KB limit on headers
-const MAX_HEADERS_SIZE: usize = 8 * 1024;
+const MAX_BUFFER_SIZE: usize = 8 * 1024;
 const BLOCK_SIZE: usize = 8 * 1024;
 
 /// Websocket handshake server.
@@ -29,17 +33,104 @@ const BLOCK_SIZE: usize = 8 * 1024;
 pub struct Server<'a, T> {
 	socket: T,
 	/// Protocols the server supports.
-	protocols: Vec<&'a str>,
+	protocols: Vec<Cow<'a, str>>,
+	/// Callback selecting a protocol from the ones offered by the client.
+	protocol_selector: Option<ProtocolSelector<'a>>,
 	/// Extensions the server supports.
 	extensions: Vec<Box<dyn Extension + Send>>,
+	/// Factories of extensions the server supports, called when the request is received.
+	extension_factories: Vec<ExtensionFactory<'a>>,
+	/// Policy deciding which `Origin` header values are allowed.
+	origins: Option<AccessPolicy<'a>>,
+	/// Policy deciding which `Host` header values are allowed.
+	hosts: Option<AccessPolicy<'a>>,
+	/// Check whether the client is within the handshake rate limit.
+	rate_limit: Option<RateLimit<'a>>,
+	/// Callback choosing the configuration of a request target.
+	router: Option<Router<'a>>,
+	/// Additional headers of the accept response.
+	response_headers: Vec<(String, Vec<u8>)>,
+	/// Limits of the request headers.
+	header_limits: HeaderLimits,
+	/// The max. size of the request.
+	max_buffer_size: usize,
+	/// Timeout of receiving the request and sending the response, if any.
+	timeout: Option<Duration>,
+	/// Require the `Sec-WebSocket-Key` to be 16 base64-encoded bytes?
+	strict_key: bool,
 	/// Encoding/decoding buffer.
 	buffer: BytesMut,
 }
 
+/// Callback signature used to select a protocol, see [`Server::set_protocol_selector`].
+type SelectFn<'a> = dyn for<'p> Fn(&[&'p str]) -> Option<&'p str> + Send + 'a;
+
+/// Boxed protocol selection callback.
+struct ProtocolSelector<'a>(Box<SelectFn<'a>>);
+
+impl fmt::Debug for ProtocolSelector<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ProtocolSelector")
+	}
+}
+
+/// Boxed extension factory, see [`Server::add_extension_with`].
+struct ExtensionFactory<'a>(Box<dyn FnOnce() -> Box<dyn Extension + Send> + Send + 'a>);
+
+impl fmt::Debug for ExtensionFactory<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ExtensionFactory")
+	}
+}
+
+/// Shared access control policy, see [`Server::set_origins`].
+#[derive(Clone)]
+struct AccessPolicy<'a>(Arc<dyn AsyncPolicy + 'a>);
+
+impl fmt::Debug for AccessPolicy<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("AccessPolicy")
+	}
+}
+
+/// Boxed routing callback, see [`Server::set_router`].
+struct Router<'a>(Box<dyn Fn(&RequestTarget<'_>) -> Route<'a> + Send + 'a>);
+
+impl fmt::Debug for Router<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("Router")
+	}
+}
+
+/// Boxed rate limit check, see [`Server::set_rate_limiter`].
+struct RateLimit<'a>(Box<dyn Fn() -> bool + Send + 'a>);
+
+impl fmt::Debug for RateLimit<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("RateLimit")
+	}
+}
+
 impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 	/// Create a new server handshake.
 	pub fn new(socket: T) -> Self {
-		Server { socket, protocols: Vec::new(), extensions: Vec::new(), buffer: BytesMut::new() }
+		Server {
+			socket,
+			protocols: Vec::new(),
+			protocol_selector: None,
+			extensions: Vec::new(),
+			extension_factories: Vec::new(),
+			origins: None,
+			hosts: None,
+			rate_limit: None,
+			router: None,
+			response_headers: Vec::new(),
+			header_limits: HeaderLimits::default(),
+			max_buffer_size: MAX_BUFFER_SIZE,
+			timeout: None,
+			strict_key: false,
+			buffer: BytesMut::new(),
+		}
 	}
 
 	/// Override the buffer to use for request/response handling.
@@ -53,9 +144,63 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 		mem::take(&mut self.buffer)
 	}
 
+	/// Set the time within which [`Server::receive_request`] and
+	/// [`Server::send_response`] must each complete.
+	///
+	/// If the client stalls, they fail with [`Error::Timeout`].
+	pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Check that the `Sec-WebSocket-Key` of requests is 16 base64-encoded bytes.
+	///
+	/// [RFC 6455][0] requires this, but by default any key of the right length is
+	/// accepted for compatibility with lax clients. With strict checking, other
+	/// keys are rejected with status code 400 and [`Error::SecWebSocketKeyInvalid`].
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-4.2.1
+	pub fn set_strict_key(&mut self, strict: bool) -> &mut Self {
+		self.strict_key = strict;
+		self
+	}
+
+	/// Set the max. number of request headers and their max. total size in bytes.
+	///
+	/// Requests with more headers fail with [`Error::Http`] and requests whose
+	/// header names and values are larger in total fail with
+	/// [`Error::HeadersTooLarge`]. By default, 32 headers of any size are allowed.
+	pub fn set_header_limits(&mut self, count: usize, size: usize) -> &mut Self {
+		self.header_limits = HeaderLimits { count, size };
+		self
+	}
+
+	/// Set the max. number of bytes of the request to buffer.
+	///
+	/// Larger requests fail with [`Error::HandshakeTooLarge`]. The default is 8 KiB.
+	pub fn set_max_buffer_size(&mut self, size: usize) -> &mut Self {
+		self.max_buffer_size = size;
+		self
+	}
+
 	/// Add a protocol the server supports.
-	pub fn add_protocol(&mut self, p: &'a str) -> &mut Self {
-		self.protocols.push(p);
+	pub fn add_protocol(&mut self, p: impl Into<Cow<'a, str>>) -> &mut Self {
+		self.protocols.push(p.into());
+		self
+	}
+
+	/// Set a callback which selects the protocol to use.
+	///
+	/// The callback is given all protocols offered by the client, in the order
+	/// in which they appear in the `Sec-WebSocket-Protocol` header(s), and
+	/// returns the one to select, if any. When set, it takes precedence over
+	/// the protocols added with [`Server::add_protocol`] and the selected
+	/// protocol is the only one returned by [`ClientRequest::protocols`].
+	pub fn set_protocol_selector<F>(&mut self, f: F) -> &mut Self
+	where
+		F: for<'p> Fn(&[&'p str]) -> Option<&'p str> + Send + 'a,
+	{
+		self.protocol_selector = Some(ProtocolSelector(Box::new(f)));
 		self
 	}
 
@@ -65,6 +210,115 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 		self
 	}
 
+	/// Add a factory creating an extension the server supports.
+	///
+	/// The factory is called when the client request is received. This allows
+	/// configuring a factory once and registering it with the server of every
+	/// accepted connection, each of which gets a fresh extension, e.g.:
+	///
+	/// ```
+	/// use soketto::{extension::Extension, handshake::Server};
+	///
+	/// fn extension() -> Box<dyn Extension + Send> {
+	///     // ...
+	/// #   unimplemented!()
+	/// }
+	///
+	/// # fn doc(socket: futures::io::Cursor<Vec<u8>>) {
+	/// let mut server = Server::new(socket);
+	/// server.add_extension_with(extension);
+	/// # }
+	/// ```
+	///
+	/// Extensions created by factories come after the ones added with
+	/// [`Server::add_extension`] and are only returned by [`Server::drain_extensions`]
+	/// once the request has been received.
+	pub fn add_extension_with<F>(&mut self, f: F) -> &mut Self
+	where
+		F: FnOnce() -> Box<dyn Extension + Send> + Send + 'a,
+	{
+		self.extension_factories.push(ExtensionFactory(Box::new(f)));
+		self
+	}
+
+	/// Only accept requests whose `Origin` header is allowed by the given policy.
+	///
+	/// Requests with a different origin are answered with `403 Forbidden` and
+	/// [`Server::receive_request`] returns [`Error::Forbidden`].
+	/// Requests without `Origin` header, which browsers always send, are not checked.
+	/// See [`access_control`](super::access_control) for the available policies.
+	pub fn set_origins(&mut self, policy: impl AsyncPolicy + 'a) -> &mut Self {
+		self.origins = Some(AccessPolicy(Arc::new(policy)));
+		self
+	}
+
+	/// Only accept requests whose `Host` header is allowed by the given policy.
+	///
+	/// Requests with a different host are answered with `403 Forbidden` and
+	/// [`Server::receive_request`] returns [`Error::Forbidden`].
+	/// See [`access_control`](super::access_control) for the available policies.
+	pub fn set_hosts(&mut self, policy: impl AsyncPolicy + 'a) -> &mut Self {
+		self.hosts = Some(AccessPolicy(Arc::new(policy)));
+		self
+	}
+
+	/// Limit the rate of handshakes of the client identified by `key`, e.g. its IP address.
+	///
+	/// If the client exceeds the rate limit, [`Server::receive_request`] answers its
+	/// request with `429 Too Many Requests` and returns [`Error::RateLimited`].
+	/// See [`rate_limit`](super::rate_limit) for an example.
+	pub fn set_rate_limiter<K>(&mut self, limiter: &RateLimiter<K>, key: K) -> &mut Self
+	where
+		K: std::hash::Hash + Eq + Clone + Send + 'a,
+	{
+		let limiter = limiter.clone();
+		self.rate_limit = Some(RateLimit(Box::new(move || limiter.try_acquire(key.clone()))));
+		self
+	}
+
+	/// Configure the handshake depending on the request target.
+	///
+	/// The router is called with the path and query of the request before
+	/// protocols are selected and extensions are configured. The protocols and
+	/// extensions of the returned [`Route`] are supported in addition to the
+	/// ones added to the server. If the route rejects the request,
+	/// [`Server::receive_request`] answers it with the route's status code and
+	/// returns [`Error::RouteRejected`], e.g.:
+	///
+	/// ```
+	/// use soketto::handshake::{server::Route, Server};
+	///
+	/// # fn doc(socket: futures::io::Cursor<Vec<u8>>) {
+	/// let mut server = Server::new(socket);
+	/// server.set_router(|target| {
+	///     let mut route = Route::new();
+	///     match target.path() {
+	///         "/ws/v1" => route.add_protocol("chat.v1"),
+	///         "/ws/v2" => route.add_protocol("chat.v2"),
+	///         _ => return Route::reject(404),
+	///     };
+	///     route
+	/// });
+	/// # }
+	/// ```
+	pub fn set_router<F>(&mut self, f: F) -> &mut Self
+	where
+		F: Fn(&RequestTarget<'_>) -> Route<'a> + Send + 'a,
+	{
+		self.router = Some(Router(Box::new(f)));
+		self
+	}
+
+	/// Add a header to be included in the response accepting the handshake,
+	/// e.g. `Set-Cookie` or `X-Request-Id`.
+	///
+	/// Headers are not checked for validity. The `Sec-WebSocket-Protocol`
+	/// header should be set with [`Response::Accept`] instead.
+	pub fn add_response_header(&mut self, name: impl Into<String>, value: impl AsRef<[u8]>) -> &mut Self {
+		self.response_headers.push((name.into(), value.as_ref().to_vec()));
+		self
+	}
+
 	/// Get back all extensions.
 	pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
 		self.extensions.drain(..)
@@ -72,24 +326,34 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 
 	/// Await an incoming client handshake request.
 	pub async fn receive_request(&mut self) -> Result<ClientRequest<'_>, Error> {
+		let result = with_timeout(self.timeout, self.read_request()).await;
+		#[cfg(feature = "tracing")]
+		match &result {
+			Ok(r) => tracing::debug!(path = r.path(), protocols = ?r.protocols, "handshake request received"),
+			Err(e) => tracing::debug!(error = %e, "invalid handshake request"),
+		}
+		#[cfg(feature = "metrics")]
+		if result.is_err() {
+			metrics::counter!("soketto_handshakes_total", "side" => "server", "outcome" => "failed").increment(1)
+		}
+		result
+	}
+
+	async fn read_request(&mut self) -> Result<ClientRequest<'_>, Error> {
 		self.buffer.clear();
 
 		let mut skip = 0;
 
 		loop {
-			crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
-
-			let limit = std::cmp::min(self.buffer.len(), MAX_HEADERS_SIZE);
+			if self.buffer.len() >= self.max_buffer_size {
+				return Err(Error::HandshakeTooLarge);
+			}
+			let n = std::cmp::min(BLOCK_SIZE, self.max_buffer_size - self.buffer.len());
+			crate::read(&mut self.socket, &mut self.buffer, n).await?;
 
 			// We don't expect body, so can search for the CRLF headers tail from
 			// the end of the buffer.
-			if self.buffer[skip..limit].windows(4).rev().any(|w| w == b"\r\n\r\n") {
-				break;
-			}
-
-			// Give up if we've reached the limit. We could emit a specific error here,
-			// but httparse will produce meaningful error for us regardless.
-			if limit == MAX_HEADERS_SIZE {
+			if self.buffer[skip..].windows(4).rev().any(|w| w == b"\r\n\r\n") {
 				break;
 			}
 
@@ -99,11 +363,93 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 			skip = self.buffer.len().saturating_sub(4);
 		}
 
-		self.decode_request()
+		if let Some(limit) = &self.rate_limit {
+			if !(limit.0)() {
+				self.write_response(&Response::Reject { status_code: 429 }).await?;
+				return Err(Error::RateLimited);
+			}
+		}
+
+		if let Some(router) = &self.router {
+			let path = Self::decode_target(&self.buffer, self.header_limits)?;
+			let route = (router.0)(&RequestTarget::new(&path));
+			if let Some(status_code) = route.rejection {
+				self.write_response(&Response::Reject { status_code }).await?;
+				return Err(Error::RouteRejected(path));
+			}
+			self.protocols.extend(route.protocols);
+			self.extensions.extend(route.extensions);
+		}
+
+		if self.strict_key && !Self::has_strict_key(&self.buffer, self.header_limits)? {
+			self.write_response(&Response::Reject { status_code: 400 }).await?;
+			return Err(Error::SecWebSocketKeyInvalid);
+		}
+
+		// The policies are cloned as the decoded request borrows from `self`.
+		let (hosts, origins) = (self.hosts.clone(), self.origins.clone());
+		if hosts.is_some() || origins.is_some() {
+			// Decoded separately, as a rejection is written before the request is returned.
+			let request = Self::decode_request(
+				&self.buffer,
+				self.header_limits,
+				&self.protocols,
+				self.protocol_selector.as_ref(),
+			)?;
+			let mut forbidden = None;
+			if let Some(p) = hosts {
+				if !p.0.is_allowed(request.headers.host).await {
+					forbidden = Some("Host")
+				}
+			}
+			if let (None, Some(p), Some(o)) = (forbidden, origins, request.headers.origin) {
+				if !p.0.is_allowed(o).await {
+					forbidden = Some("Origin")
+				}
+			}
+			if let Some(header) = forbidden {
+				self.write_response(&Response::Reject { status_code: 403 }).await?;
+				return Err(Error::Forbidden(header.into()));
+			}
+		}
+
+		let request =
+			Self::decode_request(&self.buffer, self.header_limits, &self.protocols, self.protocol_selector.as_ref())?;
+
+		self.extensions.extend(self.extension_factories.drain(..).map(|f| (f.0)()));
+		let offers = request.all_headers.iter().filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS));
+		if let Some(line) = merge_header_lines(offers.map(|h| h.value))? {
+			configure_extensions_async(&mut self.extensions, &line, Mode::Server).await?
+		}
+
+		Ok(request)
 	}
 
 	/// Respond to the client.
 	pub async fn send_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
+		#[cfg(feature = "tracing")]
+		match r {
+			Response::Accept { protocol, .. } => tracing::debug!(protocol, "handshake accepted"),
+			Response::Reject { status_code } | Response::RejectWith { status_code, .. } => {
+				tracing::debug!(status_code, "handshake rejected")
+			}
+			Response::Redirect { status_code, location } => {
+				tracing::debug!(status_code, location, "handshake redirected")
+			}
+		}
+		#[cfg(feature = "metrics")]
+		{
+			let outcome = match r {
+				Response::Accept { .. } => "accepted",
+				Response::Reject { .. } | Response::RejectWith { .. } => "rejected",
+				Response::Redirect { .. } => "redirected",
+			};
+			metrics::counter!("soketto_handshakes_total", "side" => "server", "outcome" => outcome).increment(1)
+		}
+		with_timeout(self.timeout, self.write_response(r)).await
+	}
+
+	async fn write_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
 		self.buffer.clear();
 		self.encode_response(r);
 		self.socket.write_all(&self.buffer).await?;
@@ -125,31 +471,65 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 		self.socket
 	}
 
-	// Decode client handshake request.
-	fn decode_request(&mut self) -> Result<ClientRequest, Error> {
-		let mut header_buf = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
-		let mut request = httparse::Request::new(&mut header_buf);
-
-		match request.parse(self.buffer.as_ref()) {
-			Ok(httparse::Status::Complete(_)) => (),
-			Ok(httparse::Status::Partial) => return Err(Error::IncompleteHttpRequest),
-			Err(e) => return Err(Error::Http(Box::new(e))),
+	// Decode the target of the client handshake request.
+	fn decode_target(buffer: &[u8], limits: HeaderLimits) -> Result<String, Error> {
+		let ((status, path), _) = parse_head(limits, |headers| {
+			let mut request = httparse::Request::new(headers);
+			let status = request.parse(buffer)?;
+			Ok(((status, request.path), request.headers.len()))
+		})?;
+		match status {
+			httparse::Status::Complete(_) => Ok(String::from(path.unwrap_or("/"))),
+			httparse::Status::Partial => Err(Error::IncompleteHttpRequest),
+		}
+	}
+
+	// Check that the `Sec-WebSocket-Key` header, if any, is 16 base64-encoded bytes.
+	fn has_strict_key(buffer: &[u8], limits: HeaderLimits) -> Result<bool, Error> {
+		let (_, headers) = parse_head(limits, |headers| {
+			let mut request = httparse::Request::new(headers);
+			request.parse(buffer)?;
+			Ok(((), request.headers.len()))
+		})?;
+		let Some(key) = headers.iter().find(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Key")) else {
+			return Ok(true);
 		};
-		if request.method != Some("GET") {
+		let decoded = base64::engine::general_purpose::STANDARD.decode(key.value);
+		Ok(decoded.is_ok_and(|k| k.len() == 16))
+	}
+
+	// Decode client handshake request.
+	fn decode_request<'b>(
+		buffer: &'b [u8],
+		limits: HeaderLimits,
+		supported: &[Cow<'a, str>],
+		selector: Option<&ProtocolSelector<'a>>,
+	) -> Result<ClientRequest<'b>, Error> {
+		let ((status, method, path, version), all_headers) = parse_head(limits, |headers| {
+			let mut request = httparse::Request::new(headers);
+			let status = request.parse(buffer)?;
+			Ok(((status, request.method, request.path, request.version), request.headers.len()))
+		})?;
+
+		if status.is_partial() {
+			return Err(Error::IncompleteHttpRequest);
+		}
+		if method != Some("GET") {
 			return Err(Error::InvalidRequestMethod);
 		}
-		if request.version != Some(1) {
+		if version != Some(1) {
 			return Err(Error::UnsupportedHttpVersion);
 		}
+		let request_headers = &all_headers[..];
 
-		let host = with_first_header(&request.headers, "Host", Ok)?;
+		let host = with_first_header(request_headers, "Host", Ok)?;
 
-		expect_ascii_header(request.headers, "Upgrade", "websocket")?;
-		expect_ascii_header(request.headers, "Connection", "upgrade")?;
-		expect_ascii_header(request.headers, "Sec-WebSocket-Version", "13")?;
+		expect_ascii_header(request_headers, "Upgrade", "websocket")?;
+		expect_ascii_header(request_headers, "Connection", "upgrade")?;
+		expect_ascii_header(request_headers, "Sec-WebSocket-Version", "13")?;
 
 		let origin =
-			request.headers.iter().find_map(
+			request_headers.iter().find_map(
 				|h| {
 					if h.name.eq_ignore_ascii_case("Origin") {
 						Some(h.value)
@@ -160,31 +540,35 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 			);
 		let headers = RequestHeaders { host, origin };
 
-		let ws_key = with_first_header(&request.headers, "Sec-WebSocket-Key", |k| {
+		let ws_key = with_first_header(request_headers, "Sec-WebSocket-Key", |k| {
 			WebSocketKey::try_from(k).map_err(|_| Error::SecWebSocketKeyInvalidLength(k.len()))
 		})?;
 
-		for h in request.headers.iter().filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS)) {
-			configure_extensions(&mut self.extensions, std::str::from_utf8(h.value)?)?
+		let mut offered = Vec::new();
+		for h in request_headers.iter().filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL)) {
+			let Ok(value) = str::from_utf8(h.value) else {
+				log::debug!("ignoring non-utf-8 {} header", SEC_WEBSOCKET_PROTOCOL);
+				continue;
+			};
+			offered.extend(value.split(',').map(str::trim).filter(|p| !p.is_empty()))
 		}
 
-		let mut protocols = Vec::new();
-		for p in request.headers.iter().filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL)) {
-			if let Some(&p) = self.protocols.iter().find(|x| x.as_bytes() == p.value) {
-				protocols.push(p)
-			}
-		}
+		let protocols = if let Some(select) = selector {
+			(select.0)(&offered).into_iter().collect()
+		} else {
+			offered.into_iter().filter(|&p| supported.iter().any(|x| x == p)).collect()
+		};
 
-		let path = request.path.unwrap_or("/");
+		let path = path.unwrap_or("/");
 
-		Ok(ClientRequest { ws_key, protocols, path, headers })
+		Ok(ClientRequest { ws_key, protocols, path, headers, all_headers })
 	}
 
 	// Encode server handshake response.
 	fn encode_response(&mut self, response: &Response<'_>) {
 		match response {
 			Response::Accept { key, protocol } => {
-				let accept_value = super::generate_accept_key(&key);
+				let accept_value = super::generate_accept_key(key);
 				self.buffer.extend_from_slice(
 					concat![
 						"HTTP/1.1 101 Switching Protocols",
@@ -202,20 +586,48 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 					self.buffer.extend_from_slice(p.as_bytes())
 				}
 				append_extensions(self.extensions.iter().filter(|e| e.is_enabled()), &mut self.buffer);
+				for (name, value) in &self.response_headers {
+					self.buffer.extend_from_slice(b"\r\n");
+					self.buffer.extend_from_slice(name.as_bytes());
+					self.buffer.extend_from_slice(b": ");
+					self.buffer.extend_from_slice(value)
+				}
 				self.buffer.extend_from_slice(b"\r\n\r\n")
 			}
 			Response::Reject { status_code } => {
-				self.buffer.extend_from_slice(b"HTTP/1.1 ");
-				let (_, reason) = if let Ok(i) = STATUSCODES.binary_search_by_key(status_code, |(n, _)| *n) {
-					STATUSCODES[i]
-				} else {
-					(500, "500 Internal Server Error")
-				};
-				self.buffer.extend_from_slice(reason.as_bytes());
+				self.encode_status_line(*status_code);
 				self.buffer.extend_from_slice(b"\r\n\r\n")
 			}
+			Response::RejectWith { status_code, headers, body } => {
+				self.encode_status_line(*status_code);
+				for h in headers.iter() {
+					self.buffer.extend_from_slice(b"\r\n");
+					self.buffer.extend_from_slice(h.name.as_bytes());
+					self.buffer.extend_from_slice(b": ");
+					self.buffer.extend_from_slice(h.value)
+				}
+				self.buffer.extend_from_slice(format!("\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+				self.buffer.extend_from_slice(body)
+			}
+			Response::Redirect { status_code, location } => {
+				self.encode_status_line(*status_code);
+				self.buffer.extend_from_slice(b"\r\nLocation: ");
+				self.buffer.extend_from_slice(location.as_bytes());
+				self.buffer.extend_from_slice(b"\r\nContent-Length: 0\r\n\r\n")
+			}
 		}
 	}
+
+	// Encode the status line of a rejection response.
+	fn encode_status_line(&mut self, status_code: u16) {
+		self.buffer.extend_from_slice(b"HTTP/1.1 ");
+		let (_, reason) = if let Ok(i) = STATUSCODES.binary_search_by_key(&status_code, |(n, _)| *n) {
+			STATUSCODES[i]
+		} else {
+			(500, "500 Internal Server Error")
+		};
+		self.buffer.extend_from_slice(reason.as_bytes())
+	}
 }
 
 /// Handshake request received from the client.
@@ -225,6 +637,7 @@ pub struct ClientRequest<'a> {
 	protocols: Vec<&'a str>,
 	path: &'a str,
 	headers: RequestHeaders<'a>,
+	all_headers: Vec<Header<'a>>,
 }
 
 /// Select HTTP headers sent by the client.
@@ -252,10 +665,83 @@ impl<'a> ClientRequest<'a> {
 		self.path
 	}
 
+	/// The path the client is requesting, split into path and query.
+	pub fn target(&self) -> RequestTarget<'a> {
+		RequestTarget::new(self.path)
+	}
+
 	/// Select HTTP headers sent by the client.
-	pub fn headers(&self) -> RequestHeaders {
+	pub fn headers(&self) -> RequestHeaders<'_> {
 		self.headers
 	}
+
+	/// The value of the first HTTP header with the given (case-insensitive) name.
+	pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+		self.all_headers.iter().find(|h| h.name.eq_ignore_ascii_case(name)).map(|h| h.value)
+	}
+
+	/// All HTTP headers sent by the client, in the order they were received.
+	pub fn all_headers(&self) -> &[Header<'a>] {
+		&self.all_headers
+	}
+}
+
+/// The target of a handshake request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RequestTarget<'a> {
+	path: &'a str,
+	query: Option<&'a str>,
+}
+
+impl<'a> RequestTarget<'a> {
+	fn new(target: &'a str) -> Self {
+		match target.split_once('?') {
+			Some((path, query)) => RequestTarget { path, query: Some(query) },
+			None => RequestTarget { path: target, query: None },
+		}
+	}
+
+	/// The path, without the query.
+	pub fn path(&self) -> &'a str {
+		self.path
+	}
+
+	/// The query, i.e. everything after the first `?`, if any.
+	pub fn query(&self) -> Option<&'a str> {
+		self.query
+	}
+}
+
+/// The handshake configuration of a request target, see [`Server::set_router`].
+#[derive(Debug, Default)]
+pub struct Route<'a> {
+	protocols: Vec<Cow<'a, str>>,
+	extensions: Vec<Box<dyn Extension + Send>>,
+	rejection: Option<u16>,
+}
+
+impl<'a> Route<'a> {
+	/// Create a route accepting requests.
+	pub fn new() -> Self {
+		Route::default()
+	}
+
+	/// Create a route rejecting requests with the given HTTP status code.
+	pub fn reject(status_code: u16) -> Self {
+		Route { rejection: Some(status_code), ..Route::default() }
+	}
+
+	/// Add a protocol supported on this route.
+	pub fn add_protocol(&mut self, p: impl Into<Cow<'a, str>>) -> &mut Self {
+		self.protocols.push(p.into());
+		self
+	}
+
+	/// Add an extension supported on this route.
+	pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
+		self.extensions.push(e);
+		self
+	}
 }
 
 /// Handshake response the server sends back to the client.
@@ -265,6 +751,17 @@ pub enum Response<'a> {
 	Accept { key: WebSocketKey, protocol: Option<&'a str> },
 	/// The server rejects the handshake request.
 	Reject { status_code: u16 },
+	/// The server rejects the handshake request with additional headers and a body.
+	///
+	/// The headers are not checked for validity. A `Content-Length` header is added
+	/// automatically.
+	RejectWith { status_code: u16, headers: &'a [Header<'a>], body: &'a [u8] },
+	/// The server redirects the client to another location.
+	///
+	/// The status code should be one of 301, 302, 303, 307 or 308, with 307 and
+	/// 308 keeping the request method. The location is usually a `ws` or `wss`
+	/// URL or an absolute path.
+	Redirect { status_code: u16, location: &'a str },
 }
 
 /// Known status codes and their reason phrases.
@@ -330,3 +827,282 @@ const STATUSCODES: &[(u16, &str)] = &[
 	(510, "510 Not Extended"),
 	(511, "511 Network Authentication Required"),
 ];
+
+#[cfg(test)]
+mod tests {
+	use super::Server;
+	use futures::io::Cursor;
+
+	fn request(protocols: &str) -> Cursor<Vec<u8>> {
+		let req = format!(
+			"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+			Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+			protocols
+		);
+		Cursor::new(req.into_bytes())
+	}
+
+	#[tokio::test]
+	async fn static_protocols() {
+		let mut server = Server::new(request("Sec-WebSocket-Protocol: foo, bar\r\n"));
+		server.add_protocol("baz").add_protocol(String::from("bar"));
+		let req = server.receive_request().await.unwrap();
+		assert_eq!(vec!["bar"], req.protocols().collect::<Vec<_>>());
+
+		// Header values which are not UTF-8 are ignored.
+		let mut req = request("Sec-WebSocket-Protocol: bar\r\n").into_inner();
+		let at = req.len() - 2;
+		req.splice(at..at, b"Sec-WebSocket-Protocol: \xff\r\n".iter().copied());
+		let mut server = Server::new(Cursor::new(req));
+		server.add_protocol("bar");
+		let req = server.receive_request().await.unwrap();
+		assert_eq!(vec!["bar"], req.protocols().collect::<Vec<_>>());
+	}
+
+	#[tokio::test]
+	async fn protocol_selector() {
+		let mut server = Server::new(request("Sec-WebSocket-Protocol: v1.chat\r\nSec-WebSocket-Protocol: v2.chat\r\n"));
+		server.add_protocol("v1.chat");
+		server.set_protocol_selector(|offered| offered.iter().copied().find(|p| p.starts_with("v2.")));
+		let req = server.receive_request().await.unwrap();
+		assert_eq!(vec!["v2.chat"], req.protocols().collect::<Vec<_>>());
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn extension_factory() {
+		use crate::{connection::Mode, extension::deflate::Deflate};
+		use std::sync::atomic::{AtomicUsize, Ordering};
+
+		let calls = AtomicUsize::new(0);
+		let factory = || {
+			calls.fetch_add(1, Ordering::SeqCst);
+			Box::new(Deflate::new(Mode::Server)) as Box<_>
+		};
+		for _ in 0..2 {
+			let mut server = Server::new(request("Sec-WebSocket-Extensions: permessage-deflate\r\n"));
+			server.add_extension_with(factory);
+			let before = calls.load(Ordering::SeqCst);
+			server.receive_request().await.unwrap();
+			assert_eq!(before + 1, calls.load(Ordering::SeqCst));
+			assert!(server.drain_extensions().all(|e| e.is_enabled()));
+		}
+	}
+
+	#[tokio::test]
+	async fn async_extension_configure() {
+		use crate::extension::TestExtension;
+
+		let mut server = Server::new(request("Sec-WebSocket-Extensions: x-keyed; id=7\r\n"));
+		server.add_extension(Box::new(TestExtension { name: "x-keyed", async_only: true, ..TestExtension::default() }));
+		server.receive_request().await.unwrap();
+		let extension = server.drain_extensions().next().unwrap();
+		assert!(extension.is_enabled());
+		assert!(format!("{:?}", extension).contains("\"7\""));
+	}
+
+	#[tokio::test]
+	async fn access_control() {
+		use crate::handshake::{access_control::AllowPattern, Error};
+
+		let origins: AllowPattern = ["https://*.example.com"].into_iter().collect();
+		let mut server = Server::new(request("Origin: https://app.example.com\r\n"));
+		server.set_origins(origins.clone()).set_hosts(|h: &[u8]| h == b"localhost");
+		assert!(server.receive_request().await.is_ok());
+
+		let mut server = Server::new(request("Origin: https://example.org\r\n"));
+		server.set_origins(origins.clone());
+		assert!(matches!(server.receive_request().await, Err(Error::Forbidden(h)) if h == "Origin"));
+		assert!(server.into_inner().into_inner().ends_with(b"HTTP/1.1 403 Forbidden\r\n\r\n"));
+
+		let mut server = Server::new(request(""));
+		server.set_origins(origins).set_hosts(|h: &[u8]| h == b"example.com");
+		assert!(matches!(server.receive_request().await, Err(Error::Forbidden(h)) if h == "Host"));
+		assert!(server.into_inner().into_inner().ends_with(b"HTTP/1.1 403 Forbidden\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn async_access_control() {
+		use crate::handshake::{access_control::AsyncPolicy, Error};
+		use futures::future::{BoxFuture, FutureExt};
+
+		struct Lookup;
+
+		impl AsyncPolicy for Lookup {
+			fn is_allowed<'a>(&'a self, value: &'a [u8]) -> BoxFuture<'a, bool> {
+				async move {
+					tokio::task::yield_now().await;
+					value == b"https://allowed.example.com"
+				}
+				.boxed()
+			}
+		}
+
+		let mut server = Server::new(request("Origin: https://allowed.example.com\r\n"));
+		server.set_origins(Lookup);
+		assert!(server.receive_request().await.is_ok());
+
+		let mut server = Server::new(request("Origin: https://denied.example.com\r\n"));
+		server.set_origins(Lookup);
+		assert!(matches!(server.receive_request().await, Err(Error::Forbidden(h)) if h == "Origin"));
+		assert!(server.into_inner().into_inner().ends_with(b"HTTP/1.1 403 Forbidden\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn rate_limit() {
+		use crate::handshake::{rate_limit::RateLimiter, Error};
+		use std::time::Duration;
+
+		let limiter = RateLimiter::new(1, Duration::from_secs(60));
+		let mut server = Server::new(request(""));
+		server.set_rate_limiter(&limiter, "client");
+		assert!(server.receive_request().await.is_ok());
+
+		let mut server = Server::new(request(""));
+		server.set_rate_limiter(&limiter, "client");
+		assert!(matches!(server.receive_request().await, Err(Error::RateLimited)));
+		let written = server.into_inner().into_inner();
+		assert!(written.ends_with(b"HTTP/1.1 429 Too Many Requests\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn strict_key() {
+		use crate::handshake::Error;
+
+		let mut server = Server::new(request(""));
+		server.set_strict_key(true);
+		assert!(server.receive_request().await.is_ok());
+
+		// 24 bytes long, but 18 bytes when decoded.
+		let invalid = String::from_utf8(request("").into_inner())
+			.unwrap()
+			.replace("dGhlIHNhbXBsZSBub25jZQ==", "dGhlIHNhbXBsZSBub25jZQAA");
+		let mut server = Server::new(Cursor::new(invalid.clone().into_bytes()));
+		assert!(server.receive_request().await.is_ok());
+
+		let mut server = Server::new(Cursor::new(invalid.into_bytes()));
+		server.set_strict_key(true);
+		assert!(matches!(server.receive_request().await, Err(Error::SecWebSocketKeyInvalid)));
+		let written = server.into_inner().into_inner();
+		assert!(written.ends_with(b"HTTP/1.1 400 Bad Request\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn timeout() {
+		use crate::handshake::Error;
+		use std::time::Duration;
+		use tokio::io::AsyncWriteExt;
+		use tokio_util::compat::TokioAsyncReadCompatExt;
+
+		let (mut client, server) = tokio::io::duplex(1024);
+		client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").await.unwrap();
+		let mut server = Server::new(server.compat());
+		server.set_timeout(Duration::from_millis(50));
+		assert!(matches!(server.receive_request().await, Err(Error::Timeout)));
+	}
+
+	#[tokio::test]
+	async fn router() {
+		use super::Route;
+		use crate::handshake::Error;
+
+		let route = |target: &super::RequestTarget<'_>| {
+			let mut route = Route::new();
+			match target.path() {
+				"/ws/v1" => route.add_protocol("chat.v1"),
+				"/ws/v2" => route.add_protocol("chat.v2"),
+				_ => return Route::reject(404),
+			};
+			route
+		};
+		let request = |path: &str| {
+			let req = request("Sec-WebSocket-Protocol: chat.v1, chat.v2\r\n").into_inner();
+			Cursor::new([path.as_bytes(), &req[5..]].concat())
+		};
+
+		let mut server = Server::new(request("GET /ws/v2?token=1"));
+		server.set_router(route);
+		let req = server.receive_request().await.unwrap();
+		assert_eq!(vec!["chat.v2"], req.protocols().collect::<Vec<_>>());
+		assert_eq!(("/ws/v2", Some("token=1")), (req.target().path(), req.target().query()));
+
+		let mut server = Server::new(request("GET /ws/v3"));
+		server.set_router(route);
+		assert!(matches!(server.receive_request().await, Err(Error::RouteRejected(p)) if p == "/ws/v3"));
+		assert!(server.into_inner().into_inner().ends_with(b"HTTP/1.1 404 Not Found\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn response_headers() {
+		use super::Response;
+
+		let mut server = Server::new(request(""));
+		server.add_response_header("X-Request-Id", "42").add_response_header("Set-Cookie", b"a=1");
+		let key = server.receive_request().await.unwrap().key();
+		server.send_response(&Response::Accept { key, protocol: None }).await.unwrap();
+		let written = server.into_inner().into_inner();
+		assert!(written.ends_with(b"\r\nX-Request-Id: 42\r\nSet-Cookie: a=1\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn header_limits() {
+		use crate::handshake::Error;
+
+		let baggage = (0..40).map(|i| format!("X-Baggage-{}: {}\r\n", i, i)).collect::<String>();
+		let mut server = Server::new(request(&baggage));
+		assert!(matches!(server.receive_request().await, Err(Error::Http(_))));
+
+		let mut server = Server::new(request(&baggage));
+		server.set_header_limits(64, 4096);
+		assert!(server.receive_request().await.is_ok());
+
+		let mut server = Server::new(request(&baggage));
+		server.set_header_limits(64, 512);
+		assert!(matches!(server.receive_request().await, Err(Error::HeadersTooLarge)));
+
+		let mut server = Server::new(request(&baggage));
+		server.set_header_limits(64, 4096).set_max_buffer_size(256);
+		assert!(matches!(server.receive_request().await, Err(Error::HandshakeTooLarge)));
+	}
+
+	#[tokio::test]
+	async fn reject_with() {
+		use super::{Header, Response};
+
+		let mut server = Server::new(request(""));
+		server.receive_request().await.unwrap();
+		let headers = [Header { name: "WWW-Authenticate", value: b"Bearer" }];
+		let response = Response::RejectWith { status_code: 401, headers: &headers, body: b"{}" };
+		server.send_response(&response).await.unwrap();
+		let written = server.into_inner().into_inner();
+		assert!(
+			written.ends_with(b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 2\r\n\r\n{}")
+		);
+	}
+
+	#[tokio::test]
+	async fn redirect() {
+		use super::Response;
+
+		let mut server = Server::new(request(""));
+		server.receive_request().await.unwrap();
+		server
+			.send_response(&Response::Redirect { status_code: 307, location: "wss://shard-2.example.com/" })
+			.await
+			.unwrap();
+		let written = server.into_inner().into_inner();
+		assert!(written.ends_with(
+			b"HTTP/1.1 307 Temporary Redirect\r\nLocation: wss://shard-2.example.com/\r\nContent-Length: 0\r\n\r\n"
+		));
+	}
+
+	#[tokio::test]
+	async fn all_headers() {
+		let mut server = Server::new(request("Authorization: Bearer abc\r\nX-Trace-Id: 42\r\n"));
+		let req = server.receive_request().await.unwrap();
+		assert_eq!(Some(&b"Bearer abc"[..]), req.header("authorization"));
+		assert_eq!(Some(&b"42"[..]), req.header("X-Trace-Id"));
+		assert_eq!(None, req.header("Cookie"));
+		assert_eq!(7, req.all_headers().len());
+	}
+}
@@ -106,6 +106,17 @@
 //! See `examples/hyper_server.rs` from this crate's repository for an example of
 //! starting up a WebSocket server alongside an Hyper HTTP server.
 //!
+//! # Metrics
+//!
+//! With the `metrics` feature, the following metrics are emitted through the
+//! [`metrics`](https://docs.rs/metrics) facade:
+//!
+//! - `soketto_handshakes_total` (counter, labels `side` and `outcome`)
+//! - `soketto_message_size_bytes` (histogram, label `direction`)
+//! - `soketto_compression_ratio` (histogram of the encoded to the original message
+//!   size of messages to which extensions were applied, label `direction`)
+//! - `soketto_close_total` (counter, labels `direction` and `code`)
+//!
 //! [client]: handshake::Client
 //! [server]: handshake::Server
 //! [Sender]: connection::Sender
@@ -113,20 +124,37 @@
 //! [rfc6455]: https://tools.ietf.org/html/rfc6455
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
-#![forbid(unsafe_code)]
+#![deny(unsafe_code)]
 
 pub mod base;
+#[cfg(feature = "tls")]
+pub mod client;
 pub mod connection;
 pub mod data;
 pub mod extension;
 pub mod handshake;
+pub mod owned;
+pub mod proto;
+pub mod proxy;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(all(unix, feature = "unix"))]
+pub mod unix;
+#[cfg(feature = "hyper")]
+pub mod upgrade;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 use bytes::BytesMut;
-use futures::io::{AsyncRead, AsyncReadExt};
-use std::io;
+use futures::{future, io::AsyncRead};
+use std::{io, pin::Pin, task::Poll};
 
+#[cfg(feature = "client-connect")]
+pub use client::connect;
+#[cfg(feature = "tls")]
+pub use client::connect_tls;
 pub use connection::{Mode, Receiver, Sender};
-pub use data::{Data, Incoming};
+pub use data::{Data, Incoming, Message};
 
 pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -173,14 +201,21 @@ const fn as_u64(a: usize) -> u64 {
 }
 
 /// Fill the buffer from the given `AsyncRead` impl with up to `max` bytes.
+///
+/// The buffer only ever contains the bytes read, so the future may be
+/// dropped before completion without leaving garbage behind.
 async fn read<R>(reader: &mut R, dest: &mut BytesMut, max: usize) -> io::Result<()>
 where
 	R: AsyncRead + Unpin,
 {
-	let i = dest.len();
-	dest.resize(i + max, 0u8);
-	let n = reader.read(&mut dest[i..]).await?;
-	dest.truncate(i + n);
+	let n = future::poll_fn(|cx| {
+		let i = dest.len();
+		dest.resize(i + max, 0u8);
+		let result = Pin::new(&mut *reader).poll_read(cx, &mut dest[i..]);
+		dest.truncate(i + if let Poll::Ready(Ok(n)) = result { n } else { 0 });
+		result
+	})
+	.await?;
 	if n == 0 {
 		return Err(io::ErrorKind::UnexpectedEof.into());
 	}
@@ -0,0 +1,358 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Websocket connections over transports which take ownership of buffers.
+//!
+//! Completion-based runtimes like `tokio-uring` or `monoio` hand buffers to
+//! the kernel for the duration of an I/O operation, so they cannot implement
+//! `AsyncRead` and `AsyncWrite`, which borrow them. A [`Transport`] instead
+//! takes an owned buffer and returns it once the operation has completed, and
+//! a [`Connection`] drives the websocket protocol over it.
+//!
+//! ```no_run
+//! use futures::future::LocalBoxFuture;
+//! use soketto::{connection::Mode, owned::{Connection, Transport}};
+//! use std::io;
+//!
+//! struct Socket; // e.g. a `tokio_uring::net::TcpStream`
+//!
+//! impl Transport for Socket {
+//!     fn read(&mut self, buf: Vec<u8>) -> LocalBoxFuture<'_, (io::Result<usize>, Vec<u8>)> {
+//!         unimplemented!("read into the spare capacity of `buf`")
+//!     }
+//!
+//!     fn write(&mut self, buf: Vec<u8>) -> LocalBoxFuture<'_, (io::Result<usize>, Vec<u8>)> {
+//!         unimplemented!("write the bytes of `buf`")
+//!     }
+//! }
+//!
+//! # async fn doc() -> Result<(), soketto::connection::Error> {
+//! let mut connection = Connection::new(Socket, Mode::Client);
+//! connection.send_text("hello").await?;
+//! connection.flush().await?;
+//! let mut message = Vec::new();
+//! connection.receive_data(&mut message).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+	base::{self, Header, OpCode},
+	connection::{check_reserved, CloseCode, Error, Mode},
+	data::{Data, Incoming},
+	extension::Extension,
+	proto::{self, Decoder, Encoder, Event},
+	Storage,
+};
+use futures::future::LocalBoxFuture;
+use std::{fmt, io};
+
+/// Max. number of bytes read from the transport at once.
+const MAX_READ_SIZE: usize = 64 * 1024;
+
+/// A transport which takes ownership of the buffers passed to it.
+pub trait Transport {
+	/// Read bytes into the spare capacity of `buf`, i.e. after its current
+	/// length, and return the number of bytes read together with the buffer,
+	/// whose length has been increased accordingly. Zero bytes are read at
+	/// the end of the stream.
+	fn read(&mut self, buf: Vec<u8>) -> LocalBoxFuture<'_, (io::Result<usize>, Vec<u8>)>;
+
+	/// Write bytes of `buf` and return the number of bytes written together with the buffer.
+	fn write(&mut self, buf: Vec<u8>) -> LocalBoxFuture<'_, (io::Result<usize>, Vec<u8>)>;
+}
+
+/// A websocket connection over a [`Transport`].
+///
+/// Unlike [`connection::Sender`](crate::connection::Sender) and
+/// [`connection::Receiver`](crate::connection::Receiver), sending and
+/// receiving is done through the same value, as completion-based runtimes
+/// usually run each connection on a single thread. Frames are queued until
+/// [`Connection::flush`] is called.
+pub struct Connection<T> {
+	transport: T,
+	decoder: Decoder,
+	encoder: Encoder,
+	extensions: Vec<Box<dyn Extension + Send>>,
+	/// Passed to the transport for reading.
+	read_buffer: Vec<u8>,
+	/// Frames queued for writing.
+	write_buffer: Vec<u8>,
+}
+
+impl<T> fmt::Debug for Connection<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Connection")
+			.field("decoder", &self.decoder)
+			.field("encoder", &self.encoder)
+			.field("queued", &self.write_buffer.len())
+			.finish()
+	}
+}
+
+impl<T: Transport> Connection<T> {
+	/// Create a connection over the given transport after a successful handshake.
+	pub fn new(transport: T, mode: Mode) -> Self {
+		let mut decoder = Decoder::new(base::Codec::default());
+		if mode.is_server() {
+			decoder.set_unmasked(proto::Unmasked::Reject);
+		}
+		Connection {
+			transport,
+			decoder,
+			encoder: Encoder::new(mode, base::Codec::default()),
+			extensions: Vec::new(),
+			read_buffer: Vec::new(),
+			write_buffer: Vec::new(),
+		}
+	}
+
+	/// Add extensions to use with this connection.
+	///
+	/// Only enabled extensions will be considered. Unlike with
+	/// [`Builder::add_extensions`](crate::connection::Builder::add_extensions),
+	/// conflicts between extensions are not checked.
+	pub fn add_extensions<I>(&mut self, extensions: I)
+	where
+		I: IntoIterator<Item = Box<dyn Extension + Send>>,
+	{
+		for e in extensions.into_iter().filter(|e| e.is_enabled()) {
+			let codec = self.decoder.codec_mut();
+			codec.add_reserved_bits(e.reserved_bits());
+			if let Some(opcode) = e.reserved_opcode() {
+				codec.add_reserved_opcode(opcode);
+			}
+			self.extensions.push(e)
+		}
+	}
+
+	/// Access the decoder, e.g. to configure limits.
+	pub fn decoder_mut(&mut self) -> &mut Decoder {
+		&mut self.decoder
+	}
+
+	/// Queue a text message.
+	pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
+		self.send(OpCode::Text, &mut Storage::Shared(data.as_ref().as_bytes()))
+	}
+
+	/// Queue a binary message.
+	pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		self.send(OpCode::Binary, &mut Storage::Shared(data.as_ref()))
+	}
+
+	/// Queue a frame with a reserved opcode.
+	///
+	/// The opcode must be used by one of the extensions, see
+	/// [`Sender::send_reserved`](crate::connection::Sender::send_reserved).
+	pub async fn send_reserved(&mut self, opcode: OpCode, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		check_reserved(self.decoder.codec(), opcode, data.as_ref().len())?;
+		self.send(opcode, &mut Storage::Shared(data.as_ref()))
+	}
+
+	/// Write all queued frames to the transport.
+	pub async fn flush(&mut self) -> Result<(), Error> {
+		while !self.write_buffer.is_empty() {
+			let buffer = std::mem::take(&mut self.write_buffer);
+			let (result, mut buffer) = self.transport.write(buffer).await;
+			match result {
+				Ok(0) => return Err(Error::Io(io::ErrorKind::WriteZero.into())),
+				Ok(n) => {
+					buffer.drain(..n);
+					self.write_buffer = buffer
+				}
+				Err(e) => return Err(Error::Io(e)),
+			}
+		}
+		Ok(())
+	}
+
+	/// Send a close message and flush.
+	pub async fn close(&mut self) -> Result<(), Error> {
+		self.queue(
+			&mut Header::new(OpCode::Close),
+			&mut Storage::Shared(&u16::from(CloseCode::NormalClosure).to_be_bytes()),
+		);
+		self.flush().await
+	}
+
+	/// Receive the next websocket message.
+	///
+	/// The message is appended to `message`, see
+	/// [`connection::Receiver::receive`](crate::connection::Receiver::receive).
+	/// PINGs and close messages are answered and the answers are flushed.
+	pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
+		loop {
+			let event = match self.decoder.decode(message, &mut self.extensions)? {
+				Event::Decode { mut header, opcode } => {
+					let decoded = proto::decode_with_extensions(&mut self.extensions, &mut header, message);
+					self.decoder.finish_message(opcode, message, decoded)?
+				}
+				event => event,
+			};
+			match event {
+				Event::NeedMore(n) => self.read(std::cmp::min(n, MAX_READ_SIZE)).await?,
+				Event::Frame(header) => log::trace!("recv: {}", header),
+				Event::Decode { .. } => unreachable!("extensions are applied above; qed"),
+				Event::Message { opcode, len, .. } => {
+					return match opcode {
+						OpCode::Text => Ok(Incoming::Data(Data::Text(len))),
+						oc if oc.is_reserved() => Ok(Incoming::Reserved(oc, len)),
+						_ => Ok(Incoming::Data(Data::Binary(len))),
+					}
+				}
+				Event::Fragment { opcode, fin, len } => {
+					return Ok(Incoming::Fragment { first_opcode: opcode, fin, len })
+				}
+				Event::Ping => {
+					let payload = self.decoder.control_payload().to_vec();
+					self.queue(&mut Header::new(OpCode::Pong), &mut Storage::Owned(payload));
+					self.flush().await?
+				}
+				Event::Pong => return Ok(Incoming::Pong(self.decoder.control_payload())),
+				Event::Closed(reason) => {
+					let code = reason.as_ref().map(|r| u16::from(r.code).to_be_bytes());
+					let payload = code.as_ref().map_or(&[][..], |c| &c[..]);
+					self.queue(&mut Header::new(OpCode::Close), &mut Storage::Shared(payload));
+					self.flush().await?;
+					match reason {
+						Some(reason) => return Ok(Incoming::Closed(reason)),
+						None => return Err(Error::Closed),
+					}
+				}
+			}
+		}
+	}
+
+	/// Receive the next websocket message, skipping over control frames
+	/// and frames with reserved opcodes.
+	pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
+		let message_len = message.len();
+		loop {
+			match self.receive(message).await? {
+				Incoming::Data(d) => return Ok(d),
+				Incoming::Reserved(..) => message.truncate(message_len),
+				Incoming::Fragment { first_opcode, fin: true, .. } => {
+					let len = message.len() - message_len;
+					match first_opcode {
+						OpCode::Text => return Ok(Data::Text(len)),
+						OpCode::Binary => return Ok(Data::Binary(len)),
+						_ => message.truncate(message_len),
+					}
+				}
+				Incoming::Pong(_) | Incoming::Closed(_) | Incoming::Fragment { .. } => (),
+			}
+		}
+	}
+
+	/// Apply extensions to a message and queue it.
+	fn send(&mut self, opcode: OpCode, data: &mut Storage<'_>) -> Result<(), Error> {
+		let mut header = Header::new(opcode);
+		proto::encode_with_extensions(&mut self.extensions, &mut header, data)?;
+		self.decoder.codec().check_reserved_bits(&header)?;
+		self.queue(&mut header, data);
+		Ok(())
+	}
+
+	/// Queue a frame.
+	fn queue(&mut self, header: &mut Header, data: &mut Storage<'_>) {
+		let (header_bytes, payload) = self.encoder.encode_frame(header, data);
+		log::trace!("send: {}", header);
+		self.write_buffer.extend_from_slice(header_bytes);
+		self.write_buffer.extend_from_slice(payload)
+	}
+
+	/// Read up to `n` bytes into the decoder's buffer.
+	async fn read(&mut self, n: usize) -> Result<(), Error> {
+		let mut buffer = std::mem::take(&mut self.read_buffer);
+		buffer.clear();
+		buffer.reserve_exact(n);
+		let (result, buffer) = self.transport.read(buffer).await;
+		let n = result?;
+		if n == 0 {
+			return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+		}
+		self.decoder.buffer_mut().extend_from_slice(&buffer);
+		self.read_buffer = buffer;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Connection, Transport};
+	use crate::{
+		connection::{Builder, Mode},
+		Data,
+	};
+	use futures::future::LocalBoxFuture;
+	use std::io;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+	use tokio_util::compat::TokioAsyncReadCompatExt;
+
+	/// Passes owned buffers to a borrowing stream.
+	struct Owned(DuplexStream);
+
+	impl Transport for Owned {
+		fn read(&mut self, mut buf: Vec<u8>) -> LocalBoxFuture<'_, (io::Result<usize>, Vec<u8>)> {
+			Box::pin(async move {
+				let result = self.0.read_buf(&mut buf).await;
+				(result, buf)
+			})
+		}
+
+		fn write(&mut self, buf: Vec<u8>) -> LocalBoxFuture<'_, (io::Result<usize>, Vec<u8>)> {
+			Box::pin(async move {
+				let result = self.0.write(&buf).await;
+				(result, buf)
+			})
+		}
+	}
+
+	#[tokio::test]
+	async fn echo() {
+		let (client, server) = tokio::io::duplex(16);
+		let mut connection = Connection::new(Owned(client), Mode::Client);
+		let (mut sender, mut receiver) = Builder::new(server.compat(), Mode::Server).finish();
+
+		let server = async {
+			let mut message = Vec::new();
+			receiver.receive_data(&mut message).await.unwrap();
+			sender.send_binary(&message).await.unwrap();
+			sender.flush().await.unwrap();
+		};
+		let client = async {
+			connection.send_binary([7; 100]).await.unwrap();
+			connection.flush().await.unwrap();
+			let mut message = Vec::new();
+			assert_eq!(Data::Binary(100), connection.receive_data(&mut message).await.unwrap());
+			message
+		};
+		let ((), message) = tokio::join!(server, client);
+		assert_eq!([7; 100], &message[..]);
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[tokio::test]
+	async fn compressed() {
+		use crate::connection::tests::deflate;
+
+		let (client, server) = tokio::io::duplex(1024);
+		let mut connection = Connection::new(Owned(client), Mode::Client);
+		connection.add_extensions(Some(Box::new(deflate(Mode::Client)) as Box<_>));
+		let mut server = Builder::new(server.compat(), Mode::Server);
+		server.add_extensions(Some(Box::new(deflate(Mode::Server)) as Box<_>));
+		let (_, mut receiver) = server.finish();
+
+		connection.send_text("hello hello hello").await.unwrap();
+		connection.flush().await.unwrap();
+		let mut message = Vec::new();
+		assert_eq!(Data::Text(17), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hello hello hello", &message[..]);
+	}
+}
@@ -0,0 +1,1279 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The websocket protocol without I/O.
+//!
+//! A [`Decoder`] is fed the bytes read from the socket and turns them into
+//! [`Event`]s, reassembling fragmented messages and applying extensions. An
+//! [`Encoder`] turns messages into frames ready to be written to the socket.
+//! Neither performs any I/O or depends on an async runtime, so they can be
+//! driven by custom event loops. The async [`connection`](crate::connection)
+//! is built on top of them. They do use `std` (e.g. for the random masks and
+//! [`MemoryLimiter`]), so this module is not available in `no_std` builds.
+//!
+//! ```
+//! use soketto::{base::{Header, OpCode}, connection::Mode, proto::{Decoder, Encoder, Event}, Storage};
+//!
+//! let mut encoder = Encoder::new(Mode::Client, Default::default());
+//! let mut decoder = Decoder::new(Default::default());
+//!
+//! let mut data = Storage::Shared(b"hello");
+//! let (header, payload) = encoder.encode_frame(&mut Header::new(OpCode::Text), &mut data);
+//! decoder.buffer_mut().extend_from_slice(header);
+//! decoder.buffer_mut().extend_from_slice(payload);
+//!
+//! let mut message = Vec::new();
+//! assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+//! assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Message { opcode: OpCode::Text, len: 5, .. })));
+//! assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::NeedMore(_))));
+//! assert_eq!(b"hello", &message[..]);
+//! ```
+
+use crate::{
+	base::{self, Header, OpCode},
+	connection::{CloseCode, CloseReason, Error, Mode},
+	extension::Extension,
+	Parsing, Storage,
+};
+use bytes::{Buf, BytesMut};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::{
+	borrow::Cow,
+	fmt, io,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+};
+
+/// A source of masking keys which may be shared by several [`Encoder`]s.
+#[derive(Clone)]
+pub struct MaskRng(Arc<Mutex<dyn RngCore + Send>>);
+
+impl MaskRng {
+	/// Use the given random number generator.
+	pub fn new<R: RngCore + Send + 'static>(rng: R) -> Self {
+		MaskRng(Arc::new(Mutex::new(rng)))
+	}
+
+	/// Use a deterministic random number generator seeded with the given value.
+	pub fn from_seed(seed: u64) -> Self {
+		MaskRng::new(StdRng::seed_from_u64(seed))
+	}
+
+	fn next_mask(&self) -> u32 {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).next_u32()
+	}
+}
+
+impl fmt::Debug for MaskRng {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("MaskRng")
+	}
+}
+
+/// A memory budget shared by the [`Decoder`]s of many connections.
+///
+/// The bytes of messages being received, i.e. of their frames received so far
+/// and of the output of extensions like decompression, are counted against
+/// the budget until the message is complete. Messages which would exceed the
+/// budget fail with [`Error::MemoryLimitExceeded`] and are skipped. Clones of
+/// a limiter share the same budget.
+#[derive(Debug, Clone)]
+pub struct MemoryLimiter(Arc<Budget>);
+
+#[derive(Debug)]
+struct Budget {
+	limit: usize,
+	used: AtomicUsize,
+}
+
+impl MemoryLimiter {
+	/// Create a limiter with a budget of `limit` bytes.
+	pub fn new(limit: usize) -> Self {
+		MemoryLimiter(Arc::new(Budget { limit, used: AtomicUsize::new(0) }))
+	}
+
+	/// The budget in bytes.
+	pub fn limit(&self) -> usize {
+		self.0.limit
+	}
+
+	/// The number of bytes currently in use.
+	pub fn used(&self) -> usize {
+		self.0.used.load(Ordering::Relaxed)
+	}
+
+	/// Take `n` bytes from the budget, if available.
+	fn acquire(&self, n: usize) -> bool {
+		let limit = self.0.limit;
+		self.0
+			.used
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| used.checked_add(n).filter(|u| *u <= limit))
+			.is_ok()
+	}
+
+	/// Return `n` bytes to the budget.
+	fn release(&self, n: usize) {
+		self.0.used.fetch_sub(n, Ordering::AcqRel);
+	}
+}
+
+/// Max. size of the payload of a control frame.
+const MAX_CONTROL_SIZE: usize = base::MAX_CTRL_BODY_SIZE as usize;
+
+/// Default max. size of a complete message.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// An event produced by [`Decoder::decode`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Event {
+	/// At least the given number of bytes must be added to [`Decoder::buffer_mut`]
+	/// before decoding can continue.
+	NeedMore(usize),
+	/// A frame header has been decoded.
+	Frame(Header),
+	/// A complete message has been appended to the message buffer.
+	Message {
+		/// The opcode of the message, i.e. of its first frame.
+		opcode: OpCode,
+		/// The length of the message after extensions were applied.
+		len: usize,
+		/// The length of the message payload on the wire.
+		encoded_len: usize,
+	},
+	/// A frame of a text or binary message has been appended to the message buffer.
+	///
+	/// Only produced in fragment mode, see [`Decoder::set_fragment_mode`].
+	Fragment {
+		/// The opcode of the message, i.e. of its first frame.
+		opcode: OpCode,
+		/// Is this the last frame of the message?
+		fin: bool,
+		/// The length of the frame payload.
+		len: usize,
+	},
+	/// A complete message has been appended to the message buffer, but
+	/// extensions are still to be applied to it.
+	///
+	/// Only produced if extensions are deferred, see [`Decoder::set_defer_extensions`].
+	/// The extensions must be applied with the given header before calling
+	/// [`Decoder::finish_message`], also if they fail.
+	Decode {
+		/// The header of the last frame of the message.
+		header: Header,
+		/// The opcode of the message, i.e. of its first frame.
+		opcode: OpCode,
+	},
+	/// A PING has been received, its payload is available from [`Decoder::control_payload`].
+	///
+	/// It must be answered with a PONG carrying the same payload.
+	Ping,
+	/// A PONG has been received, its payload is available from [`Decoder::control_payload`].
+	Pong,
+	/// A CLOSE has been received.
+	///
+	/// It must be answered with a CLOSE carrying the code of the reason, if any.
+	/// No more events are produced afterwards.
+	Closed(Option<CloseReason>),
+}
+
+/// How a [`Decoder`] handles frames without mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unmasked {
+	/// Accept them, as clients do.
+	Accept,
+	/// Accept them, but log a warning the first time.
+	Warn,
+	/// Fail with [`Error::UnmaskedFrame`], as servers are required to.
+	Reject,
+}
+
+/// How a [`Decoder`] handles text messages which are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Policy {
+	/// Fail with [`Error::Utf8`]. No more events are produced afterwards and
+	/// the connection should be closed with code 1007.
+	Strict,
+	/// Replace invalid sequences with U+FFFD, the replacement character.
+	Lossy,
+	/// Do not validate text messages.
+	Skip,
+}
+
+/// How a [`Decoder`] handles control frames with more than 125 bytes of payload data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedControl {
+	/// Fail with [`base::Error::InvalidControlFrameLen`], as required by RFC 6455.
+	Fail,
+	/// Keep the first 125 bytes of the payload and handle the frame as usual.
+	Truncate,
+	/// Skip the frame and return [`Error::ControlFrameTooLarge`], after which
+	/// decoding can continue.
+	Report,
+}
+
+/// The state of a [`Decoder`].
+#[derive(Debug)]
+enum State {
+	/// Expecting a frame header.
+	Header,
+	/// Expecting the payload of a data frame which starts at the given offset of the message buffer.
+	Payload(Header, usize),
+	/// Expecting the payload of a data frame which exceeds the maximum message size,
+	/// of which the given number of bytes is kept, see [`Decoder::set_partial_delivery`].
+	Overflow { header: Header, offset: usize, keep: usize, current: usize },
+	/// Expecting the payload of a control frame, of which bytes beyond the first 125 are skipped.
+	Control(Header),
+	/// Skipping the given number of bytes of a frame which exceeded the maximum message size.
+	Discard(usize),
+	/// A CLOSE has been received.
+	Closed,
+}
+
+/// Decodes frames from a byte buffer into messages and control events.
+#[derive(Debug)]
+pub struct Decoder {
+	codec: base::Codec,
+	buffer: BytesMut,
+	ctrl_buffer: BytesMut,
+	max_message_size: usize,
+	max_fragments: usize,
+	unmasked: Unmasked,
+	utf8_policy: Utf8Policy,
+	oversized_control: OversizedControl,
+	/// Produce an event for every data frame instead of complete messages?
+	fragment_mode: bool,
+	/// Keep the data of oversized messages up to the maximum message size?
+	partial_delivery: bool,
+	/// Replace invalid close codes with 1002?
+	strict_close_codes: bool,
+	/// Leave applying extensions to complete messages to the caller?
+	defer_extensions: bool,
+	state: State,
+	/// The opcode of the first frame of a fragmented message.
+	first_fragment_opcode: Option<OpCode>,
+	/// The offset of the current message in the message buffer.
+	message_start: Option<usize>,
+	/// The offset of the message buffer up to which the current text message is valid UTF-8.
+	validated: usize,
+	/// The accumulated payload length of the current message.
+	length: usize,
+	/// The number of frames of the current message.
+	fragments: usize,
+	limiter: Option<MemoryLimiter>,
+	/// The number of bytes of the current message taken from the limiter's budget.
+	reserved: usize,
+}
+
+impl Decoder {
+	/// Create a new decoder using the given codec.
+	pub fn new(codec: base::Codec) -> Self {
+		Decoder {
+			codec,
+			buffer: BytesMut::new(),
+			ctrl_buffer: BytesMut::new(),
+			max_message_size: MAX_MESSAGE_SIZE,
+			max_fragments: usize::MAX,
+			unmasked: Unmasked::Accept,
+			utf8_policy: Utf8Policy::Strict,
+			oversized_control: OversizedControl::Fail,
+			fragment_mode: false,
+			partial_delivery: false,
+			strict_close_codes: true,
+			defer_extensions: false,
+			state: State::Header,
+			first_fragment_opcode: None,
+			message_start: None,
+			validated: 0,
+			length: 0,
+			fragments: 0,
+			limiter: None,
+			reserved: 0,
+		}
+	}
+
+	/// Get a reference to the codec.
+	pub fn codec(&self) -> &base::Codec {
+		&self.codec
+	}
+
+	/// Get a mutable reference to the codec, e.g. to add the reserved bits of extensions.
+	pub fn codec_mut(&mut self) -> &mut base::Codec {
+		&mut self.codec
+	}
+
+	/// Set the maximum size of a complete message.
+	///
+	/// See [`Builder::set_max_message_size`](crate::connection::Builder::set_max_message_size).
+	pub fn set_max_message_size(&mut self, max: usize) -> &mut Self {
+		self.max_message_size = max;
+		self
+	}
+
+	/// Set the maximum number of frames a message may consist of (unlimited by default).
+	///
+	/// See [`Builder::set_max_fragments`](crate::connection::Builder::set_max_fragments).
+	pub fn set_max_fragments(&mut self, max: usize) -> &mut Self {
+		self.max_fragments = max;
+		self
+	}
+
+	/// Count the bytes of messages being received against the given budget.
+	pub fn set_memory_limiter(&mut self, limiter: MemoryLimiter) -> &mut Self {
+		self.release();
+		self.limiter = Some(limiter);
+		self
+	}
+
+	/// Set how frames without mask are handled (accepted by default).
+	pub fn set_unmasked(&mut self, unmasked: Unmasked) -> &mut Self {
+		self.unmasked = unmasked;
+		self
+	}
+
+	/// Set how text messages which are not valid UTF-8 are handled (strictly by default).
+	pub fn set_utf8_policy(&mut self, policy: Utf8Policy) -> &mut Self {
+		self.utf8_policy = policy;
+		self
+	}
+
+	/// Set how control frames with more than 125 bytes of payload data are
+	/// handled (failing by default).
+	pub fn set_oversized_control(&mut self, policy: OversizedControl) -> &mut Self {
+		self.oversized_control = policy;
+		self.codec.set_allow_oversized_control(policy != OversizedControl::Fail);
+		self
+	}
+
+	/// Produce an [`Event::Fragment`] for every frame of a text or binary
+	/// message instead of an [`Event::Message`] once the message is complete
+	/// (disabled by default).
+	///
+	/// Message size and fragment limits still apply to the message as a whole,
+	/// but extensions are not applied and text fragments are not validated, as
+	/// both require the complete message. This mode should thus not be used
+	/// with extensions which transform the payload.
+	pub fn set_fragment_mode(&mut self, on: bool) -> &mut Self {
+		self.fragment_mode = on;
+		self
+	}
+
+	/// Keep the data of messages exceeding the maximum message size up to that
+	/// size in the message buffer before failing with [`Error::MessageTooLarge`]
+	/// (disabled by default).
+	///
+	/// The data is passed on as received, i.e. extensions are not applied and
+	/// text is not validated. The rest of the message is skipped as usual.
+	pub fn set_partial_delivery(&mut self, on: bool) -> &mut Self {
+		self.partial_delivery = on;
+		self
+	}
+
+	/// Set whether invalid or reserved close codes are replaced with 1002
+	/// (protocol error), as RFC 6455 requires, or passed on (strict by default).
+	pub fn set_strict_close_codes(&mut self, strict: bool) -> &mut Self {
+		self.strict_close_codes = strict;
+		self
+	}
+
+	/// Produce an [`Event::Decode`] for complete messages instead of applying
+	/// the extensions, so that the caller can apply them asynchronously
+	/// (disabled by default).
+	pub fn set_defer_extensions(&mut self, defer: bool) -> &mut Self {
+		self.defer_extensions = defer;
+		self
+	}
+
+	/// Replace the input buffer, e.g. with bytes read after the handshake.
+	pub fn set_buffer(&mut self, b: BytesMut) -> &mut Self {
+		self.buffer = b;
+		self
+	}
+
+	/// The input buffer, to which bytes read from the socket are appended.
+	pub fn buffer_mut(&mut self) -> &mut BytesMut {
+		&mut self.buffer
+	}
+
+	/// The payload of the last PING or PONG.
+	pub fn control_payload(&self) -> &[u8] {
+		&self.ctrl_buffer
+	}
+
+	/// Is a frame being decoded, i.e. has its header but not all of its payload been decoded?
+	pub fn is_in_frame(&self) -> bool {
+		matches!(self.state, State::Payload(..) | State::Overflow { .. } | State::Control(_) | State::Discard(_))
+	}
+
+	/// Are complete text messages guaranteed to be valid UTF-8?
+	///
+	/// This is the case unless validation is skipped with [`Utf8Policy::Skip`]
+	/// or fragment mode is on, see [`Decoder::set_fragment_mode`].
+	pub fn validates_text(&self) -> bool {
+		self.utf8_policy != Utf8Policy::Skip && !self.fragment_mode
+	}
+
+	/// Has a CLOSE been received?
+	pub fn is_closed(&self) -> bool {
+		matches!(self.state, State::Closed)
+	}
+
+	/// Decode the next event from the input buffer.
+	///
+	/// Message payload data is appended to `message` and `extensions` are applied
+	/// to it in reverse order. The same message buffer must be passed until an
+	/// [`Event::Message`] is returned, as messages may span multiple calls.
+	pub fn decode(
+		&mut self,
+		message: &mut Vec<u8>,
+		extensions: &mut [Box<dyn Extension + Send>],
+	) -> Result<Event, Error> {
+		loop {
+			match self.state {
+				State::Closed => return Err(Error::Closed),
+				State::Discard(n) => {
+					let skipped = std::cmp::min(n, self.buffer.len());
+					self.buffer.advance(skipped);
+					if skipped < n {
+						self.state = State::Discard(n - skipped);
+						return Ok(Event::NeedMore(n - skipped));
+					}
+					self.state = State::Header
+				}
+				State::Header => {
+					let header = match self.codec.decode_header(&self.buffer)? {
+						Parsing::Done { value, offset } => {
+							debug_assert!(offset <= base::MAX_HEADER_SIZE);
+							self.buffer.advance(offset);
+							value
+						}
+						Parsing::NeedMore(n) => return Ok(Event::NeedMore(n)),
+					};
+					if !header.is_masked() {
+						match self.unmasked {
+							Unmasked::Accept => (),
+							Unmasked::Warn => {
+								log::warn!("accepting unmasked frames from client");
+								self.unmasked = Unmasked::Accept
+							}
+							Unmasked::Reject => return Err(Error::UnmaskedFrame),
+						}
+					}
+					if header.opcode().is_control() {
+						let len = header.payload_len();
+						if len > MAX_CONTROL_SIZE && self.oversized_control == OversizedControl::Report {
+							log::debug!("control frame exceeds {} bytes", MAX_CONTROL_SIZE);
+							self.state = State::Discard(len);
+							return Err(Error::ControlFrameTooLarge(len));
+						}
+						self.state = State::Control(header.clone());
+						return Ok(Event::Frame(header));
+					}
+					self.fragments += 1;
+					if self.fragments > self.max_fragments {
+						log::warn!("message consists of more than {} frames", self.max_fragments);
+						self.reset_message();
+						self.state = State::Discard(header.payload_len());
+						return Err(Error::TooManyFragments { maximum: self.max_fragments });
+					}
+					self.length = self.length.saturating_add(header.payload_len());
+					if self.length > self.max_message_size {
+						log::warn!("accumulated message length exceeds maximum");
+						let current = self.length;
+						if self.partial_delivery {
+							// Only the part of the frame within the maximum is kept.
+							let keep = header.payload_len() - (current - self.max_message_size);
+							if self.reserve(keep).is_ok() {
+								self.state = State::Overflow { header, offset: message.len(), keep, current };
+								continue;
+							}
+						}
+						self.reset_message();
+						self.state = State::Discard(header.payload_len());
+						return Err(Error::MessageTooLarge { current, maximum: self.max_message_size });
+					}
+					if let Err(e) = self.reserve(header.payload_len()) {
+						self.reset_message();
+						self.state = State::Discard(header.payload_len());
+						return Err(e);
+					}
+					self.message_start.get_or_insert(message.len());
+					self.state = State::Payload(header.clone(), message.len());
+					return Ok(Event::Frame(header));
+				}
+				State::Overflow { ref header, offset, keep, current } => {
+					let remaining = offset + keep - message.len();
+					let n = std::cmp::min(remaining, self.buffer.len());
+					message.extend_from_slice(&self.buffer[..n]);
+					self.buffer.advance(n);
+					if n < remaining {
+						return Ok(Event::NeedMore(remaining - n));
+					}
+					base::Codec::apply_mask(header, &mut message[offset..]);
+					self.state = State::Discard(header.payload_len() - keep);
+					self.reset_message();
+					return Err(Error::MessageTooLarge { current, maximum: self.max_message_size });
+				}
+				State::Control(ref header) => {
+					let len = std::cmp::min(header.payload_len(), MAX_CONTROL_SIZE);
+					if self.buffer.len() < len {
+						return Ok(Event::NeedMore(len - self.buffer.len()));
+					}
+					self.ctrl_buffer = self.buffer.split_to(len);
+					base::Codec::apply_mask(header, &mut self.ctrl_buffer);
+					let next = match header.payload_len() - len {
+						0 => State::Header,
+						n => {
+							log::debug!("truncating control frame of {} bytes", header.payload_len());
+							State::Discard(n)
+						}
+					};
+					match header.opcode() {
+						OpCode::Ping => {
+							self.state = next;
+							return Ok(Event::Ping);
+						}
+						OpCode::Pong => {
+							self.state = next;
+							return Ok(Event::Pong);
+						}
+						OpCode::Close => {
+							self.state = State::Closed;
+							return Ok(Event::Closed(close_reason(&self.ctrl_buffer, self.strict_close_codes)?));
+						}
+						oc => return Err(Error::UnexpectedOpCode(oc)),
+					}
+				}
+				State::Payload(ref header, offset) => {
+					let remaining = offset + header.payload_len() - message.len();
+					let n = std::cmp::min(remaining, self.buffer.len());
+					message.extend_from_slice(&self.buffer[..n]);
+					self.buffer.advance(n);
+					if n < remaining {
+						return Ok(Event::NeedMore(remaining - n));
+					}
+					let State::Payload(mut header, offset) = std::mem::replace(&mut self.state, State::Header) else {
+						unreachable!("state is State::Payload; qed")
+					};
+					base::Codec::apply_mask(&header, &mut message[offset..]);
+					if self.fragment_mode {
+						return self.on_fragment(&header);
+					}
+					if let Some(event) = self.on_payload(&mut header, message, extensions)? {
+						return Ok(event);
+					}
+				}
+			}
+		}
+	}
+
+	/// Handle the complete payload of a data frame and return the completed message, if any.
+	fn on_payload(
+		&mut self,
+		header: &mut Header,
+		message: &mut Vec<u8>,
+		extensions: &mut [Box<dyn Extension + Send>],
+	) -> Result<Option<Event>, Error> {
+		match (header.is_fin(), header.opcode()) {
+			(false, OpCode::Continue) => {
+				// Intermediate message fragment.
+				if self.first_fragment_opcode.is_none() {
+					log::debug!("continue frame while not processing message fragments");
+					self.reset_message();
+					return Err(Error::UnexpectedOpCode(OpCode::Continue));
+				}
+				self.validate_fragment(message, extensions.is_empty())?;
+				return Ok(None);
+			}
+			(false, oc) => {
+				// Initial message fragment.
+				if self.first_fragment_opcode.is_some() {
+					log::debug!("initial fragment while processing a fragmented message");
+					self.reset_message();
+					return Err(Error::UnexpectedOpCode(oc));
+				}
+				self.first_fragment_opcode = Some(oc);
+				self.apply_extensions(extensions, header, message)?;
+				self.reserve_decoded(message)?;
+				self.validate_fragment(message, extensions.is_empty())?;
+				return Ok(None);
+			}
+			(true, OpCode::Continue) => {
+				// Last message fragment.
+				if let Some(oc) = self.first_fragment_opcode.take() {
+					let len = message.len() - self.message_start.unwrap_or(0);
+					header.set_payload_len(len);
+					log::trace!("last fragment: total length = {} bytes", len);
+					if self.defer_extensions && !extensions.is_empty() {
+						return Ok(Some(Event::Decode { header: header.clone(), opcode: oc }));
+					}
+					self.apply_extensions(extensions, header, message)?;
+					header.set_opcode(oc);
+				} else {
+					log::debug!("last continue frame while not processing message fragments");
+					self.reset_message();
+					return Err(Error::UnexpectedOpCode(OpCode::Continue));
+				}
+			}
+			(true, oc) => {
+				// Regular non-fragmented message.
+				if self.first_fragment_opcode.is_some() {
+					log::debug!("regular message while processing fragmented message");
+					self.reset_message();
+					return Err(Error::UnexpectedOpCode(oc));
+				}
+				if self.defer_extensions && !extensions.is_empty() {
+					return Ok(Some(Event::Decode { header: header.clone(), opcode: oc }));
+				}
+				self.apply_extensions(extensions, header, message)?
+			}
+		}
+		self.complete_message(header.opcode(), message).map(Some)
+	}
+
+	/// Apply extensions to the current message, forgetting it if they fail.
+	fn apply_extensions(
+		&mut self,
+		extensions: &mut [Box<dyn Extension + Send>],
+		header: &mut Header,
+		message: &mut Vec<u8>,
+	) -> Result<(), Error> {
+		decode_with_extensions(extensions, header, message).map_err(|e| {
+			self.reset_message();
+			e
+		})
+	}
+
+	/// Complete a message after extensions have been applied to it.
+	///
+	/// Must be called after an [`Event::Decode`] with its opcode, the message
+	/// buffer and the result of applying the extensions, and returns the
+	/// resulting [`Event::Message`]. If the extensions failed, the message is
+	/// forgotten and their error returned.
+	pub fn finish_message(
+		&mut self,
+		opcode: OpCode,
+		message: &mut Vec<u8>,
+		decoded: Result<(), Error>,
+	) -> Result<Event, Error> {
+		if let Err(e) = decoded {
+			self.reset_message();
+			return Err(e);
+		}
+		self.complete_message(opcode, message)
+	}
+
+	/// Complete a message which the extensions may have written elsewhere.
+	///
+	/// Like [`Decoder::finish_message`], but with the result of
+	/// [`decode_with_extensions_to`]. If the message has been written to the
+	/// sink, its payload data is removed from the message buffer and it is
+	/// not validated, even if text.
+	pub fn finish_message_to(
+		&mut self,
+		opcode: OpCode,
+		message: &mut Vec<u8>,
+		written: Result<Option<usize>, Error>,
+	) -> Result<Event, Error> {
+		match written {
+			Ok(Some(len)) => {
+				message.truncate(self.message_start.unwrap_or(0));
+				let event = Event::Message { opcode, len, encoded_len: self.length };
+				self.reset_message();
+				Ok(event)
+			}
+			Ok(None) => self.complete_message(opcode, message),
+			Err(e) => {
+				self.reset_message();
+				Err(e)
+			}
+		}
+	}
+
+	/// Complete a message after extensions have been applied to it successfully.
+	fn complete_message(&mut self, opcode: OpCode, message: &mut Vec<u8>) -> Result<Event, Error> {
+		self.reserve_decoded(message)?;
+		let start = self.message_start.unwrap_or(0);
+		if opcode == OpCode::Text {
+			self.validate_text(message, start)?
+		}
+		let event = Event::Message { opcode, len: message.len() - start, encoded_len: self.length };
+		self.reset_message();
+		Ok(event)
+	}
+
+	/// Handle the complete payload of a data frame in fragment mode.
+	fn on_fragment(&mut self, header: &Header) -> Result<Event, Error> {
+		let opcode = match (self.first_fragment_opcode, header.opcode()) {
+			(Some(oc), OpCode::Continue) => oc,
+			(None, OpCode::Continue) => {
+				log::debug!("continue frame while not processing message fragments");
+				self.reset_message();
+				return Err(Error::UnexpectedOpCode(OpCode::Continue));
+			}
+			(Some(_), oc) => {
+				log::debug!("new message while processing fragmented message");
+				self.reset_message();
+				return Err(Error::UnexpectedOpCode(oc));
+			}
+			(None, oc) => oc,
+		};
+		if header.is_fin() {
+			self.reset_message()
+		} else {
+			// The fragment is handed out, so only its length counts towards the message.
+			self.release();
+			self.message_start = None;
+			self.first_fragment_opcode = Some(opcode)
+		}
+		Ok(Event::Fragment { opcode, fin: header.is_fin(), len: header.payload_len() })
+	}
+
+	/// Validate the text message starting at the given offset according to the UTF-8 policy.
+	fn validate_text(&mut self, message: &mut Vec<u8>, start: usize) -> Result<(), Error> {
+		match self.utf8_policy {
+			Utf8Policy::Strict => {
+				// The part before `validated` has been checked by `validate_fragment`.
+				if let Err(e) = std::str::from_utf8(&message[std::cmp::max(start, self.validated)..]) {
+					return Err(self.on_invalid_utf8(e));
+				}
+			}
+			Utf8Policy::Lossy => {
+				if let Cow::Owned(text) = String::from_utf8_lossy(&message[start..]) {
+					message.truncate(start);
+					message.extend_from_slice(text.as_bytes())
+				}
+			}
+			Utf8Policy::Skip => (),
+		}
+		Ok(())
+	}
+
+	/// Validate the fragments of a text message received so far, so that invalid
+	/// messages fail before they are complete.
+	///
+	/// Only done if no extensions are used, as they may transform the payload.
+	fn validate_fragment(&mut self, message: &[u8], no_extensions: bool) -> Result<(), Error> {
+		if self.utf8_policy != Utf8Policy::Strict || !no_extensions || self.first_fragment_opcode != Some(OpCode::Text)
+		{
+			return Ok(());
+		}
+		let from = std::cmp::max(self.message_start.unwrap_or(0), self.validated);
+		match std::str::from_utf8(&message[from..]) {
+			Ok(_) => self.validated = message.len(),
+			// A code point may continue in the next fragment.
+			Err(e) if e.error_len().is_none() => self.validated = from + e.valid_up_to(),
+			Err(e) => return Err(self.on_invalid_utf8(e)),
+		}
+		Ok(())
+	}
+
+	/// Fail the connection because of an invalid text message.
+	fn on_invalid_utf8(&mut self, e: std::str::Utf8Error) -> Error {
+		log::debug!("text message is not valid utf-8");
+		self.reset_message();
+		self.state = State::Closed;
+		Error::Utf8(e)
+	}
+
+	/// Take `n` more bytes for the current message from the limiter's budget, if any.
+	fn reserve(&mut self, n: usize) -> Result<(), Error> {
+		let Some(limiter) = &self.limiter else { return Ok(()) };
+		if !limiter.acquire(n) {
+			log::debug!("memory limit of {} bytes exceeded", limiter.limit());
+			return Err(Error::MemoryLimitExceeded { limit: limiter.limit() });
+		}
+		self.reserved += n;
+		Ok(())
+	}
+
+	/// Take the bytes by which extensions have grown the current message from the limiter's budget.
+	fn reserve_decoded(&mut self, message: &[u8]) -> Result<(), Error> {
+		let len = message.len() - self.message_start.unwrap_or(0);
+		if len > self.reserved {
+			if let Err(e) = self.reserve(len - self.reserved) {
+				self.reset_message();
+				return Err(e);
+			}
+		}
+		Ok(())
+	}
+
+	/// Return the bytes of the current message to the limiter's budget.
+	fn release(&mut self) {
+		if let Some(limiter) = &self.limiter {
+			limiter.release(self.reserved)
+		}
+		self.reserved = 0
+	}
+
+	/// Forget the current message.
+	fn reset_message(&mut self) {
+		self.release();
+		self.first_fragment_opcode = None;
+		self.message_start = None;
+		self.validated = 0;
+		self.length = 0;
+		self.fragments = 0
+	}
+}
+
+impl Drop for Decoder {
+	fn drop(&mut self) {
+		self.release()
+	}
+}
+
+/// Encodes frames to be written to a socket.
+#[derive(Debug)]
+pub struct Encoder {
+	mode: Mode,
+	codec: base::Codec,
+	mask_buffer: Vec<u8>,
+	/// Are client frames masked?
+	masking: bool,
+	/// The source of masking keys, if not the thread-local RNG.
+	rng: Option<MaskRng>,
+}
+
+impl Encoder {
+	/// Create a new encoder for the given mode using the given codec.
+	pub fn new(mode: Mode, codec: base::Codec) -> Self {
+		Encoder { mode, codec, mask_buffer: Vec::new(), masking: true, rng: None }
+	}
+
+	/// Enable or disable masking of client frames, see [`Builder::set_masking`].
+	///
+	/// [`Builder::set_masking`]: crate::connection::Builder::set_masking
+	pub fn set_masking(&mut self, masking: bool) -> &mut Self {
+		self.masking = masking;
+		self
+	}
+
+	/// Use the given source of masking keys, see [`Builder::set_mask_rng`].
+	///
+	/// [`Builder::set_mask_rng`]: crate::connection::Builder::set_mask_rng
+	pub fn set_rng(&mut self, rng: MaskRng) -> &mut Self {
+		self.rng = Some(rng);
+		self
+	}
+
+	/// Get a reference to the codec.
+	pub fn codec(&self) -> &base::Codec {
+		&self.codec
+	}
+
+	/// Encode the header of a frame with `len` bytes of payload data.
+	///
+	/// The payload length of the header is set and a mask is chosen if necessary.
+	/// Unlike with [`Encoder::encode_frame`], the payload has to be masked by the
+	/// caller, e.g. with [`base::Codec::apply_mask`].
+	pub fn encode_header(&mut self, header: &mut Header, len: usize) -> &[u8] {
+		self.prepare_header(header, len);
+		self.codec.encode_header(header)
+	}
+
+	/// Set the payload length and, if necessary, the mask of a header.
+	fn prepare_header(&self, header: &mut Header, len: usize) {
+		if self.mode.is_client() && self.masking {
+			header.set_masked(true);
+			header.set_mask(match &self.rng {
+				Some(rng) => rng.next_mask(),
+				None => rand::random(),
+			});
+		}
+		header.set_payload_len(len);
+	}
+
+	/// Encode a frame and return its header bytes and its payload data.
+	///
+	/// The payload length of the header is set and the data is masked if necessary.
+	/// No extensions are applied, see [`encode_with_extensions`].
+	pub fn encode_frame<'a>(&'a mut self, header: &mut Header, data: &'a mut Storage<'_>) -> (&'a [u8], &'a [u8]) {
+		self.prepare_header(header, data.as_ref().len());
+		let header_bytes = self.codec.encode_header(header);
+
+		if !header.is_masked() {
+			return (header_bytes, (*data).as_ref());
+		}
+
+		let payload = match data {
+			Storage::Shared(slice) => {
+				self.mask_buffer.clear();
+				self.mask_buffer.extend_from_slice(slice);
+				base::Codec::apply_mask(header, &mut self.mask_buffer);
+				&self.mask_buffer[..]
+			}
+			Storage::Unique(slice) => {
+				base::Codec::apply_mask(header, slice);
+				&slice[..]
+			}
+			Storage::Owned(ref mut bytes) => {
+				base::Codec::apply_mask(header, bytes);
+				&bytes[..]
+			}
+		};
+		(header_bytes, payload)
+	}
+}
+
+/// Apply extensions to an outgoing message in the order in which they are given.
+pub fn encode_with_extensions(
+	extensions: &mut [Box<dyn Extension + Send>],
+	header: &mut Header,
+	data: &mut Storage<'_>,
+) -> Result<(), Error> {
+	for e in extensions.iter_mut() {
+		log::trace!("encoding with extension: {}", e.name());
+		e.encode(header, data).map_err(Error::Extension)?
+	}
+	Ok(())
+}
+
+/// Apply extensions to an incoming message in reverse order.
+pub fn decode_with_extensions(
+	extensions: &mut [Box<dyn Extension + Send>],
+	header: &mut Header,
+	message: &mut Vec<u8>,
+) -> Result<(), Error> {
+	for e in extensions.iter_mut().rev() {
+		log::trace!("decoding with extension: {}", e.name());
+		e.decode(header, message).map_err(Error::Extension)?
+	}
+	Ok(())
+}
+
+/// Apply the extensions for which `filter` returns true to an outgoing
+/// message with [`Extension::encode_async`].
+pub async fn encode_with_extensions_async(
+	extensions: &mut [Box<dyn Extension + Send>],
+	header: &mut Header,
+	data: &mut Storage<'_>,
+	mut filter: impl FnMut(&dyn Extension) -> bool,
+) -> Result<(), Error> {
+	for e in extensions.iter_mut().filter(|e| filter(&***e)) {
+		log::trace!("encoding with extension: {}", e.name());
+		e.encode_async(header, data).await.map_err(Error::Extension)?
+	}
+	Ok(())
+}
+
+/// Apply extensions to an incoming message in reverse order with [`Extension::decode_async`].
+pub async fn decode_with_extensions_async(
+	extensions: &mut [Box<dyn Extension + Send>],
+	header: &mut Header,
+	message: &mut Vec<u8>,
+) -> Result<(), Error> {
+	for e in extensions.iter_mut().rev() {
+		log::trace!("decoding with extension: {}", e.name());
+		e.decode_async(header, message).await.map_err(Error::Extension)?
+	}
+	Ok(())
+}
+
+/// Apply extensions to an incoming message like [`decode_with_extensions_async`],
+/// but let the first one write it to `sink` with [`Extension::decode_to`].
+///
+/// Returns the number of bytes written, or `None` if the message has been
+/// decoded into `message` instead.
+pub async fn decode_with_extensions_to(
+	extensions: &mut [Box<dyn Extension + Send>],
+	header: &mut Header,
+	message: &mut Vec<u8>,
+	sink: &mut (dyn io::Write + Send),
+) -> Result<Option<usize>, Error> {
+	let Some((first, rest)) = extensions.split_first_mut() else {
+		return Ok(None);
+	};
+	decode_with_extensions_async(rest, header, message).await?;
+	log::trace!("decoding with extension: {}", first.name());
+	if let Some(n) = first.decode_to(header, message, sink).map_err(Error::Extension)? {
+		return Ok(Some(n));
+	}
+	first.decode_async(header, message).await.map_err(Error::Extension)?;
+	Ok(None)
+}
+
+/// Parse the payload of a received CLOSE frame, replacing invalid codes if `strict`.
+fn close_reason(data: &[u8], strict: bool) -> Result<Option<CloseReason>, Error> {
+	if data.len() < 2 {
+		return Ok(None);
+	}
+	// Check that the reason string is properly encoded
+	let descr = std::str::from_utf8(&data[2..])?.into();
+	let code = u16::from_be_bytes([data[0], data[1]]);
+	let reason = CloseReason { code: code.into(), descr: Some(descr) };
+	if !strict {
+		return Ok(Some(reason));
+	}
+
+	// Status codes are defined in
+	// https://tools.ietf.org/html/rfc6455#section-7.4.1 and
+	// https://mailarchive.ietf.org/arch/msg/hybi/P_1vbD9uyHl63nbIIbFxKMfSwcM/
+	match code {
+		| 1000..=1003
+		| 1007..=1011
+		| 1012 // Service Restart
+		| 1013 // Try Again Later
+		| 1015
+		| 3000..=4999 => Ok(Some(reason)), // acceptable codes
+		_ => {
+			// invalid code => protocol error (1002)
+			Ok(Some(CloseReason { code: CloseCode::ProtocolError, descr: None }))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Decoder, Encoder, Event, MemoryLimiter, OversizedControl};
+	use crate::{
+		base::{Header, OpCode},
+		connection::{CloseCode, Error, Mode},
+		Storage,
+	};
+
+	fn frame(encoder: &mut Encoder, opcode: OpCode, fin: bool, data: &[u8]) -> Vec<u8> {
+		let mut header = Header::new(opcode);
+		header.set_fin(fin);
+		let mut data = Storage::Shared(data);
+		let (header, payload) = encoder.encode_frame(&mut header, &mut data);
+		[header, payload].concat()
+	}
+
+	#[test]
+	fn memory_limiter() {
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let first = frame(&mut encoder, OpCode::Binary, false, &[1; 8]);
+		let last = frame(&mut encoder, OpCode::Continue, true, &[2; 2]);
+		let other = frame(&mut encoder, OpCode::Binary, true, &[3; 5]);
+
+		let limiter = MemoryLimiter::new(10);
+		let mut a = Decoder::new(Default::default());
+		a.set_memory_limiter(limiter.clone());
+		let mut b = Decoder::new(Default::default());
+		b.set_memory_limiter(limiter.clone());
+
+		let mut message = Vec::new();
+		a.buffer_mut().extend_from_slice(&first);
+		assert!(matches!(a.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(a.decode(&mut message, &mut []), Ok(Event::NeedMore(_))));
+		assert_eq!(8, limiter.used());
+
+		b.buffer_mut().extend_from_slice(&[&other[..], &other[..]].concat());
+		assert!(matches!(b.decode(&mut Vec::new(), &mut []), Err(Error::MemoryLimitExceeded { limit: 10 })));
+
+		a.buffer_mut().extend_from_slice(&last);
+		assert!(matches!(a.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(a.decode(&mut message, &mut []), Ok(Event::Message { len: 10, .. })));
+		assert_eq!(0, limiter.used());
+
+		// The skipped frame is followed by one which fits.
+		assert!(matches!(b.decode(&mut Vec::new(), &mut []), Ok(Event::Frame(_))));
+		assert_eq!(5, limiter.used());
+		drop(b);
+		assert_eq!(0, limiter.used());
+	}
+
+	#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+	#[test]
+	fn extension_error() {
+		use crate::{base::Codec, connection::tests::deflate, extension::Extension};
+
+		let mut compressor = deflate(Mode::Client);
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(b"hello");
+		compressor.encode(&mut header, &mut data).unwrap();
+		let compressed = data.as_ref().to_vec();
+
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let mut input = Vec::new();
+		for payload in [&[0xff, 0xff][..], &compressed[..]] {
+			let mut header = Header::new(OpCode::Text);
+			header.set_rsv1(true);
+			let mut data = Storage::Shared(payload);
+			let (header, payload) = encoder.encode_frame(&mut header, &mut data);
+			input.extend([header, payload].concat())
+		}
+
+		let mut codec = Codec::default();
+		codec.add_reserved_bits((true, false, false));
+		let mut decoder = Decoder::new(codec);
+		let mut extensions = vec![Box::new(deflate(Mode::Server)) as Box<dyn Extension + Send>];
+		decoder.buffer_mut().extend_from_slice(&input);
+		let mut message = Vec::new();
+		assert!(matches!(decoder.decode(&mut message, &mut extensions), Ok(Event::Frame(_))));
+		assert!(matches!(decoder.decode(&mut message, &mut extensions), Err(Error::Extension(_))));
+
+		// The failed message is forgotten and the next one decoded into a fresh buffer.
+		let mut message = Vec::new();
+		assert!(matches!(decoder.decode(&mut message, &mut extensions), Ok(Event::Frame(_))));
+		assert!(matches!(decoder.decode(&mut message, &mut extensions), Ok(Event::Message { len: 5, .. })));
+		assert_eq!(b"hello", &message[..]);
+	}
+
+	#[test]
+	fn too_many_fragments() {
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let mut input = frame(&mut encoder, OpCode::Binary, false, b"a");
+		input.extend(frame(&mut encoder, OpCode::Continue, false, b"b"));
+		input.extend(frame(&mut encoder, OpCode::Continue, true, b"c"));
+
+		let mut decoder = Decoder::new(Default::default());
+		decoder.set_max_fragments(3);
+		decoder.buffer_mut().extend_from_slice(&input);
+		let mut message = Vec::new();
+		let mut result = decoder.decode(&mut message, &mut []);
+		while let Ok(Event::Frame(_)) = result {
+			result = decoder.decode(&mut message, &mut [])
+		}
+		assert!(matches!(result, Ok(Event::Message { len: 3, .. })));
+
+		let mut decoder = Decoder::new(Default::default());
+		decoder.set_max_fragments(2);
+		decoder.buffer_mut().extend_from_slice(&input);
+		let mut message = Vec::new();
+		let mut result = decoder.decode(&mut message, &mut []);
+		while let Ok(Event::Frame(_)) = result {
+			result = decoder.decode(&mut message, &mut [])
+		}
+		assert!(matches!(result, Err(Error::TooManyFragments { maximum: 2 })));
+	}
+
+	#[test]
+	fn oversized_control() {
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let mut input = frame(&mut encoder, OpCode::Ping, true, &[1; 200]);
+		input.extend(frame(&mut encoder, OpCode::Text, true, b"hello"));
+
+		let mut decoder = Decoder::new(Default::default());
+		decoder.buffer_mut().extend_from_slice(&input);
+		assert!(matches!(decoder.decode(&mut Vec::new(), &mut []), Err(Error::Codec(_))));
+
+		let mut decoder = Decoder::new(Default::default());
+		decoder.set_oversized_control(OversizedControl::Truncate);
+		decoder.buffer_mut().extend_from_slice(&input);
+		let mut message = Vec::new();
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Ping)));
+		assert_eq!(&[1; 125], decoder.control_payload());
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Message { len: 5, .. })));
+
+		let mut decoder = Decoder::new(Default::default());
+		decoder.set_oversized_control(OversizedControl::Report);
+		decoder.buffer_mut().extend_from_slice(&input);
+		let mut message = Vec::new();
+		assert!(matches!(decoder.decode(&mut message, &mut []), Err(Error::ControlFrameTooLarge(200))));
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Message { len: 5, .. })));
+		assert_eq!(b"hello", &message[..]);
+	}
+
+	#[test]
+	fn fragmented_message_with_interleaved_ping() {
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let mut input = frame(&mut encoder, OpCode::Text, false, b"hel");
+		input.extend(frame(&mut encoder, OpCode::Ping, true, b"ping"));
+		input.extend(frame(&mut encoder, OpCode::Continue, true, b"lo"));
+
+		// Feed the input byte by byte.
+		let mut decoder = Decoder::new(Default::default());
+		let mut message = b"x".to_vec();
+		let mut events = Vec::new();
+		for byte in input {
+			decoder.buffer_mut().extend_from_slice(&[byte]);
+			loop {
+				match decoder.decode(&mut message, &mut []).unwrap() {
+					Event::NeedMore(_) => break,
+					Event::Frame(_) => (),
+					Event::Ping => events.push(decoder.control_payload().to_vec()),
+					Event::Message { opcode, len, .. } => events.push(format!("{:?} {}", opcode, len).into_bytes()),
+					e => panic!("unexpected event: {:?}", e),
+				}
+			}
+		}
+		assert_eq!(vec![b"ping".to_vec(), b"Text 5".to_vec()], events);
+		assert_eq!(b"xhello", &message[..]);
+	}
+
+	#[test]
+	fn incremental_utf8_validation() {
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let mut decoder = Decoder::new(Default::default());
+		let mut message = Vec::new();
+		let mut decode = |decoder: &mut Decoder, bytes: &[u8]| {
+			decoder.buffer_mut().extend_from_slice(bytes);
+			loop {
+				match decoder.decode(&mut message, &mut []) {
+					Ok(Event::Frame(_)) => (),
+					other => return other,
+				}
+			}
+		};
+
+		// A code point split across fragments.
+		let text = "é".as_bytes();
+		assert!(matches!(
+			decode(&mut decoder, &frame(&mut encoder, OpCode::Text, false, &text[..1])),
+			Ok(Event::NeedMore(_))
+		));
+		let last = frame(&mut encoder, OpCode::Continue, true, &text[1..]);
+		assert!(matches!(decode(&mut decoder, &last), Ok(Event::Message { len: 2, .. })));
+
+		// Invalid fragments fail before the message is complete.
+		let invalid = frame(&mut encoder, OpCode::Text, false, b"a\xffb");
+		assert!(matches!(decode(&mut decoder, &invalid), Err(Error::Utf8(_))));
+		assert!(decoder.is_closed());
+	}
+
+	#[test]
+	fn too_large_message_is_skipped() {
+		let mut encoder = Encoder::new(Mode::Server, Default::default());
+		let mut decoder = Decoder::new(Default::default());
+		decoder.set_max_message_size(4);
+		decoder.buffer_mut().extend_from_slice(&frame(&mut encoder, OpCode::Binary, true, b"too large"));
+		decoder.buffer_mut().extend_from_slice(&frame(&mut encoder, OpCode::Close, true, b"\x03\xe8bye"));
+
+		let mut message = Vec::new();
+		assert!(matches!(
+			decoder.decode(&mut message, &mut []),
+			Err(Error::MessageTooLarge { current: 9, maximum: 4 })
+		));
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(
+			matches!(decoder.decode(&mut message, &mut []), Ok(Event::Closed(Some(r))) if r.code == CloseCode::NormalClosure)
+		);
+		assert!(matches!(decoder.decode(&mut message, &mut []), Err(Error::Closed)));
+		assert!(message.is_empty());
+	}
+
+	#[test]
+	fn partial_delivery() {
+		let mut encoder = Encoder::new(Mode::Client, Default::default());
+		let mut decoder = Decoder::new(Default::default());
+		decoder.set_max_message_size(6).set_partial_delivery(true);
+		decoder.buffer_mut().extend_from_slice(&frame(&mut encoder, OpCode::Text, false, b"too"));
+		decoder.buffer_mut().extend_from_slice(&frame(&mut encoder, OpCode::Continue, true, b" large"));
+		decoder.buffer_mut().extend_from_slice(&frame(&mut encoder, OpCode::Binary, true, b"fine"));
+
+		let mut message = Vec::new();
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(
+			decoder.decode(&mut message, &mut []),
+			Err(Error::MessageTooLarge { current: 9, maximum: 6 })
+		));
+		assert_eq!(b"too la", &message[..]);
+		message.clear();
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Frame(_))));
+		assert!(matches!(decoder.decode(&mut message, &mut []), Ok(Event::Message { len: 4, .. })));
+		assert_eq!(b"fine", &message[..]);
+	}
+}
@@ -0,0 +1,176 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! SOCKS5 [proxy protocol][rfc1928] with [username/password authentication][rfc1929].
+//!
+//! [rfc1928]: https://www.rfc-editor.org/rfc/rfc1928
+//! [rfc1929]: https://www.rfc-editor.org/rfc/rfc1929
+
+use super::Error;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::net::IpAddr;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS_AUTH: u8 = 2;
+const USER_PASS_VERSION: u8 = 1;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// A SOCKS5 `CONNECT` request.
+#[derive(Debug)]
+pub struct Socks5<'a> {
+	/// The target host, either a domain name or an IP address.
+	host: &'a str,
+	/// The target port.
+	port: u16,
+	/// Username and password to authenticate with, if any.
+	credentials: Option<(&'a str, &'a str)>,
+}
+
+impl<'a> Socks5<'a> {
+	/// Create a `CONNECT` request for the given target.
+	///
+	/// Domain names are resolved by the proxy.
+	pub fn new(host: &'a str, port: u16) -> Self {
+		Socks5 { host, port, credentials: None }
+	}
+
+	/// Authenticate with the proxy using username and password.
+	///
+	/// If set, the proxy may still choose not to require authentication.
+	pub fn set_credentials(&mut self, user: &'a str, password: &'a str) -> &mut Self {
+		self.credentials = Some((user, password));
+		self
+	}
+
+	/// Negotiate with the proxy over the socket connected to it.
+	///
+	/// On success the socket is tunneled to the target and can be given to
+	/// [`handshake::Client`](crate::handshake::Client).
+	pub async fn connect<T: AsyncRead + AsyncWrite + Unpin>(&self, socket: &mut T) -> Result<(), Error> {
+		// Method selection.
+
+		if self.credentials.is_some() {
+			socket.write_all(&[VERSION, 2, NO_AUTH, USER_PASS_AUTH]).await?
+		} else {
+			socket.write_all(&[VERSION, 1, NO_AUTH]).await?
+		}
+		socket.flush().await?;
+
+		let mut reply = [0; 2];
+		socket.read_exact(&mut reply).await?;
+		match reply {
+			[VERSION, NO_AUTH] => (),
+			[VERSION, USER_PASS_AUTH] if self.credentials.is_some() => self.authenticate(socket).await?,
+			[VERSION, NO_ACCEPTABLE_METHOD] => return Err(Error::NoAcceptableAuthMethod),
+			_ => return Err(Error::InvalidResponse),
+		}
+
+		// Connect request.
+
+		let mut request = vec![VERSION, CMD_CONNECT, 0];
+		match self.host.parse::<IpAddr>() {
+			Ok(IpAddr::V4(ip)) => {
+				request.push(ATYP_IPV4);
+				request.extend_from_slice(&ip.octets())
+			}
+			Ok(IpAddr::V6(ip)) => {
+				request.push(ATYP_IPV6);
+				request.extend_from_slice(&ip.octets())
+			}
+			Err(_) => {
+				let len = u8::try_from(self.host.len()).map_err(|_| Error::InvalidTarget)?;
+				request.extend_from_slice(&[ATYP_DOMAIN, len]);
+				request.extend_from_slice(self.host.as_bytes())
+			}
+		}
+		request.extend_from_slice(&self.port.to_be_bytes());
+		socket.write_all(&request).await?;
+		socket.flush().await?;
+
+		let mut reply = [0; 4];
+		socket.read_exact(&mut reply).await?;
+		if reply[0] != VERSION {
+			return Err(Error::InvalidResponse);
+		}
+		if reply[1] != 0 {
+			return Err(Error::SocksRejected { reply: reply[1] });
+		}
+
+		// Skip the bound address and port.
+
+		let len = match reply[3] {
+			ATYP_IPV4 => 4,
+			ATYP_IPV6 => 16,
+			ATYP_DOMAIN => {
+				let mut len = 0;
+				socket.read_exact(std::slice::from_mut(&mut len)).await?;
+				usize::from(len)
+			}
+			_ => return Err(Error::InvalidResponse),
+		};
+		let mut address = vec![0; len + 2];
+		socket.read_exact(&mut address).await?;
+		Ok(())
+	}
+
+	async fn authenticate<T: AsyncRead + AsyncWrite + Unpin>(&self, socket: &mut T) -> Result<(), Error> {
+		let (user, password) = self.credentials.expect("authentication is only offered with credentials; qed");
+		let user_len = u8::try_from(user.len()).map_err(|_| Error::InvalidTarget)?;
+		let password_len = u8::try_from(password.len()).map_err(|_| Error::InvalidTarget)?;
+
+		let mut request = vec![USER_PASS_VERSION, user_len];
+		request.extend_from_slice(user.as_bytes());
+		request.push(password_len);
+		request.extend_from_slice(password.as_bytes());
+		socket.write_all(&request).await?;
+		socket.flush().await?;
+
+		let mut reply = [0; 2];
+		socket.read_exact(&mut reply).await?;
+		match reply {
+			[USER_PASS_VERSION, 0] => Ok(()),
+			[USER_PASS_VERSION, _] => Err(Error::AuthenticationFailed),
+			_ => Err(Error::InvalidResponse),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Error, Socks5};
+	use futures::io::Cursor;
+
+	#[tokio::test]
+	async fn connect() {
+		// Every request is written over zeroes, followed by the proxy's reply.
+		let mut data = vec![0; 4];
+		data.extend_from_slice(&[5, 2]);
+		data.extend_from_slice(&[0; 13]);
+		data.extend_from_slice(&[1, 0]);
+		data.extend_from_slice(&[0; 18]);
+		data.extend_from_slice(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90]);
+		let mut socket = Cursor::new(data);
+		Socks5::new("example.com", 80).set_credentials("user", "secret").connect(&mut socket).await.unwrap();
+		let data = socket.into_inner();
+		assert_eq!(&[5, 2, 0, 2], &data[..4]);
+		assert_eq!(b"\x01\x04user\x06secret", &data[6..19]);
+		assert_eq!(b"\x05\x01\x00\x03\x0bexample.com\x00\x50", &data[21..39]);
+
+		let mut data = vec![0; 3];
+		data.extend_from_slice(&[5, 0]);
+		data.extend_from_slice(&[0; 10]);
+		data.extend_from_slice(&[5, 5, 0, 1]);
+		let result = Socks5::new("10.0.0.1", 80).connect(&mut Cursor::new(data)).await;
+		assert!(matches!(result, Err(Error::SocksRejected { reply: 5 })));
+	}
+}
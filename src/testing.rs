@@ -0,0 +1,289 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Utilities to test code using websocket connections without sockets.
+//!
+//! [`duplex`] creates a pair of connected in-memory streams and a [`Peer`]
+//! plays the remote end of a connection frame by frame, which allows sending
+//! frames a well-behaved implementation never would:
+//!
+//! ```
+//! use soketto::{base::OpCode, connection::{CloseCode, Mode}, testing, Incoming};
+//!
+//! # async fn doc() -> Result<(), soketto::BoxedError> {
+//! let (builder, mut peer) = testing::pair(Mode::Server);
+//! let (_sender, mut receiver) = builder.finish();
+//!
+//! peer.send_close(999, "invalid code").await?;
+//! let mut message = Vec::new();
+//! assert!(matches!(receiver.receive(&mut message).await?, Incoming::Closed(r) if r.code == CloseCode::ProtocolError));
+//!
+//! let (header, payload) = peer.receive_frame().await?;
+//! assert_eq!((OpCode::Close, &1002_u16.to_be_bytes()[..]), (header.opcode(), &payload[..]));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+	base::{self, Header, OpCode},
+	connection::{Builder, CloseCode, Error, Mode},
+	proto::Encoder,
+	Parsing, Storage,
+};
+use bytes::{Buf, BytesMut};
+use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::{
+	cmp,
+	collections::VecDeque,
+	io,
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	task::{Context, Poll, Waker},
+};
+
+/// Default capacity of the buffers of [`pair`].
+const CAPACITY: usize = 64 * 1024;
+
+/// Create a pair of connected in-memory streams.
+///
+/// Bytes written to one stream can be read from the other. Writes wait while
+/// `capacity` bytes are buffered and not yet read. Closing or dropping a
+/// stream makes reads of the other one return end of file once the buffered
+/// bytes are read.
+pub fn duplex(capacity: usize) -> (Stream, Stream) {
+	let a = Arc::new(Mutex::new(Pipe::new(capacity)));
+	let b = Arc::new(Mutex::new(Pipe::new(capacity)));
+	(Stream { read: a.clone(), write: b.clone() }, Stream { read: b, write: a })
+}
+
+/// Create a connection [`Builder`] for the given mode and a [`Peer`] playing
+/// the remote end of the connection.
+pub fn pair(mode: Mode) -> (Builder<Stream>, Peer) {
+	let (local, remote) = duplex(CAPACITY);
+	let peer_mode = if mode.is_client() { Mode::Server } else { Mode::Client };
+	(Builder::new(local, mode), Peer::new(remote, peer_mode))
+}
+
+/// The bytes written in one direction of a [`duplex`] pair.
+#[derive(Debug)]
+struct Pipe {
+	buffer: VecDeque<u8>,
+	capacity: usize,
+	is_closed: bool,
+	reader: Option<Waker>,
+	writer: Option<Waker>,
+}
+
+impl Pipe {
+	fn new(capacity: usize) -> Self {
+		Pipe { buffer: VecDeque::new(), capacity, is_closed: false, reader: None, writer: None }
+	}
+
+	fn close(&mut self) {
+		self.is_closed = true;
+		if let Some(w) = self.reader.take() {
+			w.wake()
+		}
+		if let Some(w) = self.writer.take() {
+			w.wake()
+		}
+	}
+}
+
+/// One end of an in-memory stream, see [`duplex`].
+#[derive(Debug)]
+pub struct Stream {
+	read: Arc<Mutex<Pipe>>,
+	write: Arc<Mutex<Pipe>>,
+}
+
+impl AsyncRead for Stream {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		let mut pipe = self.read.lock().unwrap_or_else(PoisonError::into_inner);
+		if pipe.buffer.is_empty() && !buf.is_empty() {
+			if pipe.is_closed {
+				return Poll::Ready(Ok(0));
+			}
+			pipe.reader = Some(cx.waker().clone());
+			return Poll::Pending;
+		}
+		let n = cmp::min(buf.len(), pipe.buffer.len());
+		for (dst, src) in buf.iter_mut().zip(pipe.buffer.drain(..n)) {
+			*dst = src
+		}
+		if let Some(w) = pipe.writer.take() {
+			w.wake()
+		}
+		Poll::Ready(Ok(n))
+	}
+}
+
+impl AsyncWrite for Stream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let mut pipe = self.write.lock().unwrap_or_else(PoisonError::into_inner);
+		if pipe.is_closed {
+			return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+		}
+		let n = cmp::min(buf.len(), pipe.capacity - pipe.buffer.len());
+		if n == 0 && !buf.is_empty() {
+			pipe.writer = Some(cx.waker().clone());
+			return Poll::Pending;
+		}
+		pipe.buffer.extend(&buf[..n]);
+		if let Some(w) = pipe.reader.take() {
+			w.wake()
+		}
+		Poll::Ready(Ok(n))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+		self.write.lock().unwrap_or_else(PoisonError::into_inner).close();
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl Drop for Stream {
+	fn drop(&mut self) {
+		self.write.lock().unwrap_or_else(PoisonError::into_inner).close();
+		self.read.lock().unwrap_or_else(PoisonError::into_inner).close()
+	}
+}
+
+/// A scripted remote end of a connection.
+///
+/// Frames are sent as given, without validation, fragmentation or extensions,
+/// and received frames are returned as they are, including control frames.
+#[derive(Debug)]
+pub struct Peer {
+	stream: Stream,
+	encoder: Encoder,
+	codec: base::Codec,
+	buffer: BytesMut,
+}
+
+impl Peer {
+	/// Create a peer using the given mode on the given stream.
+	pub fn new(stream: Stream, mode: Mode) -> Self {
+		let mut codec = base::Codec::new();
+		// Accept whatever the other end sends.
+		codec.add_reserved_bits((true, true, true));
+		for opcode in (3..8).chain(11..16) {
+			codec.add_reserved_opcode(OpCode::try_from(opcode).expect("3..8 and 11..16 are opcodes; qed"));
+		}
+		Peer { stream, encoder: Encoder::new(mode, base::Codec::new()), codec, buffer: BytesMut::new() }
+	}
+
+	/// Send a frame with the given header and payload.
+	///
+	/// The payload length of the header is set and the payload is masked if the peer is a client.
+	pub async fn send_frame(&mut self, mut header: Header, payload: &[u8]) -> io::Result<()> {
+		let mut data = Storage::Shared(payload);
+		let (header, payload) = self.encoder.encode_frame(&mut header, &mut data);
+		self.stream.write_all(header).await?;
+		self.stream.write_all(payload).await
+	}
+
+	/// Send a complete text message.
+	pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+		self.send_frame(Header::new(OpCode::Text), text.as_bytes()).await
+	}
+
+	/// Send a complete binary message.
+	pub async fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+		self.send_frame(Header::new(OpCode::Binary), data).await
+	}
+
+	/// Send a PING.
+	pub async fn send_ping(&mut self, data: &[u8]) -> io::Result<()> {
+		self.send_frame(Header::new(OpCode::Ping), data).await
+	}
+
+	/// Send a CLOSE with the given code and reason, which need not be valid.
+	pub async fn send_close(&mut self, code: impl Into<CloseCode>, reason: &str) -> io::Result<()> {
+		let payload = [&u16::from(code.into()).to_be_bytes()[..], reason.as_bytes()].concat();
+		self.send_frame(Header::new(OpCode::Close), &payload).await
+	}
+
+	/// Send raw bytes, e.g. a malformed header.
+	pub async fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+		self.stream.write_all(bytes).await
+	}
+
+	/// Close the stream.
+	pub async fn close(&mut self) -> io::Result<()> {
+		self.stream.close().await
+	}
+
+	/// Receive the next frame and return its header and unmasked payload.
+	pub async fn receive_frame(&mut self) -> Result<(Header, Vec<u8>), Error> {
+		let header = loop {
+			match self.codec.decode_header(&self.buffer)? {
+				Parsing::Done { value, offset } => {
+					self.buffer.advance(offset);
+					break value;
+				}
+				Parsing::NeedMore(n) => crate::read(&mut self.stream, &mut self.buffer, n).await?,
+			}
+		};
+		while self.buffer.len() < header.payload_len() {
+			let n = header.payload_len() - self.buffer.len();
+			crate::read(&mut self.stream, &mut self.buffer, n).await?
+		}
+		let mut payload = self.buffer.split_to(header.payload_len()).to_vec();
+		base::Codec::apply_mask(&header, &mut payload);
+		Ok((header, payload))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::pair;
+	use crate::{
+		base::{Header, OpCode},
+		connection::{Error, Mode},
+		Data,
+	};
+
+	#[tokio::test]
+	async fn interleaved_ping() {
+		let (builder, mut peer) = pair(Mode::Client);
+		let (_, mut receiver) = builder.finish();
+
+		let mut first = Header::new(OpCode::Text);
+		first.set_fin(false);
+		peer.send_frame(first, b"hel").await.unwrap();
+		peer.send_ping(b"ping").await.unwrap();
+		peer.send_frame(Header::new(OpCode::Continue), b"lo").await.unwrap();
+
+		let mut message = Vec::new();
+		assert_eq!(Data::Text(5), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hello", &message[..]);
+		let (header, payload) = peer.receive_frame().await.unwrap();
+		assert!(header.is_masked());
+		assert_eq!((OpCode::Pong, &b"ping"[..]), (header.opcode(), &payload[..]));
+	}
+
+	#[tokio::test]
+	async fn malformed_header() {
+		let (builder, mut peer) = pair(Mode::Server);
+		let (_, mut receiver) = builder.finish();
+
+		// A PING with the reserved bits set.
+		peer.send_raw(&[0xf9, 0x00]).await.unwrap();
+		assert!(matches!(receiver.receive(&mut Vec::new()).await, Err(Error::Codec(_))));
+
+		let (builder, mut peer) = pair(Mode::Server);
+		let (_, mut receiver) = builder.finish();
+		peer.close().await.unwrap();
+		assert!(matches!(receiver.receive(&mut Vec::new()).await, Err(Error::Closed)));
+	}
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Websocket handshakes over Unix domain sockets.
+//!
+//! Local daemons often offer websocket endpoints on a Unix domain socket
+//! instead of a TCP port. As there is no host name, the client sends
+//! [`HOST`] as `Host` header, which servers should accept:
+//!
+//! ```no_run
+//! # async fn doc() -> Result<(), soketto::BoxedError> {
+//! let mut client = soketto::unix::connect("/run/daemon.sock", "/events").await?;
+//! client.handshake().await?;
+//! let (sender, receiver) = client.into_builder().finish();
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::handshake::{Client, Server};
+use std::{io, path::Path};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// The `Host` header value sent by [`connect`].
+pub const HOST: &str = "localhost";
+
+/// The stream used by [`connect`] and [`accept`].
+pub type Stream = Compat<UnixStream>;
+
+/// Connect to the socket at `path` and prepare a handshake for `resource`.
+///
+/// The handshake is sent with [`HOST`] as `Host` header. Use [`Client::new`]
+/// with a connected stream to send another one.
+pub async fn connect(path: impl AsRef<Path>, resource: &str) -> io::Result<Client<'static, Stream>> {
+	let socket = UnixStream::connect(path).await?;
+	Ok(Client::new(socket.compat(), HOST, String::from(resource)))
+}
+
+/// Accept a connection and prepare to receive its handshake request.
+///
+/// Unix domain sockets have no host name, so the `Host` header of the
+/// request is not meaningful and should not be restricted with
+/// [`Server::set_hosts`].
+pub async fn accept(listener: &UnixListener) -> io::Result<Server<'static, Stream>> {
+	let (socket, _) = listener.accept().await?;
+	Ok(Server::new(socket.compat()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{accept, connect, HOST};
+	use crate::handshake::{server::Response, ServerResponse};
+	use tokio::net::UnixListener;
+
+	#[tokio::test]
+	async fn handshake() {
+		let path = std::env::temp_dir().join(format!("soketto-{}.sock", rand::random::<u32>()));
+		let listener = UnixListener::bind(&path).unwrap();
+
+		let server = tokio::spawn(async move {
+			let mut server = accept(&listener).await.unwrap();
+			let request = server.receive_request().await.unwrap();
+			assert_eq!("/events", request.path());
+			assert_eq!(HOST.as_bytes(), request.headers().host);
+			let key = request.key();
+			server.send_response(&Response::Accept { key, protocol: None }).await.unwrap();
+			let (_, mut receiver) = server.into_builder().finish();
+			let mut message = Vec::new();
+			receiver.receive_data(&mut message).await.unwrap();
+			message
+		});
+
+		let mut client = connect(&path, "/events").await.unwrap();
+		assert!(matches!(client.handshake().await.unwrap(), ServerResponse::Accepted { .. }));
+		let (mut sender, _) = client.into_builder().finish();
+		sender.send_text("hello").await.unwrap();
+		sender.flush().await.unwrap();
+		assert_eq!(b"hello", &server.await.unwrap()[..]);
+		std::fs::remove_file(path).unwrap();
+	}
+}
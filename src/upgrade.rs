@@ -0,0 +1,14 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Integration with HTTP servers which support connection upgrades.
+
+#[cfg(feature = "hyper")]
+pub mod hyper;
+#[cfg(feature = "tower")]
+pub mod tower;
@@ -0,0 +1,73 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Upgrade [`hyper`] requests to websocket connections.
+//!
+//! ```no_run
+//! # async fn doc(mut req: hyper::Request<hyper::body::Incoming>) -> Result<(), soketto::BoxedError> {
+//! use soketto::{handshake::http::Server, upgrade::hyper::upgrade};
+//!
+//! let (response, upgrading) = upgrade(&mut req, Server::new())?;
+//! tokio::spawn(async move {
+//!     let (sender, receiver) = upgrading.await?.finish();
+//!     // ...
+//!     # Ok::<_, soketto::handshake::Error>(())
+//! });
+//! // Return `response` from the hyper service.
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::connection;
+use crate::handshake::http::{Error, Server};
+use futures::io::{BufReader, BufWriter};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use std::{
+	future::Future,
+	pin::Pin,
+	task::{Context, Poll},
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// The I/O resource of an upgraded connection.
+pub type Stream = BufReader<BufWriter<Compat<TokioIo<Upgraded>>>>;
+
+/// Validate a websocket upgrade request and prepare the upgrade.
+///
+/// On success, the returned [`http::Response`] must be sent back to the client
+/// (e.g. by returning it from the hyper service) and the [`Upgrading`] future
+/// must be awaited separately, as it only resolves once the response has been sent.
+pub fn upgrade<B>(req: &mut http::Request<B>, mut server: Server) -> Result<(http::Response<()>, Upgrading), Error> {
+	let response = server.receive_request(req)?;
+	let on_upgrade = hyper::upgrade::on(req);
+	Ok((response, Upgrading { server: Some(server), on_upgrade }))
+}
+
+/// A future which resolves to a [`connection::Builder`] once hyper has upgraded
+/// the HTTP connection.
+#[derive(Debug)]
+pub struct Upgrading {
+	server: Option<Server>,
+	on_upgrade: OnUpgrade,
+}
+
+impl Future for Upgrading {
+	type Output = Result<connection::Builder<Stream>, Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let upgraded = match Pin::new(&mut self.on_upgrade).poll(cx) {
+			Poll::Ready(Ok(upgraded)) => upgraded,
+			Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::Http(Box::new(e)))),
+			Poll::Pending => return Poll::Pending,
+		};
+		let server = self.server.take().expect("`Upgrading` polled after completion");
+		let stream = BufReader::new(BufWriter::new(TokioIo::new(upgraded).compat()));
+		Poll::Ready(Ok(server.into_builder(stream)))
+	}
+}
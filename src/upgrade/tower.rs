@@ -0,0 +1,200 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A [`tower_service::Service`] performing the websocket handshake as middleware.
+//!
+//! Websocket upgrade requests are answered by the middleware, which spawns a
+//! tokio task handing the upgraded connection to a user provided handler. All
+//! other requests are passed on to the inner service.
+//!
+//! ```no_run
+//! use soketto::{connection::Builder, upgrade::{hyper::Stream, tower::WebSocketLayer}};
+//! use tower_layer::Layer;
+//!
+//! async fn handler(request: http::Request<()>, builder: Builder<Stream>) {
+//!     let (sender, receiver) = builder.finish();
+//!     // ...
+//! }
+//!
+//! # fn doc<S>(inner: S) {
+//! let service = WebSocketLayer::new(handler).layer(inner);
+//! # }
+//! ```
+
+use super::hyper::{upgrade, Stream};
+use crate::connection;
+use crate::handshake::http::{is_upgrade_request, Server};
+use futures::future::{self, Either, Ready};
+use std::{
+	fmt,
+	future::Future,
+	sync::Arc,
+	task::{Context, Poll},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Creates the handshake [`Server`] for every upgrade request.
+type ServerFactory = Arc<dyn Fn() -> Server + Send + Sync>;
+
+/// A [`Layer`] wrapping services with [`WebSocketUpgrade`].
+#[derive(Clone)]
+pub struct WebSocketLayer<H> {
+	handler: H,
+	server: ServerFactory,
+}
+
+impl<H> fmt::Debug for WebSocketLayer<H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("WebSocketLayer").finish_non_exhaustive()
+	}
+}
+
+impl<H> WebSocketLayer<H> {
+	/// Create a new layer which hands upgraded connections to the given handler.
+	///
+	/// The handler is given the head of the upgrade request (i.e. without body)
+	/// and the [`connection::Builder`] of the websocket connection.
+	pub fn new(handler: H) -> Self {
+		WebSocketLayer { handler, server: Arc::new(Server::new) }
+	}
+
+	/// Set the function creating the handshake [`Server`] for each upgrade request.
+	///
+	/// This allows configuring extensions, e.g. permessage-deflate.
+	pub fn with_server<F>(mut self, f: F) -> Self
+	where
+		F: Fn() -> Server + Send + Sync + 'static,
+	{
+		self.server = Arc::new(f);
+		self
+	}
+}
+
+impl<S, H: Clone> Layer<S> for WebSocketLayer<H> {
+	type Service = WebSocketUpgrade<S, H>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		WebSocketUpgrade { inner, handler: self.handler.clone(), server: self.server.clone() }
+	}
+}
+
+/// A [`Service`] which answers websocket upgrade requests and passes all
+/// other requests to the inner service.
+///
+/// See [`WebSocketLayer`] for construction.
+#[derive(Clone)]
+pub struct WebSocketUpgrade<S, H> {
+	inner: S,
+	handler: H,
+	server: ServerFactory,
+}
+
+impl<S: fmt::Debug, H> fmt::Debug for WebSocketUpgrade<S, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("WebSocketUpgrade").field("inner", &self.inner).finish_non_exhaustive()
+	}
+}
+
+impl<S, H, F, B, R> Service<http::Request<B>> for WebSocketUpgrade<S, H>
+where
+	S: Service<http::Request<B>, Response = http::Response<R>>,
+	H: Fn(http::Request<()>, connection::Builder<Stream>) -> F + Clone + Send + 'static,
+	F: Future<Output = ()> + Send + 'static,
+	R: Default,
+{
+	type Response = http::Response<R>;
+	type Error = S::Error;
+	type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+		if !is_upgrade_request(&req) {
+			return Either::Left(self.inner.call(req));
+		}
+
+		let (response, upgrading) = match upgrade(&mut req, (self.server)()) {
+			Ok(x) => x,
+			Err(e) => {
+				log::debug!("websocket upgrade failed: {}", e);
+				let mut response = http::Response::new(R::default());
+				*response.status_mut() = http::StatusCode::BAD_REQUEST;
+				return Either::Right(future::ready(Ok(response)));
+			}
+		};
+
+		let mut head = http::Request::new(());
+		*head.method_mut() = req.method().clone();
+		*head.uri_mut() = req.uri().clone();
+		*head.version_mut() = req.version();
+		*head.headers_mut() = req.headers().clone();
+
+		let handler = self.handler.clone();
+		tokio::spawn(async move {
+			match upgrading.await {
+				Ok(builder) => handler(head, builder).await,
+				Err(e) => log::debug!("websocket upgrade failed: {}", e),
+			}
+		});
+
+		Either::Right(future::ready(Ok(response.map(|()| R::default()))))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::WebSocketLayer;
+	use std::convert::Infallible;
+	use tower_layer::Layer;
+	use tower_service::Service;
+
+	fn inner() -> impl Service<http::Request<()>, Response = http::Response<String>, Error = Infallible> {
+		struct Inner;
+		impl Service<http::Request<()>> for Inner {
+			type Response = http::Response<String>;
+			type Error = Infallible;
+			type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+			fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Infallible>> {
+				std::task::Poll::Ready(Ok(()))
+			}
+
+			fn call(&mut self, _: http::Request<()>) -> Self::Future {
+				futures::future::ready(Ok(http::Response::new(String::from("inner"))))
+			}
+		}
+		Inner
+	}
+
+	#[tokio::test]
+	async fn routes_requests() {
+		let layer = WebSocketLayer::new(|_, _| async {});
+		let mut service = layer.layer(inner());
+
+		let response = service.call(http::Request::new(())).await.unwrap();
+		assert_eq!("inner", response.body());
+
+		let request = http::Request::get("/")
+			.header("Connection", "upgrade")
+			.header("Upgrade", "websocket")
+			.header("Sec-WebSocket-Version", "13")
+			.header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+			.body(())
+			.unwrap();
+		let response = service.call(request).await.unwrap();
+		assert_eq!(http::StatusCode::SWITCHING_PROTOCOLS, response.status());
+		assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", response.headers()["Sec-WebSocket-Accept"]);
+
+		let request = http::Request::get("/").header("Connection", "upgrade").header("Upgrade", "websocket").body(());
+		let response = service.call(request.unwrap()).await.unwrap();
+		assert_eq!(http::StatusCode::BAD_REQUEST, response.status());
+	}
+}
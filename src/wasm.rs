@@ -0,0 +1,238 @@
+// Copyright (c) 2024 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Websocket connections in the browser, backed by its `WebSocket` API.
+//!
+//! Browsers do not give access to raw sockets, so [`connect`] opens a
+//! connection with the browser's `WebSocket` and returns a [`Sender`] and
+//! [`Receiver`] pair with the same methods as their
+//! [`connection`](crate::connection) counterparts where the browser supports
+//! them. The handshake, PINGs and extensions like compression are handled by
+//! the browser.
+//!
+//! This module only works on `wasm32-unknown-unknown` in a browser or a web worker.
+//!
+//! ```no_run
+//! # async fn doc() -> Result<(), soketto::connection::Error> {
+//! let (mut sender, mut receiver) = soketto::wasm::connect("wss://example.com/chat", &["chat"]).await?;
+//! sender.send_text("hello").await?;
+//! let mut message = Vec::new();
+//! receiver.receive_data(&mut message).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+	connection::{CloseCode, CloseReason, Error},
+	data::{Data, Incoming},
+};
+use futures::{channel::mpsc, StreamExt};
+use js_sys::{Array, ArrayBuffer, Uint8Array};
+use std::{fmt, io, rc::Rc};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+/// An event of the browser's `WebSocket`.
+#[derive(Debug)]
+enum Event {
+	Open,
+	Text(String),
+	Binary(Vec<u8>),
+	Error,
+	Close(CloseReason),
+}
+
+/// The browser's `WebSocket` and its event handlers.
+struct Socket {
+	ws: WebSocket,
+	_onopen: Closure<dyn FnMut(web_sys::Event)>,
+	_onmessage: Closure<dyn FnMut(MessageEvent)>,
+	_onerror: Closure<dyn FnMut(web_sys::Event)>,
+	_onclose: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl fmt::Debug for Socket {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Socket").field("url", &self.ws.url()).finish()
+	}
+}
+
+impl Drop for Socket {
+	fn drop(&mut self) {
+		self.ws.set_onopen(None);
+		self.ws.set_onmessage(None);
+		self.ws.set_onerror(None);
+		self.ws.set_onclose(None);
+		let _ = self.ws.close();
+	}
+}
+
+/// Connect to a `ws://` or `wss://` URL, offering the given protocols.
+///
+/// Fails if the browser cannot establish the connection. Browsers do not
+/// report the reason, e.g. whether the server rejected the handshake.
+pub async fn connect(url: &str, protocols: &[&str]) -> Result<(Sender, Receiver), Error> {
+	let protocols = protocols.iter().map(|p| JsValue::from_str(p)).collect::<Array>();
+	let ws = WebSocket::new_with_str_sequence(url, &protocols).map_err(js_error)?;
+	ws.set_binary_type(BinaryType::Arraybuffer);
+
+	let (tx, mut events) = mpsc::unbounded();
+	let onopen = {
+		let tx = tx.clone();
+		Closure::<dyn FnMut(web_sys::Event)>::new(move |_| {
+			let _ = tx.unbounded_send(Event::Open);
+		})
+	};
+	let onmessage = {
+		let tx = tx.clone();
+		Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| {
+			let data = e.data();
+			let event = match data.as_string() {
+				Some(text) => Event::Text(text),
+				None => match data.dyn_into::<ArrayBuffer>() {
+					Ok(buffer) => Event::Binary(Uint8Array::new(&buffer).to_vec()),
+					Err(_) => return,
+				},
+			};
+			let _ = tx.unbounded_send(event);
+		})
+	};
+	let onerror = {
+		let tx = tx.clone();
+		Closure::<dyn FnMut(web_sys::Event)>::new(move |_| {
+			let _ = tx.unbounded_send(Event::Error);
+		})
+	};
+	let onclose = Closure::<dyn FnMut(CloseEvent)>::new(move |e: CloseEvent| {
+		let _ = tx.unbounded_send(Event::Close(CloseReason { code: e.code().into(), descr: Some(e.reason()) }));
+	});
+	ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+	ws.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+	ws.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+	ws.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+	let socket = Rc::new(Socket { ws, _onopen: onopen, _onmessage: onmessage, _onerror: onerror, _onclose: onclose });
+
+	match events.next().await {
+		Some(Event::Open) => {
+			log::debug!("connected to {}", url);
+			let sender = Sender { socket: socket.clone() };
+			let receiver = Receiver { socket, events, is_closed: false };
+			Ok((sender, receiver))
+		}
+		_ => Err(Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "websocket connection failed"))),
+	}
+}
+
+/// The sending half of a browser connection.
+#[derive(Debug)]
+pub struct Sender {
+	socket: Rc<Socket>,
+}
+
+/// The receiving half of a browser connection.
+#[derive(Debug)]
+pub struct Receiver {
+	socket: Rc<Socket>,
+	events: mpsc::UnboundedReceiver<Event>,
+	is_closed: bool,
+}
+
+impl Sender {
+	/// The protocol selected by the server, if any.
+	pub fn protocol(&self) -> Option<String> {
+		Some(self.socket.ws.protocol()).filter(|p| !p.is_empty())
+	}
+
+	/// Send a text value over the websocket connection.
+	pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
+		self.check_open()?;
+		self.socket.ws.send_with_str(data.as_ref()).map_err(js_error)
+	}
+
+	/// Send a text value over the websocket connection.
+	pub async fn send_text_owned(&mut self, data: String) -> Result<(), Error> {
+		self.send_text(data).await
+	}
+
+	/// Send some binary data over the websocket connection.
+	pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		self.check_open()?;
+		self.socket.ws.send_with_u8_array(data.as_ref()).map_err(js_error)
+	}
+
+	/// Send some binary data over the websocket connection.
+	pub async fn send_binary_mut(&mut self, mut data: impl AsMut<[u8]>) -> Result<(), Error> {
+		self.send_binary(data.as_mut()).await
+	}
+
+	/// Flush the socket buffer.
+	///
+	/// This is a no-op, as the browser sends data as soon as possible.
+	pub async fn flush(&mut self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// Send a close message and close the connection.
+	pub async fn close(&mut self) -> Result<(), Error> {
+		self.socket.ws.close_with_code(CloseCode::NormalClosure.into()).map_err(js_error)
+	}
+
+	fn check_open(&self) -> Result<(), Error> {
+		if self.socket.ws.ready_state() != WebSocket::OPEN {
+			return Err(Error::Closed);
+		}
+		Ok(())
+	}
+}
+
+impl Receiver {
+	/// Receive the next websocket message.
+	///
+	/// The message is appended to the given `message` argument. PONGs are
+	/// handled by the browser and never returned.
+	pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
+		loop {
+			if self.is_closed {
+				return Err(Error::Closed);
+			}
+			match self.events.next().await {
+				Some(Event::Text(text)) => {
+					message.extend_from_slice(text.as_bytes());
+					return Ok(Incoming::Data(Data::Text(text.len())));
+				}
+				Some(Event::Binary(data)) => {
+					message.extend_from_slice(&data);
+					return Ok(Incoming::Data(Data::Binary(data.len())));
+				}
+				Some(Event::Close(reason)) => {
+					log::debug!("{} closed: {:?}", self.socket.ws.url(), reason);
+					self.is_closed = true;
+					return Ok(Incoming::Closed(reason));
+				}
+				// The browser fires a close event after an error.
+				Some(Event::Open | Event::Error) => (),
+				None => self.is_closed = true,
+			}
+		}
+	}
+
+	/// Receive the next websocket message, skipping over the close message.
+	pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
+		loop {
+			if let Incoming::Data(d) = self.receive(message).await? {
+				return Ok(d);
+			}
+		}
+	}
+}
+
+/// Convert an exception thrown by the browser into an error.
+fn js_error(e: JsValue) -> Error {
+	Error::Io(io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+}